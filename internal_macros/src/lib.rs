@@ -49,19 +49,50 @@ fn build_apply_body(
     }
 }
 
+// Validation of the object arguments when the runtime is built with the
+// `debug_runtime` feature, the arguments are numbered from 1 and the function
+// object isn't checked.
+fn build_arg_checks(parsed_invoke: &syn::ItemFn) -> Vec<syn::Stmt> {
+    let native_name = parsed_invoke.ident.to_string();
+
+    parsed_invoke
+        .decl
+        .inputs
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter_map(|(i, input)| match input {
+            syn::FnArg::Captured(syn::ArgCaptured {
+                pat: syn::Pat::Ident(pat),
+                ty: syn::Type::Path(ty_path),
+                ..
+            }) if type_path_to_simple_name(ty_path) == Ok("Object".to_string()) => {
+                let ident = &pat.ident;
+                Some(syn::parse_quote! {
+                    crate::checks::check_object(#native_name, #i, &#ident);
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
 #[proc_macro_attribute]
 pub fn trivial_apply(
     _attr: proc_macro::TokenStream,
     item: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
-    let parsed_invoke = syn::parse_macro_input!(item as syn::ItemFn);
-    let invoke_ident = &parsed_invoke.ident;
+    let mut parsed_invoke = syn::parse_macro_input!(item as syn::ItemFn);
 
     if let Some(vararg) = parsed_invoke.decl.variadic {
         return compile_error_spanned(vararg, "cannot derive trivial apply for variadic functions")
             .into();
     }
 
+    let arg_checks = build_arg_checks(&parsed_invoke);
+    parsed_invoke.block.stmts.splice(0..0, arg_checks);
+    let invoke_ident = &parsed_invoke.ident;
+
     let abi = &parsed_invoke.abi;
 
     let apply_fn_name_str = format!("{}_apply", invoke_ident.to_string().replace("_invoke", ""));
@@ -138,6 +169,7 @@ fn build_llvm_type_construction(
             match ty_name.as_str() {
                 "i64" | "u64" => quote_spanned!(ty.span()=> #ctx_ident.i64_type()),
                 "i32" | "u32" => quote_spanned!(ty.span()=> #ctx_ident.i32_type()),
+                "f64" => quote_spanned!(ty.span()=> #ctx_ident.f64_type()),
                 "c_char" => quote_spanned!(ty.span()=> #ctx_ident.i8_type()),
                 "c_void" => quote_spanned!(ty.span()=> #ctx_ident.void_type()),
                 "VaList" => quote_spanned!(ty.span()=> #module_ident
@@ -0,0 +1,80 @@
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use unlispc::error;
+
+pub const DEFAULT_CACHE_DIR: &str = "target-unlisp";
+
+/// Object files of compiled units, e.g. files, keyed by the fingerprint of
+/// their expanded HIR.
+pub struct ObjectCache {
+    dir: Option<PathBuf>,
+    explain: bool,
+    // why cached objects aren't used, see rebuild_all
+    rebuild_reason: Option<String>,
+}
+
+impl ObjectCache {
+    pub fn new(dir: Option<&str>, explain: bool) -> Self {
+        Self {
+            dir: dir.map(PathBuf::from),
+            explain: explain,
+            rebuild_reason: None,
+        }
+    }
+
+    /// Compiles every unit again, replacing the cached objects, e.g. when
+    /// the code compiled has to be shown. `reason` is what --explain-rebuild
+    /// says about it.
+    pub fn rebuild_all(&mut self, reason: &str) {
+        self.rebuild_reason = Some(reason.to_string());
+    }
+
+    fn explain(&self, unit: &str, msg: String) {
+        if self.explain {
+            eprintln!("{}: {}", unit, msg);
+        }
+    }
+
+    pub fn get_or_compile<F>(
+        &self,
+        unit: &str,
+        fingerprint: u64,
+        compile: F,
+    ) -> Result<Vec<u8>, Box<dyn Error>>
+    where
+        F: FnOnce() -> Result<Vec<u8>, error::Error>,
+    {
+        let dir = match self.dir.as_ref() {
+            Some(dir) => dir,
+            None => {
+                self.explain(unit, "rebuilt (cache disabled)".to_string());
+                return Ok(compile()?);
+            }
+        };
+
+        let cached_path = dir.join(format!("{:016x}.o", fingerprint));
+
+        let reason = match (&self.rebuild_reason, fs::read(&cached_path)) {
+            (Some(reason), _) => reason.clone(),
+            (None, Ok(object)) => {
+                self.explain(
+                    unit,
+                    format!("up to date, reusing {}", cached_path.display()),
+                );
+                return Ok(object);
+            }
+            (None, Err(_)) => format!("no cached object for fingerprint {:016x}", fingerprint),
+        };
+
+        let object = compile()?;
+
+        fs::create_dir_all(dir)?;
+        fs::write(&cached_path, &object)?;
+
+        self.explain(unit, format!("rebuilt ({})", reason));
+
+        Ok(object)
+    }
+}
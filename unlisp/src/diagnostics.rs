@@ -0,0 +1,102 @@
+use serde::Serialize;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// An error or warning about a form of a file, with the position reading
+/// stopped at for reader errors and where the form starts otherwise. Errors
+/// about the file as a whole are at its start.
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub file: String,
+    // counted from 1, the column in bytes
+    pub line: usize,
+    pub col: usize,
+}
+
+// set by --diagnostics=json
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+static COLLECTED: Mutex<Vec<Diagnostic>> = Mutex::new(Vec::new());
+
+pub fn set_json_output(json: bool) {
+    JSON_OUTPUT.store(json, Ordering::SeqCst);
+}
+
+pub fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::SeqCst)
+}
+
+/// Prints `text` to stderr, or with JSON output keeps the diagnostic for
+/// `flush`.
+pub fn report(diagnostic: Diagnostic, text: &str) {
+    if json_output() {
+        COLLECTED.lock().unwrap().push(diagnostic);
+    } else {
+        eprintln!("{}", text);
+    }
+}
+
+/// Reports an error about a file as a whole, e.g. one found after all of its
+/// forms were evaluated.
+pub fn report_file_error(file: &str, message: &str) {
+    let diagnostic = Diagnostic {
+        severity: Severity::Error,
+        message: message.to_string(),
+        file: file.to_string(),
+        line: 1,
+        col: 1,
+    };
+
+    report(diagnostic, message);
+}
+
+/// With JSON output, writes the diagnostics reported so far to stderr as a
+/// JSON array on one line, an empty one when there were none.
+pub fn flush() {
+    if !json_output() {
+        return;
+    }
+
+    let diagnostics: Vec<_> = COLLECTED.lock().unwrap().drain(..).collect();
+    eprintln!("{}", serde_json::to_string(&diagnostics).unwrap());
+}
+
+/// Writes the text of a debug dump, e.g. of `--dump-closures`, with the
+/// program's output rather than to stderr, so it never mixes with the
+/// diagnostics. While stdout carries an object file, that's stderr.
+pub fn dump(text: &str) {
+    if let Err(e) = unlisp_rt::output::write_stdout(format_args!("{}", text)) {
+        eprintln!("couldn't write dump: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_fields() {
+        let diagnostic = Diagnostic {
+            severity: Severity::Warning,
+            message: "wrong number of arguments".to_string(),
+            file: "a.unl".to_string(),
+            line: 3,
+            col: 1,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&[diagnostic]).unwrap(),
+            "[{\"severity\":\"warning\",\"message\":\"wrong number of arguments\",\
+             \"file\":\"a.unl\",\"line\":3,\"col\":1}]"
+        );
+    }
+}
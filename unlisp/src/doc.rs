@@ -0,0 +1,476 @@
+use unlispc::repr::{Call, Form, Lambda, Literal, Quote, HIR};
+
+use unlisp_rt::predefined;
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DefinitionKind {
+    Function,
+    Macro,
+    Native,
+}
+
+impl DefinitionKind {
+    fn name(self) -> &'static str {
+        match self {
+            DefinitionKind::Function => "function",
+            DefinitionKind::Macro => "macro",
+            DefinitionKind::Native => "native function",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Definition {
+    pub name: String,
+    // the parameters as written, e.g. "x (y integer) & rest"
+    pub arglist: String,
+    pub doc: Option<String>,
+    pub kind: DefinitionKind,
+    // file and line, none for natives
+    pub location: Option<(String, usize)>,
+}
+
+/// Definitions by name, a later definition of a name replaces the earlier
+/// one like it does when the files are loaded.
+pub type Definitions = BTreeMap<String, Definition>;
+
+pub fn collect_natives(definitions: &mut Definitions) {
+    for native in predefined::native_functions() {
+        let mut arglist = native.arglist;
+        if native.has_restarg {
+            arglist.push("&".to_string());
+            arglist.push("rest".to_string());
+        }
+
+        definitions.insert(
+            native.name.clone(),
+            Definition {
+                name: native.name,
+                arglist: arglist.join(" "),
+                doc: native.doc.map(str::to_string),
+                kind: DefinitionKind::Native,
+                location: None,
+            },
+        );
+    }
+}
+
+fn symbol(form: &Form) -> Option<&str> {
+    match form {
+        Form::Symbol(s) => Some(s.as_str()),
+        _ => None,
+    }
+}
+
+fn list(form: &Form) -> Option<&[Form]> {
+    match form {
+        Form::List(forms) => Some(forms.as_slice()),
+        _ => None,
+    }
+}
+
+// (quote name)
+fn quoted_symbol(form: &Form) -> Option<&str> {
+    let forms = list(form)?;
+    match forms {
+        [quote, name] if symbol(quote) == Some("quote") => symbol(name),
+        _ => None,
+    }
+}
+
+/// The function or macro a top-level form defines, recognized by its shape
+/// since a form that failed may not macroexpand.
+pub fn defined_name(form: &Form) -> Option<&str> {
+    let forms = list(form)?;
+    let is_lambda = |form: &Form| {
+        list(form).and_then(|forms| forms.first()).and_then(symbol) == Some("lambda")
+    };
+
+    match forms {
+        [head, name, _, ..] if matches!(symbol(head), Some("defun") | Some("defmacro")) => {
+            symbol(name)
+        }
+        [head, name, func] if symbol(head) == Some("set-symbol-function!") && is_lambda(func) => {
+            quoted_symbol(name)
+        }
+        _ => None,
+    }
+}
+
+// the calls a form can make and still only define things
+const DEFINING_CALLS: &[&str] = &[
+    "declare-pure",
+    "set-compiler-macro!",
+    "set-macro!",
+    "set-print-hook!",
+    "set-symbol-function!",
+    "set-symbol-value!",
+];
+
+/// Whether a macroexpanded top-level form only defines functions, macros and
+/// variables, like the expansions of defun, defmacro, defstruct and defvar.
+/// `unlisp doc` evaluates these so later forms expand, and no others.
+pub fn only_defines(hir: &HIR) -> bool {
+    match hir {
+        HIR::DeclareVar(_) | HIR::Literal(_) | HIR::Quote(_) => true,
+        HIR::Lambda(_) | HIR::Closure(_) => true,
+        HIR::LetBlock(block) => {
+            block.bindings.iter().all(|(_, val)| only_defines(val))
+                && block.body.iter().all(only_defines)
+        }
+        HIR::Call(call) => !call.is_lexical && DEFINING_CALLS.contains(&call.fn_name.as_str()),
+        HIR::If(_) | HIR::SetExpr(_) => false,
+    }
+}
+
+fn lambda_of(hir: &HIR) -> Option<&Lambda> {
+    match hir {
+        HIR::Lambda(lambda) => Some(lambda),
+        HIR::Closure(closure) => Some(&closure.lambda),
+        _ => None,
+    }
+}
+
+// (quote name)
+fn quoted_name(hir: &HIR) -> Option<&str> {
+    match hir {
+        HIR::Quote(Quote {
+            body: Literal::SymbolLiteral(name),
+        }) => Some(name),
+        _ => None,
+    }
+}
+
+fn variable(hir: &HIR) -> Option<&str> {
+    match hir {
+        HIR::Literal(Literal::SymbolLiteral(name)) => Some(name),
+        _ => None,
+    }
+}
+
+fn render_arglist(lambda: &Lambda) -> String {
+    let mut args: Vec<_> = lambda
+        .arglist
+        .iter()
+        .zip(&lambda.arg_types)
+        .map(|(arg, ty)| match ty {
+            Some(ty) => format!("({} {})", arg, ty.name()),
+            None => arg.clone(),
+        })
+        .collect();
+
+    if let Some(restarg) = &lambda.restarg {
+        args.push(format!("& {}", restarg));
+    }
+
+    args.join(" ")
+}
+
+// a string followed by more forms, a lone string is what the function returns
+fn docstring(body: &[HIR]) -> Option<String> {
+    match body.first() {
+        Some(HIR::Literal(Literal::StringLiteral(doc))) if body.len() > 1 => Some(doc.clone()),
+        _ => None,
+    }
+}
+
+struct Collector<'a, 'h> {
+    definitions: &'a mut Definitions,
+    file: &'a str,
+    line: usize,
+    // lambdas bound by the enclosing lets, innermost last
+    bound: Vec<(&'h str, &'h Lambda)>,
+    // variables passed to set-macro!, as defmacro's expansion does
+    macro_vars: Vec<&'h str>,
+}
+
+impl<'a, 'h> Collector<'a, 'h> {
+    fn walk(&mut self, hir: &'h HIR) {
+        match hir {
+            HIR::LetBlock(block) => {
+                let outer = self.bound.len();
+
+                for (var, val) in &block.bindings {
+                    self.walk(val);
+                    if let Some(lambda) = lambda_of(val) {
+                        self.bound.push((var, lambda));
+                    }
+                }
+
+                for hir in &block.body {
+                    self.walk(hir);
+                }
+
+                self.bound.truncate(outer);
+            }
+            HIR::Call(call) if !call.is_lexical => self.call(call),
+            _ => (),
+        }
+    }
+
+    fn call(&mut self, call: &'h Call) {
+        match (call.fn_name.as_str(), call.args.as_slice()) {
+            ("set-symbol-function!", [name, func]) => {
+                let name = match quoted_name(name) {
+                    Some(name) => name,
+                    None => return,
+                };
+
+                let (lambda, kind) = match variable(func) {
+                    Some(var) => {
+                        let lambda = self.bound.iter().rev().find(|(bound, _)| *bound == var);
+                        let kind = if self.macro_vars.contains(&var) {
+                            DefinitionKind::Macro
+                        } else {
+                            DefinitionKind::Function
+                        };
+                        (lambda.map(|(_, lambda)| *lambda), kind)
+                    }
+                    None => (lambda_of(func), DefinitionKind::Function),
+                };
+
+                if let Some(lambda) = lambda {
+                    // calls from a macro expansion have the span of the macro call
+                    let line = call.span.map_or(self.line, |span| span.line as usize);
+
+                    self.definitions.insert(
+                        name.to_string(),
+                        Definition {
+                            name: name.to_string(),
+                            arglist: render_arglist(lambda),
+                            doc: docstring(&lambda.body),
+                            kind,
+                            location: Some((self.file.to_string(), line)),
+                        },
+                    );
+                }
+            }
+            ("set-macro!", [func]) => match (variable(func), func) {
+                (Some(var), _) => self.macro_vars.push(var),
+                // (set-macro! (symbol-function (quote name)))
+                (None, HIR::Call(get)) if get.fn_name == "symbol-function" => {
+                    let name = get.args.first().and_then(quoted_name);
+                    if let Some(definition) = name.and_then(|n| self.definitions.get_mut(n)) {
+                        definition.kind = DefinitionKind::Macro;
+                    }
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Adds the functions and macros a macroexpanded top-level form defines,
+/// the form starting at `line` of `file`. These are found in the calls to
+/// set-symbol-function! and set-macro! the expansions of defun, defmacro
+/// and defstruct make, at top level or in lets.
+pub fn collect_hir(definitions: &mut Definitions, hir: &HIR, file: &str, line: usize) {
+    let mut collector = Collector {
+        definitions,
+        file,
+        line,
+        bound: vec![],
+        macro_vars: vec![],
+    };
+    collector.walk(hir);
+}
+
+fn signature(definition: &Definition) -> String {
+    if definition.arglist.is_empty() {
+        format!("({})", definition.name)
+    } else {
+        format!("({} {})", definition.name, definition.arglist)
+    }
+}
+
+/// One markdown document with an index and an entry per definition, sorted
+/// by name. Definitions without a docstring are marked as undocumented.
+pub fn render_markdown(definitions: &Definitions) -> String {
+    let mut out = String::from("# Reference\n\n");
+
+    for definition in definitions.values() {
+        out.push_str(&format!(
+            "- `{}` ({})\n",
+            definition.name,
+            definition.kind.name()
+        ));
+    }
+
+    for definition in definitions.values() {
+        out.push_str(&format!("\n## `{}`\n\n", definition.name));
+        out.push_str(&format!("`{}`\n\n", signature(definition)));
+
+        match &definition.location {
+            Some((file, line)) => out.push_str(&format!(
+                "{}, defined in {}:{}\n\n",
+                definition.kind.name(),
+                file,
+                line
+            )),
+            None => out.push_str(&format!("{} of the runtime\n\n", definition.kind.name())),
+        }
+
+        match &definition.doc {
+            Some(doc) => out.push_str(&format!("{}\n", doc)),
+            None => out.push_str("**Undocumented.**\n"),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unlispc::{lexer, reader, repr};
+
+    // The expansions of defmacro, defun and defstruct, written out since
+    // the stdlib isn't loaded.
+    const FIXTURE: &str = "\
+(let ((--macro-fn (lambda unless (c & body)
+                    \"Evaluates body when c is nil.\"
+                    (list (quote if) c nil body))))
+  (set-macro! --macro-fn)
+  (set-symbol-function! (quote unless) --macro-fn))
+
+; not a definition
+(println 1)
+
+(set-symbol-function!
+ (quote area)
+ (lambda area ((w integer) h)
+   \"Multiplies w and h.\"
+   (* w h)))
+
+(set-symbol-function! (quote answer) (lambda answer () \"the answer\"))
+
+(let ()
+  (set-symbol-function! (quote make-point) (lambda (x y) (make-record (quote point) x y)))
+  (set-symbol-function! (quote point-x) (lambda (p) (record-ref p (quote point) 0)))
+  (quote point))
+
+(set-symbol-function! (quote my-quote) (lambda (x) x))
+(set-macro! (symbol-function (quote my-quote)))
+
+(set-symbol-function! (quote area) (lambda area (w h) (* w h)))
+";
+
+    // collects the definitions of the forms, also returning whether each
+    // form only defines
+    fn collect_src(definitions: &mut Definitions, file: &str, src: &str) -> Vec<bool> {
+        unlisp_rt::defs::unlisp_rt_init_runtime();
+
+        reader::Reader::from_str(src)
+            .read_all_forms()
+            .unwrap()
+            .into_iter()
+            .map(|(start, form)| {
+                let hir = repr::form_to_hir_with_transforms(&form).unwrap();
+                collect_hir(definitions, &hir, file, lexer::line_at(src.as_bytes(), start));
+                only_defines(&hir)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_golden_markdown() {
+        let mut definitions = Definitions::new();
+        let defining = collect_src(&mut definitions, "fixture.unl", FIXTURE);
+        assert_eq!(defining, vec![true, false, true, true, true, true, true, true]);
+
+        assert_eq!(
+            render_markdown(&definitions),
+            "# Reference
+
+- `answer` (function)
+- `area` (function)
+- `make-point` (function)
+- `my-quote` (macro)
+- `point-x` (function)
+- `unless` (macro)
+
+## `answer`
+
+`(answer)`
+
+function, defined in fixture.unl:16
+
+**Undocumented.**
+
+## `area`
+
+`(area w h)`
+
+function, defined in fixture.unl:26
+
+**Undocumented.**
+
+## `make-point`
+
+`(make-point x y)`
+
+function, defined in fixture.unl:18
+
+**Undocumented.**
+
+## `my-quote`
+
+`(my-quote x)`
+
+macro, defined in fixture.unl:23
+
+**Undocumented.**
+
+## `point-x`
+
+`(point-x p)`
+
+function, defined in fixture.unl:18
+
+**Undocumented.**
+
+## `unless`
+
+`(unless c & body)`
+
+macro, defined in fixture.unl:1
+
+Evaluates body when c is nil.
+"
+        );
+    }
+
+    #[test]
+    fn test_docstring_and_annotations() {
+        let mut definitions = Definitions::new();
+        collect_src(
+            &mut definitions,
+            "f.unl",
+            "(set-symbol-function! (quote area) (lambda ((w integer) h) \"Doc.\" (* w h)))",
+        );
+
+        let area = &definitions["area"];
+        assert_eq!(area.arglist, "(w integer) h");
+        assert_eq!(area.doc, Some("Doc.".to_string()));
+        assert_eq!(area.location, Some(("f.unl".to_string(), 1)));
+    }
+
+    #[test]
+    fn test_natives_have_docs() {
+        unlisp_rt::defs::unlisp_rt_init_runtime();
+
+        let mut definitions = Definitions::new();
+        collect_natives(&mut definitions);
+
+        let undocumented: Vec<_> = definitions
+            .values()
+            .filter(|definition| definition.doc.is_none())
+            .map(|definition| definition.name.as_str())
+            .collect();
+        assert_eq!(undocumented, Vec::<&str>::new());
+        assert_eq!(definitions["cons"].arglist, "x list");
+    }
+}
@@ -0,0 +1,189 @@
+use unlispc::repr::{self, Form, Inlining, Lambda, Literal, HIR};
+
+/// The form a HIR is compiled from: macros are expanded, constant calls
+/// folded and inlined calls replaced by the inlined bodies, like the
+/// compiler sees them. Closures are written as the lambdas they're made of,
+/// and calls through a variable as `funcall`.
+pub fn hir_to_form(hir: &HIR) -> Form {
+    match hir {
+        HIR::DeclareVar(declare_var) => {
+            Form::List(vec![symbol("declare-var"), symbol(&declare_var.var_name)])
+        }
+        HIR::Literal(Literal::ListLiteral(items)) if items.is_empty() => Form::List(vec![]),
+        HIR::Literal(literal @ Literal::ListLiteral(_))
+        | HIR::Literal(literal @ Literal::DottedListLiteral(..)) => quoted(literal),
+        HIR::Literal(literal) => literal_to_form(literal),
+        HIR::Lambda(lambda) => lambda_to_form(lambda),
+        HIR::Closure(closure) => lambda_to_form(&closure.lambda),
+        HIR::Call(call) => {
+            let mut form = if call.is_lexical {
+                vec![symbol("funcall"), symbol(&call.fn_name)]
+            } else {
+                vec![symbol(&call.fn_name)]
+            };
+            form.extend(call.args.iter().map(hir_to_form));
+
+            Form::List(form)
+        }
+        HIR::LetBlock(let_block) => {
+            // what (funcall f args...) is converted into
+            if let ([(var, f)], [HIR::Call(call)]) =
+                (let_block.bindings.as_slice(), let_block.body.as_slice())
+            {
+                if var == repr::FUNCALL_VAR && call.is_lexical && call.fn_name == *var {
+                    let mut form = vec![symbol("funcall"), hir_to_form(f)];
+                    form.extend(call.args.iter().map(hir_to_form));
+
+                    return Form::List(form);
+                }
+            }
+
+            let bindings = let_block
+                .bindings
+                .iter()
+                .map(|(var, val)| Form::List(vec![symbol(var), hir_to_form(val)]))
+                .collect();
+
+            let mut form = vec![symbol("let"), Form::List(bindings)];
+            form.extend(let_block.body.iter().map(hir_to_form));
+
+            Form::List(form)
+        }
+        // keywords evaluate to themselves
+        HIR::Quote(quote) => match &quote.body {
+            Literal::SymbolLiteral(s) if s.starts_with(':') => symbol(s),
+            literal => quoted(literal),
+        },
+        HIR::If(if_hir) => {
+            let mut form = vec![
+                symbol("if"),
+                hir_to_form(&if_hir.cond),
+                hir_to_form(&if_hir.then_hir),
+            ];
+            form.extend(if_hir.else_hir.as_ref().map(|hir| hir_to_form(hir)));
+
+            Form::List(form)
+        }
+        HIR::SetExpr(set_expr) => Form::List(vec![
+            symbol("set!"),
+            symbol(&set_expr.name),
+            hir_to_form(&set_expr.val),
+        ]),
+    }
+}
+
+fn symbol(name: &str) -> Form {
+    Form::Symbol(name.to_string())
+}
+
+fn quoted(literal: &Literal) -> Form {
+    Form::List(vec![symbol("quote"), literal_to_form(literal)])
+}
+
+fn literal_to_form(literal: &Literal) -> Form {
+    match literal {
+        Literal::T => Form::T,
+        Literal::SymbolLiteral(s) => symbol(s),
+        Literal::IntegerLiteral(i) => Form::Integer(*i),
+        Literal::BigIntegerLiteral(i) => Form::BigInteger(i.clone()),
+        Literal::FloatLiteral(f) => Form::Float(*f),
+        Literal::CharLiteral(c) => Form::Char(*c),
+        Literal::StringLiteral(s) => Form::String(s.clone()),
+        Literal::ListLiteral(items) => Form::List(items.iter().map(literal_to_form).collect()),
+        Literal::DottedListLiteral(items, tail) => Form::DottedList(
+            items.iter().map(literal_to_form).collect(),
+            Box::new(literal_to_form(tail)),
+        ),
+    }
+}
+
+fn lambda_to_form(lambda: &Lambda) -> Form {
+    let mut form = vec![symbol("lambda")];
+    form.extend(lambda.name.as_deref().map(symbol));
+
+    let mut arglist: Vec<_> = lambda
+        .arglist
+        .iter()
+        .zip(&lambda.arg_types)
+        .map(|(arg, ty)| match ty {
+            Some(ty) => Form::List(vec![symbol(arg), symbol(ty.name())]),
+            None => symbol(arg),
+        })
+        .collect();
+    if let Some(restarg) = &lambda.restarg {
+        arglist.push(symbol("&"));
+        arglist.push(symbol(restarg));
+    }
+    form.push(Form::List(arglist));
+
+    // the options that change how the lambda is compiled, unknown ones are
+    // ignored by the compiler too
+    let mut options = vec![];
+    match lambda.declarations.inlining {
+        Inlining::Default => (),
+        Inlining::Inline => options.push(Form::List(vec![symbol("inline")])),
+        Inlining::NotInline => options.push(Form::List(vec![symbol("notinline")])),
+    }
+    if !lambda.declarations.optimize {
+        options.push(Form::List(vec![symbol("optimize"), Form::Integer(0)]));
+    }
+    if !options.is_empty() {
+        options.insert(0, symbol("declare"));
+        form.push(Form::List(options));
+    }
+
+    form.extend(lambda.body.iter().map(hir_to_form));
+
+    Form::List(form)
+}
+
+/// The forms written one per line, as the printer writes data.
+pub fn forms_text(forms: &[Form]) -> String {
+    forms
+        .iter()
+        .map(|form| {
+            let obj = repr::form_to_runtime_object(form).expect("expanded forms have no labels");
+            format!("{}\n", obj)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unlispc::reader;
+
+    fn expand(src: &str) -> String {
+        unlisp_rt::defs::unlisp_rt_init_runtime();
+
+        let forms: Vec<_> = reader::Reader::from_str(src)
+            .read_all_forms()
+            .unwrap()
+            .into_iter()
+            .map(|(_, form)| hir_to_form(&repr::form_to_hir_with_transforms(&form).unwrap()))
+            .collect();
+
+        forms_text(&forms)
+    }
+
+    #[test]
+    fn test_forms_read_back_as_written() {
+        let src = "\
+(declare-var v)
+(let ((x 1) (y nil)) (if x (quote (a . b)) :k) (set! y \"s\") (quote t))
+(lambda named ((x integer) & rest) (declare (inline)) (f x #\\a 1.5) rest)
+(lambda (x) (declare (optimize 0)) x)
+(funcall (quote car) 1 2)
+";
+
+        assert_eq!(expand(src), src);
+    }
+
+    #[test]
+    fn test_calls_through_variables_are_funcalls() {
+        assert_eq!(
+            expand("(let ((g (quote car))) (g 1))"),
+            "(let ((g (quote car))) (funcall g 1))\n"
+        );
+    }
+}
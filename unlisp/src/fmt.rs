@@ -0,0 +1,398 @@
+use unlispc::lexer::{Lexer, Token};
+use unlispc::reader;
+
+use std::error::Error;
+use std::fmt;
+
+/// Lines are wrapped to fit this many columns where possible.
+pub const WIDTH: usize = 100;
+const INDENT: usize = 2;
+
+// calls aligning their arguments under the first one only do it when it
+// starts before this column
+const MAX_ALIGN_COLUMN: usize = WIDTH / 2;
+
+// forms printed with their first arguments on the head's line and the rest,
+// the body, indented by INDENT, with the number of those first arguments
+const BODY_FORMS: &[(&str, usize)] = &[
+    ("defun", 2),
+    ("defmacro", 2),
+    ("lambda", 1),
+    ("let", 1),
+    ("macrolet", 1),
+    ("symbol-macrolet", 1),
+    ("when", 1),
+    ("unless", 1),
+    ("when-let", 1),
+    ("if-let", 1),
+    ("destructuring-bind", 2),
+    ("prog1", 1),
+    ("prog2", 2),
+    ("do", 0),
+    ("comment", 0),
+    ("ignore-errors", 0),
+    ("deftest", 1),
+    ("defstruct", 1),
+    ("defvar", 1),
+    ("defonce", 1),
+];
+
+// body forms whose body goes on lines of its own even when they'd fit on one
+const ALWAYS_BROKEN: &[&str] = &["defun", "defmacro"];
+
+#[derive(Debug)]
+pub struct FormatError {
+    // the line reading stopped on, none when the source was read fine
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for FormatError {}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    // as written in the source, so numbers, strings and escapes are kept
+    Atom { text: String, symbol: bool },
+    List(Vec<Node>),
+    Comment(String),
+}
+
+// a top-level form or comment
+struct Item {
+    node: Node,
+    // there were blank lines before it, they're kept as one
+    blank_before: bool,
+}
+
+fn read_items(src: &str) -> Result<Vec<Item>, FormatError> {
+    let mut lexer = Lexer::from_str(src);
+    lexer.set_keep_comments(true);
+
+    let error = |lexer: &Lexer<_>, message: String| FormatError {
+        line: Some(lexer.line()),
+        message,
+    };
+
+    let mut items = vec![];
+    let mut open_lists: Vec<Vec<Node>> = vec![];
+    // the line a top-level item starts on and the one the previous one ends on
+    let mut start_line = 0;
+    let mut prev_end_line = None;
+
+    loop {
+        let tok = match lexer.next_token() {
+            Ok(Some(tok)) => tok,
+            Ok(None) => break,
+            Err(e) => return Err(error(&lexer, e.to_string())),
+        };
+        let start = lexer.token_start();
+
+        if open_lists.is_empty() {
+            start_line = lexer.line_of(start);
+        }
+
+        let node = match tok {
+            Token::LeftPar => {
+                open_lists.push(vec![]);
+                continue;
+            }
+            Token::RightPar => match open_lists.pop() {
+                Some(items) => Node::List(items),
+                None => return Err(error(&lexer, "unbalanced parens".to_string())),
+            },
+            Token::Comment(text) => Node::Comment(text.trim_end().to_string()),
+            tok => Node::Atom {
+                text: src[start..lexer.position()].to_string(),
+                symbol: matches!(tok, Token::Symbol(_) | Token::EscapedSymbol(_)),
+            },
+        };
+
+        match open_lists.last_mut() {
+            Some(list) => list.push(node),
+            None => {
+                items.push(Item {
+                    node,
+                    blank_before: prev_end_line.is_some_and(|end| start_line > end + 1),
+                });
+                prev_end_line = Some(lexer.line());
+            }
+        }
+    }
+
+    if !open_lists.is_empty() {
+        return Err(error(&lexer, "unexpected end of input".to_string()));
+    }
+
+    Ok(items)
+}
+
+// the node on one line, none when it can't be written on one
+fn flat(node: &Node) -> Option<String> {
+    match node {
+        Node::Atom { text, .. } if text.contains(['\n', '\r']) => None,
+        Node::Atom { text, .. } => Some(text.clone()),
+        Node::Comment(_) => None,
+        Node::List(items) => {
+            let items: Option<Vec<_>> = items.iter().map(flat).collect();
+            Some(format!("({})", items?.join(" ")))
+        }
+    }
+}
+
+fn column(out: &str) -> usize {
+    let line_start = out.rfind('\n').map_or(0, |i| i + 1);
+    out[line_start..].chars().count()
+}
+
+fn newline(out: &mut String, col: usize) {
+    out.push('\n');
+    out.push_str(&" ".repeat(col));
+}
+
+// how many items go on the list's first line and the column the others are
+// indented to, one per line
+fn layout(items: &[Node], col: usize) -> (usize, usize) {
+    let head = match &items[0] {
+        Node::Atom { text, symbol: true } => text.as_str(),
+        // data and lists of lists, like let bindings and cond clauses, have
+        // the items aligned under the first one
+        _ => return (1, col + 1),
+    };
+
+    let on_first_line = |count: usize| {
+        !items
+            .iter()
+            .take(count)
+            .any(|item| matches!(item, Node::Comment(_)))
+    };
+
+    if let Some((_, args)) = BODY_FORMS.iter().find(|(name, _)| *name == head) {
+        let named_lambda = match items.get(1) {
+            Some(Node::Atom { .. }) => head == "lambda",
+            _ => false,
+        };
+        let count = 1 + args + named_lambda as usize;
+
+        if on_first_line(count) {
+            return (count.min(items.len()), col + INDENT);
+        }
+    }
+
+    let arg_col = col + 1 + head.chars().count() + 1;
+    if items.len() > 1 && on_first_line(2) && arg_col <= MAX_ALIGN_COLUMN {
+        (2, arg_col)
+    } else {
+        (1, col + INDENT)
+    }
+}
+
+// writes the node starting at the current column of out
+fn print(node: &Node, out: &mut String) {
+    let col = column(out);
+
+    let items = match node {
+        Node::Atom { text, .. } | Node::Comment(text) => return out.push_str(text),
+        Node::List(items) => items,
+    };
+
+    let always_broken = match items.first() {
+        Some(Node::Atom { text, .. }) => ALWAYS_BROKEN.contains(&text.as_str()) && items.len() > 3,
+        _ => false,
+    };
+
+    if let Some(flat) = flat(node).filter(|_| !always_broken) {
+        if col + flat.chars().count() <= WIDTH {
+            return out.push_str(&flat);
+        }
+    }
+
+    let (on_first_line, body_col) = layout(items, col);
+
+    // arguments that are all atoms fill the lines instead of taking one each
+    let fill = items[1..].iter().all(|item| match item {
+        Node::Atom { .. } => flat(item).is_some(),
+        _ => false,
+    });
+
+    out.push('(');
+    for (i, item) in items.iter().enumerate() {
+        match (i, item) {
+            (0, _) => (),
+            (i, _) if i < on_first_line => out.push(' '),
+            (_, Node::Atom { text, .. })
+                if fill && column(out) + 1 + text.chars().count() <= WIDTH =>
+            {
+                out.push(' ')
+            }
+            _ => newline(out, body_col),
+        }
+        print(item, out);
+    }
+
+    // a comment runs to the end of the line
+    if let Some(Node::Comment(_)) = items.last() {
+        newline(out, body_col);
+    }
+    out.push(')');
+}
+
+/// Formats a whole file: top-level forms and comments are written one after
+/// another, with blank lines between them kept as a single one, and comments
+/// go on their own lines before the form that follows them. A '#!' line is
+/// kept, a byte order mark isn't and lines end with "\n". Fails on sources
+/// the reader can't read, the result reads back to the same forms.
+pub fn format_source(src: &str) -> Result<String, FormatError> {
+    let mut reader = reader::Reader::from_str(src);
+    let forms = reader.read_all_forms().map_err(|e| FormatError {
+        line: Some(reader.line()),
+        message: e.to_string(),
+    })?;
+
+    let mut out = String::new();
+
+    let without_bom = src.trim_start_matches('\u{feff}');
+    if without_bom.starts_with("#!") {
+        out.push_str(without_bom.lines().next().unwrap().trim_end());
+        out.push('\n');
+    }
+
+    for (i, item) in read_items(src)?.iter().enumerate() {
+        if i > 0 && item.blank_before {
+            out.push('\n');
+        }
+        print(&item.node, &mut out);
+        out.push('\n');
+    }
+
+    // only the positions of the forms may differ
+    let read_back = reader::Reader::from_str(&out).read_all_forms().ok();
+    let same_forms = read_back.is_some_and(|read_back| {
+        read_back.len() == forms.len()
+            && read_back.iter().zip(&forms).all(|((_, a), (_, b))| a == b)
+    });
+
+    if !same_forms {
+        return Err(FormatError {
+            line: None,
+            message: "formatting would change the forms read, the source is left as is".to_string(),
+        });
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STDLIB: &str = include_str!("../../stdlib.unl");
+    const STDLIB_TESTS: &str = include_str!("../../unlispc/tests/stdlib-tests.unl");
+
+    const FIXTURE: &str = "\
+#!/usr/bin/env unlisp
+; leading comment
+(defun  area ((w integer) h)  \"Multiplies w and h.\"  (* w h))   ; trailing
+
+
+(defun long-one (first-argument second-argument) (let ((sum (+ first-argument second-argument)) (product (* first-argument second-argument))) (if (> sum product) (list :sum sum) (list :product product))))
+(cond ((emptyp x) ; nothing there
+   0) ((consp x) (+ 1 (length (cdr x)))) (t 1))
+(list 1 2.50 #\\space |odd symbol| \"multi
+line\" (quote (a . b)))
+(foo ; dangling
+)
+";
+
+    #[test]
+    fn test_fixture() {
+        assert_eq!(
+            format_source(FIXTURE).unwrap(),
+            "\
+#!/usr/bin/env unlisp
+; leading comment
+(defun area ((w integer) h)
+  \"Multiplies w and h.\"
+  (* w h))
+; trailing
+
+(defun long-one (first-argument second-argument)
+  (let ((sum (+ first-argument second-argument)) (product (* first-argument second-argument)))
+    (if (> sum product) (list :sum sum) (list :product product))))
+(cond ((emptyp x)
+       ; nothing there
+       0)
+      ((consp x) (+ 1 (length (cdr x))))
+      (t 1))
+(list 1
+      2.50
+      #\\space
+      |odd symbol|
+      \"multi
+line\"
+      (quote (a . b)))
+(foo
+  ; dangling
+  )
+"
+        );
+    }
+
+    #[test]
+    fn test_long_argument_lists_are_wrapped() {
+        let args: Vec<_> = (0..30).map(|i| format!("argument-{}", i)).collect();
+        let src = format!("(some-function {})", args.join(" "));
+
+        let formatted = format_source(&src).unwrap();
+        let lines: Vec<_> = formatted.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines.iter().all(|line| line.len() <= WIDTH));
+        assert!(lines[0].starts_with("(some-function argument-0 argument-1 "));
+        assert!(lines[1].starts_with("               argument-"));
+    }
+
+    #[test]
+    fn test_idempotent() {
+        for src in [FIXTURE, STDLIB, STDLIB_TESTS].iter() {
+            let once = format_source(src).unwrap();
+            assert_eq!(format_source(&once).unwrap(), once);
+        }
+    }
+
+    #[test]
+    fn test_idempotent_on_every_prefix_of_stdlib() {
+        // every top-level form alone, and with the ones before it
+        let forms = reader::Reader::from_str(STDLIB).read_all_forms().unwrap();
+        for (start, _) in forms {
+            for src in [&STDLIB[start..], &STDLIB[..start]].iter() {
+                let once = format_source(src).unwrap();
+                assert_eq!(format_source(&once).unwrap(), once);
+            }
+        }
+    }
+
+    #[test]
+    fn test_comments_are_kept() {
+        let src = "; a\n(f x ; b\n y) ; c\n; d";
+        let formatted = format_source(src).unwrap();
+
+        let comments: Vec<_> = formatted
+            .lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with(';'))
+            .collect();
+        assert_eq!(comments, vec!["; a", "; b", "; c", "; d"]);
+    }
+
+    #[test]
+    fn test_reader_errors() {
+        let err = format_source("(f x)\n(g").unwrap_err();
+        assert_eq!(err.line, Some(2));
+        assert!(format_source("(. a)").is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use unlispc::error::{self, ErrorType};
+use unlispc::reader::{self, Reader};
+use unlispc::repr::{Form, FormSpans};
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+/// Files included by included files are read up to this many levels deep.
+pub const MAX_INCLUDE_DEPTH: usize = 16;
+
+const INCLUDE: &str = "%include";
+
+/// A top-level form or the error reading it, with the file and position
+/// it's reported at.
+pub struct SourceForm {
+    pub form: Result<Form, Box<dyn Error>>,
+    // where the form and the forms in it start, see repr::form_to_hir_with_spans
+    pub spans: FormSpans,
+    pub file: String,
+    pub line: usize,
+    pub col: usize,
+}
+
+// a file being read, the ones an include is in make up the chain it's
+// checked against for cycles
+#[derive(Clone)]
+struct SourceFile {
+    name: String,
+    // included paths are relative to it
+    dir: PathBuf,
+    // none for stdin, which can't be included
+    canonical: Option<PathBuf>,
+}
+
+fn include_error(message: String) -> Box<dyn Error> {
+    Box::new(error::Error::new(ErrorType::Reader, message))
+}
+
+// the path of a (%include "path") directive, none for other forms
+fn include_path(form: &Form) -> Option<Result<&str, Box<dyn Error>>> {
+    let items = match form {
+        Form::List(items) => items,
+        _ => return None,
+    };
+
+    match items.as_slice() {
+        [Form::Symbol(head), Form::String(path)] if head == INCLUDE => Some(Ok(path)),
+        [Form::Symbol(head), ..] if head == INCLUDE => Some(Err(include_error(format!(
+            "{} takes a single string, the path of a file",
+            INCLUDE
+        )))),
+        _ => None,
+    }
+}
+
+// the forms of the file a directive in the last file of chain names
+fn read_included(path: &str, chain: &[SourceFile]) -> Result<Vec<SourceForm>, Box<dyn Error>> {
+    if chain.len() > MAX_INCLUDE_DEPTH {
+        return Err(include_error(format!(
+            "includes nested deeper than {} files",
+            MAX_INCLUDE_DEPTH
+        )));
+    }
+
+    let resolved = chain.last().unwrap().dir.join(path);
+    let name = resolved.to_string_lossy().into_owned();
+
+    let src = fs::read_to_string(&resolved)
+        .map_err(|e| include_error(format!("couldn't include {}: {}", name, e)))?;
+
+    let canonical = fs::canonicalize(&resolved).ok();
+    let is_same = |f: &SourceFile| f.canonical.is_some() && f.canonical == canonical;
+    if let Some(start) = chain.iter().position(is_same) {
+        let names: Vec<_> = chain[start..].iter().map(|f| f.name.as_str()).collect();
+        return Err(include_error(format!(
+            "include cycle: {} -> {}",
+            names.join(" -> "),
+            name
+        )));
+    }
+
+    let mut chain = chain.to_vec();
+    chain.push(SourceFile {
+        name,
+        dir: resolved.parent().map(PathBuf::from).unwrap_or_default(),
+        canonical,
+    });
+
+    Ok(FileForms::with_chain(Reader::from_str(&src), chain).collect())
+}
+
+/// The top-level forms of a file with `(%include "path")` directives
+/// replaced by the forms of the files they name, read before anything is
+/// macroexpanded. Paths are relative to the file the directive is in, or to
+/// the current directory for stdin, and the forms keep the name and lines of
+/// their own file. A directive whose file can't be read is a reader error
+/// reported at the directive, like cycles and includes nested deeper than
+/// `MAX_INCLUDE_DEPTH`.
+pub struct FileForms<S: reader::Source> {
+    reader: Reader<S>,
+    chain: Vec<SourceFile>,
+    spliced: VecDeque<SourceForm>,
+}
+
+impl<S: reader::Source> FileForms<S> {
+    /// `path` is where the file was opened from, `name` the name it's
+    /// reported by.
+    pub fn new(reader: Reader<S>, path: &str, name: &str, is_stdin: bool) -> Self {
+        let path = PathBuf::from(path);
+        let file = if is_stdin {
+            SourceFile {
+                name: name.to_string(),
+                dir: PathBuf::new(),
+                canonical: None,
+            }
+        } else {
+            SourceFile {
+                name: name.to_string(),
+                dir: path.parent().map(PathBuf::from).unwrap_or_default(),
+                canonical: fs::canonicalize(&path).ok(),
+            }
+        };
+
+        Self::with_chain(reader, vec![file])
+    }
+
+    fn with_chain(reader: Reader<S>, chain: Vec<SourceFile>) -> Self {
+        Self {
+            reader,
+            chain,
+            spliced: VecDeque::new(),
+        }
+    }
+}
+
+impl<S: reader::Source> Iterator for FileForms<S> {
+    type Item = SourceForm;
+
+    fn next(&mut self) -> Option<SourceForm> {
+        loop {
+            if let Some(form) = self.spliced.pop_front() {
+                return Some(form);
+            }
+
+            let file = self.chain.last().unwrap().name.clone();
+
+            let form = match self.reader.read_form() {
+                Ok(Some(form)) => form,
+                Ok(None) => return None,
+                Err(e) => {
+                    return Some(SourceForm {
+                        form: Err(e),
+                        spans: FormSpans::default(),
+                        file,
+                        line: self.reader.line(),
+                        col: self.reader.column(),
+                    })
+                }
+            };
+
+            let line = self.reader.form_line();
+            let col = self.reader.form_column();
+            let spans = self.reader.form_spans().clone();
+
+            // owned, so the form can be moved when it isn't a directive
+            let path = include_path(&form).map(|path| path.map(str::to_string));
+
+            let spliced = match path {
+                None => vec![SourceForm {
+                    form: Ok(form),
+                    spans,
+                    file,
+                    line,
+                    col,
+                }],
+                Some(path) => match path.and_then(|path| read_included(&path, &self.chain)) {
+                    Ok(forms) => forms,
+                    Err(e) => vec![SourceForm {
+                        form: Err(e),
+                        spans,
+                        file,
+                        line,
+                        col,
+                    }],
+                },
+            };
+            self.spliced.extend(spliced);
+        }
+    }
+}
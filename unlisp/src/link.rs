@@ -0,0 +1,439 @@
+use std::fmt;
+use std::process::Command;
+
+pub const DEFAULT_LINKER: &str = "clang";
+
+/// Most times the link command is run, the first attempt included.
+pub const MAX_ATTEMPTS: usize = 3;
+
+// libraries added on Linux that the runtime links without on systems that
+// don't have them separately, e.g. musl has both in libc
+const OPTIONAL_LIBS: &[&str] = &["pthread", "dl"];
+
+const LLD_FLAG: &str = "-fuse-ld=lld";
+
+/// A cause of a failed link recognized in the linker's output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    /// A `-lNAME` library, by its name.
+    MissingLibrary(String),
+    UnknownFlag(String),
+    /// A C runtime start file like `crt1.o`, which no argument can replace.
+    MissingCrtFile(String),
+    /// The system linker the compiler driver runs.
+    MissingLinker,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::MissingLibrary(lib) => write!(f, "library {} not found", lib),
+            Failure::UnknownFlag(flag) => write!(f, "unknown flag {}", flag),
+            Failure::MissingCrtFile(file) => write!(f, "C runtime file {} not found", file),
+            Failure::MissingLinker => write!(f, "the system linker is missing"),
+        }
+    }
+}
+
+// the text between the first pair of quotes in `s`, which may be `'`, `"`
+// or a backtick closed by `'`
+fn quoted(s: &str) -> Option<&str> {
+    let start = s.find(|c| c == '\'' || c == '"' || c == '`')?;
+    let open = s[start..].chars().next()?;
+    let close = if open == '`' { '\'' } else { open };
+    let rest = &s[start + 1..];
+
+    rest.find(close).map(|end| &rest[..end])
+}
+
+// the word after `marker` in `line`, up to a colon or whitespace
+fn word_after<'a>(line: &'a str, marker: &str) -> Option<&'a str> {
+    let start = line.find(marker)? + marker.len();
+    let word = line[start..]
+        .split(|c: char| c == ':' || c.is_whitespace())
+        .next()?;
+
+    Some(word).filter(|word| !word.is_empty())
+}
+
+fn is_crt_file(name: &str) -> bool {
+    name.starts_with("crt") && name.ends_with(".o")
+}
+
+fn parse_line(line: &str) -> Option<Failure> {
+    // GNU ld with glibc or musl: "cannot find -ldl", "cannot find crt1.o",
+    // lld: "unable to find library -ldl", "cannot open crt1.o"
+    let missing = word_after(line, "cannot find ")
+        .or_else(|| word_after(line, "unable to find library "))
+        .or_else(|| word_after(line, "cannot open "));
+
+    if let Some(missing) = missing {
+        if missing.starts_with("-l") && missing.len() > 2 {
+            return Some(Failure::MissingLibrary(missing[2..].to_string()));
+        }
+        if is_crt_file(missing) {
+            return Some(Failure::MissingCrtFile(missing.to_string()));
+        }
+        // collect2: fatal error: cannot find 'ld'
+        if quoted(missing) == Some("ld") {
+            return Some(Failure::MissingLinker);
+        }
+    }
+
+    // clang: Executable "ld" doesn't exist!, or -fuse-ld=lld without lld
+    if line.contains("unable to execute command") || line.contains("invalid linker name") {
+        return Some(Failure::MissingLinker);
+    }
+
+    // clang: unknown argument: '-x', gcc: unrecognized command-line option
+    // '-x', GNU ld: unrecognized option '--x', lld: unknown argument '--x'
+    if line.contains("unknown argument") || line.contains("unrecognized") {
+        return quoted(line).map(|flag| Failure::UnknownFlag(flag.to_string()));
+    }
+
+    None
+}
+
+/// The causes of a failed link recognized in the linker's stderr, in the
+/// order they're reported.
+pub fn parse_failures(stderr: &str) -> Vec<Failure> {
+    let mut failures = vec![];
+
+    for failure in stderr.lines().filter_map(parse_line) {
+        if !failures.contains(&failure) {
+            failures.push(failure);
+        }
+    }
+
+    failures
+}
+
+// The arguments to try next after a link failed, with what was changed.
+// Only optional libraries and the lld flag are ever dropped, and lld only
+// tried when the system linker is missing, so a retry never changes what
+// gets linked. None when no change is known to help.
+fn adjusted(args: &[String], failures: &[Failure]) -> Option<(Vec<String>, Vec<String>)> {
+    let mut args = args.to_vec();
+    let mut changes = vec![];
+
+    for failure in failures {
+        match failure {
+            Failure::MissingLibrary(lib) if OPTIONAL_LIBS.contains(&lib.as_str()) => {
+                let flag = format!("-l{}", lib);
+                if args.contains(&flag) {
+                    args.retain(|arg| *arg != flag);
+                    changes.push(format!("dropping {}", flag));
+                }
+            }
+            Failure::UnknownFlag(flag) if flag == LLD_FLAG => {
+                if args.contains(flag) {
+                    args.retain(|arg| arg != flag);
+                    changes.push(format!("dropping {}", flag));
+                }
+            }
+            Failure::MissingLinker if !args.iter().any(|arg| arg == LLD_FLAG) => {
+                args.insert(0, LLD_FLAG.to_string());
+                changes.push(format!("adding {}", LLD_FLAG));
+            }
+            _ => (),
+        }
+    }
+
+    if changes.is_empty() {
+        None
+    } else {
+        Some((args, changes))
+    }
+}
+
+fn shell_quoted(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=+,:@%".contains(c);
+
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// A run of the link command.
+#[derive(Debug, Clone)]
+pub struct Attempt {
+    pub linker: String,
+    pub args: Vec<String>,
+    /// The linker's stderr when it failed, or why it couldn't be run.
+    pub error: Option<String>,
+}
+
+impl Attempt {
+    /// The command as it can be pasted into a shell.
+    pub fn command_line(&self) -> String {
+        let mut words = vec![shell_quoted(&self.linker)];
+        words.extend(self.args.iter().map(|arg| shell_quoted(arg)));
+        words.join(" ")
+    }
+
+    pub fn failures(&self) -> Vec<Failure> {
+        self.error
+            .as_deref()
+            .map(parse_failures)
+            .unwrap_or_default()
+    }
+}
+
+/// Every attempt of a link that failed, with the last one's failure.
+#[derive(Debug)]
+pub struct LinkError {
+    pub attempts: Vec<Attempt>,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "failed to create binary, link commands tried ({}):",
+            self.attempts.len()
+        )?;
+
+        for (i, attempt) in self.attempts.iter().enumerate() {
+            writeln!(f, "  {}. {}", i + 1, attempt.command_line())?;
+
+            for line in attempt.error.iter().flat_map(|error| error.lines()) {
+                writeln!(f, "     {}", line)?;
+            }
+        }
+
+        let failures = self
+            .attempts
+            .last()
+            .map(Attempt::failures)
+            .unwrap_or_default();
+
+        for failure in failures.iter() {
+            if let Failure::MissingCrtFile(file) = failure {
+                writeln!(
+                    f,
+                    "{} is part of the C library's development files (e.g. libc6-dev or musl-dev), \
+                     which need to be installed to link binaries",
+                    file
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn run(linker: &str, args: Vec<String>) -> Attempt {
+    let error = match Command::new(linker).args(&args).output() {
+        Ok(output) if output.status.success() => None,
+        Ok(output) => Some(String::from_utf8_lossy(&output.stderr).into_owned()),
+        Err(e) => Some(format!("couldn't run {}: {}", linker, e)),
+    };
+
+    Attempt {
+        linker: linker.to_string(),
+        args,
+        error,
+    }
+}
+
+/// Runs `linker` with `args`. When it fails with a cause a change of the
+/// arguments is known to fix, e.g. a missing `-ldl`, and `retry` is on, it's
+/// run again with the change, at most `MAX_ATTEMPTS` times in all. `log` is
+/// told about every attempt and change.
+pub fn link(
+    linker: &str,
+    args: Vec<String>,
+    retry: bool,
+    mut log: impl FnMut(&str),
+) -> Result<(), LinkError> {
+    let mut attempts: Vec<Attempt> = vec![];
+    let mut args = args;
+
+    loop {
+        let attempt = run(linker, args);
+        let n = attempts.len() + 1;
+
+        if attempt.error.is_none() {
+            log(&format!(
+                "link attempt {} succeeded: {}",
+                n,
+                attempt.command_line()
+            ));
+            return Ok(());
+        }
+
+        let failures = attempt.failures();
+        let causes: Vec<_> = failures.iter().map(Failure::to_string).collect();
+        let causes = if causes.is_empty() {
+            "unrecognized linker error".to_string()
+        } else {
+            causes.join(", ")
+        };
+        log(&format!(
+            "link attempt {} failed ({}): {}",
+            n,
+            causes,
+            attempt.command_line()
+        ));
+
+        let next = if retry && n < MAX_ATTEMPTS {
+            adjusted(&attempt.args, &failures)
+        } else {
+            None
+        };
+        attempts.push(attempt);
+
+        match next {
+            // an earlier attempt ran with these already
+            Some((next, _)) if attempts.iter().any(|a| a.args == next) => break,
+            Some((next, changes)) => {
+                log(&format!("retrying the link, {}", changes.join(", ")));
+                args = next;
+            }
+            None => break,
+        }
+    }
+
+    Err(LinkError { attempts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GLIBC_MISSING_DL: &str = "\
+/usr/bin/ld: cannot find -ldl: No such file or directory
+clang: error: linker command failed with exit code 1 (use -v to see invocation)
+";
+
+    const MUSL_MISSING_PTHREAD: &str = "\
+/usr/lib/gcc/x86_64-alpine-linux-musl/12.2.1/../../../../x86_64-alpine-linux-musl/bin/ld: \
+cannot find -lpthread: No such file or directory
+clang-15: error: linker command failed with exit code 1 (use -v to see invocation)
+";
+
+    const LLD_MISSING_DL: &str = "\
+ld.lld: error: unable to find library -ldl
+clang: error: linker command failed with exit code 1 (use -v to see invocation)
+";
+
+    const GLIBC_MISSING_CRT: &str = "\
+/usr/bin/ld: cannot find crt1.o: No such file or directory
+/usr/bin/ld: cannot find crti.o: No such file or directory
+clang: error: linker command failed with exit code 1 (use -v to see invocation)
+";
+
+    const LLD_MISSING_CRT: &str = "\
+ld.lld: error: cannot open crt1.o: No such file or directory
+clang: error: linker command failed with exit code 1 (use -v to see invocation)
+";
+
+    const MISSING_LD: &str = "\
+clang: error: unable to execute command: Executable \"ld\" doesn't exist!
+clang: error: linker command failed with exit code 1 (use -v to see invocation)
+";
+
+    const UNKNOWN_FLAGS: &str = "\
+clang: error: unknown argument: '-fno-such-flag'
+/usr/bin/ld: unrecognized option '--no-such-option'
+ld.lld: error: unknown argument '--no-such-lld-option'
+";
+
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_missing_libraries() {
+        assert_eq!(
+            parse_failures(GLIBC_MISSING_DL),
+            vec![Failure::MissingLibrary("dl".to_string())]
+        );
+        assert_eq!(
+            parse_failures(MUSL_MISSING_PTHREAD),
+            vec![Failure::MissingLibrary("pthread".to_string())]
+        );
+        assert_eq!(
+            parse_failures(LLD_MISSING_DL),
+            vec![Failure::MissingLibrary("dl".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_parse_missing_crt_files_and_linker() {
+        assert_eq!(
+            parse_failures(GLIBC_MISSING_CRT),
+            vec![
+                Failure::MissingCrtFile("crt1.o".to_string()),
+                Failure::MissingCrtFile("crti.o".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse_failures(LLD_MISSING_CRT),
+            vec![Failure::MissingCrtFile("crt1.o".to_string())]
+        );
+        assert_eq!(parse_failures(MISSING_LD), vec![Failure::MissingLinker]);
+        assert_eq!(
+            parse_failures("collect2: fatal error: cannot find 'ld'"),
+            vec![Failure::MissingLinker]
+        );
+        assert_eq!(
+            parse_failures("clang: error: invalid linker name in argument '-fuse-ld=lld'"),
+            vec![Failure::MissingLinker]
+        );
+    }
+
+    #[test]
+    fn test_parse_unknown_flags() {
+        assert_eq!(
+            parse_failures(UNKNOWN_FLAGS),
+            vec![
+                Failure::UnknownFlag("-fno-such-flag".to_string()),
+                Failure::UnknownFlag("--no-such-option".to_string()),
+                Failure::UnknownFlag("--no-such-lld-option".to_string()),
+            ]
+        );
+        assert_eq!(parse_failures("undefined reference to `main'"), vec![]);
+    }
+
+    #[test]
+    fn test_only_safe_adjustments() {
+        let args = strings(&["-lpthread", "-ldl", "a.o", "rt.a", "-o", "a"]);
+
+        let (next, changes) = adjusted(&args, &parse_failures(GLIBC_MISSING_DL)).unwrap();
+        assert_eq!(next, strings(&["-lpthread", "a.o", "rt.a", "-o", "a"]));
+        assert_eq!(changes, strings(&["dropping -ldl"]));
+
+        let (next, _) = adjusted(&args, &parse_failures(MISSING_LD)).unwrap();
+        assert_eq!(next[0], LLD_FLAG);
+
+        // lld is tried once, then dropped when the driver doesn't know it
+        let with_lld = next;
+        assert_eq!(adjusted(&with_lld, &parse_failures(MISSING_LD)), None);
+        let (next, _) = adjusted(&with_lld, &[Failure::UnknownFlag(LLD_FLAG.to_string())]).unwrap();
+        assert_eq!(next, args);
+
+        assert_eq!(adjusted(&args, &parse_failures(GLIBC_MISSING_CRT)), None);
+        assert_eq!(
+            adjusted(&args, &[Failure::MissingLibrary("m".to_string())]),
+            None
+        );
+        assert_eq!(adjusted(&args, &parse_failures(UNKNOWN_FLAGS)), None);
+    }
+
+    #[test]
+    fn test_command_lines_can_be_pasted() {
+        let attempt = Attempt {
+            linker: "clang".to_string(),
+            args: strings(&["-ldl", "my dir/a.o", "it's.a", "-o", "a"]),
+            error: None,
+        };
+
+        assert_eq!(
+            attempt.command_line(),
+            "clang -ldl 'my dir/a.o' 'it'\\''s.a' -o a"
+        );
+    }
+}
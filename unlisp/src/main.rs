@@ -1,17 +1,40 @@
+use std::env;
 use std::error::Error;
 use std::fs;
 use std::io;
-use std::io::{Read, Write};
-use std::process::Command;
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 
-use unlispc::codegen::context::CodegenContext;
+mod cache;
+mod diagnostics;
+mod doc;
+mod expand;
+mod fmt;
+mod include;
+mod link;
+mod repl_input;
+mod watch;
+
+use cache::ObjectCache;
+use diagnostics::{Diagnostic, Severity};
+use include::{FileForms, SourceForm};
+use repl_input::InputState;
+
+use unlisp_rt::escapes::{self, SymbolCase};
+use unlispc::codegen::context::{self, CodegenContext};
+use unlispc::error::ErrorType;
+use unlispc::fingerprint;
+use unlispc::fold;
+use unlispc::inline;
 use unlispc::reader;
 use unlispc::repr;
 
 use clap::{App, AppSettings, Arg, SubCommand};
 
-fn read_and_parse<'a, T: Read>(
-    reader: &mut reader::Reader<'a, T>,
+fn read_and_parse<S: reader::Source>(
+    reader: &mut reader::Reader<S>,
 ) -> Result<Option<repr::HIR>, Box<dyn Error>> {
     let form = reader.read_form()?;
     Ok(form
@@ -20,164 +43,922 @@ fn read_and_parse<'a, T: Read>(
         .transpose()?)
 }
 
+const STDIO_PATH: &str = "-";
+
+fn source_name(path: &str) -> &str {
+    if path == STDIO_PATH {
+        "<stdin>"
+    } else {
+        path
+    }
+}
+
+/// How evaluating a single top-level form of a file ended.
+pub enum FormOutcome {
+    Evaluated(repr::HIR),
+    // with the name of the function or macro the form defines, if any
+    Failed(ErrorType, Option<String>),
+}
+
+impl FormOutcome {
+    // the form couldn't be read, macroexpanded or compiled
+    fn is_compile_failure(&self) -> bool {
+        match self {
+            FormOutcome::Failed(ty, _) => *ty != ErrorType::Runtime,
+            FormOutcome::Evaluated(_) => false,
+        }
+    }
+}
+
+/// Where `eval_and_expand_file` stops when forms fail.
+#[derive(Clone, Copy, PartialEq)]
+pub enum StopAt {
+    End,
+    FirstFailure,
+    // runtime errors don't stop it
+    FirstCompileFailure,
+}
+
+// errors that aren't compiler errors come from reading the source
+fn error_type(e: &dyn Error) -> ErrorType {
+    e.downcast_ref::<unlispc::error::Error>()
+        .map(|e| e.ty.clone())
+        .unwrap_or(ErrorType::Reader)
+}
+
 pub fn eval_and_expand_file(
     ctx: &mut CodegenContext,
     path: &str,
     panic_on_err: bool,
-) -> Result<Vec<repr::HIR>, Box<dyn Error>> {
-    let mut file = fs::File::open(path).expect("stdlib file not found");
+    stop_at: StopAt,
+) -> Vec<FormOutcome> {
+    let mut source: Box<dyn Read> = if path == STDIO_PATH {
+        Box::new(io::stdin())
+    } else {
+        Box::new(fs::File::open(path).expect("stdlib file not found"))
+    };
+
+    let name = source_name(path);
+
+    // reader errors are reported where reading stopped, with its line in the
+    // text too, runtime errors at the call that raised them when it's known,
+    // with its line and column in the text too, other errors where the form
+    // starts
+    let report_err = |e: &dyn Error, defined: Option<&str>, at: &SourceForm| {
+        let raised_at = e
+            .downcast_ref::<unlispc::error::Error>()
+            .and_then(|e| e.location.clone());
+
+        let location = match &raised_at {
+            _ if at.form.is_err() => format!("{}:{}", at.file, at.line),
+            Some(raised_at) => raised_at.to_string(),
+            None => at.file.clone(),
+        };
 
-    let report_err = |msg| {
         if panic_on_err {
-            panic!("[{}] {}", path, msg);
-        } else {
-            eprintln!("[{}] {}", path, msg);
+            panic!("[{}] {}", location, e);
         }
+
+        let (file, line, col) = match raised_at {
+            Some(raised_at) => (
+                raised_at.file,
+                raised_at.line as usize,
+                raised_at.col as usize,
+            ),
+            None => (at.file.clone(), at.line, at.col),
+        };
+
+        let diagnostic = Diagnostic {
+            severity: Severity::Error,
+            message: e.to_string(),
+            file,
+            line,
+            col,
+        };
+        diagnostics::report(diagnostic, &format!("[{}] {}", location, e));
+
+        FormOutcome::Failed(error_type(e), defined.map(str::to_string))
     };
 
-    let mut expanded = vec![];
+    let mut outcomes = vec![];
 
-    let mut reader = reader::Reader::create(&mut file);
-    loop {
-        match read_and_parse(&mut reader) {
-            Ok(Some(hir)) => match unsafe { ctx.eval_hirs(&[hir.clone()]) } {
-                Ok(_) => expanded.push(hir),
-                Err(e) => report_err(e.to_string()),
-            },
-            Ok(None) => break,
-            Err(e) => report_err(e.to_string()),
+    let reader = reader::Reader::create(&mut source);
+    for source_form in FileForms::new(reader, path, name, path == STDIO_PATH) {
+        let form = match &source_form.form {
+            Ok(form) => form,
+            Err(e) => {
+                outcomes.push(report_err(e.as_ref(), None, &source_form));
+                if stop_at != StopAt::End {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let defined = doc::defined_name(form);
+
+        let hir = repr::form_to_hir_with_spans(form, &source_form.spans, &source_form.file);
+
+        let outcome = match hir {
+            Ok(hir) => {
+                let result = unsafe { ctx.eval_top_level_hir(&hir) };
+                // keep the form's output ahead of its error message
+                unlisp_rt::output::flush_stdout();
+
+                // warnings about a call are reported at it, with its column
+                // too, the calls of the form are in its file
+                for (warning, span) in ctx.take_located_warnings() {
+                    let (location, line, col) = match span {
+                        Some(span) => (
+                            format!("{}:{}:{}", source_form.file, span.line, span.col),
+                            span.line as usize,
+                            span.col as usize,
+                        ),
+                        None => (
+                            format!("{}:{}", source_form.file, source_form.line),
+                            source_form.line,
+                            source_form.col,
+                        ),
+                    };
+
+                    let text = format!("[{}] warning: {}", location, warning);
+                    let diagnostic = Diagnostic {
+                        severity: Severity::Warning,
+                        message: warning,
+                        file: source_form.file.clone(),
+                        line,
+                        col,
+                    };
+                    diagnostics::report(diagnostic, &text);
+                }
+
+                match result {
+                    Ok(_) => FormOutcome::Evaluated(hir),
+                    Err(e) => report_err(&e, defined, &source_form),
+                }
+            }
+            Err(e) => report_err(&e, defined, &source_form),
+        };
+
+        let stop = match (&outcome, stop_at) {
+            (FormOutcome::Evaluated(_), _) | (_, StopAt::End) => false,
+            (_, StopAt::FirstFailure) => true,
+            (outcome, StopAt::FirstCompileFailure) => outcome.is_compile_failure(),
+        };
+        outcomes.push(outcome);
+
+        if stop {
+            break;
         }
-        ctx.reinitialize();
     }
 
-    Ok(expanded)
+    outcomes
 }
 
-pub fn eval_stdlib(ctx: &mut CodegenContext, path: Option<&str>) {
-    if path.is_none() {
-        return;
+fn evaluated_hirs(outcomes: Vec<FormOutcome>) -> Vec<repr::HIR> {
+    outcomes
+        .into_iter()
+        .filter_map(|outcome| match outcome {
+            FormOutcome::Evaluated(hir) => Some(hir),
+            FormOutcome::Failed(..) => None,
+        })
+        .collect()
+}
+
+// (set-definition-failed! (quote name))
+fn failed_definition_stub(name: &str) -> repr::HIR {
+    let sym = |s: &str| repr::Form::Symbol(s.to_string());
+    let form = repr::Form::List(vec![
+        sym("set-definition-failed!"),
+        repr::Form::List(vec![sym("quote"), sym(name)]),
+    ]);
+
+    repr::form_to_hir_with_transforms(&form).expect("stub form doesn't compile")
+}
+
+// Like evaluated_hirs, but a definition that failed to compile is replaced by
+// a stub that raises an error when it's called.
+fn hirs_with_failed_definition_stubs(outcomes: Vec<FormOutcome>) -> Vec<repr::HIR> {
+    outcomes
+        .into_iter()
+        .filter_map(|outcome| match outcome {
+            FormOutcome::Failed(ErrorType::Runtime, _) => None,
+            FormOutcome::Failed(_, name) => name.map(|name| failed_definition_stub(&name)),
+            FormOutcome::Evaluated(hir) => Some(hir),
+        })
+        .collect()
+}
+
+/// Evaluates the files in order and returns the expanded forms of each one
+/// with its name.
+pub fn eval_preloads(ctx: &mut CodegenContext, paths: &[String]) -> Vec<(String, Vec<repr::HIR>)> {
+    paths
+        .iter()
+        .map(|path| {
+            let outcomes = eval_and_expand_file(ctx, path, true, StopAt::End);
+            (source_name(path).to_string(), evaluated_hirs(outcomes))
+        })
+        .collect()
+}
+
+// set by --dump-closures
+static DUMP_CLOSURES: AtomicBool = AtomicBool::new(false);
+
+// called after the preloads, so only closures of the user's code are dumped
+fn enable_closure_dump(ctx: &mut CodegenContext) {
+    if DUMP_CLOSURES.load(Ordering::SeqCst) {
+        ctx.set_closure_dump_hook(Some(Box::new(diagnostics::dump)));
+    }
+}
+
+// set by --dump-symbols
+static DUMP_SYMBOLS: AtomicBool = AtomicBool::new(false);
+
+// set by --dump-compiled, with the substring of --dump-filter
+static DUMP_COMPILED: AtomicBool = AtomicBool::new(false);
+static DUMP_FILTER: Mutex<Option<String>> = Mutex::new(None);
+
+// called after the preloads like enable_closure_dump
+fn enable_ir_dump(ctx: &mut CodegenContext) {
+    if DUMP_COMPILED.load(Ordering::SeqCst) {
+        let filter = DUMP_FILTER.lock().unwrap().clone();
+        ctx.set_ir_dump_hook(Some(Box::new(diagnostics::dump)), filter);
     }
+}
+
+// set by --werror
+static WARNINGS_ARE_ERRORS: AtomicBool = AtomicBool::new(false);
 
-    let path = path.unwrap();
-    let _ = eval_and_expand_file(ctx, path, true);
+// called after the preloads like enable_closure_dump, so only the user's
+// code fails on warnings
+fn enable_werror(ctx: &mut CodegenContext) {
+    ctx.set_warnings_are_errors(WARNINGS_ARE_ERRORS.load(Ordering::SeqCst));
 }
 
-fn repl(ctx: &mut CodegenContext, dump_compiled: bool) {
-    let mut stdin = io::stdin();
+fn print_prompt(prompt: &str) {
+    print!("{}", prompt);
+    io::stdout().flush().unwrap();
+}
 
-    let prompt = || {
-        print!(">>> ");
-        io::stdout().flush().unwrap();
-    };
+// writes a line to stderr, in the color when colors are on
+fn eprintln_colored(text: &str, color: &str, colors: bool) {
+    if colors {
+        eprintln!("{}", repl_input::colored(text, color));
+    } else {
+        eprintln!("{}", text);
+    }
+}
+
+fn eval_and_print(ctx: &mut CodegenContext, hir: &repr::HIR, colors: bool) {
+    let result = unsafe { ctx.eval_top_level_hir(hir) };
+    unlisp_rt::output::flush_stdout();
+
+    for warning in ctx.take_warnings() {
+        let text = format!("warning: {}", warning);
+        eprintln_colored(&text, repl_input::WARNING_COLOR, colors);
+    }
+
+    match result {
+        // through the runtime, so fresh-line knows the line is done
+        Ok(obj) => {
+            let _ = unlisp_rt::output::write_stdout(format_args!("{}\n", obj));
+        }
+        Err(err) => eprintln_colored(&err.to_string(), repl_input::ERROR_COLOR, colors),
+    }
+}
+
+fn repl(ctx: &mut CodegenContext) {
+    let mut stdin = io::stdin();
 
     let mut reader = reader::Reader::create(&mut stdin);
 
-    prompt();
+    print_prompt(">>> ");
     loop {
         match read_and_parse(&mut reader) {
-            Ok(Some(hir)) => unsafe {
-                match ctx.compile_hirs(&[hir]) {
-                    Ok(compiled_fn) => {
-                        if dump_compiled {
-                            eprintln!("Expression compiled to LLVM IR:");
-                            ctx.get_module().print_to_stderr();
-                        }
-                        match unlisp_rt::exceptions::run_with_global_ex_handler(|| {
-                            compiled_fn.call()
-                        }) {
-                            Ok(obj) => println!("{}", obj),
-                            Err(err) => eprintln!("{}", err),
-                        }
-                    }
-                    Err(err) => eprintln!("{}", err),
-                }
-            },
+            Ok(Some(hir)) => eval_and_print(ctx, &hir, false),
             Ok(None) => break,
             Err(e) => eprintln!("{}", e),
         }
-        ctx.reinitialize();
-        prompt();
+        print_prompt(">>> ");
+    }
+}
+
+// Reads whole lines until the buffer holds complete forms, showing the
+// nesting depth in the continuation prompt. With colors, errors and warnings
+// are colored.
+fn fancy_repl(ctx: &mut CodegenContext, colors: bool) {
+    let stdin = io::stdin();
+    let mut buffer = String::new();
+
+    print_prompt(">>> ");
+    loop {
+        let mut line = String::new();
+        if stdin
+            .lock()
+            .read_line(&mut line)
+            .expect("couldn't read from stdin")
+            == 0
+        {
+            break;
+        }
+        buffer.push_str(&line);
+
+        if let InputState::Incomplete(depth) = repl_input::validate(&buffer) {
+            print_prompt(&repl_input::continuation_prompt(depth));
+            continue;
+        }
+
+        {
+            let mut reader = reader::Reader::from_str(&buffer);
+
+            loop {
+                match read_and_parse(&mut reader) {
+                    Ok(Some(hir)) => eval_and_print(ctx, &hir, colors),
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln_colored(&e.to_string(), repl_input::ERROR_COLOR, colors);
+                        break;
+                    }
+                }
+            }
+        }
+
+        buffer.clear();
+        print_prompt(">>> ");
     }
 }
 
-fn launch_repl(stdlib_path: Option<&str>, dump_compiled: bool) {
+// continuation prompts are only useful on a terminal that can show them
+fn fancy_repl_supported() -> bool {
+    match env::var("TERM") {
+        Ok(term) => term != "dumb",
+        Err(_) => false,
+    }
+}
+
+// colors are only written to a terminal, not into pipes and files
+fn stderr_color_supported() -> bool {
+    fancy_repl_supported() && io::stderr().is_terminal()
+}
+
+fn launch_repl(preloads: &[String], fancy: bool) {
     unlisp_rt::defs::unlisp_rt_init_runtime();
     let mut codegen_ctx = CodegenContext::new();
-    eval_stdlib(&mut codegen_ctx, stdlib_path);
-    repl(&mut codegen_ctx, dump_compiled)
+    eval_preloads(&mut codegen_ctx, preloads);
+    enable_closure_dump(&mut codegen_ctx);
+    enable_ir_dump(&mut codegen_ctx);
+    enable_werror(&mut codegen_ctx);
+
+    if fancy && fancy_repl_supported() {
+        fancy_repl(&mut codegen_ctx, stderr_color_supported())
+    } else {
+        repl(&mut codegen_ctx)
+    }
+}
+
+const EXIT_RUNTIME_ERROR: i32 = 1;
+const EXIT_READ_ERROR: i32 = 2;
+
+// Read, macroexpansion and compilation errors take precedence over runtime
+// errors, since they mean that part of the file wasn't run at all.
+fn exit_code(outcomes: &[FormOutcome]) -> i32 {
+    outcomes
+        .iter()
+        .map(|outcome| match outcome {
+            FormOutcome::Evaluated(_) => 0,
+            FormOutcome::Failed(ErrorType::Runtime, _) => EXIT_RUNTIME_ERROR,
+            FormOutcome::Failed(..) => EXIT_READ_ERROR,
+        })
+        .max()
+        .unwrap_or(0)
 }
 
-fn exec_file(stdlib_path: Option<&str>, file: &str) -> bool {
+fn exec_file(preloads: &[String], file: &str, fail_fast: bool, summary: bool) -> i32 {
     unlisp_rt::defs::unlisp_rt_init_runtime();
     let mut codegen_ctx = CodegenContext::new();
 
-    eval_stdlib(&mut codegen_ctx, stdlib_path);
-    eval_and_expand_file(&mut codegen_ctx, file, false).is_ok()
+    eval_preloads(&mut codegen_ctx, preloads);
+    enable_closure_dump(&mut codegen_ctx);
+    enable_ir_dump(&mut codegen_ctx);
+    enable_werror(&mut codegen_ctx);
+    let stop_at = if fail_fast {
+        StopAt::FirstFailure
+    } else {
+        StopAt::End
+    };
+    let outcomes = eval_and_expand_file(&mut codegen_ctx, file, false, stop_at);
+    diagnostics::flush();
+
+    if DUMP_SYMBOLS.load(Ordering::SeqCst) {
+        let symbols = unsafe { unlisp_rt::symbols::describe_interned_symbols() };
+        diagnostics::dump(&symbols);
+    }
+
+    if summary && !diagnostics::json_output() {
+        let errors = outcomes
+            .iter()
+            .filter(|outcome| match outcome {
+                FormOutcome::Failed(..) => true,
+                _ => false,
+            })
+            .count();
+        eprintln!("{} forms, {} errors", outcomes.len(), errors);
+    }
+
+    exit_code(&outcomes)
+}
+
+/// Flags of the compile subcommand.
+struct AotOptions {
+    emit_obj: bool,
+    keep_temps: bool,
+    // compile the remaining forms after one fails to compile
+    keep_going: bool,
+    // produce the output anyway, failed definitions raise an error when called
+    allow_errors: bool,
+    linker: String,
+    // link again without what's known to be optional when linking fails
+    link_retry: bool,
 }
 
-fn aot_file(stdlib_path: Option<&str>, rt_lib_path: &str, file: &str, out: &str) -> bool {
+fn aot_file(
+    preloads: &[String],
+    rt_lib_path: &str,
+    file: &str,
+    out: &str,
+    options: &AotOptions,
+    cache: &ObjectCache,
+) -> bool {
+    let emit_obj = options.emit_obj;
+
+    if !emit_obj && out == STDIO_PATH {
+        eprintln!("cannot write linked binary to stdout, use --emit=obj");
+        return false;
+    }
+
+    let dumps = DUMP_CLOSURES.load(Ordering::SeqCst) || DUMP_COMPILED.load(Ordering::SeqCst);
+    if dumps && out == STDIO_PATH && diagnostics::json_output() {
+        eprintln!("cannot dump to stderr with --diagnostics=json, write the object to a file");
+        return false;
+    }
+
     unlisp_rt::defs::unlisp_rt_init_runtime();
+    unlisp_rt::predefined::set_namespace_conflicts_are_errors(true);
+
+    // what the forms print while they're evaluated mustn't end up in the
+    // object file
+    if out == STDIO_PATH {
+        unlisp_rt::output::send_stdout_to_stderr(true);
+    }
 
     let mut expand_ctx = CodegenContext::new();
     let mut aot_ctx = CodegenContext::new();
+    // nothing interrupts a compiled program
+    aot_ctx.set_interrupt_checks(false);
+    aot_ctx.set_source_locations(false);
+    aot_ctx.set_static_values(true);
+    // the IR of the program, rather than of the forms evaluated to expand it
+    enable_ir_dump(&mut aot_ctx);
 
-    let mut expanded = vec![];
+    // with JSON diagnostics, they're the only thing written about the forms
+    let progress = !diagnostics::json_output();
+
+    if progress {
+        eprintln!("Compiling file: {}...", source_name(file));
+    }
 
-    println!("Compiling file: {}...", file);
+    let mut units = eval_preloads(&mut expand_ctx, preloads);
+    enable_closure_dump(&mut expand_ctx);
+    enable_werror(&mut expand_ctx);
 
-    if let Some(stdlib) = stdlib_path {
-        expanded.append(
-            &mut eval_and_expand_file(&mut expand_ctx, stdlib, true)
-                .expect("stdlib evaluation shouldn't return error"),
+    let stop_at = if options.keep_going {
+        StopAt::End
+    } else {
+        StopAt::FirstCompileFailure
+    };
+    let outcomes = eval_and_expand_file(&mut expand_ctx, file, false, stop_at);
+
+    let failed = outcomes.iter().filter(|o| o.is_compile_failure()).count();
+    if failed > 0 && !options.allow_errors {
+        if progress && options.keep_going {
+            eprintln!("{} forms failed to compile, nothing was written", failed);
+        } else if progress {
+            eprintln!("stopped at the first form that failed to compile, see --keep-going");
+        }
+        return false;
+    } else if failed > 0 && progress {
+        eprintln!(
+            "{} forms failed to compile, calling their definitions raises an error",
+            failed
         );
     }
 
-    let expanded_file = eval_and_expand_file(&mut expand_ctx, file, false);
-
-    if expanded_file.is_err() {
+    if unlisp_rt::predefined::namespace_conflicts() > 0 {
+        diagnostics::report_file_error(
+            source_name(file),
+            "cannot compile a file that redefines functions as macros or vice versa",
+        );
         return false;
     }
 
-    expanded.append(&mut expanded_file.unwrap());
+    units.push((
+        source_name(file).to_string(),
+        hirs_with_failed_definition_stubs(outcomes),
+    ));
+
+    let objects = match compile_units(&mut aot_ctx, &units, cache) {
+        Ok(objects) => objects,
+        Err(e) => {
+            diagnostics::report_file_error(source_name(file), &e.to_string());
+            return false;
+        }
+    };
+
+    // objects written to stdout are linked in a temporary directory
+    let base = if out == STDIO_PATH {
+        let name = format!("unlisp_{}", process::id());
+        env::temp_dir().join(name).to_string_lossy().into_owned()
+    } else {
+        out.to_string()
+    };
+
+    // <output>.o has main, the units are numbered in the order they run
+    let object_files: Vec<_> = (0..objects.len())
+        .map(|i| {
+            if i + 1 == objects.len() {
+                format!("{}.o", base)
+            } else {
+                format!("{}.{}.o", base, i)
+            }
+        })
+        .collect();
+
+    for (object_file, object) in object_files.iter().zip(objects.iter()) {
+        if let Err(e) = fs::write(object_file, object) {
+            eprintln!("couldn't write object file {}: {}", object_file, e);
+            return false;
+        }
+    }
+
+    let linked = if emit_obj {
+        link_object(file, out, &base, &object_files, options, progress)
+    } else {
+        link_binary(file, rt_lib_path, out, &object_files, options, progress)
+    };
 
-    let object_file = format!("{}.o", out);
+    // the objects are kept when linking fails, so the failure can be inspected
+    if linked && !options.keep_temps {
+        for object_file in object_files.iter() {
+            if let Err(e) = fs::remove_file(object_file) {
+                eprintln!("couldn't remove object file {}: {}", object_file, e);
+            }
+        }
+    }
+
+    linked
+}
+
+// Every file is compiled to an object of its own, cached by a fingerprint of
+// its expanded forms, the options and the global variables the files before
+// it declare, so a changed file is the only one compiled again, unless it
+// declares other variables. The small object with `main` is always compiled
+// and comes last.
+fn compile_units(
+    ctx: &mut CodegenContext,
+    units: &[(String, Vec<repr::HIR>)],
+    cache: &ObjectCache,
+) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let mut objects = vec![];
+    let mut init_fn_names = vec![];
+    let mut declared = vec![];
 
-    if let Err(e) = aot_ctx.compile_hirs_to_file(&object_file, expanded.as_slice()) {
-        eprintln!("{}", e);
+    for (i, (name, hirs)) in units.iter().enumerate() {
+        let mut flags = fingerprint::codegen_flags();
+        flags.push(format!("unit {}: {}", i, name));
+        flags.extend(declared.iter().map(|var| format!("declared {}", var)));
+        let fingerprint = fingerprint::fingerprint_hirs(hirs, &flags);
+
+        // a cached object is found by the name it was compiled with
+        let init_fn_name = format!("unlisp_unit_{:016x}", fingerprint);
+        let object = cache.get_or_compile(name, fingerprint, || {
+            ctx.compile_unit_to_memory(hirs, &init_fn_name)
+        })?;
+
+        objects.push(object);
+        init_fn_names.push(init_fn_name);
+
+        // units compiled later refer to them, whether this one was compiled
+        for var in fingerprint::declared_vars(hirs) {
+            ctx.declare_global_var(&var);
+            declared.push(var);
+        }
+    }
+
+    objects.push(ctx.compile_main_to_memory(&init_fn_names)?);
+
+    Ok(objects)
+}
+
+// --emit=obj links the objects into one relocatable object file
+fn link_object(
+    file: &str,
+    out: &str,
+    base: &str,
+    object_files: &[String],
+    options: &AotOptions,
+    progress: bool,
+) -> bool {
+    let linked_file = if out == STDIO_PATH {
+        format!("{}.linked.o", base)
+    } else {
+        out.to_string()
+    };
+
+    let mut cmd_args = vec!["-r".to_string()];
+    cmd_args.extend(object_files.iter().cloned());
+    cmd_args.push("-o".to_string());
+    cmd_args.push(linked_file.clone());
+
+    let linked = link::link(&options.linker, cmd_args, options.link_retry, |line| {
+        if progress {
+            eprintln!("{}", line);
+        }
+    });
+
+    if let Err(e) = linked {
+        diagnostics::report_file_error(source_name(file), e.to_string().trim_end());
         return false;
     }
 
-    println!("Linking with runtime library: {}...", rt_lib_path);
+    if out == STDIO_PATH {
+        let object = fs::read(&linked_file).expect("couldn't read linked object file");
+        io::stdout()
+            .write_all(&object)
+            .expect("couldn't write to stdout");
+
+        if let Err(e) = fs::remove_file(&linked_file) {
+            eprintln!("couldn't remove object file {}: {}", linked_file, e);
+        }
+    }
+
+    true
+}
+
+fn link_binary(
+    file: &str,
+    rt_lib_path: &str,
+    out: &str,
+    object_files: &[String],
+    options: &AotOptions,
+    progress: bool,
+) -> bool {
+    if progress {
+        eprintln!("Linking with runtime library: {}...", rt_lib_path);
+    }
 
     let mut cmd_args = vec![];
 
     #[cfg(target_os = "linux")]
     {
-        cmd_args.push("-lpthread");
-        cmd_args.push("-ldl");
+        cmd_args.push("-lpthread".to_string());
+        cmd_args.push("-ldl".to_string());
     }
 
-    cmd_args.push(object_file.as_str());
-    cmd_args.push(rt_lib_path);
-    cmd_args.push("-o");
-    cmd_args.push(out);
+    cmd_args.extend(object_files.iter().cloned());
+    cmd_args.push(rt_lib_path.to_string());
+    cmd_args.push("-o".to_string());
+    cmd_args.push(out.to_string());
 
-    let linker_output = Command::new("clang")
-        .args(cmd_args.as_slice())
-        .output()
-        .expect("failed to execute linker");
+    let linked = link::link(&options.linker, cmd_args, options.link_retry, |line| {
+        if progress {
+            eprintln!("{}", line);
+        }
+    });
 
-    if !linker_output.status.success() {
-        eprintln!(
-            "failed to create binary: \n {}",
-            String::from_utf8_lossy(&linker_output.stderr)
-        );
+    if let Err(e) = linked {
+        diagnostics::report_file_error(source_name(file), e.to_string().trim_end());
+        return false;
+    }
+
+    true
+}
+
+// Every build runs the compile command again without --watch in a process of
+// its own, so it starts from a fresh runtime and a crash doesn't stop the
+// watching. Only returns when the files can't be watched.
+fn watch_and_compile(file: &str, preloads: &[String]) {
+    let args: Vec<_> = env::args().skip(1).filter(|arg| arg != "--watch").collect();
+    let exe = env::current_exe().expect("couldn't find the unlisp executable");
+
+    let mut files = vec![file];
+    files.extend(preloads.iter().map(String::as_str));
+
+    let result = watch::watch(&files, || match Command::new(&exe).args(&args).status() {
+        Ok(status) if status.success() => eprintln!("Build succeeded, watching for changes..."),
+        Ok(_) => eprintln!("Build failed, watching for changes..."),
+        Err(e) => eprintln!("couldn't run the build: {}", e),
+    });
+
+    if let Err(e) = result {
+        eprintln!("couldn't watch {}: {}", file, e);
+    }
+}
+
+// The forms are macroexpanded and the definitions found in the expansions,
+// see doc::collect_hir. Forms that only define are evaluated so the macros
+// they define expand in later forms, other forms aren't run. Expansion and
+// evaluation errors are reported and the form skipped, reader errors fail.
+fn doc_files(preloads: &[String], files: &[&str], out: &str) -> bool {
+    unlisp_rt::defs::unlisp_rt_init_runtime();
+    let mut ctx = CodegenContext::new();
+
+    let mut definitions = doc::Definitions::new();
+    doc::collect_natives(&mut definitions);
+
+    let paths = preloads.iter().map(String::as_str).chain(files.iter().cloned());
+
+    for path in paths {
+        let mut source: Box<dyn Read> = if path == STDIO_PATH {
+            Box::new(io::stdin())
+        } else {
+            match fs::File::open(path) {
+                Ok(file) => Box::new(file),
+                Err(e) => {
+                    eprintln!("couldn't read {}: {}", source_name(path), e);
+                    return false;
+                }
+            }
+        };
+
+        let name = source_name(path);
+        let reader = reader::Reader::create(&mut source);
+        for source_form in FileForms::new(reader, path, name, path == STDIO_PATH) {
+            let location = format!("{}:{}", source_form.file, source_form.line);
+
+            let form = match &source_form.form {
+                Ok(form) => form,
+                Err(e) => {
+                    eprintln!("[{}] {}", location, e);
+                    return false;
+                }
+            };
+
+            let hir = repr::form_to_hir_with_spans(form, &source_form.spans, &source_form.file);
+            let hir = match hir {
+                Ok(hir) => hir,
+                Err(e) => {
+                    eprintln!("[{}] {}", location, e);
+                    continue;
+                }
+            };
+
+            doc::collect_hir(&mut definitions, &hir, &source_form.file, source_form.line);
+
+            if doc::only_defines(&hir) {
+                let result = unsafe { ctx.eval_top_level_hir(&hir) };
+                ctx.take_warnings();
+
+                if let Err(e) = result {
+                    eprintln!("[{}] {}", location, e);
+                }
+            }
+        }
+    }
+
+    let markdown = doc::render_markdown(&definitions);
+
+    if out == STDIO_PATH {
+        io::stdout()
+            .write_all(markdown.as_bytes())
+            .expect("couldn't write to stdout");
+    } else if let Err(e) = fs::write(out, markdown) {
+        eprintln!("couldn't write {}: {}", out, e);
         return false;
     }
 
     true
 }
 
+// Writes the forms of the file to stdout as the compiler sees them, with
+// %include directives spliced in and macros expanded, see
+// expand::hir_to_form. Like for doc, forms that only define are evaluated so
+// the macros they define expand in later forms, other forms aren't run.
+// Forms that fail to read or expand are reported and the result is a
+// failure, the other forms are still written.
+fn expand_file(preloads: &[String], path: &str) -> bool {
+    unlisp_rt::defs::unlisp_rt_init_runtime();
+    let mut ctx = CodegenContext::new();
+    eval_preloads(&mut ctx, preloads);
+
+    let mut source: Box<dyn Read> = if path == STDIO_PATH {
+        Box::new(io::stdin())
+    } else {
+        match fs::File::open(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("couldn't read {}: {}", source_name(path), e);
+                return false;
+            }
+        }
+    };
+
+    let mut expanded = vec![];
+    let mut failed = false;
+
+    let name = source_name(path);
+    let reader = reader::Reader::create(&mut source);
+    for source_form in FileForms::new(reader, path, name, path == STDIO_PATH) {
+        let location = format!("{}:{}", source_form.file, source_form.line);
+
+        let hir = source_form.form.as_ref().map_err(|e| e.to_string()).and_then(|form| {
+            repr::form_to_hir_with_spans(form, &source_form.spans, &source_form.file)
+                .map_err(|e| e.to_string())
+        });
+        let hir = match hir {
+            Ok(hir) => hir,
+            Err(e) => {
+                eprintln!("[{}] {}", location, e);
+                failed = true;
+                continue;
+            }
+        };
+
+        expanded.push(expand::hir_to_form(&hir));
+
+        if doc::only_defines(&hir) {
+            let result = unsafe { ctx.eval_top_level_hir(&hir) };
+            ctx.take_warnings();
+
+            if let Err(e) = result {
+                eprintln!("[{}] {}", location, e);
+                failed = true;
+            }
+        }
+    }
+
+    // one form per line when the formatter can't lay them out
+    let text = expand::forms_text(&expanded);
+    let text = fmt::format_source(&text).unwrap_or(text);
+    io::stdout()
+        .write_all(text.as_bytes())
+        .expect("couldn't write to stdout");
+
+    !failed
+}
+
+// formats the file in place, or with check writes the result to stdout and
+// fails when it differs from the file, stdin is always formatted to stdout
+fn fmt_file(path: &str, check: bool) -> bool {
+    let src = if path == STDIO_PATH {
+        let mut src = String::new();
+        io::stdin().read_to_string(&mut src).map(|_| src)
+    } else {
+        fs::read_to_string(path)
+    };
+
+    let src = match src {
+        Ok(src) => src,
+        Err(e) => {
+            eprintln!("couldn't read {}: {}", source_name(path), e);
+            return false;
+        }
+    };
+
+    let formatted = match fmt::format_source(&src) {
+        Ok(formatted) => formatted,
+        Err(e) => {
+            match e.line {
+                Some(line) => eprintln!("[{}:{}] {}", source_name(path), line, e),
+                None => eprintln!("[{}] {}", source_name(path), e),
+            }
+            return false;
+        }
+    };
+
+    if check || path == STDIO_PATH {
+        io::stdout()
+            .write_all(formatted.as_bytes())
+            .expect("couldn't write to stdout");
+    } else if formatted != src {
+        if let Err(e) = fs::write(path, &formatted) {
+            eprintln!("couldn't write {}: {}", path, e);
+            return false;
+        }
+    }
+
+    !check || formatted == src
+}
+
+fn exit_with_code(code: i32) -> ! {
+    // process::exit doesn't flush stdout
+    unlisp_rt::output::flush_stdout();
+    std::process::exit(code);
+}
+
+fn exit_with_failure() -> ! {
+    exit_with_code(1);
+}
+
 fn main() {
     let app = App::new("unlisp")
         .version("0.1.0")
@@ -194,12 +975,77 @@ fn main() {
              .long("no-stdlib")
              .conflicts_with("stdlib-path")
              .help("Don't precompile stdlib file"))
+        .arg(Arg::with_name("preload")
+             .long("preload")
+             .value_name("FILE")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .help("Loads a file after stdlib, can be repeated (also read from UNLISP_PRELOAD)"))
+        .arg(Arg::with_name("print-passes")
+             .long("print-passes")
+             .help("Print the optimization pipeline to stderr"))
+        .arg(Arg::with_name("max-nesting-depth")
+             .long("max-nesting-depth")
+             .value_name("N")
+             .takes_value(true)
+             .help("Reports forms with lists nested deeper than N as errors (default: 10000)"))
+        .arg(Arg::with_name("symbol-case")
+             .long("symbol-case")
+             .value_name("MODE")
+             .takes_value(true)
+             .possible_values(escapes::SYMBOL_CASES)
+             .default_value("preserve")
+             .help("Reads symbols written without bars as written, in lowercase or in uppercase, a file can change it with (%readtable-case MODE)"))
+        .arg(Arg::with_name("no-fold")
+             .long("no-fold")
+             .help("Don't evaluate list constructors and integer arithmetic with constant arguments at compile time"))
+        .arg(Arg::with_name("inline-threshold")
+             .long("inline-threshold")
+             .value_name("N")
+             .takes_value(true)
+             .help("Inlines calls of functions declared (inline) whose bodies have at most N nodes, 0 turns inlining off (default: 48)"))
+        .arg(Arg::with_name("enable-debug-natives")
+             .long("enable-debug-natives")
+             .help("Define shared-structure?, list-cells and points-to for looking into how lists share cells"))
+        .arg(Arg::with_name("dump-closures")
+             .long("dump-closures")
+             .help("Print the captured variables and struct layout of every compiled closure to stderr"))
+        .arg(Arg::with_name("dump-symbols")
+             .long("dump-symbols")
+             .help("Print every interned symbol with its function binding to stderr after a file is evaluated"))
+        .arg(Arg::with_name("dump-compiled")
+             .long("dump-compiled")
+             .help("Print the LLVM IR of the functions compiled for every evaluated form, or for the whole compiled program, to stderr"))
+        .arg(Arg::with_name("dump-filter")
+             .long("dump-filter")
+             .value_name("SUBSTRING")
+             .takes_value(true)
+             .help("Dump the IR only of functions whose names contain SUBSTRING, implies --dump-compiled"))
+        .arg(Arg::with_name("werror")
+             .long("werror")
+             .help("Treat warnings as errors, a form with warnings fails to compile and isn't run"))
+        .arg(Arg::with_name("diagnostics")
+             .long("diagnostics")
+             .value_name("FORMAT")
+             .takes_value(true)
+             .possible_values(&["text", "json"])
+             .default_value("text")
+             .help("Write the errors and warnings about the forms of a file evaluated or compiled to stderr as text lines or as a JSON array"))
+        .arg(Arg::with_name("script")
+             .long("script")
+             .value_name("FILE")
+             .takes_value(true)
+             .help("Eval a script file, meant for '#!/usr/bin/env -S unlisp --script' lines"))
         .subcommand(SubCommand::with_name("repl")
                     .about("Launch Unlisp REPL")
                     .arg(Arg::with_name("dump-compiled")
                          .long("dump-compiled")
                          .short("d")
-                         .help("Dump compiled IR to stderr")))
+                         .help("Dump compiled IR to stderr, like the top-level --dump-compiled"))
+                    .arg(Arg::with_name("no-fancy-repl")
+                         .long("no-fancy-repl")
+                         .help("Don't read multi-line input with nesting depth prompts")))
         .subcommand(SubCommand::with_name("eval")
                     .about("Eval a file")
                     .arg(Arg::with_name("file")
@@ -208,7 +1054,10 @@ fn main() {
                          .value_name("FILE")
                          .takes_value(true)
                          .required(true)
-                         .help("A file to eval")))
+                         .help("A file to eval"))
+                    .arg(Arg::with_name("fail-fast")
+                         .long("fail-fast")
+                         .help("Stop at the first form that fails")))
         .subcommand(SubCommand::with_name("compile")
                     .about("AOT compile a file")
                     .arg(Arg::with_name("file")
@@ -217,48 +1066,213 @@ fn main() {
                          .value_name("FILE")
                          .takes_value(true)
                          .required(true)
-                         .help("A file to compile"))
+                         .help("A file to compile (- reads from stdin)"))
                     .arg(Arg::with_name("output")
                          .short("o")
                          .long("output")
                          .value_name("FILE")
                          .takes_value(true)
-                         .help("An output binary file"))
+                         .help("An output binary file (- writes object to stdout, requires --emit=obj)"))
+                    .arg(Arg::with_name("emit")
+                         .long("emit")
+                         .value_name("KIND")
+                         .takes_value(true)
+                         .possible_values(&["exe", "obj"])
+                         .default_value("exe")
+                         .help("Emit a linked executable or an object file only"))
+                    .arg(Arg::with_name("cache-dir")
+                         .long("cache-dir")
+                         .value_name("DIR")
+                         .takes_value(true)
+                         .conflicts_with("no-cache")
+                         .help("Directory for cached object files (default: ./target-unlisp)"))
+                    .arg(Arg::with_name("no-cache")
+                         .long("no-cache")
+                         .help("Always run codegen, don't read or write cached objects"))
+                    .arg(Arg::with_name("keep-going")
+                         .long("keep-going")
+                         .help("Compile the remaining forms after a form fails to compile and report all failures (no output is written)"))
+                    .arg(Arg::with_name("allow-errors")
+                         .long("allow-errors")
+                         .requires("keep-going")
+                         .help("Write the output despite failures, calling a definition that failed to compile raises an error"))
+                    .arg(Arg::with_name("keep-temps")
+                         .long("keep-temps")
+                         .help("Keep the intermediate object files <output>.o and <output>.N.o after linking"))
+                    .arg(Arg::with_name("linker")
+                         .long("linker")
+                         .value_name("PROGRAM")
+                         .takes_value(true)
+                         .default_value(link::DEFAULT_LINKER)
+                         .help("The compiler driver that links the binary"))
+                    .arg(Arg::with_name("no-link-retry")
+                         .long("no-link-retry")
+                         .help("Link once, don't retry without -lpthread/-ldl when they're missing or with -fuse-ld=lld when the system linker is"))
+                    .arg(Arg::with_name("watch")
+                         .long("watch")
+                         .help("Compile again every time the file, the stdlib or a preload changes, until killed"))
+                    .arg(Arg::with_name("explain-rebuild")
+                         .long("explain-rebuild")
+                         .help("Print which units were rebuilt and why"))
                     .arg(Arg::with_name("runtime-lib")
                          .long("runtime-lib-path")
                          .value_name("FILE")
                          .takes_value(true)
-                         .help("Path to Unlisp runtime library to link (default: ./unlisp_rt_staticlib/target/<debug/release>/libunlisp_rt.a)")));
+                         .help("Path to Unlisp runtime library to link (default: ./unlisp_rt_staticlib/target/<debug/release>/libunlisp_rt.a)"))
+                    .arg(Arg::with_name("debug-runtime")
+                         .long("debug-runtime")
+                         .conflicts_with("runtime-lib")
+                         .help("Link the runtime built with the debug_runtime feature, which aborts on corrupted objects (default: ./unlisp_rt_staticlib/target/debug-runtime/<debug/release>/libunlisp_rt.a)")))
+        .subcommand(SubCommand::with_name("doc")
+                    .about("Write a markdown reference of the natives and of the functions and macros defined in the stdlib, the preloads and the given files")
+                    .arg(Arg::with_name("file")
+                         .short("f")
+                         .long("file")
+                         .value_name("FILE")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("A file to document, can be repeated (- reads from stdin)"))
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .value_name("FILE")
+                         .takes_value(true)
+                         .help("An output markdown file (default: stdout)")))
+        .subcommand(SubCommand::with_name("expand")
+                    .about("Print the forms of a file with %include directives spliced in and macros expanded")
+                    .arg(Arg::with_name("file")
+                         .short("f")
+                         .long("file")
+                         .value_name("FILE")
+                         .takes_value(true)
+                         .required(true)
+                         .help("A file to expand (- reads from stdin)")))
+        .subcommand(SubCommand::with_name("fmt")
+                    .about("Format a file in place, with 2-space indents and lines of at most 100 columns where possible")
+                    .arg(Arg::with_name("file")
+                         .short("f")
+                         .long("file")
+                         .value_name("FILE")
+                         .takes_value(true)
+                         .required(true)
+                         .help("A file to format (- formats stdin to stdout)"))
+                    .arg(Arg::with_name("check")
+                         .long("check")
+                         .help("Write the formatted file to stdout instead and exit with 1 if it differs from the file")));
     let matches = app.get_matches();
 
-    let stdlib_path;
+    if matches.is_present("print-passes") {
+        for step in context::pass_pipeline() {
+            eprintln!("{}", step);
+        }
 
-    if matches.is_present("no-stdlib") {
-        stdlib_path = None;
-    } else {
-        stdlib_path = Some(matches.value_of("stdlib-path").unwrap_or("./stdlib.unl"));
+        if matches.subcommand_name().is_none() && !matches.is_present("script") {
+            return;
+        }
+    }
+
+    if let Some(depth) = matches.value_of("max-nesting-depth") {
+        match depth.parse() {
+            Ok(depth) => repr::set_max_nesting_depth(depth),
+            Err(_) => {
+                eprintln!("invalid --max-nesting-depth: {}", depth);
+                exit_with_failure();
+            }
+        }
+    }
+
+    if let Some(case) = matches.value_of("symbol-case").and_then(SymbolCase::parse) {
+        escapes::set_symbol_case(case);
+    }
+
+    if matches.is_present("no-fold") {
+        fold::set_constant_folding(false);
+    }
+
+    if let Some(threshold) = matches.value_of("inline-threshold") {
+        match threshold.parse() {
+            Ok(threshold) => inline::set_inline_threshold(threshold),
+            Err(_) => {
+                eprintln!("invalid --inline-threshold: {}", threshold);
+                exit_with_failure();
+            }
+        }
+    }
+
+    if matches.is_present("enable-debug-natives") {
+        unlisp_rt::debug::enable_debug_natives();
+    }
+
+    if matches.is_present("dump-closures") {
+        DUMP_CLOSURES.store(true, Ordering::SeqCst);
+    }
+
+    if matches.is_present("dump-symbols") {
+        DUMP_SYMBOLS.store(true, Ordering::SeqCst);
+    }
+
+    let repl_dumps = matches
+        .subcommand_matches("repl")
+        .is_some_and(|matches| matches.is_present("dump-compiled"));
+    if matches.is_present("dump-compiled") || repl_dumps {
+        DUMP_COMPILED.store(true, Ordering::SeqCst);
+    }
+
+    if let Some(filter) = matches.value_of("dump-filter") {
+        DUMP_COMPILED.store(true, Ordering::SeqCst);
+        *DUMP_FILTER.lock().unwrap() = Some(filter.to_string());
+    }
+
+    if matches.is_present("werror") {
+        WARNINGS_ARE_ERRORS.store(true, Ordering::SeqCst);
+    }
+
+    if matches.value_of("diagnostics") == Some("json") {
+        diagnostics::set_json_output(true);
+    }
+
+    let mut preloads = vec![];
+
+    if !matches.is_present("no-stdlib") {
+        preloads.push(matches.value_of("stdlib-path").unwrap_or("./stdlib.unl").to_string());
+    }
+
+    if let Some(env_preloads) = env::var_os("UNLISP_PRELOAD") {
+        preloads.extend(
+            env::split_paths(&env_preloads)
+                .filter(|p| !p.as_os_str().is_empty())
+                .map(|p| p.to_string_lossy().into_owned()),
+        );
+    }
+
+    if let Some(cli_preloads) = matches.values_of("preload") {
+        preloads.extend(cli_preloads.map(String::from));
+    }
+
+    if let Some(script) = matches.value_of("script") {
+        match exec_file(&preloads, script, false, false) {
+            0 => return,
+            code => exit_with_code(code),
+        }
     }
 
     match matches.subcommand_name() {
         Some("repl") => {
-            launch_repl(
-                stdlib_path,
-                matches
-                    .subcommand_matches("repl")
-                    .unwrap()
-                    .is_present("dump-compiled"),
-            );
+            let matches = matches.subcommand_matches("repl").unwrap();
+            launch_repl(&preloads, !matches.is_present("no-fancy-repl"));
         }
         Some("eval") => {
-            if !exec_file(
-                stdlib_path,
-                matches
-                    .subcommand_matches("eval")
-                    .unwrap()
-                    .value_of("file")
-                    .unwrap(),
+            let matches = matches.subcommand_matches("eval").unwrap();
+
+            match exec_file(
+                &preloads,
+                matches.value_of("file").unwrap(),
+                matches.is_present("fail-fast"),
+                true,
             ) {
-                std::process::exit(1);
+                0 => (),
+                code => exit_with_code(code),
             }
         }
         Some("compile") => {
@@ -267,19 +1281,96 @@ fn main() {
             #[cfg(not(debug_assertions))]
             let default_rt_lib_path = "./unlisp_rt_staticlib/target/release/libunlisp_rt.a";
 
+            #[cfg(debug_assertions)]
+            let debug_rt_lib_path =
+                "./unlisp_rt_staticlib/target/debug-runtime/debug/libunlisp_rt.a";
+            #[cfg(not(debug_assertions))]
+            let debug_rt_lib_path =
+                "./unlisp_rt_staticlib/target/debug-runtime/release/libunlisp_rt.a";
+
             let matches = matches.subcommand_matches("compile").unwrap();
 
-            let runtime_lib_path = matches
-                .value_of("runtime-lib")
-                .unwrap_or(default_rt_lib_path);
+            let runtime_lib_path = if matches.is_present("debug-runtime") {
+                debug_rt_lib_path
+            } else {
+                matches
+                    .value_of("runtime-lib")
+                    .unwrap_or(default_rt_lib_path)
+            };
+
+            let cache_dir = if matches.is_present("no-cache") {
+                None
+            } else {
+                Some(matches.value_of("cache-dir").unwrap_or(cache::DEFAULT_CACHE_DIR))
+            };
+            let mut cache = ObjectCache::new(cache_dir, matches.is_present("explain-rebuild"));
+            // a cached object has no IR to show
+            if DUMP_COMPILED.load(Ordering::SeqCst) {
+                cache.rebuild_all("--dump-compiled shows the IR of every unit");
+            }
 
-            if !aot_file(
-                stdlib_path,
+            let file = matches.value_of("file").unwrap();
+            if matches.is_present("watch") {
+                if file == STDIO_PATH {
+                    eprintln!("cannot watch stdin, --watch needs a file");
+                } else {
+                    watch_and_compile(file, &preloads);
+                }
+                exit_with_failure();
+            }
+
+            let compiled = aot_file(
+                &preloads,
                 runtime_lib_path,
-                matches.value_of("file").unwrap(),
+                file,
                 matches.value_of("output").unwrap_or("./a.out"),
+                &AotOptions {
+                    emit_obj: matches.value_of("emit") == Some("obj"),
+                    keep_temps: matches.is_present("keep-temps"),
+                    keep_going: matches.is_present("keep-going"),
+                    allow_errors: matches.is_present("allow-errors"),
+                    linker: matches.value_of("linker").unwrap().to_string(),
+                    link_retry: !matches.is_present("no-link-retry"),
+                },
+                &cache,
+            );
+            // after the errors found once the forms were evaluated
+            diagnostics::flush();
+
+            if !compiled {
+                exit_with_failure();
+            }
+        }
+        Some("doc") => {
+            let matches = matches.subcommand_matches("doc").unwrap();
+            let files: Vec<_> = matches
+                .values_of("file")
+                .map(|files| files.collect())
+                .unwrap_or_default();
+
+            if !doc_files(
+                &preloads,
+                &files,
+                matches.value_of("output").unwrap_or(STDIO_PATH),
+            ) {
+                exit_with_failure();
+            }
+        }
+        Some("expand") => {
+            let matches = matches.subcommand_matches("expand").unwrap();
+
+            if !expand_file(&preloads, matches.value_of("file").unwrap()) {
+                exit_with_failure();
+            }
+        }
+        Some("fmt") => {
+            let matches = matches.subcommand_matches("fmt").unwrap();
+
+            if !fmt_file(
+                matches.value_of("file").unwrap(),
+                matches.is_present("check"),
             ) {
-                std::process::exit(1);
+                exit_with_failure();
             }
         }
         Some(cmd) => panic!("unknown command: {}", cmd),
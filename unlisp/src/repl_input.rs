@@ -0,0 +1,127 @@
+use unlispc::lexer::{Lexer, Token};
+
+use std::error::Error;
+use std::io;
+
+#[derive(Debug, PartialEq)]
+pub enum InputState {
+    Complete,
+    // open parens at the end of the buffer
+    Incomplete(usize),
+}
+
+fn is_gen_eof(e: &(dyn Error + 'static)) -> bool {
+    match e.downcast_ref::<io::Error>() {
+        Some(io_err) => io_err.kind() == io::ErrorKind::UnexpectedEof,
+        None => false,
+    }
+}
+
+// Decides whether the REPL should keep reading lines before handing the
+// buffer to the reader. Malformed input counts as complete, so the reader
+// reports the error.
+pub fn validate(buffer: &str) -> InputState {
+    // a trailing comment needs its newline to be skipped
+    let buffer = format!("{}\n", buffer);
+    let mut lexer = Lexer::from_str(&buffer);
+    let mut depth = 0usize;
+
+    loop {
+        match lexer.next_token() {
+            Ok(Some(Token::LeftPar)) => depth += 1,
+            Ok(Some(Token::RightPar)) => {
+                if depth == 0 {
+                    return InputState::Complete;
+                }
+                depth -= 1;
+            }
+            Ok(Some(_)) => (),
+            Ok(None) => break,
+            // an unterminated string or |symbol|
+            Err(ref e) if is_gen_eof(e.as_ref()) => return InputState::Incomplete(depth),
+            Err(_) => return InputState::Complete,
+        }
+    }
+
+    if depth == 0 {
+        InputState::Complete
+    } else {
+        InputState::Incomplete(depth)
+    }
+}
+
+pub fn continuation_prompt(depth: usize) -> String {
+    format!("..{}> ", depth)
+}
+
+// ANSI color codes of the errors and warnings the REPL writes
+pub const ERROR_COLOR: &str = "31";
+pub const WARNING_COLOR: &str = "33";
+
+/// `text` between the escape codes showing it in `color` on a terminal.
+pub fn colored(text: &str, color: &str) -> String {
+    format!("\x1b[{}m{}\x1b[0m", color, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete_forms() {
+        for src in &[
+            "",
+            "1",
+            "(+ 1 2)",
+            "(foo) (bar)",
+            "(a (b c) d)",
+            "; (comment",
+            "(list #\\( #\\))",
+            "(list \"\\\"(\")",
+        ] {
+            assert_eq!(validate(src), InputState::Complete, "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_incomplete_forms() {
+        assert_eq!(validate("(+ 1"), InputState::Incomplete(1));
+        assert_eq!(validate("(let ((x 1)"), InputState::Incomplete(2));
+        assert_eq!(validate("(a (b"), InputState::Incomplete(2));
+        assert_eq!(validate("(foo) (bar"), InputState::Incomplete(1));
+    }
+
+    #[test]
+    fn test_parens_in_strings_and_comments() {
+        assert_eq!(validate("(print \"(\")"), InputState::Complete);
+        assert_eq!(validate("(print \")\""), InputState::Incomplete(1));
+        assert_eq!(
+            validate("(print \"unterminated )"),
+            InputState::Incomplete(1)
+        );
+        assert_eq!(validate("(foo ; )\n"), InputState::Incomplete(1));
+        assert_eq!(validate("(foo ; )\n)"), InputState::Complete);
+        assert_eq!(validate("(quote |a ) b"), InputState::Incomplete(1));
+        assert_eq!(validate("(quote |a ) b|)"), InputState::Complete);
+    }
+
+    #[test]
+    fn test_escaped_quotes_and_parens() {
+        assert_eq!(validate("(print \"a\\\"(\")"), InputState::Complete);
+        assert_eq!(validate("(print \"a\\\")"), InputState::Incomplete(1));
+        assert_eq!(validate("(print \"a\\\\\")"), InputState::Complete);
+        assert_eq!(validate("(list #\\\" #\\( #\\;)"), InputState::Complete);
+    }
+
+    #[test]
+    fn test_colored() {
+        assert_eq!(colored("oops", ERROR_COLOR), "\x1b[31moops\x1b[0m");
+    }
+
+    #[test]
+    fn test_malformed_input_is_complete() {
+        assert_eq!(validate(")"), InputState::Complete);
+        assert_eq!(validate("(foo) )"), InputState::Complete);
+        assert_eq!(validate("(foo #)"), InputState::Complete);
+    }
+}
@@ -0,0 +1,67 @@
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Changes within this long of each other cause a single rebuild.
+pub const DEBOUNCE: Duration = Duration::from_millis(300);
+
+// the path a changed file is compared by, files that don't exist yet are
+// taken as they are
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn changed_path(event: &DebouncedEvent) -> Option<&Path> {
+    match event {
+        DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Chmod(path)
+        | DebouncedEvent::Remove(path) => Some(path),
+        // editors that save by renaming a temporary file over the old one
+        DebouncedEvent::Rename(_, to) => Some(to),
+        _ => None,
+    }
+}
+
+/// Calls `rebuild` once and then again every time one of `files` changes,
+/// until the process is killed. The directories of the files are watched
+/// rather than the files themselves, so a file replaced by an editor is
+/// still watched after it's saved.
+pub fn watch(files: &[&str], mut rebuild: impl FnMut()) -> notify::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, DEBOUNCE)?;
+
+    let files: HashSet<_> = files
+        .iter()
+        .map(|file| canonical(Path::new(file)))
+        .collect();
+    let dirs: HashSet<_> = files.iter().filter_map(|file| file.parent()).collect();
+
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+
+    rebuild();
+
+    let is_watched = |event: &DebouncedEvent| {
+        changed_path(event).is_some_and(|path| files.contains(&canonical(path)))
+    };
+
+    loop {
+        let event = rx.recv().expect("file watcher stopped");
+
+        if let DebouncedEvent::Error(e, _) = event {
+            return Err(e);
+        }
+
+        if is_watched(&event) {
+            // several files saved at once rebuild once
+            while rx.recv_timeout(DEBOUNCE).is_ok() {}
+            rebuild();
+        }
+    }
+}
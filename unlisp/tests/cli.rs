@@ -0,0 +1,1540 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+fn repo_root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("..")
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("unlisp_cli_{}_{}", std::process::id(), name))
+}
+
+fn unlisp(args: &[&str], stdin: &[u8]) -> Output {
+    unlisp_with_env(args, &[], stdin)
+}
+
+fn unlisp_with_env(args: &[&str], envs: &[(&str, &str)], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_unlisp"))
+        .current_dir(repo_root())
+        .args(args)
+        .env_remove("UNLISP_PRELOAD")
+        .envs(envs.iter().cloned())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to launch unlisp");
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin)
+        .expect("failed to write stdin");
+
+    child.wait_with_output().expect("failed to wait for unlisp")
+}
+
+#[test]
+fn test_linking_to_stdout_is_rejected() {
+    let output = unlisp(&["compile", "-f", "-", "-o", "-"], b"");
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--emit=obj"));
+}
+
+#[test]
+#[ignore] // needs the runtime staticlib built, see README
+fn test_compile_stdin_to_stdout_object() {
+    let output = unlisp(
+        &["compile", "-f", "-", "--emit=obj", "-o", "-"],
+        b"(defun -main () (println (fibo 10)))",
+    );
+    assert!(output.status.success());
+
+    let object_file = temp_path("stdin.o");
+    let binary = temp_path("stdin.bin");
+    fs::write(&object_file, &output.stdout).unwrap();
+
+    let rt_lib = repo_root().join("unlisp_rt_staticlib/target/debug/libunlisp_rt.a");
+    let linked = Command::new("clang")
+        .args(&["-lpthread", "-ldl"])
+        .arg(&object_file)
+        .arg(&rt_lib)
+        .arg("-o")
+        .arg(&binary)
+        .status()
+        .expect("failed to execute linker");
+    assert!(linked.success());
+
+    let run = Command::new(&binary).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "89\n");
+
+    let _ = fs::remove_file(object_file);
+    let _ = fs::remove_file(binary);
+}
+
+#[test]
+fn test_output_of_forms_compiled_to_stdout_goes_to_stderr() {
+    let output = unlisp(
+        &["compile", "-f", "-", "--emit=obj", "-o", "-", "--no-cache"],
+        b"(println \"loading\") (format t \"~a~%\" 1) (defun -main () nil)",
+    );
+    assert!(output.status.success());
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("loading\n1\n"), "{}", stderr);
+    assert!(!output.stdout.starts_with(b"loading"));
+    #[cfg(target_os = "linux")]
+    assert!(output.stdout.starts_with(b"\x7fELF"));
+}
+
+#[test]
+fn test_room_and_run_tests_compiled_to_stdout_go_to_stderr() {
+    let output = unlisp(
+        &["compile", "-f", "-", "--emit=obj", "-o", "-", "--no-cache"],
+        b"(room) (run-tests) (defun -main () nil)",
+    );
+    assert!(output.status.success());
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("conses"), "{}", stderr);
+    assert!(stderr.contains("tests passed\n"), "{}", stderr);
+    #[cfg(target_os = "linux")]
+    assert!(output.stdout.starts_with(b"\x7fELF"));
+}
+
+fn write_temp(name: &str, contents: &str) -> String {
+    let path = temp_path(name);
+    fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn test_preloads_load_in_order() {
+    let first = write_temp("preload_first.unl", "(defmacro twice (x) (qquote (+ (unq x) (unq x))))");
+    let second = write_temp("preload_second.unl", "(defun quadruple (x) (twice (twice x)))");
+    let main = write_temp("preload_main.unl", "(println (quadruple 3))");
+
+    let output = unlisp(
+        &["--preload", &first, "--preload", &second, "eval", "-f", &main],
+        b"",
+    );
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "12\n");
+}
+
+#[test]
+fn test_env_preloads_come_before_cli_preloads() {
+    let from_env = write_temp("preload_env.unl", "(defvar preload-origin 1)");
+    let from_cli = write_temp("preload_cli.unl", "(defvar preload-origin (+ preload-origin 1))");
+    let main = write_temp("preload_env_main.unl", "(println preload-origin)");
+
+    let output = unlisp_with_env(
+        &["--preload", &from_cli, "eval", "-f", &main],
+        &[("UNLISP_PRELOAD", &from_env)],
+        b"",
+    );
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "2\n");
+}
+
+#[test]
+fn test_preloads_without_stdlib() {
+    let preload = write_temp(
+        "preload_no_stdlib.unl",
+        "(set-symbol-function! (quote inc) (lambda (x) (+ x 1)))",
+    );
+    let main = write_temp("preload_no_stdlib_main.unl", "(println (inc 1))");
+
+    let output = unlisp(&["--no-stdlib", "--preload", &preload, "eval", "-f", &main], b"");
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "2\n");
+}
+
+#[test]
+fn test_script_with_shebang() {
+    let script = write_temp(
+        "shebang.unl",
+        "#!/usr/bin/env -S unlisp --script\n(println (+ 1 2))\n",
+    );
+
+    let output = unlisp(&["--script", &script], b"");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+
+    let output = unlisp(&["eval", "-f", &script], b"");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+}
+
+#[test]
+#[ignore] // needs the runtime staticlib built, see README
+fn test_room_in_compiled_binary() {
+    let source = write_temp(
+        "room.unl",
+        "(defun -main () (reset-room) (cons 1 (cons 2 nil)) (println (first (room))))",
+    );
+    let binary = temp_path("room.bin");
+    let binary_str = binary.to_string_lossy().into_owned();
+
+    let output = unlisp(&["compile", "-f", &source, "-o", &binary_str], b"");
+    assert!(output.status.success());
+
+    let run = Command::new(&binary).output().unwrap();
+    assert!(String::from_utf8_lossy(&run.stdout).ends_with("(conses . 2)\n"));
+
+    let _ = fs::remove_file(binary);
+}
+
+#[test]
+fn test_constant_defvars_in_compiled_binary() {
+    let source = write_temp(
+        "defvars.unl",
+        "(defvar *limit* 100)
+         (defvar *greeting* \"hi\")
+         (defvar *range* (quote (1 to 100)))
+         (defvar *dynamic* (+ *limit* (length (list 1 2))))
+         (defun -main () (println (list *limit* *greeting* *range* *dynamic*)))",
+    );
+    let object = temp_path("defvars.o").to_string_lossy().into_owned();
+
+    // the constructor setting the constants doesn't need the runtime to
+    // compile
+    let output = unlisp(
+        &["compile", "-f", &source, "--emit=obj", "-o", &object, "--no-cache"],
+        b"",
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let _ = fs::remove_file(object);
+
+    // running it does, see README
+    let rt_lib = repo_root().join("unlisp_rt_staticlib/target/debug/libunlisp_rt.a");
+    if !rt_lib.exists() {
+        return;
+    }
+
+    let binary = temp_path("defvars.bin");
+    let binary_str = binary.to_string_lossy().into_owned();
+
+    let output = unlisp(&["compile", "-f", &source, "-o", &binary_str, "--no-cache"], b"");
+    assert!(output.status.success());
+
+    let run = Command::new(&binary).output().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&run.stdout),
+        "(100 \"hi\" (1 to 100) 102)\n"
+    );
+
+    let _ = fs::remove_file(binary);
+}
+
+#[test]
+#[ignore] // needs the runtime staticlib built, see README
+fn test_object_file_removed_unless_keep_temps() {
+    let source = write_temp("temps.unl", "(defun -main () (println 1))");
+    let binary = temp_path("temps.bin");
+    let binary_str = binary.to_string_lossy().into_owned();
+    let object = format!("{}.o", binary_str);
+
+    let output = unlisp(&["compile", "-f", &source, "-o", &binary_str], b"");
+    assert!(output.status.success());
+    assert!(binary.exists());
+    assert!(!PathBuf::from(&object).exists());
+
+    let output = unlisp(
+        &["compile", "-f", &source, "-o", &binary_str, "--keep-temps"],
+        b"",
+    );
+    assert!(output.status.success());
+    assert!(PathBuf::from(&object).exists());
+
+    let _ = fs::remove_file(object);
+    let _ = fs::remove_file(binary);
+}
+
+#[test]
+fn test_object_cache_follows_macro_changes() {
+    let cache_dir = temp_path("cache");
+    let cache_dir_str = cache_dir.to_string_lossy().into_owned();
+    let object = temp_path("cached.o").to_string_lossy().into_owned();
+    let main = write_temp("cache_main.unl", "(defun -main () (println (answer)))");
+
+    let compile = |macro_src: &str| {
+        let macros = write_temp("cache_macros.unl", macro_src);
+        let output = unlisp(
+            &[
+                "--preload", &macros,
+                "compile", "-f", &main, "--emit=obj", "-o", &object,
+                "--cache-dir", &cache_dir_str, "--explain-rebuild",
+            ],
+            b"",
+        );
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+
+    assert!(compile("(defmacro answer () 42)").contains("rebuilt"));
+    assert!(compile("(defmacro answer () 42)").contains("up to date"));
+    assert!(compile("(defmacro answer () 43)").contains("rebuilt"));
+
+    let output = unlisp(
+        &["compile", "-f", &main, "--emit=obj", "-o", &object, "--no-cache", "--explain-rebuild"],
+        b"",
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cache disabled"));
+
+    let _ = fs::remove_dir_all(cache_dir);
+    let _ = fs::remove_file(object);
+}
+
+#[test]
+fn test_object_cache_rebuilds_only_changed_files() {
+    let cache_dir = temp_path("unit_cache");
+    let cache_dir_str = cache_dir.to_string_lossy().into_owned();
+    let object = temp_path("units.o").to_string_lossy().into_owned();
+    let first = write_temp("units_first.unl", "(defun first-part () 1)");
+    let second = temp_path("units_second.unl").to_string_lossy().into_owned();
+    let main = write_temp(
+        "units_main.unl",
+        "(defun -main () (println (list (first-part) (second-part))))",
+    );
+
+    // the units rebuilt, by name
+    let compile = |second_src: &str| -> Vec<String> {
+        fs::write(&second, second_src).unwrap();
+        let output = unlisp(
+            &[
+                "--preload", &first, "--preload", &second,
+                "compile", "-f", &main, "--emit=obj", "-o", &object,
+                "--cache-dir", &cache_dir_str, "--explain-rebuild",
+            ],
+            b"",
+        );
+        assert!(output.status.success());
+
+        String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .filter_map(|line| line.find(": rebuilt").map(|i| line[..i].to_string()))
+            .collect()
+    };
+
+    assert_eq!(compile("(defun second-part () 2)").len(), 4);
+    assert!(compile("(defun second-part () 2)").is_empty());
+    assert_eq!(compile("(defun second-part () 3)"), vec![second.clone()]);
+
+    let _ = fs::remove_dir_all(cache_dir);
+    let _ = fs::remove_file(object);
+}
+
+#[test]
+fn test_namespace_conflicts_warn_in_eval() {
+    let output = unlisp(
+        &["eval", "-f", "-"],
+        b"(defun f (x) x)\n(defmacro f (x) x)\n(defmacro g (x) x)\n\n(defun g (x) x)\n\
+          (println (f 1))",
+    );
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success());
+    assert!(stderr.contains("warning: function f redefined as a macro"));
+    assert!(stderr.contains("warning: macro g redefined as a function"));
+    // both definitions are named
+    assert!(stderr.contains("(defined at <stdin>:1, redefined at <stdin>:2)"), "{}", stderr);
+    assert!(stderr.contains("(defined at <stdin>:3, redefined at <stdin>:5)"), "{}", stderr);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1"));
+}
+
+#[test]
+fn test_namespace_conflicts_fail_compilation() {
+    let object = temp_path("conflict.o");
+    let object = object.to_str().unwrap();
+
+    for src in [
+        &b"(defun f (x) x) (defmacro f (x) x) (defun -main () nil)"[..],
+        &b"(defmacro g (x) x) (defun g (x) x) (defun -main () nil)"[..],
+    ]
+    .iter()
+    {
+        let output = unlisp(
+            &["compile", "-f", "-", "--emit=obj", "-o", object, "--no-cache"],
+            src,
+        );
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success());
+        assert!(stderr.contains("redefined as a"));
+        assert!(stderr.contains("(defined at <stdin>:1, redefined at <stdin>:1)"), "{}", stderr);
+
+        let output = unlisp(
+            &[
+                "--diagnostics=json", "compile", "-f", "-", "--emit=obj", "-o", object,
+                "--no-cache",
+            ],
+            src,
+        );
+
+        // the form and the file, in the one JSON array
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success());
+        assert!(stderr.starts_with('[') && stderr.ends_with("]\n"), "{}", stderr);
+        assert_eq!(stderr.lines().count(), 1, "{}", stderr);
+        assert!(stderr.contains("redefined as a"), "{}", stderr);
+        assert!(
+            stderr.contains(
+                "{\"severity\":\"error\",\"message\":\"cannot compile a file that redefines \
+                 functions as macros or vice versa\",\"file\":\"<stdin>\",\"line\":1,\"col\":1}"
+            ),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_macro_arity_misuse() {
+    let src = b"(defmacro m (x) x) (defun -main () (m 1 2))";
+
+    let output = unlisp(&["eval", "-f", "-"], src);
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("wrong number of argument forms (2) passed to macro m"));
+
+    let object = temp_path("macro_arity.o");
+    let output = unlisp(
+        &["compile", "-f", "-", "--emit=obj", "-o", object.to_str().unwrap(), "--no-cache"],
+        src,
+    );
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("wrong number of argument forms (2) passed to macro m"));
+}
+
+#[test]
+fn test_fancy_repl_continuation_prompt() {
+    let output = unlisp_with_env(
+        &["repl"],
+        &[("TERM", "xterm")],
+        b"(+ 1\n(+ 1\n1))\nundefined-var\n",
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(stdout.contains("..1> "));
+    assert!(stdout.contains("..2> "));
+    assert!(stdout.contains("3"));
+    // stderr is a pipe, so errors aren't colored
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "compilation error: undefined symbol: undefined-var\n"
+    );
+
+    let output = unlisp_with_env(
+        &["repl", "--no-fancy-repl"],
+        &[("TERM", "xterm")],
+        b"(+ 1\n2)\n",
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(!stdout.contains("..1> "));
+    assert!(stdout.contains("3"));
+}
+
+#[test]
+fn test_print_passes() {
+    let output = unlisp(&["--print-passes"], b"");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(output.status.success());
+    assert!(!stderr.trim().is_empty());
+    assert!(stderr.contains("codegen opt level"));
+
+    let output = unlisp(&["--print-passes", "eval", "-f", "-"], b"(println 1)");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("codegen opt level"));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+}
+
+// stdout and stderr share one pipe, so the output shows the order in which
+// they were written
+fn unlisp_merged_output(args: &[&str], stdin: &[u8]) -> Vec<u8> {
+    let mut child = Command::new("sh")
+        .current_dir(repo_root())
+        .arg("-c")
+        .arg("exec \"$0\" \"$@\" 2>&1")
+        .arg(env!("CARGO_BIN_EXE_unlisp"))
+        .args(args)
+        .env_remove("UNLISP_PRELOAD")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to launch unlisp");
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin)
+        .expect("failed to write stdin");
+
+    child.wait_with_output().expect("failed to wait for unlisp").stdout
+}
+
+#[test]
+fn test_output_precedes_errors_in_pipe() {
+    let output = unlisp_merged_output(
+        &["eval", "-f", "-"],
+        b"(prin1 1) (error \"boom\") (stdout-write \"2\")",
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output),
+        "1[<stdin>:1:11] runtime error: boom\n23 forms, 1 errors\n"
+    );
+
+    let output = unlisp_merged_output(&["repl", "--no-fancy-repl"], b"(prin1 1)\n(error \"boom\")\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output),
+        ">>> 11\n>>> runtime error: boom\n>>> "
+    );
+}
+
+#[test]
+#[ignore] // needs the runtime staticlib built, see README
+fn test_compiled_binary_flushes_before_error_exit() {
+    let source = write_temp(
+        "flush.unl",
+        "(defun -main () (prin1 1) (stdout-write \"2\") (error \"boom\"))",
+    );
+    let binary = temp_path("flush.bin");
+    let binary_str = binary.to_string_lossy().into_owned();
+
+    let output = unlisp(&["compile", "-f", &source, "-o", &binary_str], b"");
+    assert!(output.status.success());
+
+    let run = Command::new(&binary).output().unwrap();
+    assert_eq!(run.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "12");
+    assert_eq!(String::from_utf8_lossy(&run.stderr), "runtime error: boom\n");
+
+    let _ = fs::remove_file(binary);
+}
+
+#[test]
+fn test_deeply_nested_input_is_diagnosed() {
+    let mut input = "(".repeat(100_000);
+    input.push_str(" (prin1 7)");
+
+    let output = unlisp(&["eval", "-f", "-"], input.as_bytes());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "[<stdin>:1] reader error: form nested deeper than 10000 levels (see --max-nesting-depth)\n\
+         1 forms, 1 errors\n"
+    );
+
+    let output = unlisp(
+        &["--no-stdlib", "--max-nesting-depth", "4", "eval", "-f", "-"],
+        b"(prin1 (quote ((1)))) (prin1 (quote (((1)))))",
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "((1))");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("form nested deeper than 4 levels"));
+}
+
+#[test]
+fn test_deeply_nested_constant_compiles() {
+    let depth = 5_000;
+    let source = format!(
+        "(defun -main () (quote {}1{}))",
+        "(".repeat(depth),
+        ")".repeat(depth)
+    );
+
+    let output = unlisp(
+        &["compile", "-f", "-", "--emit=obj", "-o", "-", "--no-cache"],
+        source.as_bytes(),
+    );
+    assert!(output.status.success());
+    assert!(!output.stdout.is_empty());
+
+    let output = unlisp(
+        &[
+            "--max-nesting-depth",
+            "100",
+            "compile",
+            "-f",
+            "-",
+            "--emit=obj",
+            "-o",
+            "-",
+            "--no-cache",
+        ],
+        source.as_bytes(),
+    );
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("reader error: form nested deeper than 100 levels"));
+}
+
+#[test]
+fn test_eval_exit_codes() {
+    let cases = [
+        ("exit_ok.unl", "(println 1) (println 2)", 0, "2 forms, 0 errors\n"),
+        ("exit_runtime.unl", "(println 1) (error \"boom\") (println 2)", 1, "3 forms, 1 errors\n"),
+        ("exit_compile.unl", "(println undefined-var) (error \"boom\")", 2, "2 forms, 2 errors\n"),
+        ("exit_reader.unl", "(println 1) (println 2", 2, "2 forms, 1 errors\n"),
+    ];
+
+    for (name, src, code, summary) in cases.iter() {
+        let file = write_temp(name, src);
+        let output = unlisp(&["eval", "-f", &file], b"");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert_eq!(output.status.code(), Some(*code), "{}", stderr);
+        assert!(stderr.ends_with(summary), "{}", stderr);
+    }
+}
+
+#[test]
+fn test_eval_fail_fast() {
+    let file = write_temp(
+        "fail_fast.unl",
+        "(prin1 1) (error \"boom\") (prin1 2) (undefined-fn)",
+    );
+
+    let output = unlisp(&["eval", "-f", &file], b"");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "12");
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with("4 forms, 2 errors\n"));
+
+    let output = unlisp(&["eval", "-f", &file, "--fail-fast"], b"");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        format!("[{}:1:11] runtime error: boom\n2 forms, 1 errors\n", file)
+    );
+}
+
+#[test]
+fn test_runtime_errors_report_the_call_that_raised_them() {
+    let file = write_temp("error_location.unl", "(defun f (x)\n  (car x))\n\n(f 1)");
+
+    let output = unlisp(&["eval", "-f", &file], b"");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr.starts_with(&format!("[{}:2:3] runtime error: ", file)),
+        "{}",
+        stderr
+    );
+
+    let output = unlisp(&["--diagnostics=json", "eval", "-f", &file], b"");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("\"file\":\"{}\",\"line\":2,\"col\":3", file)),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_symbol_order_is_stable_across_runs() {
+    let src = b"(quote (zeta alpha mid)) (println (all-symbols)) (println (room))";
+
+    let first = unlisp(&["eval", "-f", "-"], src);
+    let second = unlisp(&["eval", "-f", "-"], src);
+
+    assert!(first.status.success());
+    assert!(!first.stdout.is_empty());
+    assert_eq!(first.stdout, second.stdout);
+}
+
+#[test]
+fn test_all_symbols_golden() {
+    let output = unlisp(
+        &["eval", "-f", "-"],
+        b"(defun golden-b () nil) (defvar golden-c 1) (quote golden-a) \
+          (println (all-symbols \"golden-\"))",
+    );
+
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "(golden-a golden-b golden-c)\n"
+    );
+}
+
+#[test]
+fn test_no_fold_gives_the_same_output() {
+    let src = b"(defun pairs () (list (cons 1 2) (append (list 1 2) (list 3)) (reverse (list 1 2)))) \
+                (println (pairs)) (set-first! (pairs) 0) (println (pairs))";
+
+    let folded = unlisp(&["eval", "-f", "-"], src);
+    let not_folded = unlisp(&["--no-fold", "eval", "-f", "-"], src);
+
+    assert_eq!(
+        String::from_utf8_lossy(&folded.stdout),
+        "((1 . 2) (1 2 3) (2 1))\n((1 . 2) (1 2 3) (2 1))\n"
+    );
+    assert_eq!(folded.stdout, not_folded.stdout);
+}
+
+#[test]
+fn test_dump_closures_golden() {
+    let src = b"(defun adder (n) (lambda (x) (lambda () (+ n x))))";
+
+    let output = unlisp(&["--dump-closures", "eval", "-f", "-"], src);
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "1 forms, 0 errors\n");
+    // only closures of the file, not of the stdlib
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "closure adder
+  arglist: (n)
+  restarg: none
+  free variables: none
+  fields:
+    0-7: function header
+closure adder/lambda
+  arglist: (x)
+  restarg: none
+  free variables:
+    n: parameter
+  fields:
+    0-7: function header
+    8: n
+closure adder/lambda/lambda
+  arglist: ()
+  restarg: none
+  free variables:
+    n: outer capture
+    x: parameter
+  fields:
+    0-7: function header
+    8: n
+    9: x
+"
+    );
+}
+
+#[test]
+fn test_dumps_stay_out_of_json_diagnostics() {
+    let src = b"(defun adder (n) (lambda (x) (+ n x)))\n(println undefined-var)";
+
+    let output = unlisp(
+        &["--diagnostics=json", "--dump-closures", "--dump-compiled", "eval", "-f", "-"],
+        src,
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("closure adder\n"), "{}", stdout);
+    assert!(!dumped_functions(&output.stdout).is_empty(), "{}", stdout);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "[{\"severity\":\"error\",\"message\":\"compilation error: undefined symbol: undefined-var\",\
+         \"file\":\"<stdin>\",\"line\":2,\"col\":1}]\n"
+    );
+
+    // stdout has the object, stderr the diagnostics
+    let output = unlisp(
+        &["--diagnostics=json", "--dump-closures", "compile", "-f", "-", "--emit=obj", "-o", "-"],
+        src,
+    );
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot dump"));
+}
+
+#[test]
+fn test_princ_prin1_print_output() {
+    let output = unlisp(
+        &["eval", "-f", "-"],
+        br#"(princ "a\"b") (prin1 "a\"b") (princ #\c) (prin1 #\c) (print (quote |d e|))"#,
+    );
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "a\"b\"a\\\"b\"c#\\c\n|d e|"
+    );
+}
+
+#[test]
+#[ignore] // needs the runtime staticlib built, see README
+fn test_compiled_calls_with_many_arguments() {
+    let numbers = |from: usize, to: usize| {
+        (from..to)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    let mut src = String::from("(defun -main ()");
+    let mut defs = String::new();
+    let mut expected = String::new();
+
+    for n in [1, 7, 32, 100, 255].iter() {
+        let n = *n;
+        let params = (0..n)
+            .map(|i| format!("p{}", i))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        defs.push_str(&format!(
+            "(defun f{n} ({params}) (list p0 p{last}))
+             (defun r{n} ({params} & rest) (list p0 p{last} rest))",
+            n = n,
+            params = params,
+            last = n - 1
+        ));
+        src.push_str(&format!(
+            " (println (list (f{n} {args}) (apply (symf f{n}) (list {args}))
+                             (r{n} {args} {more}) (apply (symf r{n}) 0 (list {args_rest} {more}))))",
+            n = n,
+            args = numbers(0, n),
+            args_rest = numbers(1, n),
+            more = numbers(n, n + 2)
+        ));
+        expected.push_str(&format!(
+            "((0 {last}) (0 {last}) (0 {last} ({n} {n1})) (0 {last} ({n} {n1})))\n",
+            last = n - 1,
+            n = n,
+            n1 = n + 1
+        ));
+    }
+    src.push(')');
+
+    let source = write_temp("many_args.unl", &format!("{}\n{}", defs, src));
+    let binary = temp_path("many_args.bin");
+    let binary_str = binary.to_string_lossy().into_owned();
+
+    let output = unlisp(&["compile", "-f", &source, "-o", &binary_str], b"");
+    assert!(output.status.success());
+
+    let run = Command::new(&binary).output().unwrap();
+    assert!(run.status.success());
+    assert_eq!(String::from_utf8_lossy(&run.stdout), expected);
+
+    let _ = fs::remove_file(binary);
+}
+
+#[test]
+fn test_fresh_line_after_terpri() {
+    let output = unlisp(
+        &["eval", "-f", "-"],
+        br#"(princ "a") (terpri) (fresh-line) (princ "b") (fresh-line) (fresh-line) (princ "c")"#,
+    );
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "a\nb\nc");
+}
+
+#[test]
+fn test_fresh_line_after_repl_result() {
+    let output = unlisp(&["repl", "--no-fancy-repl"], b"(princ \"a\")\n(fresh-line)\n");
+
+    assert_eq!(String::from_utf8_lossy(&output.stdout), ">>> a\"a\"\n>>> nil\n>>> ");
+}
+
+#[test]
+fn test_doc_covers_stdlib_and_natives() {
+    let output = unlisp(&["doc"], b"");
+
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+
+    let markdown = String::from_utf8_lossy(&output.stdout);
+    let stdlib = fs::read_to_string(repo_root().join("stdlib.unl")).unwrap();
+
+    // every defun and defmacro of the stdlib gets an entry
+    for line in stdlib.lines() {
+        let words: Vec<_> = line.split_whitespace().collect();
+        if words.len() < 2 || (words[0] != "(defun" && words[0] != "(defmacro") {
+            continue;
+        }
+        assert!(markdown.contains(&format!("\n## `{}`\n", words[1])), "{}", words[1]);
+    }
+
+    for entry in [
+        "- `defun` (macro)",
+        "- `append` (function)",
+        "- `princ` (native function)",
+        "- `apply` (native function)",
+    ]
+    .iter()
+    {
+        assert!(markdown.contains(entry), "{}", entry);
+    }
+
+    // natives are documented too
+    assert!(markdown.contains(
+        "`(apply f & rest)`\n\nnative function of the runtime\n\nCalls f with the other arguments"
+    ));
+
+    // the stdlib redefines defun, the entry is of the last definition
+    let defun_line = stdlib
+        .lines()
+        .rposition(|line| line.starts_with("(defmacro defun "))
+        .unwrap()
+        + 1;
+    let defined_at = format!("macro, defined in ./stdlib.unl:{}\n", defun_line);
+    assert!(markdown.contains(&defined_at), "{}", defined_at);
+}
+
+#[test]
+fn test_doc_of_file_to_output() {
+    let dir = temp_path("doc");
+    fs::create_dir_all(&dir).unwrap();
+    let dir_name = dir.to_string_lossy().into_owned();
+
+    let source = dir.join("main.unl");
+    fs::write(
+        &source,
+        "(%include \"shapes.unl\")
+(defun area (w h) \"Multiplies w and h.\" (* w h))
+(println (area 2 3))
+(defun sign (x)
+  \"The sign of x.\"
+  (if (< x 0) (return -1))
+  1)
+",
+    )
+    .unwrap();
+    fs::write(dir.join("shapes.unl"), "\n(defstruct point x y)\n").unwrap();
+    let out = dir.join("out.md");
+
+    let output = unlisp(
+        &["doc", "-f", source.to_str().unwrap(), "-o", out.to_str().unwrap()],
+        b"",
+    );
+
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    // only the definitions are evaluated
+    assert!(output.stdout.is_empty());
+
+    let markdown = fs::read_to_string(&out).unwrap();
+    let defined = |name: &str, signature: &str, at: &str| {
+        format!("## `{}`\n\n`{}`\n\nfunction, defined in {}/{}\n\n", name, signature, dir_name, at)
+    };
+
+    let entries = [
+        defined("area", "(area w h)", "main.unl:2") + "Multiplies w and h.\n",
+        // the docstring isn't hidden in the block a return needs
+        defined("sign", "(sign x)", "main.unl:4") + "The sign of x.\n",
+        // the functions defstruct defines, in the included file
+        defined("make-point", "(make-point x y)", "shapes.unl:2"),
+        defined("set-point-y!", "(set-point-y! x val)", "shapes.unl:2"),
+    ];
+    for entry in entries.iter() {
+        assert!(markdown.contains(entry.as_str()), "{}", entry);
+    }
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn test_expand_shows_included_and_expanded_forms() {
+    let dir = temp_path("expand");
+    fs::create_dir_all(&dir).unwrap();
+    let dir_name = dir.to_string_lossy().into_owned();
+
+    let source = dir.join("main.unl");
+    fs::write(
+        &source,
+        "(%include \"macros.unl\")\n(println (tw 21))\n(let (1) 2)\n(if t (tw 1))\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("macros.unl"),
+        "(let ((m (lambda (x) (list (quote +) x x))))\n  (set-macro! m)\n  (set-symbol-function! (quote tw) m))\n",
+    )
+    .unwrap();
+
+    let output = unlisp(&["--no-stdlib", "expand", "-f", source.to_str().unwrap()], b"");
+
+    assert_eq!(output.status.code(), Some(1));
+    // only the definitions are evaluated
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "(let ((m (lambda (x) (list (quote +) x x)))) (set-macro! m) (set-symbol-function! (quote tw) m))
+(println (+ 21 21))
+(if t (+ 1 1))
+"
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        format!("[{}/main.unl:3] reader error: let binding is not a list\n", dir_name)
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+const KEEP_GOING_SOURCE: &str = "\
+(defun one () 1)
+(defun broken-a () undefined-a)
+(defun two () 2)
+(defun three () 3)
+(defun broken-b (x) (+ x undefined-b))
+(defun four () 4)
+(defun -main () (println (+ (one) (two) (three) (four))) (broken-a))
+";
+
+#[test]
+fn test_compile_stops_at_first_failed_form() {
+    let source = write_temp("stop.unl", KEEP_GOING_SOURCE);
+    let binary = temp_path("stop.bin");
+    let binary_str = binary.to_string_lossy().into_owned();
+
+    let output = unlisp(&["compile", "-f", &source, "-o", &binary_str], b"");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert_eq!(stderr.matches("undefined symbol").count(), 1, "{}", stderr);
+    assert!(stderr.contains("--keep-going"), "{}", stderr);
+    assert!(!binary.exists());
+}
+
+#[test]
+fn test_compile_keep_going_reports_every_failed_form() {
+    let source = write_temp("keep_going.unl", KEEP_GOING_SOURCE);
+    let binary = temp_path("keep_going.bin");
+    let binary_str = binary.to_string_lossy().into_owned();
+
+    let output = unlisp(
+        &["compile", "-f", &source, "-o", &binary_str, "--keep-going"],
+        b"",
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(stderr.contains("undefined symbol: undefined-a"), "{}", stderr);
+    assert!(stderr.contains("undefined symbol: undefined-b"), "{}", stderr);
+    assert!(stderr.contains("2 forms failed to compile"), "{}", stderr);
+    assert!(!binary.exists());
+
+    let output = unlisp(&["compile", "-f", &source, "--allow-errors"], b"");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--keep-going"));
+}
+
+#[test]
+#[ignore] // needs the runtime staticlib built, see README
+fn test_compile_allow_errors_links_failed_definition_stubs() {
+    let source = write_temp("allow_errors.unl", KEEP_GOING_SOURCE);
+    let binary = temp_path("allow_errors.bin");
+    let binary_str = binary.to_string_lossy().into_owned();
+
+    let output = unlisp(
+        &[
+            "compile", "-f", &source, "-o", &binary_str, "--no-cache", "--keep-going",
+            "--allow-errors",
+        ],
+        b"",
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("2 forms failed to compile"), "{}", stderr);
+
+    let run = Command::new(&binary).output().unwrap();
+    assert_eq!(run.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "10\n");
+    assert_eq!(
+        String::from_utf8_lossy(&run.stderr),
+        "runtime error: definition of broken-a failed to compile\n"
+    );
+
+    let _ = fs::remove_file(binary);
+}
+
+#[test]
+fn test_debug_natives_need_flag() {
+    let src = b"(points-to (list 1 2)) (points-to (range 20)) (points-to nil)";
+
+    let output = unlisp(&["--enable-debug-natives", "eval", "-f", "-"], src);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3, "{}", stdout);
+
+    let two_cells: Vec<_> = lines[0].split(' ').collect();
+    assert_eq!(two_cells.len(), 2);
+    assert!(two_cells.iter().all(|cell| cell.starts_with("0x")));
+    assert_eq!(lines[1].split(' ').count(), 9);
+    assert!(lines[1].ends_with(" ..."));
+    assert_eq!(lines[2], "no cells");
+
+    let output = unlisp(&["eval", "-f", "-"], src);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("points-to"));
+}
+
+#[test]
+fn test_wrong_arity_warning_tells_the_line() {
+    let output = unlisp(
+        &["eval", "-f", "-"],
+        b"(defun two (a b) a)\n\n(defun calls-two ()\n  (two 1 2 3))\n(prin1 (two 1 2))",
+    );
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "[<stdin>:4:3] warning: wrong number of arguments (3) passed to two, which takes 2\n"
+    );
+}
+
+#[test]
+fn test_fmt_check_and_in_place() {
+    let path = temp_path("fmt.unl");
+    let src = "; answer\n(defun answer () (let ((x 40)) (+ x 2)))\n";
+    let formatted = "; answer\n(defun answer ()\n  (let ((x 40)) (+ x 2)))\n";
+    fs::write(&path, src).unwrap();
+    let path_arg = path.to_str().unwrap();
+
+    let output = unlisp(&["fmt", "--check", "-f", path_arg], b"");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), formatted);
+    assert_eq!(fs::read_to_string(&path).unwrap(), src);
+
+    let output = unlisp(&["fmt", "-f", path_arg], b"");
+    assert!(output.status.success());
+    assert_eq!(fs::read_to_string(&path).unwrap(), formatted);
+
+    let output = unlisp(&["fmt", "--check", "-f", path_arg], b"");
+    assert!(output.status.success());
+
+    let output = unlisp(&["fmt", "-f", "-"], b"(f\n(g");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("[<stdin>:2] "));
+
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+fn test_werror_fails_on_warnings() {
+    let src = b"(defun two (a b) a)\n(defun calls-two () (two 1 2 3))\n(defun -main () (two 1 2))";
+    let warning =
+        "[<stdin>:2:21] warning: wrong number of arguments (3) passed to two, which takes 2\n";
+
+    let output = unlisp(&["eval", "-f", "-"], src);
+    assert!(output.status.success());
+
+    let output = unlisp(&["--werror", "eval", "-f", "-"], src);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with(warning), "{}", stderr);
+    assert!(stderr.contains("which are treated as errors"), "{}", stderr);
+
+    let compile = ["compile", "-f", "-", "--emit=obj", "-o", "-", "--no-cache"];
+    let output = unlisp(&compile, src);
+    assert!(output.status.success());
+    assert!(!output.stdout.is_empty());
+
+    let werror_compile: Vec<_> = ["--werror"].iter().chain(compile.iter()).cloned().collect();
+    let output = unlisp(&werror_compile, src);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(warning));
+}
+
+#[test]
+fn test_json_diagnostics() {
+    let src = b"(println 1)\n  (println undefined-var)\n(println (car 1";
+
+    let output = unlisp(&["--diagnostics=json", "eval", "-f", "-"], src);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "[{\"severity\":\"error\",\"message\":\"compilation error: undefined symbol: undefined-var\",\
+         \"file\":\"<stdin>\",\"line\":2,\"col\":3},\
+         {\"severity\":\"error\",\"message\":\"unexpected end of file\",\
+         \"file\":\"<stdin>\",\"line\":3,\"col\":15}]\n"
+    );
+
+    let output = unlisp(&["--diagnostics=json", "eval", "-f", "-"], b"(println 1)");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "[]\n");
+}
+
+#[test]
+fn test_include_splices_forms_of_other_files() {
+    let dir = temp_path("include");
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    let dir_name = dir.to_string_lossy().into_owned();
+
+    let main = dir.join("main.unl");
+    fs::write(
+        &main,
+        "(%include \"lib/defs.unl\")\n(println (twice (forty-two)))\n(%include \"missing.unl\")\n(%include \"lib/broken.unl\")",
+    )
+    .unwrap();
+    // paths in included files are relative to them
+    fs::write(
+        dir.join("lib/defs.unl"),
+        "(%include \"macros.unl\")\n(defun forty-two () 42)",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("lib/macros.unl"),
+        "(defmacro twice (x) (qquote (+ (unq x) (unq x))))",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("lib/broken.unl"),
+        "(println 1)\n\n  (println undefined-var)",
+    )
+    .unwrap();
+
+    let output = unlisp(
+        &["--diagnostics=json", "eval", "-f", main.to_str().unwrap()],
+        b"",
+    );
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "84\n1\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        format!(
+            "[{{\"severity\":\"error\",\"message\":\"reader error: couldn't include {dir}/missing.unl: \
+             No such file or directory (os error 2)\",\"file\":\"{dir}/main.unl\",\"line\":3,\"col\":1}},\
+             {{\"severity\":\"error\",\"message\":\"compilation error: undefined symbol: undefined-var\",\
+             \"file\":\"{dir}/lib/broken.unl\",\"line\":3,\"col\":3}}]\n",
+            dir = dir_name
+        )
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn test_include_cycles_and_depth() {
+    let dir = temp_path("include_cycle");
+    fs::create_dir_all(&dir).unwrap();
+    let dir_name = dir.to_string_lossy().into_owned();
+
+    fs::write(dir.join("a.unl"), "(println 1)\n(%include \"b.unl\")").unwrap();
+    fs::write(dir.join("b.unl"), "(%include \"a.unl\")").unwrap();
+
+    let output = unlisp(&["eval", "-f", dir.join("a.unl").to_str().unwrap()], b"");
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        format!(
+            "[{dir}/b.unl:1] reader error: include cycle: {dir}/a.unl -> {dir}/b.unl -> {dir}/a.unl\n\
+             2 forms, 1 errors\n",
+            dir = dir_name
+        )
+    );
+
+    // each file includes the next one
+    for i in 0..20 {
+        let src = format!("(%include \"nested_{}.unl\")", i + 1);
+        fs::write(dir.join(format!("nested_{}.unl", i)), src).unwrap();
+    }
+
+    let output = unlisp(
+        &["eval", "-f", dir.join("nested_0.unl").to_str().unwrap()],
+        b"",
+    );
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        format!(
+            "[{}/nested_16.unl:1] reader error: includes nested deeper than 16 files\n\
+             1 forms, 1 errors\n",
+            dir_name
+        )
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+#[ignore] // relies on file system notifications, which not every file system sends
+fn test_watch_rebuilds_on_change() {
+    let dir = temp_path("watch");
+    fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("watched.unl");
+    let object = dir.join("watched.o");
+    fs::write(&source, "(defun -main () (println 1))").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_unlisp"))
+        .current_dir(repo_root())
+        .args(&["compile", "--watch", "--no-cache", "--emit=obj", "-f"])
+        .arg(&source)
+        .arg("-o")
+        .arg(&object)
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to launch unlisp");
+
+    let (tx, rx) = mpsc::channel();
+    let stderr = BufReader::new(child.stderr.take().unwrap());
+    thread::spawn(move || {
+        for line in stderr.lines() {
+            if tx.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let next_build = || loop {
+        let line = rx
+            .recv_timeout(Duration::from_secs(60))
+            .expect("no build finished");
+        if line.starts_with("Build ") {
+            return line;
+        }
+    };
+
+    assert!(next_build().starts_with("Build succeeded"));
+    let first_object = fs::read(&object).unwrap();
+
+    // a form failing to compile doesn't stop the watching
+    fs::write(&source, "(defun -main () (println undefined-var))").unwrap();
+    assert!(next_build().starts_with("Build failed"));
+
+    fs::write(&source, "(defun -main () (println 2))").unwrap();
+    assert!(next_build().starts_with("Build succeeded"));
+    assert_ne!(fs::read(&object).unwrap(), first_object);
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let _ = fs::remove_dir_all(dir);
+}
+
+// the names on the summary line before the IR of each dumped function
+fn dumped_functions(stdout: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .filter(|line| line.starts_with("; ") && line.ends_with(" instructions"))
+        .map(|line| line[2..line.find(':').unwrap()].to_string())
+        .collect()
+}
+
+#[test]
+fn test_dump_compiled_shows_new_functions() {
+    let src = b"(defun adder3 (a b c) (+ a b c))";
+
+    let output = unlisp(&["--dump-compiled", "eval", "-f", "-"], src);
+
+    assert!(output.status.success());
+    // only the functions of the form, not the stdlib's or runtime
+    // declarations, each once
+    assert_eq!(
+        dumped_functions(&output.stdout),
+        vec!["__repl_form", "adder3", "invoke_adder3", "apply_adder3"]
+    );
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("declare "));
+
+    let output = unlisp(&["--dump-filter", "adder3", "eval", "-f", "-"], src);
+
+    assert!(output.status.success());
+    assert_eq!(
+        dumped_functions(&output.stdout),
+        vec!["adder3", "invoke_adder3", "apply_adder3"]
+    );
+
+    // the blocks of an if don't dump the function again
+    let output = unlisp(
+        &["--dump-filter", "pick", "eval", "-f", "-"],
+        b"(defun pick (x) (if x 1 2))",
+    );
+
+    assert!(output.status.success());
+    assert_eq!(
+        dumped_functions(&output.stdout),
+        vec!["pick", "invoke_pick", "apply_pick"]
+    );
+}
+
+#[test]
+fn test_dump_symbols() {
+    let src = b"(defun add2 (a b) (+ a b))
+                (defmacro unless2 (c & body) (qquote (if (unq c) nil (do (unqs body)))))
+                (defvar *counter* 0)";
+
+    let output = unlisp(&["--dump-symbols", "eval", "-f", "-"], src);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<_> = stdout.lines().collect();
+
+    assert!(lines.contains(&"add2: function, 2 args"), "{}", stdout);
+    assert!(lines.contains(&"unless2: macro, 1+ args"), "{}", stdout);
+    assert!(
+        lines.contains(&"*counter*: no function, bound"),
+        "{}",
+        stdout
+    );
+    // the stdlib's definitions are there too, sorted by name
+    assert!(lines.contains(&"when: macro, 1+ args"), "{}", stdout);
+    let add2 = lines.iter().position(|l| l.starts_with("add2:")).unwrap();
+    let unless2 = lines.iter().position(|l| l.starts_with("unless2:")).unwrap();
+    assert!(add2 < unless2);
+
+    // stderr only has the diagnostics
+    let output = unlisp(&["--dump-symbols", "--diagnostics=json", "eval", "-f", "-"], src);
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "[]\n");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("add2: function, 2 args\n"));
+}
+
+#[test]
+fn test_symbol_case_flag() {
+    let src = b"(Println (Quote (Foo |Bar|)))";
+
+    let output = unlisp(&["--symbol-case=downcase", "eval", "-f", "-"], src);
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "(foo |Bar|)\n");
+
+    let output = unlisp(&["--symbol-case=upcase", "eval", "-f", "-"], src);
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "(FOO |Bar|)\n");
+
+    let output = unlisp(&["eval", "-f", "-"], src);
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Println"));
+
+    let output = unlisp(&["--symbol-case=sideways", "eval", "-f", "-"], src);
+
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_format_to_stdout() {
+    let src = b"(println (format t \"~a and ~s~%\" 1 \"x\"))";
+
+    let output = unlisp(&["eval", "-f", "-"], src);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout, "1 and \"x\"\nnil\n");
+}
+
+// A linker that fails like GNU ld without libdl while -ldl is passed and
+// otherwise creates the output file, so linking needs no runtime staticlib.
+#[cfg(target_os = "linux")]
+fn fake_linker(name: &str, script: &str) -> String {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = write_temp(name, script);
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+#[cfg(target_os = "linux")]
+const LINKER_WITHOUT_LIBDL: &str = r#"#!/bin/sh
+for arg in "$@"; do
+    if [ "$arg" = "-ldl" ]; then
+        echo "/usr/bin/ld: cannot find -ldl: No such file or directory" >&2
+        exit 1
+    fi
+done
+while [ "$1" != "-o" ]; do shift; done
+touch "$2"
+"#;
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_link_retries_without_missing_optional_libs() {
+    let source = write_temp("link_retry.unl", "(defun -main () (println 1))");
+    let linker = fake_linker("link_retry_ld.sh", LINKER_WITHOUT_LIBDL);
+    let binary = temp_path("link_retry.bin");
+    let binary_str = binary.to_string_lossy().into_owned();
+    let args = [
+        "compile", "-f", &source, "-o", &binary_str, "--no-cache", "--linker", &linker,
+    ];
+
+    let output = unlisp(&args, b"");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(output.status.success(), "{}", stderr);
+    assert!(binary.exists());
+    assert!(
+        stderr.contains("link attempt 1 failed (library dl not found)"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("retrying the link, dropping -ldl"));
+    assert!(stderr.contains("link attempt 2 succeeded"), "{}", stderr);
+
+    let _ = fs::remove_file(&binary);
+
+    let mut args = args.to_vec();
+    args.push("--no-link-retry");
+    let output = unlisp(&args, b"");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(!binary.exists());
+    assert!(stderr.contains("link commands tried (1)"), "{}", stderr);
+    assert!(!stderr.contains("retrying"), "{}", stderr);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_link_failure_lists_the_commands_tried() {
+    let source = write_temp("link_fail.unl", "(defun -main () (println 1))");
+    let linker = fake_linker(
+        "link_fail_ld.sh",
+        "#!/bin/sh\necho '/usr/bin/ld: cannot find crt1.o: No such file or directory' >&2\nexit 1\n",
+    );
+    let binary = temp_path("link_fail.bin");
+    let binary_str = binary.to_string_lossy().into_owned();
+
+    let args = [
+        "compile", "-f", &source, "-o", &binary_str, "--no-cache", "--linker", &linker,
+    ];
+
+    let output = unlisp(&args, b"");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    // nothing is known to fix a missing crt1.o, so there's no retry
+    assert!(stderr.contains("link commands tried (1)"), "{}", stderr);
+    assert!(
+        stderr.contains(&format!("  1. {} -lpthread -ldl {}.o", linker, binary_str)),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("     /usr/bin/ld: cannot find crt1.o"));
+    assert!(stderr.contains("crt1.o is part of the C library's development files"));
+
+    let mut json_args = vec!["--diagnostics=json"];
+    json_args.extend(args.iter());
+    let output = unlisp(&json_args, b"");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert_eq!(stderr.lines().count(), 1, "{}", stderr);
+    assert!(
+        stderr.starts_with(&format!(
+            "[{{\"severity\":\"error\",\"message\":\"failed to create binary, link commands tried (1):\\n  1. {} -lpthread -ldl {}.o",
+            linker, binary_str
+        )),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.ends_with(&format!("\"file\":\"{}\",\"line\":1,\"col\":1}}]\n", source)),
+        "{}",
+        stderr
+    );
+
+    // the object is kept when linking fails
+    let _ = fs::remove_file(format!("{}.o", binary_str));
+}
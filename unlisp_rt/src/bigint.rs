@@ -0,0 +1,324 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Mul, Neg, Sub};
+
+const BASE: u64 = 1 << 32;
+// largest power of 10 fitting into a limb, used for decimal conversions
+const DECIMAL_BASE: u32 = 1_000_000_000;
+const DECIMAL_DIGITS: usize = 9;
+
+/// Sign and magnitude integer for values that don't fit into i64. The
+/// magnitude is little-endian base 2^32 without trailing zero limbs, so zero
+/// is an empty magnitude and is never negative.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    magnitude: Vec<u32>,
+}
+
+fn trim(mut magnitude: Vec<u32>) -> Vec<u32> {
+    while magnitude.last() == Some(&0) {
+        magnitude.pop();
+    }
+    magnitude
+}
+
+fn cmp_magnitudes(x: &[u32], y: &[u32]) -> Ordering {
+    x.len()
+        .cmp(&y.len())
+        .then_with(|| x.iter().rev().cmp(y.iter().rev()))
+}
+
+fn add_magnitudes(x: &[u32], y: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(x.len().max(y.len()) + 1);
+    let mut carry = 0u64;
+
+    for i in 0..x.len().max(y.len()) {
+        let sum = *x.get(i).unwrap_or(&0) as u64 + *y.get(i).unwrap_or(&0) as u64 + carry;
+        result.push((sum % BASE) as u32);
+        carry = sum / BASE;
+    }
+
+    result.push(carry as u32);
+    trim(result)
+}
+
+// x must not be smaller than y
+fn sub_magnitudes(x: &[u32], y: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(x.len());
+    let mut borrow = 0i64;
+
+    for i in 0..x.len() {
+        let mut diff = x[i] as i64 - *y.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += BASE as i64;
+            borrow = 1;
+        }
+        result.push(diff as u32);
+    }
+
+    trim(result)
+}
+
+fn mul_magnitudes(x: &[u32], y: &[u32]) -> Vec<u32> {
+    let mut result = vec![0u32; x.len() + y.len()];
+
+    for (i, &xi) in x.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &yj) in y.iter().enumerate() {
+            let cur = result[i + j] as u64 + xi as u64 * yj as u64 + carry;
+            result[i + j] = (cur % BASE) as u32;
+            carry = cur / BASE;
+        }
+        result[i + y.len()] = carry as u32;
+    }
+
+    trim(result)
+}
+
+// divides in place, returns the remainder
+fn div_rem_small(magnitude: &mut Vec<u32>, divisor: u32) -> u32 {
+    let mut rem = 0u64;
+
+    for limb in magnitude.iter_mut().rev() {
+        let cur = rem * BASE + *limb as u64;
+        *limb = (cur / divisor as u64) as u32;
+        rem = cur % divisor as u64;
+    }
+
+    let trimmed = trim(std::mem::replace(magnitude, vec![]));
+    *magnitude = trimmed;
+
+    rem as u32
+}
+
+impl BigInt {
+    fn new(negative: bool, magnitude: Vec<u32>) -> Self {
+        let magnitude = trim(magnitude);
+        let negative = negative && !magnitude.is_empty();
+
+        Self {
+            negative: negative,
+            magnitude: magnitude,
+        }
+    }
+
+    pub fn from_i64(i: i64) -> Self {
+        let abs = (i as i128).abs() as u64;
+        Self::new(i < 0, vec![abs as u32, (abs >> 32) as u32])
+    }
+
+    pub fn from_u64(u: u64) -> Self {
+        Self::new(false, vec![u as u32, (u >> 32) as u32])
+    }
+
+    /// `None` when the value doesn't fit, so callers can demote results.
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.magnitude.len() > 2 {
+            return None;
+        }
+
+        let abs = self
+            .magnitude
+            .iter()
+            .rev()
+            .fold(0i128, |acc, &limb| acc * BASE as i128 + limb as i128);
+        let value = if self.negative { -abs } else { abs };
+
+        if value < i64::min_value() as i128 || value > i64::max_value() as i128 {
+            None
+        } else {
+            Some(value as i64)
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let abs = self
+            .magnitude
+            .iter()
+            .rev()
+            .fold(0f64, |acc, &limb| acc * BASE as f64 + limb as f64);
+
+        if self.negative {
+            -abs
+        } else {
+            abs
+        }
+    }
+
+    /// Parses an optionally signed decimal integer.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (negative, digits) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+
+        if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+
+        let mut magnitude = vec![];
+
+        // most significant chunk first, so that the rest are exactly
+        // DECIMAL_DIGITS long
+        let first_len = match digits.len() % DECIMAL_DIGITS {
+            0 => DECIMAL_DIGITS,
+            n => n,
+        };
+        let mut chunk_start = 0;
+        let mut chunk_end = first_len;
+
+        while chunk_start < digits.len() {
+            let chunk: u32 = digits[chunk_start..chunk_end].parse().unwrap();
+            let scale = 10u32.pow((chunk_end - chunk_start) as u32);
+
+            magnitude = add_magnitudes(&mul_magnitudes(&magnitude, &[scale]), &[chunk]);
+
+            chunk_start = chunk_end;
+            chunk_end += DECIMAL_DIGITS;
+        }
+
+        Some(Self::new(negative, magnitude))
+    }
+
+    /// Parses an optionally signed integer with digits in `radix` (2 to 36),
+    /// letters in either case.
+    pub fn parse_radix(s: &str, radix: u32) -> Option<Self> {
+        let (negative, digits) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+
+        if digits.is_empty() {
+            return None;
+        }
+
+        let mut magnitude = vec![];
+        for c in digits.chars() {
+            let digit = c.to_digit(radix)?;
+            magnitude = add_magnitudes(&mul_magnitudes(&magnitude, &[radix]), &[digit]);
+        }
+
+        Some(Self::new(negative, magnitude))
+    }
+
+    /// The digits in `radix` (2 to 36), lowercase, with a minus sign when
+    /// negative.
+    pub fn to_string_radix(&self, radix: u32) -> String {
+        if self.magnitude.is_empty() {
+            return "0".to_string();
+        }
+
+        let mut magnitude = self.magnitude.clone();
+        let mut digits = vec![];
+
+        while !magnitude.is_empty() {
+            let digit = div_rem_small(&mut magnitude, radix);
+            digits.push(std::char::from_digit(digit, radix).unwrap());
+        }
+
+        if self.negative {
+            digits.push('-');
+        }
+
+        digits.into_iter().rev().collect()
+    }
+}
+
+impl<'a> Add for &'a BigInt {
+    type Output = BigInt;
+
+    fn add(self, rhs: Self) -> BigInt {
+        if self.negative == rhs.negative {
+            return BigInt::new(
+                self.negative,
+                add_magnitudes(&self.magnitude, &rhs.magnitude),
+            );
+        }
+
+        match cmp_magnitudes(&self.magnitude, &rhs.magnitude) {
+            Ordering::Less => BigInt::new(
+                rhs.negative,
+                sub_magnitudes(&rhs.magnitude, &self.magnitude),
+            ),
+            _ => BigInt::new(
+                self.negative,
+                sub_magnitudes(&self.magnitude, &rhs.magnitude),
+            ),
+        }
+    }
+}
+
+impl<'a> Neg for &'a BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::new(!self.negative, self.magnitude.clone())
+    }
+}
+
+impl<'a> Sub for &'a BigInt {
+    type Output = BigInt;
+
+    fn sub(self, rhs: Self) -> BigInt {
+        self + &-rhs
+    }
+}
+
+impl<'a> Mul for &'a BigInt {
+    type Output = BigInt;
+
+    fn mul(self, rhs: Self) -> BigInt {
+        BigInt::new(
+            self.negative != rhs.negative,
+            mul_magnitudes(&self.magnitude, &rhs.magnitude),
+        )
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, rhs: &Self) -> Ordering {
+        match (self.negative, rhs.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_magnitudes(&self.magnitude, &rhs.magnitude),
+            (true, true) => cmp_magnitudes(&rhs.magnitude, &self.magnitude),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, rhs: &Self) -> Option<Ordering> {
+        Some(self.cmp(rhs))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        if self.magnitude.is_empty() {
+            return write!(f, "0");
+        }
+
+        let mut magnitude = self.magnitude.clone();
+        let mut chunks = vec![];
+
+        while !magnitude.is_empty() {
+            chunks.push(div_rem_small(&mut magnitude, DECIMAL_BASE));
+        }
+
+        if self.negative {
+            write!(f, "-")?;
+        }
+
+        let mut chunks = chunks.into_iter().rev();
+        write!(f, "{}", chunks.next().unwrap())?;
+        for chunk in chunks {
+            write!(f, "{:09}", chunk)?;
+        }
+
+        Ok(())
+    }
+}
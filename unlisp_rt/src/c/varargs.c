@@ -3,6 +3,7 @@
 
 union UntaggedObject {
   long long i;
+  double f;
   void *list;
   void *symbol;
   void *function;
@@ -15,13 +16,18 @@ struct Object {
   union UntaggedObject obj;
 };
 
-extern struct Object *va_list_to_obj_array(unsigned long long n, va_list *list) {
-  struct Object *arr = malloc(n * sizeof(struct Object));
+extern void va_list_into_obj_array(unsigned long long n, va_list *list,
+                                   struct Object *arr) {
   unsigned long long i = 0;
 
   for (i = 0; i < n; i++) {
     arr[i] = va_arg(*list, struct Object);
   }
+}
+
+extern struct Object *va_list_to_obj_array(unsigned long long n, va_list *list) {
+  struct Object *arr = malloc(n * sizeof(struct Object));
+  va_list_into_obj_array(n, list, arr);
 
   return arr;
 }
@@ -0,0 +1,58 @@
+//! Checks of the debug runtime, built with the `debug_runtime` feature. A
+//! corrupted object or function aborts the process with a message saying
+//! where it was found, before it can do more damage. Without the feature the
+//! checks are empty and inlined away.
+
+use crate::defs::{Function, Object};
+
+#[cfg(feature = "debug_runtime")]
+fn abort_with(message: String) -> ! {
+    eprintln!("unlisp debug runtime: {}", message);
+    std::process::abort()
+}
+
+/// Checks argument `arg`, counted from 1, of a native function.
+#[cfg(feature = "debug_runtime")]
+pub fn check_object(native: &str, arg: usize, o: &Object) {
+    if let Some(problem) = o.corruption() {
+        abort_with(format!("{} in argument {} of {}", problem, arg, native))
+    }
+}
+
+/// Checks an object about to be unpacked by `method`.
+#[cfg(feature = "debug_runtime")]
+pub fn check_unpacked(method: &str, o: &Object) {
+    if let Some(problem) = o.corruption() {
+        abort_with(format!("{} unpacked by {}", problem, method))
+    }
+}
+
+/// Checks a function object before `caller` calls it.
+#[cfg(feature = "debug_runtime")]
+pub fn check_function(caller: &str, f: *const Function) {
+    let problem = if f.is_null() {
+        "null function".to_string()
+    } else if f as usize % std::mem::align_of::<Function>() != 0 {
+        format!("misaligned function pointer {:p}", f)
+    } else if unsafe { (*f).invoke_f_ptr.is_null() } {
+        "function without an invoke pointer".to_string()
+    } else if unsafe { (*f).apply_to_f_ptr.is_null() } {
+        "function without an apply pointer".to_string()
+    } else {
+        return;
+    };
+
+    abort_with(format!("{} called by {}", problem, caller))
+}
+
+#[cfg(not(feature = "debug_runtime"))]
+#[inline(always)]
+pub fn check_object(_: &str, _: usize, _: &Object) {}
+
+#[cfg(not(feature = "debug_runtime"))]
+#[inline(always)]
+pub fn check_unpacked(_: &str, _: &Object) {}
+
+#[cfg(not(feature = "debug_runtime"))]
+#[inline(always)]
+pub fn check_function(_: &str, _: *const Function) {}
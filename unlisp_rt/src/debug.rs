@@ -0,0 +1,139 @@
+use crate::defs::*;
+use crate::predefined::{init_symbol_fn, write_stdout_or_raise};
+
+use unlisp_internal_macros::trivial_apply;
+
+use libc::c_void;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DEBUG_NATIVES: AtomicBool = AtomicBool::new(false);
+
+/// Makes every initialization of the runtime register the natives of this
+/// module (`--enable-debug-natives`), they aren't there by default so code
+/// can't come to depend on them.
+pub fn enable_debug_natives() {
+    DEBUG_NATIVES.store(true, Ordering::SeqCst);
+}
+
+pub fn debug_natives_enabled() -> bool {
+    DEBUG_NATIVES.load(Ordering::SeqCst)
+}
+
+// cells printed by points-to
+const POINTS_TO_CELLS: usize = 8;
+
+fn cell(x: &Object) -> Option<*mut Cons> {
+    let x = if x.ty == ObjType::Box {
+        x.unpack_underlying()
+    } else {
+        x.clone()
+    };
+
+    if x.ty == ObjType::Cons {
+        Some(x.unpack_cons())
+    } else {
+        None
+    }
+}
+
+// the cells of a list's spine, a cycle ends at the first cell seen twice
+unsafe fn spine(x: &Object) -> Vec<*mut Cons> {
+    let mut cells = vec![];
+    let mut seen = HashSet::new();
+    let mut next = cell(x);
+
+    while let Some(c) = next {
+        if !seen.insert(c) {
+            break;
+        }
+        cells.push(c);
+        next = cell(&*(*c).cdr);
+    }
+
+    cells
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_shared_structure_invoke(
+    _: *const Function,
+    x: Object,
+    y: Object,
+) -> Object {
+    let x_cells: HashSet<_> = spine(&x).into_iter().collect();
+
+    if spine(&y).iter().any(|c| x_cells.contains(c)) {
+        Object::t()
+    } else {
+        Object::nil()
+    }
+}
+
+// cells reachable through both car and cdr, each counted once
+#[trivial_apply]
+unsafe extern "C" fn native_list_cells_invoke(_: *const Function, x: Object) -> Object {
+    let mut seen = HashSet::new();
+    let mut stack: Vec<_> = cell(&x).into_iter().collect();
+
+    while let Some(c) = stack.pop() {
+        if !seen.insert(c) {
+            continue;
+        }
+        stack.extend(cell(&*(*c).car));
+        stack.extend(cell(&*(*c).cdr));
+    }
+
+    Object::from_count(seen.len() as u64)
+}
+
+// e.g. "0x55d1c2a0 0x55d1c2c0 ..." for the spine's cells, "no cells" for
+// atoms and nil
+#[trivial_apply]
+unsafe extern "C" fn native_points_to_invoke(_: *const Function, x: Object) -> Object {
+    let cells = spine(&x);
+
+    let mut line = cells
+        .iter()
+        .take(POINTS_TO_CELLS)
+        .map(|c| format!("{:#x}", *c as usize))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if cells.is_empty() {
+        line.push_str("no cells");
+    } else if cells.len() > POINTS_TO_CELLS {
+        line.push_str(" ...");
+    }
+
+    write_stdout_or_raise(format_args!("{}\n", line));
+
+    Object::nil()
+}
+
+/// Registers `shared-structure?`, `list-cells` and `points-to`, which show
+/// whether lists share cons cells. None of them loops on circular lists.
+pub fn init() {
+    init_symbol_fn(
+        native_shared_structure_invoke as *const c_void,
+        native_shared_structure_apply as *const c_void,
+        "shared-structure?",
+        &["x", "y"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_list_cells_invoke as *const c_void,
+        native_list_cells_apply as *const c_void,
+        "list-cells",
+        &["x"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_points_to_invoke as *const c_void,
+        native_points_to_apply as *const c_void,
+        "points-to",
+        &["x"],
+        false,
+    );
+}
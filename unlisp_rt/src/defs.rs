@@ -4,7 +4,10 @@ use libc::strcmp;
 use std::ffi::CStr;
 use std::ffi::VaList;
 use std::fmt;
+use std::mem;
 use std::ptr;
+use std::slice;
+use std::sync::Once;
 
 #[cfg(feature = "llvm_defs")]
 use inkwell::context::Context;
@@ -13,7 +16,12 @@ use inkwell::module::Module;
 #[cfg(feature = "llvm_defs")]
 use inkwell::AddressSpace;
 
-use crate::{exceptions, predefined, symbols};
+use crate::bigint::BigInt;
+use crate::streams::Stream;
+use crate::{
+    checks, escapes, exceptions, predefined, print_hooks, print_shared, stats, symbols,
+    test_registry, threads,
+};
 use unlisp_internal_macros::runtime_fn;
 
 // TODO: use lazy_static here
@@ -21,19 +29,30 @@ static mut T: *mut Symbol = ptr::null_mut();
 static mut NIL: *mut Symbol = ptr::null_mut();
 
 pub fn to_heap<T>(x: T) -> *mut T {
+    stats::record_bytes(std::mem::size_of::<T>());
     Box::into_raw(Box::new(x))
 }
 
+pub fn cons_to_heap(cons: Cons) -> *mut Cons {
+    stats::record_cons();
+    to_heap(cons)
+}
+
 // TODO: revise usage of Copy here
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub union UntaggedObject {
     int: i64,
+    float: f64,
     m_box: *mut MutableBox,
     cons: *mut Cons,
     sym: *mut Symbol,
     function: *mut Function,
     string: *const c_char,
+    bigint: *mut BigInt,
+    character: u32,
+    record: *mut Record,
+    stream: *mut Stream,
 }
 
 #[derive(Clone, Eq, PartialEq)]
@@ -45,6 +64,11 @@ pub enum ObjType {
     Function = 4,
     String = 5,
     Cons = 6,
+    Float64 = 7,
+    BigInt = 8,
+    Char = 9,
+    Record = 10,
+    Stream = 11,
 }
 
 impl fmt::Display for ObjType {
@@ -56,6 +80,11 @@ impl fmt::Display for ObjType {
             ObjType::Symbol => "symbol",
             ObjType::String => "string",
             ObjType::Cons => "cons",
+            ObjType::Float64 => "float",
+            ObjType::BigInt => "integer",
+            ObjType::Char => "char",
+            ObjType::Record => "record",
+            ObjType::Stream => "stream",
         };
 
         write!(f, "{}", name)
@@ -83,6 +112,16 @@ impl PartialEq for Object {
                 ObjType::Symbol => self.obj.sym == rhs.obj.sym,
                 ObjType::String => strcmp(self.obj.string, rhs.obj.string) == 0,
                 ObjType::Cons => *self.obj.cons == *rhs.obj.cons,
+                // floats are equal when their bit patterns are, so a NaN is
+                // equal to itself and 0.0 is not equal to -0.0
+                ObjType::Float64 => self.obj.float.to_bits() == rhs.obj.float.to_bits(),
+                // bignums are always demoted when they fit, so they are never
+                // equal to a fixnum
+                ObjType::BigInt => *self.obj.bigint == *rhs.obj.bigint,
+                ObjType::Char => self.obj.character == rhs.obj.character,
+                // records are only equal to themselves, like functions
+                ObjType::Record => self.obj.record == rhs.obj.record,
+                ObjType::Stream => self.obj.stream == rhs.obj.stream,
             }
         }
     }
@@ -126,12 +165,17 @@ impl ListLike {
         self.0 as *mut Cons
     }
 
+    // counts cons cells, so an improper list like (a b . c) has length 2
     pub fn len(&self) -> u64 {
-        if self.is_nil() {
-            0
-        } else {
-            1 + self.cdr().len()
+        let mut len = 0;
+        let mut cur = self.to_object();
+
+        while cur.ty == ObjType::Cons {
+            len += 1;
+            cur = unsafe { (*cur.unpack_cons()).cdr() };
         }
+
+        len
     }
 
     pub fn to_object(&self) -> Object {
@@ -148,7 +192,7 @@ impl ListLike {
             cdr: to_heap(self.to_object()),
         };
 
-        Self::from_cons(to_heap(cons))
+        Self::from_cons(cons_to_heap(cons))
     }
 
     pub fn cons(&self, o: Object) -> Self {
@@ -167,7 +211,7 @@ impl ListLike {
         if self.is_nil() {
             self.clone()
         } else {
-            unsafe { (*self.as_cons()).cdr().unpack_list_like() }
+            unsafe { (*(*self.as_cons()).cdr).unpack_list_like() }
         }
     }
 
@@ -190,6 +234,39 @@ impl Object {
         struct_ty.set_body(&[int32_ty.into(), int8_ptr_ty.into()], false);
     }
 
+    /// What's wrong with an object whose type tag is out of range or whose
+    /// pointer is null or misaligned, for the debug runtime.
+    pub fn corruption(&self) -> Option<String> {
+        // the tag is read as a number, an enum with an invalid value is
+        // undefined behaviour
+        let tag = unsafe { *(self as *const Object as *const u32) };
+        if tag < ObjType::Int64 as u32 || tag > ObjType::Stream as u32 {
+            return Some(format!("invalid type tag {}", tag));
+        }
+
+        let (ptr, align) = unsafe {
+            match self.ty {
+                ObjType::Int64 | ObjType::Float64 | ObjType::Char => return None,
+                ObjType::Box => (self.obj.m_box as usize, mem::align_of::<MutableBox>()),
+                ObjType::Symbol => (self.obj.sym as usize, mem::align_of::<Symbol>()),
+                ObjType::Function => (self.obj.function as usize, mem::align_of::<Function>()),
+                ObjType::String => (self.obj.string as usize, 1),
+                ObjType::Cons => (self.obj.cons as usize, mem::align_of::<Cons>()),
+                ObjType::BigInt => (self.obj.bigint as usize, mem::align_of::<BigInt>()),
+                ObjType::Record => (self.obj.record as usize, mem::align_of::<Record>()),
+                ObjType::Stream => (self.obj.stream as usize, mem::align_of::<Stream>()),
+            }
+        };
+
+        if ptr == 0 {
+            Some(format!("null {} pointer", self.ty))
+        } else if ptr % align != 0 {
+            Some(format!("misaligned {} pointer {:#x}", self.ty, ptr))
+        } else {
+            None
+        }
+    }
+
     fn type_err(&self, target_ty: ObjType) -> ! {
         unsafe { exceptions::raise_cast_error(format!("{}", self.ty), format!("{}", target_ty)) };
     }
@@ -203,6 +280,8 @@ impl Object {
     }
 
     pub fn unpack_int(&self) -> i64 {
+        checks::check_unpacked("Object::unpack_int", self);
+
         if self.ty == ObjType::Box {
             return self.unpack_underlying().unpack_int();
         }
@@ -214,7 +293,79 @@ impl Object {
         }
     }
 
+    pub fn unpack_float(&self) -> f64 {
+        checks::check_unpacked("Object::unpack_float", self);
+
+        if self.ty == ObjType::Box {
+            return self.unpack_underlying().unpack_float();
+        }
+
+        if self.ty == ObjType::Float64 {
+            unsafe { self.obj.float }
+        } else {
+            self.type_err(ObjType::Float64);
+        }
+    }
+
+    pub fn unpack_char(&self) -> char {
+        checks::check_unpacked("Object::unpack_char", self);
+
+        if self.ty == ObjType::Box {
+            return self.unpack_underlying().unpack_char();
+        }
+
+        if self.ty == ObjType::Char {
+            unsafe { std::char::from_u32(self.obj.character).unwrap() }
+        } else {
+            self.type_err(ObjType::Char);
+        }
+    }
+
+    pub fn unpack_bigint(&self) -> *mut BigInt {
+        checks::check_unpacked("Object::unpack_bigint", self);
+
+        if self.ty == ObjType::Box {
+            return self.unpack_underlying().unpack_bigint();
+        }
+
+        if self.ty == ObjType::BigInt {
+            unsafe { self.obj.bigint }
+        } else {
+            self.type_err(ObjType::BigInt);
+        }
+    }
+
+    pub fn unpack_record(&self) -> *mut Record {
+        checks::check_unpacked("Object::unpack_record", self);
+
+        if self.ty == ObjType::Box {
+            return self.unpack_underlying().unpack_record();
+        }
+
+        if self.ty == ObjType::Record {
+            unsafe { self.obj.record }
+        } else {
+            self.type_err(ObjType::Record);
+        }
+    }
+
+    pub fn unpack_stream(&self) -> *mut Stream {
+        checks::check_unpacked("Object::unpack_stream", self);
+
+        if self.ty == ObjType::Box {
+            return self.unpack_underlying().unpack_stream();
+        }
+
+        if self.ty == ObjType::Stream {
+            unsafe { self.obj.stream }
+        } else {
+            self.type_err(ObjType::Stream);
+        }
+    }
+
     pub fn unpack_cons(&self) -> *mut Cons {
+        checks::check_unpacked("Object::unpack_cons", self);
+
         if self.ty == ObjType::Box {
             return self.unpack_underlying().unpack_cons();
         }
@@ -227,6 +378,8 @@ impl Object {
     }
 
     pub fn unpack_list_like(&self) -> ListLike {
+        checks::check_unpacked("Object::unpack_list_like", self);
+
         if self.ty == ObjType::Box {
             return self.unpack_underlying().unpack_list_like();
         }
@@ -239,6 +392,8 @@ impl Object {
     }
 
     pub fn unpack_symbol(&self) -> *mut Symbol {
+        checks::check_unpacked("Object::unpack_symbol", self);
+
         if self.ty == ObjType::Box {
             return self.unpack_underlying().unpack_symbol();
         }
@@ -251,6 +406,8 @@ impl Object {
     }
 
     pub fn unpack_function(&self) -> *mut Function {
+        checks::check_unpacked("Object::unpack_function", self);
+
         if self.ty == ObjType::Box {
             return self.unpack_underlying().unpack_function();
         }
@@ -263,6 +420,8 @@ impl Object {
     }
 
     pub fn unpack_string(&self) -> *const c_char {
+        checks::check_unpacked("Object::unpack_string", self);
+
         if self.ty == ObjType::Box {
             return self.unpack_underlying().unpack_string();
         }
@@ -275,6 +434,8 @@ impl Object {
     }
 
     pub fn unpack_box(&self) -> *mut MutableBox {
+        checks::check_unpacked("Object::unpack_box", self);
+
         if self.ty == ObjType::Box {
             // get the most underlying box
             unsafe {
@@ -302,6 +463,43 @@ impl Object {
         }
     }
 
+    pub fn from_float(f: f64) -> Object {
+        Self {
+            ty: ObjType::Float64,
+            obj: UntaggedObject { float: f },
+        }
+    }
+
+    pub fn from_char(c: char) -> Object {
+        Self {
+            ty: ObjType::Char,
+            obj: UntaggedObject {
+                character: c as u32,
+            },
+        }
+    }
+
+    /// For lengths and counters: a fixnum, or a bignum for counts past
+    /// `i64::MAX` rather than a negative fixnum.
+    pub fn from_count(count: u64) -> Object {
+        if count > i64::max_value() as u64 {
+            Object::from_bigint(BigInt::from_u64(count))
+        } else {
+            Object::from_int(count as i64)
+        }
+    }
+
+    /// Demotes to a fixnum when the value fits into i64.
+    pub fn from_bigint(i: BigInt) -> Object {
+        match i.to_i64() {
+            Some(i) => Object::from_int(i),
+            None => Self {
+                ty: ObjType::BigInt,
+                obj: UntaggedObject { bigint: to_heap(i) },
+            },
+        }
+    }
+
     pub fn from_cons(cons: *mut Cons) -> Object {
         Self {
             ty: ObjType::Cons,
@@ -309,6 +507,20 @@ impl Object {
         }
     }
 
+    pub fn from_record(record: *mut Record) -> Object {
+        Self {
+            ty: ObjType::Record,
+            obj: UntaggedObject { record: record },
+        }
+    }
+
+    pub fn from_stream(stream: *mut Stream) -> Object {
+        Self {
+            ty: ObjType::Stream,
+            obj: UntaggedObject { stream: stream },
+        }
+    }
+
     pub fn from_symbol(sym: *mut Symbol) -> Object {
         Self {
             ty: ObjType::Symbol,
@@ -346,58 +558,194 @@ impl Object {
     }
 }
 
-unsafe fn display_cons(cons: *const Cons, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+/// Character names used by the reader and the printer, characters not listed
+/// here are written as themselves, e.g. `#\a`.
+pub const CHAR_NAMES: &[(&str, char)] = &[("space", ' '), ("newline", '\n'), ("tab", '\t')];
+
+pub fn format_char(c: char) -> String {
+    match CHAR_NAMES.iter().find(|(_, named)| *named == c) {
+        Some((name, _)) => format!("#\\{}", name),
+        None => format!("#\\{}", c),
+    }
+}
+
+/// Formats a float so that reading the result back yields the same value.
+/// Infinities and NaN are printed as `+inf.0`, `-inf.0` and `+nan.0`, NaN
+/// payloads are not preserved.
+pub fn format_float(f: f64) -> String {
+    if f.is_nan() {
+        return "+nan.0".to_string();
+    }
+
+    if f.is_infinite() {
+        return if f > 0.0 { "+inf.0" } else { "-inf.0" }.to_string();
+    }
+
+    // debug formatting is the shortest representation that round-trips
+    let s = format!("{:?}", f);
+
+    if s.contains('.') || s.contains('e') {
+        s
+    } else {
+        format!("{}.0", s)
+    }
+}
+
+/// Formats a float as a hex float, e.g. `0x1.8p3` for 12.0, which reads
+/// back to the same value. Subnormals are normalized like the others are,
+/// infinities and NaN are printed like `format_float` does.
+pub fn format_hex_float(f: f64) -> String {
+    if f.is_nan() || f.is_infinite() {
+        return format_float(f);
+    }
+
+    let sign = if f.is_sign_negative() { "-" } else { "" };
+    if f == 0.0 {
+        return format!("{}0x0p0", sign);
+    }
+
+    let bits = f.to_bits();
+    let biased_exponent = ((bits >> 52) & 0x7ff) as i64;
+    let mut fraction = bits & ((1 << 52) - 1);
+    let mut exponent = biased_exponent - 1023;
+
+    if biased_exponent == 0 {
+        // the leading one goes where the implicit bit of normal floats is
+        let shift = fraction.leading_zeros() as i64 - 11;
+        fraction = (fraction << shift) & ((1 << 52) - 1);
+        exponent = -1022 - shift;
+    }
+
+    let digits = format!("{:013x}", fraction);
+    let digits = digits.trim_end_matches('0');
+
+    if digits.is_empty() {
+        format!("{}0x1p{}", sign, exponent)
+    } else {
+        format!("{}0x1.{}p{}", sign, digits, exponent)
+    }
+}
+
+/// How objects are printed. `Readably` (`prin1`, `~s`) writes strings,
+/// characters and symbols so that reading the output gives an equal object,
+/// `Aesthetically` (`princ`, `~a`) writes them as they are.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrintMode {
+    Readably,
+    Aesthetically,
+}
+
+/// An object displayed in a given print mode, see `Object::printed`.
+pub struct Printed<'a> {
+    obj: &'a Object,
+    mode: PrintMode,
+}
+
+impl Object {
+    /// Displays the object in `mode`, `Display` for `Object` itself prints
+    /// readably.
+    pub fn printed(&self, mode: PrintMode) -> Printed {
+        Printed { obj: self, mode }
+    }
+}
+
+unsafe fn display_cons(
+    cons: *const Cons,
+    mode: PrintMode,
+    f: &mut fmt::Formatter,
+) -> Result<(), fmt::Error> {
     write!(f, "(")?;
-    write!(f, "{}", *(*cons).car)?;
+    write!(f, "{}", (*(*cons).car).printed(mode))?;
 
     let mut obj = (*cons).cdr;
     while (*obj).ty == ObjType::Cons {
         let cons = (*obj).unpack_cons();
-        write!(f, " {}", *(*cons).car)?;
+        write!(f, " {}", (*(*cons).car).printed(mode))?;
         obj = (*cons).cdr;
     }
 
     if (*obj).is_nil() {
         write!(f, ")")?;
     } else {
-        write!(f, " . {})", (*obj))?;
+        write!(f, " . {})", (*obj).printed(mode))?;
     }
 
     Ok(())
 }
 
-impl fmt::Display for Object {
+impl<'a> fmt::Display for Printed<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let obj = self.obj;
+        let readably = self.mode == PrintMode::Readably;
+
         unsafe {
-            match self.ty {
-                ObjType::Int64 => write!(f, "{}", self.obj.int),
-                ObjType::Cons => display_cons(self.obj.cons, f),
-                ObjType::Box => write!(f, "{}", (*(*self.obj.m_box).0)),
+            match obj.ty {
+                ObjType::Cons | ObjType::Record if print_shared::print_shared() => {
+                    print_shared::display(obj, self.mode, f)
+                }
+                ObjType::Int64 => write!(f, "{}", obj.obj.int),
+                ObjType::Float64 => write!(f, "{}", format_float(obj.obj.float)),
+                ObjType::BigInt => write!(f, "{}", *obj.obj.bigint),
+                ObjType::Char => {
+                    let c = std::char::from_u32(obj.obj.character).unwrap();
+                    if readably {
+                        write!(f, "{}", format_char(c))
+                    } else {
+                        write!(f, "{}", c)
+                    }
+                }
+                ObjType::Cons => display_cons(obj.obj.cons, self.mode, f),
+                ObjType::Record => {
+                    let record = &*obj.obj.record;
+                    if let Some(printed) = print_hooks::hooked_print(obj, record.ty) {
+                        return write!(f, "{}", printed);
+                    }
+
+                    write!(f, "#<{}", Object::from_symbol(record.ty).printed(self.mode))?;
+                    for slot in record.slots.iter() {
+                        write!(f, " {}", slot.printed(self.mode))?;
+                    }
+                    write!(f, ">")
+                }
+                ObjType::Stream => write!(f, "{}", *obj.obj.stream),
+                ObjType::Box => write!(f, "{}", (*(*obj.obj.m_box).0).printed(self.mode)),
                 ObjType::Function => write!(
                     f,
                     "#<FUNCTION{}/{}>",
-                    if (*self.obj.function).is_macro {
+                    if (*obj.obj.function).is_macro {
                         "+MACRO"
                     } else {
                         ""
                     },
-                    (*self.obj.function).arg_count
-                ),
-                ObjType::Symbol => write!(
-                    f,
-                    "{}",
-                    CStr::from_ptr((*self.obj.sym).name).to_str().unwrap()
-                ),
-                ObjType::String => write!(
-                    f,
-                    "\"{}\"",
-                    CStr::from_ptr(self.obj.string).to_str().unwrap()
+                    (*obj.obj.function).arg_count
                 ),
+                ObjType::Symbol => {
+                    let name = CStr::from_ptr((*obj.obj.sym).name).to_str().unwrap();
+                    if !readably || obj.obj.sym == NIL || obj.obj.sym == T {
+                        write!(f, "{}", name)
+                    } else {
+                        write!(f, "{}", escapes::escape_symbol(name))
+                    }
+                }
+                ObjType::String => {
+                    let bytes = CStr::from_ptr(obj.obj.string).to_bytes();
+                    if readably {
+                        write!(f, "\"{}\"", escapes::escape_string(bytes))
+                    } else {
+                        write!(f, "{}", String::from_utf8_lossy(bytes))
+                    }
+                }
             }
         }
     }
 }
 
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.printed(PrintMode::Readably))
+    }
+}
+
 #[repr(C)]
 #[derive(Clone)]
 pub struct Cons {
@@ -409,7 +757,7 @@ impl PartialEq for Cons {
     fn eq(&self, rhs: &Self) -> bool {
         unsafe {
             (self.car == rhs.car || *(self.car) == *(rhs.car))
-                && (self.cdr == rhs.cdr || *(self.car) == *(rhs.cdr))
+                && (self.cdr == rhs.cdr || *(self.cdr) == *(rhs.cdr))
         }
     }
 }
@@ -439,10 +787,17 @@ impl Cons {
     }
 
     pub fn cons(&self, obj: Object) -> Self {
-        Self::new(obj, Object::from_cons(to_heap(self.clone())))
+        Self::new(obj, Object::from_cons(cons_to_heap(self.clone())))
     }
 }
 
+/// An instance of a structure defined by `defstruct`, the type is the
+/// structure's name and the slots are in the order they were declared.
+pub struct Record {
+    pub ty: *mut Symbol,
+    pub slots: Vec<Object>,
+}
+
 #[repr(C)]
 pub struct Symbol {
     pub name: *const c_char,
@@ -570,6 +925,16 @@ pub extern "C" fn unlisp_rt_object_from_int(i: i64) -> Object {
     Object::from_int(i)
 }
 
+#[runtime_fn]
+pub extern "C" fn unlisp_rt_object_from_float(f: f64) -> Object {
+    Object::from_float(f)
+}
+
+#[runtime_fn]
+pub extern "C" fn unlisp_rt_object_from_char(c: u32) -> Object {
+    Object::from_char(std::char::from_u32(c).expect("invalid char code"))
+}
+
 #[runtime_fn]
 pub extern "C" fn unlisp_rt_object_from_string(string: *const c_char) -> Object {
     Object::from_string(string)
@@ -580,6 +945,18 @@ pub extern "C" fn unlisp_rt_int_from_obj(o: Object) -> i64 {
     o.unpack_int()
 }
 
+// literals outside of i64 range are compiled as their decimal representation
+#[runtime_fn]
+pub unsafe extern "C" fn unlisp_rt_object_from_bigint_str(s: *const c_char) -> Object {
+    let s = CStr::from_ptr(s).to_str().unwrap();
+    Object::from_bigint(BigInt::parse(s).unwrap())
+}
+
+#[runtime_fn]
+pub extern "C" fn unlisp_rt_function_from_obj(o: Object) -> *mut Function {
+    o.unpack_function()
+}
+
 #[runtime_fn]
 pub extern "C" fn unlisp_rt_object_from_function(f: *mut Function) -> Object {
     Object::from_function(f)
@@ -592,7 +969,12 @@ pub extern "C" fn unlisp_rt_object_from_symbol(s: *mut Symbol) -> Object {
 
 #[runtime_fn]
 pub extern "C" fn unlisp_rt_object_from_cons(cons: Cons) -> Object {
-    Object::from_cons(to_heap(cons))
+    Object::from_cons(cons_to_heap(cons))
+}
+
+#[runtime_fn]
+pub extern "C" fn unlisp_rt_object_cons(car: Object, cdr: Object) -> Object {
+    Object::from_cons(cons_to_heap(Cons::new(car, cdr)))
 }
 
 #[runtime_fn]
@@ -600,16 +982,75 @@ pub extern "C" fn unlisp_rt_object_from_list(list: ListLike) -> Object {
     list.to_object()
 }
 
+/// A copy of the cells of a constant list built once per module, nested
+/// lists included, so every evaluation of the quoted list gets a fresh one.
+/// Atoms are shared. The cells are copied from a worklist rather than
+/// recursively, as deeply nested constants would overflow the stack.
+#[runtime_fn]
+pub unsafe extern "C" fn unlisp_rt_copy_constant(o: Object) -> Object {
+    let mut cells = vec![];
+    let copy = copy_cell(&o, &mut cells);
+
+    while let Some(cell) = cells.pop() {
+        let car = copy_cell(&*(*cell).car, &mut cells);
+        let cdr = copy_cell(&*(*cell).cdr, &mut cells);
+        *(*cell).car = car;
+        *(*cell).cdr = cdr;
+    }
+
+    copy
+}
+
+// a new cell with the car and cdr of the one `o` is, which still point into
+// the constant until the cell is taken off `cells`, or `o` for an atom
+unsafe fn copy_cell(o: &Object, cells: &mut Vec<*mut Cons>) -> Object {
+    if o.ty != ObjType::Cons {
+        return o.clone();
+    }
+
+    let cons = &*o.unpack_cons();
+    let cell = cons_to_heap(Cons::new(cons.car(), cons.cdr()));
+    cells.push(cell);
+
+    Object::from_cons(cell)
+}
+
 #[runtime_fn]
 pub extern "C" fn unlisp_rt_object_is_nil(o: Object) -> bool {
     o.is_nil()
 }
 
+// names match the ones accepted in arglist type annotations, nil counts as a list
+#[runtime_fn]
+pub extern "C" fn unlisp_rt_object_type_name(o: Object) -> *const c_char {
+    let name: &'static [u8] = match o.ty {
+        _ if o.is_nil() => b"list\0",
+        ObjType::Int64 => b"integer\0",
+        ObjType::Float64 => b"float\0",
+        ObjType::BigInt => b"integer\0",
+        ObjType::Char => b"char\0",
+        ObjType::Box => b"box\0",
+        ObjType::Symbol => b"symbol\0",
+        ObjType::Function => b"function\0",
+        ObjType::String => b"string\0",
+        ObjType::Cons => b"list\0",
+        ObjType::Record => b"record\0",
+        ObjType::Stream => b"stream\0",
+    };
+
+    name.as_ptr() as *const c_char
+}
+
 #[runtime_fn]
 pub extern "C" fn unlisp_rt_nil_object() -> Object {
     Object::nil()
 }
 
+#[runtime_fn]
+pub extern "C" fn unlisp_rt_nil_list() -> ListLike {
+    ListLike::from_nil()
+}
+
 #[runtime_fn]
 pub extern "C" fn unlisp_rt_t_object() -> Object {
     Object::t()
@@ -617,6 +1058,8 @@ pub extern "C" fn unlisp_rt_t_object() -> Object {
 
 #[runtime_fn]
 pub extern "C" fn unlisp_rt_check_arity(f: *const Function, arg_count: u64) -> bool {
+    checks::check_function("unlisp_rt_check_arity", f);
+
     let has_restarg = unsafe { (*f).has_restarg };
     let params_count = unsafe { (*f).arg_count };
 
@@ -627,6 +1070,8 @@ pub extern "C" fn unlisp_rt_check_arity(f: *const Function, arg_count: u64) -> b
 
 extern "C" {
     pub fn va_list_to_obj_array(n: u64, list: VaList) -> *mut Object;
+    // like va_list_to_obj_array, into an array of at least n objects
+    pub fn va_list_into_obj_array(n: u64, list: VaList, arr: *mut Object);
 }
 
 pub fn obj_array_to_list_like(n: u64, arr: *mut Object, mut list_like: ListLike) -> ListLike {
@@ -637,6 +1082,89 @@ pub fn obj_array_to_list_like(n: u64, arr: *mut Object, mut list_like: ListLike)
     list_like
 }
 
+/// Calls with at most this many arguments go through the invoke function of
+/// the callee, with more they're passed in a list to its apply function.
+pub const MAX_INVOKE_ARGS: usize = 8;
+
+/// Room for the arguments of an invoke call on the stack, only the ones
+/// written are read.
+pub type InvokeArgs = mem::MaybeUninit<[Object; MAX_INVOKE_ARGS]>;
+
+// the invoke function of f called with the objects of args, for every
+// argument count up to MAX_INVOKE_ARGS
+macro_rules! invoke_with_args {
+    (@object $i:tt) => { Object };
+    ($f:ident, $args:ident, $($n:literal => [$($i:tt)*]),*) => {
+        match ((*$f).has_restarg, $args.len()) {
+            $(
+                (false, $n) => {
+                    let invoke: unsafe extern "C" fn(
+                        *const Function,
+                        $(invoke_with_args!(@object $i),)*
+                    ) -> Object = mem::transmute((*$f).invoke_f_ptr);
+                    invoke($f, $($args[$i].clone(),)*)
+                }
+                (true, $n) => {
+                    let invoke: unsafe extern "C" fn(
+                        *const Function,
+                        u64,
+                        $(invoke_with_args!(@object $i),)*
+                    ) -> Object = mem::transmute((*$f).invoke_f_ptr);
+                    invoke($f, $n - (*$f).arg_count, $($args[$i].clone(),)*)
+                }
+            )*
+            (_, n) => panic!("{} arguments don't fit an invoke call", n),
+        }
+    };
+}
+
+/// Calls `f` with the arguments of `args` through its invoke function like
+/// compiled calls do, so no list is built. The arity has to be checked
+/// already and there can be at most `MAX_INVOKE_ARGS` arguments.
+pub unsafe fn invoke_args_array(f: *const Function, args: &[Object]) -> Object {
+    checks::check_function("invoke_args_array", f);
+
+    invoke_with_args!(
+        f,
+        args,
+        0 => [],
+        1 => [0],
+        2 => [0 1],
+        3 => [0 1 2],
+        4 => [0 1 2 3],
+        5 => [0 1 2 3 4],
+        6 => [0 1 2 3 4 5],
+        7 => [0 1 2 3 4 5 6],
+        8 => [0 1 2 3 4 5 6 7]
+    )
+}
+
+/// The first `n` objects written to `args`.
+pub unsafe fn invoke_args_slice(args: &mut InvokeArgs, n: usize) -> &[Object] {
+    slice::from_raw_parts(args.as_mut_ptr() as *const Object, n)
+}
+
+/// Calls `f` with `n` arguments from an array, compiled code does this
+/// rather than calling the invoke function when there are many arguments.
+/// The arity has to be checked already.
+#[runtime_fn]
+pub unsafe extern "C" fn unlisp_rt_apply_args_array(
+    f: *const Function,
+    n: u64,
+    args: *const Object,
+) -> Object {
+    checks::check_function("unlisp_rt_apply_args_array", f);
+
+    // the array is on the caller's stack, so the list gets copies
+    let args = (0..n as usize).rev().fold(ListLike::from_nil(), |acc, i| {
+        acc.cons((*args.add(i)).clone())
+    });
+
+    let apply_fn: unsafe extern "C" fn(*const Function, ListLike) -> Object =
+        std::mem::transmute((*f).apply_to_f_ptr);
+    apply_fn(f, args)
+}
+
 #[runtime_fn]
 pub extern "C" fn unlisp_rt_va_list_into_list(n: u64, va_list: VaList) -> Object {
     let obj_array = unsafe { va_list_to_obj_array(n, va_list) };
@@ -658,13 +1186,30 @@ pub unsafe extern "C" fn unlisp_rt_list_cons(el: Object, list: ListLike) -> List
     list.cons(el)
 }
 
+static INIT_RUNTIME: Once = Once::new();
+
+/// Initializes the runtime the first time it's called, later calls (from any
+/// thread) do nothing, so every context can call it.
 #[runtime_fn]
 pub extern "C" fn unlisp_rt_init_runtime() {
+    INIT_RUNTIME.call_once(reset_runtime);
+}
+
+/// Drops all symbols, registered tests and print hooks and sets up the
+/// natives again.
+/// Unlike `unlisp_rt_init_runtime` this isn't safe to call while code is
+/// evaluated on other threads, it's meant for giving tests a fresh runtime.
+pub fn reset_runtime() {
     symbols::init();
+    test_registry::init();
+    print_hooks::init();
+    print_shared::init();
+    threads::init();
     predefined::init();
     unsafe {
-        let t = symbols::get_or_intern_symbol("t".to_string());
-        let nil = symbols::get_or_intern_symbol("nil".to_string());
+        // not interned, so |t| and |nil| read as ordinary symbols
+        let t = symbols::make_uninterned_symbol("t");
+        let nil = symbols::make_uninterned_symbol("nil");
 
         (*t).value = to_heap(Object::from_symbol(t));
         (*nil).value = to_heap(Object::from_symbol(nil));
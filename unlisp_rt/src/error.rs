@@ -1,16 +1,34 @@
+use crate::source_locations::SourceLocation;
+
 use std::fmt;
 
 #[derive(Debug, Clone)]
-pub struct RuntimeError(String);
+pub struct RuntimeError {
+    message: String,
+    location: Option<SourceLocation>,
+}
 
 impl RuntimeError {
     pub fn new(message: impl Into<String>) -> Self {
-        Self(message.into())
+        Self {
+            message: message.into(),
+            location: None,
+        }
+    }
+
+    pub fn with_location(self, location: Option<SourceLocation>) -> Self {
+        Self { location, ..self }
+    }
+
+    /// Where the call that raised the error is in the source, when the code
+    /// was compiled with source locations.
+    pub fn location(&self) -> Option<&SourceLocation> {
+        self.location.as_ref()
     }
 }
 
 impl fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.message)
     }
 }
@@ -0,0 +1,486 @@
+use crate::bigint::BigInt;
+use crate::symbols;
+
+use std::str;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// Escapes understood inside string literals, as (letter after '\', byte).
+// Both the reader and the printer use this table, so whatever the printer
+// writes reads back to the same string.
+const NAMED_ESCAPES: &[(u8, u8)] = &[
+    (b'"', b'"'),
+    (b'\\', b'\\'),
+    (b'n', b'\n'),
+    (b't', b'\t'),
+    (b'r', b'\r'),
+];
+
+fn push_hex_escape(out: &mut String, byte: u8) {
+    out.push_str(&format!("\\x{:02x}", byte));
+}
+
+fn push_escaped_char(out: &mut String, c: char) {
+    if c.is_ascii() {
+        let byte = c as u8;
+        if let Some((letter, _)) = NAMED_ESCAPES.iter().find(|(_, b)| *b == byte) {
+            out.push('\\');
+            out.push(*letter as char);
+            return;
+        }
+    }
+
+    if c.is_control() {
+        let mut buf = [0; 4];
+        for byte in c.encode_utf8(&mut buf).bytes() {
+            push_hex_escape(out, byte);
+        }
+    } else {
+        out.push(c);
+    }
+}
+
+/// Writes string contents the way the reader expects them between quotes.
+/// Control characters and bytes that aren't valid UTF-8 become `\xNN`.
+pub fn escape_string(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    let mut rest = bytes;
+
+    while !rest.is_empty() {
+        let (valid, invalid) = match str::from_utf8(rest) {
+            Ok(s) => (s, &[][..]),
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                let invalid_len = e.error_len().unwrap_or(after.len());
+                let (invalid, after) = after.split_at(invalid_len);
+                rest = after;
+                (str::from_utf8(valid).unwrap(), invalid)
+            }
+        };
+
+        for c in valid.chars() {
+            push_escaped_char(&mut out, c);
+        }
+        for byte in invalid {
+            push_hex_escape(&mut out, *byte);
+        }
+
+        if invalid.is_empty() {
+            break;
+        }
+    }
+
+    out
+}
+
+/// Reverses `escape_string`: turns the text between the quotes of a string
+/// literal into the bytes of the string.
+pub fn unescape_string(text: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(text.len());
+    let mut bytes = text.iter().cloned();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            out.push(byte);
+            continue;
+        }
+
+        let letter = bytes
+            .next()
+            .ok_or_else(|| "string literal ends with \\".to_string())?;
+
+        if letter == b'x' {
+            let mut hex_digit = || bytes.next().and_then(|b| (b as char).to_digit(16));
+            let hex = match (hex_digit(), hex_digit()) {
+                (Some(hi), Some(lo)) => Some((hi * 16 + lo) as u8),
+                _ => None,
+            };
+
+            match hex {
+                Some(0) => Err("\\x00 can't be used in a string".to_string())?,
+                Some(byte) => out.push(byte),
+                None => Err("\\x must be followed by two hex digits".to_string())?,
+            }
+
+            continue;
+        }
+
+        match NAMED_ESCAPES.iter().find(|(l, _)| *l == letter) {
+            Some((_, byte)) => out.push(*byte),
+            None => Err(format!(
+                "unknown escape \\{} in string literal",
+                letter as char
+            ))?,
+        }
+    }
+
+    Ok(out)
+}
+
+/// Characters a symbol can be written with without escaping, `.` is allowed
+/// too as long as the symbol isn't just a dot.
+pub fn is_symbol_char(c: char) -> bool {
+    c.is_alphanumeric() || "&*-?+<>=_!:%".contains(c)
+}
+
+/// Whether digits (with the sign already stripped) are a float with a
+/// fraction, an exponent or both.
+pub fn is_float_syntax(digits: &str) -> bool {
+    fn skip_digits(s: &str) -> Option<&str> {
+        let rest = s.trim_start_matches(|c: char| c.is_ascii_digit());
+        if rest.len() == s.len() {
+            None
+        } else {
+            Some(rest)
+        }
+    }
+
+    let mut rest = match skip_digits(digits) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut has_fraction_or_exponent = false;
+
+    if rest.starts_with('.') {
+        rest = match skip_digits(&rest[1..]) {
+            Some(rest) => rest,
+            None => return false,
+        };
+        has_fraction_or_exponent = true;
+    }
+
+    if rest.starts_with('e') || rest.starts_with('E') {
+        let exponent = rest[1..].trim_start_matches(|c| c == '+' || c == '-');
+        if rest.len() - exponent.len() > 2 {
+            return false;
+        }
+        rest = match skip_digits(exponent) {
+            Some(rest) => rest,
+            None => return false,
+        };
+        has_fraction_or_exponent = true;
+    }
+
+    rest.is_empty() && has_fraction_or_exponent
+}
+
+// hex digits, an optional fraction and a binary exponent, like `1.8p3`
+// (after the sign and `0x`). Returns the integer and fraction digits and the
+// exponent, which saturates far beyond the range of f64.
+fn hex_float_parts(hex: &str) -> Option<(&str, &str, i64)> {
+    let p = hex.find(|c| c == 'p' || c == 'P')?;
+    let (mantissa, exponent) = (&hex[..p], &hex[p + 1..]);
+
+    let (int_digits, frac_digits) = match mantissa.find('.') {
+        Some(point) => (&mantissa[..point], Some(&mantissa[point + 1..])),
+        None => (mantissa, None),
+    };
+
+    let is_hex = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit());
+    if !is_hex(int_digits) || !frac_digits.map_or(true, is_hex) {
+        return None;
+    }
+
+    let exp_digits = exponent.trim_start_matches(|c| c == '+' || c == '-');
+    if exponent.len() - exp_digits.len() > 1
+        || exp_digits.is_empty()
+        || !exp_digits.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let magnitude = exp_digits
+        .chars()
+        .fold(0i64, |acc, c| (acc * 10 + c.to_digit(10).unwrap() as i64).min(1 << 20));
+    let exponent = if exponent.starts_with('-') { -magnitude } else { magnitude };
+
+    Some((int_digits, frac_digits.unwrap_or(""), exponent))
+}
+
+/// Whether digits (with the sign already stripped) are a hex float, `0x`
+/// followed by hex digits, an optional fraction and a binary exponent, e.g.
+/// `0x1.8p3` for 12.0.
+pub fn is_hex_float_syntax(digits: &str) -> bool {
+    (digits.starts_with("0x") || digits.starts_with("0X"))
+        && hex_float_parts(&digits[2..]).is_some()
+}
+
+// `mantissa * 2^exponent` rounded to nearest, ties to even, with `sticky`
+// set when nonzero bits below the mantissa were dropped
+fn round_to_f64(mantissa: u64, exponent: i64, sticky: bool) -> f64 {
+    if mantissa == 0 {
+        return 0.0;
+    }
+
+    let bits = 64 - mantissa.leading_zeros() as i64;
+    // the value is in [2^top, 2^(top + 1))
+    let top = exponent + bits - 1;
+    if top > 1023 {
+        return std::f64::INFINITY;
+    }
+
+    // subnormals keep fewer bits
+    let kept_bits = if top >= -1022 { 53 } else { 53 - (-1022 - top) };
+    if kept_bits < 0 {
+        return 0.0;
+    }
+
+    let shift = bits - kept_bits;
+    let (kept, mut exponent) = if shift > 0 {
+        let mantissa = mantissa as u128;
+        let kept = mantissa >> shift;
+        let rest = mantissa & ((1 << shift) - 1);
+        let half = 1 << (shift - 1);
+        let round_up = rest > half || (rest == half && (sticky || kept & 1 == 1));
+
+        (kept as u64 + round_up as u64, exponent + shift)
+    } else {
+        (mantissa << -shift, exponent + shift)
+    };
+
+    // exact from here on, scaled in steps that stay in range
+    let mut f = kept as f64;
+    while exponent > 1000 {
+        f *= 2f64.powi(1000);
+        exponent -= 1000;
+    }
+    while exponent < -1000 {
+        f *= 2f64.powi(-1000);
+        exponent += 1000;
+    }
+
+    f * 2f64.powi(exponent as i32)
+}
+
+/// The value of a hex float, see `is_hex_float_syntax`, rounded to the
+/// nearest float like decimal floats are. None for other syntax.
+pub fn parse_hex_float(atom: &str) -> Option<f64> {
+    let digits = atom.trim_start_matches(|c| c == '+' || c == '-');
+    if atom.len() - digits.len() > 1 || !is_hex_float_syntax(digits) {
+        return None;
+    }
+
+    let (int_digits, frac_digits, mut exponent) = hex_float_parts(&digits[2..])?;
+    let mut mantissa = 0u64;
+    let mut sticky = false;
+
+    // digits that don't fit into the mantissa only matter for rounding
+    for (i, c) in int_digits.chars().chain(frac_digits.chars()).enumerate() {
+        let digit = c.to_digit(16).unwrap() as u64;
+        let is_fraction = i >= int_digits.len();
+
+        if mantissa < 1 << 60 {
+            mantissa = mantissa * 16 + digit;
+            if is_fraction {
+                exponent -= 4;
+            }
+        } else {
+            sticky |= digit != 0;
+            if !is_fraction {
+                exponent += 4;
+            }
+        }
+    }
+
+    let f = round_to_f64(mantissa, exponent, sticky);
+    Some(if atom.starts_with('-') { -f } else { f })
+}
+
+/// A number as the reader reads it, see `parse_number`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParsedNumber {
+    Int(i64),
+    BigInt(BigInt),
+    Float(f64),
+}
+
+/// Whether the reader turns an unescaped atom into a number.
+pub fn is_number_syntax(atom: &str) -> bool {
+    if ["+inf.0", "-inf.0", "+nan.0"].contains(&atom) {
+        return true;
+    }
+
+    let digits = atom.trim_start_matches(|c| c == '+' || c == '-');
+
+    if atom.len() - digits.len() > 1 || !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return false;
+    }
+
+    digits.chars().all(|c| c.is_ascii_digit())
+        || is_float_syntax(digits)
+        || is_hex_float_syntax(digits)
+}
+
+/// The number an unescaped atom reads as, None when it's read as a symbol.
+/// Integers outside of i64 are bignums.
+pub fn parse_number(atom: &str) -> Option<ParsedNumber> {
+    match atom {
+        "+inf.0" => return Some(ParsedNumber::Float(std::f64::INFINITY)),
+        "-inf.0" => return Some(ParsedNumber::Float(std::f64::NEG_INFINITY)),
+        "+nan.0" => return Some(ParsedNumber::Float(std::f64::NAN)),
+        _ => (),
+    }
+
+    if !is_number_syntax(atom) {
+        return None;
+    }
+
+    let digits = atom.trim_start_matches(|c| c == '+' || c == '-');
+
+    if digits.chars().all(|c| c.is_ascii_digit()) {
+        return Some(match atom.parse::<i64>() {
+            Ok(i) => ParsedNumber::Int(i),
+            Err(_) => ParsedNumber::BigInt(BigInt::parse(atom).unwrap()),
+        });
+    }
+
+    if is_hex_float_syntax(digits) {
+        return parse_hex_float(atom).map(ParsedNumber::Float);
+    }
+
+    atom.parse::<f64>().ok().map(ParsedNumber::Float)
+}
+
+/// How the reader spells symbols written without bars or backslashes.
+/// Escaped symbols are read as written in every mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymbolCase {
+    Preserve,
+    Downcase,
+    Upcase,
+}
+
+pub const SYMBOL_CASES: &[&str] = &["preserve", "downcase", "upcase"];
+
+impl SymbolCase {
+    /// One of `SYMBOL_CASES`, in any case.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "preserve" => Some(SymbolCase::Preserve),
+            "downcase" => Some(SymbolCase::Downcase),
+            "upcase" => Some(SymbolCase::Upcase),
+            _ => None,
+        }
+    }
+
+    /// The name an unescaped symbol written as `name` is read as. With upcase,
+    /// builtins are read by their lowercase names, see `is_builtin_name`.
+    pub fn normalize(self, name: &str) -> String {
+        match self {
+            SymbolCase::Preserve => name.to_string(),
+            SymbolCase::Downcase => name.to_lowercase(),
+            SymbolCase::Upcase => {
+                let lowercase = name.to_lowercase();
+                if is_builtin_name(&lowercase) {
+                    lowercase
+                } else {
+                    name.to_uppercase()
+                }
+            }
+        }
+    }
+}
+
+// The names the compiler, the natives and the stdlib look for as written,
+// besides the ones of functions and variables: literals, directives,
+// keywords, special forms, declarations, type designators and the variable
+// if-it binds. Sorted.
+const BUILTIN_NAMES: &[&str] = &[
+    "%include",
+    ":direction",
+    ":input",
+    ":output",
+    ":radix",
+    "char",
+    "cons",
+    "declare",
+    "declare-var",
+    "float",
+    "funcall",
+    "function",
+    "if",
+    "inline",
+    "integer",
+    "it",
+    "lambda",
+    "let",
+    "list",
+    "macrolet",
+    "nil",
+    "notinline",
+    "null",
+    "number",
+    "optimize",
+    "quote",
+    "record",
+    "set!",
+    "stream",
+    "string",
+    "symbol",
+    "symbol-macrolet",
+    "t",
+];
+
+/// Whether the lowercase `name` is one of the builtins, which are named in
+/// lowercase: a symbol with a function or a value, like the natives and the
+/// definitions of the stdlib, or a name the compiler looks for.
+pub fn is_builtin_name(name: &str) -> bool {
+    BUILTIN_NAMES.binary_search(&name).is_ok() || symbols::is_bound_name(name)
+}
+
+// a SymbolCase as its index in SYMBOL_CASES
+static SYMBOL_CASE: AtomicU8 = AtomicU8::new(0);
+
+/// Sets the mode readers start with (`--symbol-case`), the printer escapes
+/// symbols whose names it would change.
+pub fn set_symbol_case(case: SymbolCase) {
+    SYMBOL_CASE.store(case as u8, Ordering::SeqCst);
+}
+
+pub fn symbol_case() -> SymbolCase {
+    match SYMBOL_CASE.load(Ordering::SeqCst) {
+        1 => SymbolCase::Downcase,
+        2 => SymbolCase::Upcase,
+        _ => SymbolCase::Preserve,
+    }
+}
+
+fn symbol_needs_bars(name: &str) -> bool {
+    name.is_empty()
+        || name == "."
+        // these read as the empty list and t, not as symbols
+        || name == "nil"
+        || name == "t"
+        || !name.chars().all(|c| is_symbol_char(c) || c == '.')
+        || is_number_syntax(name)
+        // read with the current mode, the name would change
+        || symbol_case().normalize(name) != name
+}
+
+/// Writes a symbol name so that it reads back as the same symbol, between
+/// bars if it would otherwise read as something else. Doesn't apply to the
+/// nil and t objects themselves, which are printed as they are.
+pub fn escape_symbol(name: &str) -> String {
+    if symbol_needs_bars(name) {
+        format!("|{}|", name.replace('|', "||"))
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_names_sorted() {
+        assert!(BUILTIN_NAMES.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_upcase_reads_builtins_in_lowercase() {
+        let names = ["Foo", "NIL", "List", "set!", ":Radix"];
+        let read: Vec<_> = names.iter().map(|name| SymbolCase::Upcase.normalize(name)).collect();
+
+        assert_eq!(read, vec!["FOO", "nil", "list", "set!", ":radix"]);
+    }
+}
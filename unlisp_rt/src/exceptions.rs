@@ -1,10 +1,15 @@
 use libc::c_char;
+use std::cell::{RefCell, UnsafeCell};
 use std::ffi::CStr;
 use std::mem;
 use std::ptr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use crate::defs::{Function, Object};
+use crate::defs::{Function, Object, Symbol};
 use crate::error::RuntimeError;
+use crate::output;
+use crate::source_locations;
 
 use unlisp_internal_macros::runtime_fn;
 
@@ -12,22 +17,61 @@ const JMP_BUF_SIZE: usize = mem::size_of::<u32>() * 40;
 
 type JmpBuf = [i8; JMP_BUF_SIZE];
 
-#[export_name = "glob_jmp_buf"]
-#[no_mangle]
-#[used]
-static mut GLOB_JMP_BUF: JmpBuf = [0; JMP_BUF_SIZE];
+// Each thread has its own handler, so contexts evaluating code on different
+// threads catch only their own errors. Nested handlers save the previous
+// buffer on the Rust stack and restore it on exit.
+thread_local! {
+    static JMP_BUF: UnsafeCell<JmpBuf> = UnsafeCell::new([0; JMP_BUF_SIZE]);
+    static ERR_MSG: RefCell<Option<String>> = RefCell::new(None);
+    // where the last error was raised, see source_locations
+    static ERR_LOCATION: RefCell<Option<source_locations::SourceLocation>> = RefCell::new(None);
+    static BLOCK_EXIT: RefCell<Option<BlockExit>> = RefCell::new(None);
+    // tags of the blocks running on this thread, innermost last
+    static ACTIVE_BLOCKS: RefCell<Vec<u64>> = RefCell::new(vec![]);
+    // the innermost error handler in effect on this thread
+    static HANDLERS: RefCell<Option<Rc<HandlerFrame>>> = RefCell::new(None);
+    // restarts established on this thread, innermost last
+    static RESTARTS: RefCell<Vec<Restart>> = RefCell::new(vec![]);
+}
+
+// a return-from on its way to the block it exits, or an invoked restart on
+// its way to its restart-case
+struct BlockExit {
+    tag: u64,
+    value: Object,
+    restart: Option<usize>,
+}
+
+// an error handler, with the handlers that were in effect when it was
+// established, which are the ones in effect while it runs
+struct HandlerFrame {
+    handler: Rc<dyn Fn(&str)>,
+    outer: Option<Rc<HandlerFrame>>,
+}
 
-#[export_name = "err_msg_ptr"]
-#[no_mangle]
-#[used]
-static mut ERR_MSG_PTR: *mut i8 = ptr::null_mut();
+struct Restart {
+    name: *mut Symbol,
+    // of the restart-case's block
+    tag: u64,
+    // in the restart-case's restarts
+    index: usize,
+}
+
+// what a handler caught, the exit is there when it was a return-from and
+// then its block is still running
+struct Caught {
+    msg: Option<String>,
+    exit: Option<BlockExit>,
+}
+
+static NEXT_BLOCK_TAG: AtomicU64 = AtomicU64::new(0);
 
 unsafe fn jmp_buf_ptr(buf: &mut JmpBuf) -> *mut i8 {
     &mut buf[0] as *mut i8
 }
 
 unsafe fn glob_jmp_buf_ptr() -> *mut i8 {
-    jmp_buf_ptr(&mut GLOB_JMP_BUF)
+    JMP_BUF.with(|buf| jmp_buf_ptr(&mut *buf.get()))
 }
 
 extern "C" {
@@ -35,9 +79,7 @@ extern "C" {
     fn longjmp(buf: *const i8) -> !;
 }
 
-pub unsafe fn run_with_global_ex_handler<F: FnOnce() -> Object>(
-    f: F,
-) -> Result<Object, RuntimeError> {
+unsafe fn run_with_handler<F: FnOnce() -> Object>(f: F) -> Result<Object, Caught> {
     let mut prev_handler: JmpBuf = mem::zeroed();
 
     ptr::copy_nonoverlapping(
@@ -46,10 +88,19 @@ pub unsafe fn run_with_global_ex_handler<F: FnOnce() -> Object>(
         JMP_BUF_SIZE,
     );
 
+    let handlers = HANDLERS.with(|h| h.borrow().clone());
+
     let result = if setjmp(glob_jmp_buf_ptr()) == 0 {
         Ok(f())
     } else {
-        Err(RuntimeError::new((*(ERR_MSG_PTR as *mut String)).clone()))
+        // a handler that exited to here left only the ones outside it in
+        // effect
+        HANDLERS.with(|h| *h.borrow_mut() = handlers);
+
+        Err(Caught {
+            msg: ERR_MSG.with(|msg| msg.borrow_mut().take()),
+            exit: BLOCK_EXIT.with(|exit| exit.borrow_mut().take()),
+        })
     };
 
     ptr::copy_nonoverlapping(
@@ -61,8 +112,196 @@ pub unsafe fn run_with_global_ex_handler<F: FnOnce() -> Object>(
     result
 }
 
+// hands what a handler caught on to the one around it
+unsafe fn reraise(caught: Caught) -> ! {
+    let Caught { msg, exit } = caught;
+    ERR_MSG.with(|cell| *cell.borrow_mut() = msg);
+    BLOCK_EXIT.with(|cell| *cell.borrow_mut() = exit);
+    longjmp(glob_jmp_buf_ptr())
+}
+
+/// Runs `f`, returning the error it raises. A return-from in `f` to a block
+/// around the handler isn't an error, it goes on to the block. The error
+/// handlers around aren't in effect in `f`, its errors end here.
+pub unsafe fn run_with_global_ex_handler<F: FnOnce() -> Object>(
+    f: F,
+) -> Result<Object, RuntimeError> {
+    let outer = HANDLERS.with(|h| h.borrow_mut().take());
+    let result = run_with_handler(f);
+    HANDLERS.with(|h| *h.borrow_mut() = outer);
+
+    match result {
+        Ok(value) => Ok(value),
+        Err(caught @ Caught { exit: Some(_), .. }) => reraise(caught),
+        Err(caught) => {
+            let location = ERR_LOCATION.with(|location| location.borrow_mut().take());
+            Err(RuntimeError::new(caught.msg.unwrap_or_default()).with_location(location))
+        }
+    }
+}
+
+/// Runs `f`, then `cleanup`, also when `f` raises an error or exits to a
+/// block around, which then goes on after the cleanup.
+pub unsafe fn run_with_cleanup<F: FnOnce() -> Object, C: FnOnce()>(f: F, cleanup: C) -> Object {
+    let result = run_with_handler(f);
+    cleanup();
+
+    match result {
+        Ok(value) => value,
+        Err(caught) => reraise(caught),
+    }
+}
+
+/// Calls `f` with a tag no other block has, `exit_block` with the tag makes
+/// `run_block` return the value right away, from however deep in `f` it's
+/// called. Errors and exits of other blocks go on to the handler around.
+pub unsafe fn run_block<F: FnOnce(u64) -> Object>(f: F) -> Object {
+    let tag = NEXT_BLOCK_TAG.fetch_add(1, Ordering::SeqCst);
+
+    ACTIVE_BLOCKS.with(|blocks| blocks.borrow_mut().push(tag));
+    let result = run_with_handler(|| f(tag));
+    ACTIVE_BLOCKS.with(|blocks| blocks.borrow_mut().pop());
+
+    match result {
+        Ok(value) => value,
+        Err(Caught {
+            exit: Some(exit), ..
+        }) if exit.tag == tag => exit.value,
+        Err(caught) => reraise(caught),
+    }
+}
+
+/// Unwinds to the block with the tag, which has to be running on this
+/// thread, otherwise it's an error.
+pub unsafe fn exit_block(tag: u64, value: Object) -> ! {
+    if !ACTIVE_BLOCKS.with(|blocks| blocks.borrow().contains(&tag)) {
+        raise_error("return-from: the block was already exited".to_string())
+    }
+
+    reraise(Caught {
+        msg: None,
+        exit: Some(BlockExit {
+            tag,
+            value,
+            restart: None,
+        }),
+    })
+}
+
+/// Runs `f` with `handler` called with the message of every error raised
+/// in it, before the error unwinds. The handler declines by returning, the
+/// error then goes on to the handlers outside and unwinds as usual, or takes
+/// over by exiting, e.g. by invoking a restart. While it runs only the
+/// handlers outside it are in effect.
+pub unsafe fn run_with_error_handler<F: FnOnce() -> Object>(
+    handler: Rc<dyn Fn(&str)>,
+    f: F,
+) -> Object {
+    let outer = HANDLERS.with(|h| h.borrow().clone());
+    let frame = HandlerFrame {
+        handler,
+        outer: outer.clone(),
+    };
+
+    HANDLERS.with(|h| *h.borrow_mut() = Some(Rc::new(frame)));
+    let result = run_with_handler(f);
+    HANDLERS.with(|h| *h.borrow_mut() = outer);
+
+    match result {
+        Ok(value) => value,
+        Err(caught) => reraise(caught),
+    }
+}
+
+// calls the handlers in effect, innermost first, each one with only the
+// ones outside it in effect
+unsafe fn signal_error(msg: &str) {
+    let handlers = HANDLERS.with(|h| h.borrow().clone());
+
+    let mut frame = handlers.clone();
+    while let Some(current) = frame {
+        HANDLERS.with(|h| *h.borrow_mut() = current.outer.clone());
+        (current.handler)(msg);
+        frame = current.outer.clone();
+    }
+
+    HANDLERS.with(|h| *h.borrow_mut() = handlers);
+}
+
+/// Runs `f` with restarts of the names established around it. Invoking one
+/// of them with `invoke_restart` exits `f` and makes this return the index
+/// of its name and the value it was invoked with. When a name is there more
+/// than once, the first one is invoked.
+pub unsafe fn run_with_restarts<F: FnOnce() -> Object>(
+    names: &[*mut Symbol],
+    f: F,
+) -> Result<Object, (usize, Object)> {
+    let tag = NEXT_BLOCK_TAG.fetch_add(1, Ordering::SeqCst);
+    let depth = RESTARTS.with(|restarts| restarts.borrow().len());
+
+    RESTARTS.with(|restarts| {
+        let established = names.iter().enumerate().rev();
+        restarts
+            .borrow_mut()
+            .extend(established.map(|(index, &name)| Restart { name, tag, index }))
+    });
+    ACTIVE_BLOCKS.with(|blocks| blocks.borrow_mut().push(tag));
+
+    let result = run_with_handler(f);
+
+    ACTIVE_BLOCKS.with(|blocks| blocks.borrow_mut().pop());
+    RESTARTS.with(|restarts| restarts.borrow_mut().truncate(depth));
+
+    match result {
+        Ok(value) => Ok(value),
+        Err(Caught {
+            exit: Some(exit), ..
+        }) if exit.tag == tag => Err((exit.restart.unwrap_or(0), exit.value)),
+        Err(caught) => reraise(caught),
+    }
+}
+
+/// Names of the restarts established on this thread, innermost first.
+pub fn active_restarts() -> Vec<*mut Symbol> {
+    RESTARTS.with(|restarts| restarts.borrow().iter().rev().map(|r| r.name).collect())
+}
+
+/// Exits to the innermost restart with the name, see `run_with_restarts`.
+/// It's an error when there's none.
+pub unsafe fn invoke_restart(name: *mut Symbol, value: Object) -> ! {
+    let restart = RESTARTS.with(|restarts| {
+        restarts
+            .borrow()
+            .iter()
+            .rev()
+            .find(|restart| restart.name == name)
+            .map(|restart| (restart.tag, restart.index))
+    });
+
+    match restart {
+        Some((tag, index)) => reraise(Caught {
+            msg: None,
+            exit: Some(BlockExit {
+                tag,
+                value,
+                restart: Some(index),
+            }),
+        }),
+        None => raise_error(format!(
+            "invoke-restart: no restart named {} is active",
+            Object::from_symbol(name)
+        )),
+    }
+}
+
 pub unsafe fn raise_error(msg: String) -> ! {
-    ERR_MSG_PTR = Box::into_raw(Box::new(msg)) as *mut i8;
+    // output printed before the error shouldn't be lost if the handler exits
+    output::flush_stdout();
+    // before the handlers, which run code of their own
+    let location = source_locations::source_location();
+    ERR_LOCATION.with(|err_location| *err_location.borrow_mut() = location);
+    signal_error(&msg);
+    ERR_MSG.with(|err_msg| *err_msg.borrow_mut() = Some(msg));
     longjmp(glob_jmp_buf_ptr())
 }
 
@@ -104,13 +343,18 @@ pub unsafe extern "C" fn unlisp_rt_run_with_global_ex_handler(f: *mut Function)
     let invoke_fn: unsafe extern "C" fn(*const Function) -> Object =
         mem::transmute((*f).invoke_f_ptr);
 
-    match run_with_global_ex_handler(|| invoke_fn(f)) {
+    let ret_code = match run_with_global_ex_handler(|| invoke_fn(f)) {
         Ok(_) => 0,
         Err(e) => {
             eprintln!("runtime error: {}", e);
             1
         }
-    }
+    };
+
+    // this is the last runtime call of a compiled binary's main
+    output::flush_stdout();
+
+    ret_code
 }
 
 #[runtime_fn]
@@ -141,3 +385,25 @@ pub unsafe extern "C" fn unlisp_rt_raise_undef_fn_error(name: *const c_char) ->
 
     raise_error(msg);
 }
+
+unsafe fn c_str_to_str<'a>(s: *const c_char) -> &'a str {
+    CStr::from_ptr(s).to_str().unwrap()
+}
+
+#[runtime_fn]
+pub unsafe extern "C" fn unlisp_rt_raise_type_error(
+    arg_name: *const c_char,
+    fn_name: *const c_char,
+    expected: *const c_char,
+    actual: *const c_char,
+) -> ! {
+    let msg = format!(
+        "argument {} of {}: expected {}, got {}",
+        c_str_to_str(arg_name),
+        c_str_to_str(fn_name),
+        c_str_to_str(expected),
+        c_str_to_str(actual)
+    );
+
+    raise_error(msg);
+}
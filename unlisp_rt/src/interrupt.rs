@@ -0,0 +1,51 @@
+use crate::defs::Object;
+use crate::exceptions;
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use unlisp_internal_macros::runtime_fn;
+
+pub const INTERRUPTED_MESSAGE: &str = "evaluation interrupted";
+
+thread_local! {
+    // set from another thread to stop the code running on this one
+    static INTERRUPTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    // how many `run_masked` calls are running on this thread
+    static MASKED: Cell<usize> = Cell::new(0);
+}
+
+/// The flag that interrupts code running on the current thread, another
+/// thread sets it to stop it. It stays set until `clear_interrupt`, so code
+/// that catches the error is interrupted again at its next check.
+pub fn interrupt_flag() -> Arc<AtomicBool> {
+    INTERRUPTED.with(Arc::clone)
+}
+
+pub fn clear_interrupt() {
+    INTERRUPTED.with(|flag| flag.store(false, Ordering::SeqCst));
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.with(|flag| flag.load(Ordering::Relaxed))
+}
+
+/// Runs `f` without interrupting it, e.g. the cleanup of an unwind-protect
+/// that's unwinding because of the interrupt. The interrupt stays set and
+/// is raised again at the first check after `f`, however `f` exits.
+pub unsafe fn run_masked<F: FnOnce() -> Object>(f: F) -> Object {
+    MASKED.with(|masked| masked.set(masked.get() + 1));
+
+    exceptions::run_with_cleanup(f, || MASKED.with(|masked| masked.set(masked.get() - 1)))
+}
+
+// called by JIT compiled code on entry to every function, tail recursive
+// loops included, and by natives that catch errors, which don't catch the
+// interrupt
+#[runtime_fn]
+pub unsafe extern "C" fn unlisp_rt_check_interrupt() {
+    if is_interrupted() && MASKED.with(Cell::get) == 0 {
+        exceptions::raise_error(INTERRUPTED_MESSAGE.to_string());
+    }
+}
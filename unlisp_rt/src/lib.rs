@@ -3,8 +3,25 @@
 #![feature(custom_inner_attributes)]
 #![feature(proc_macro_hygiene)]
 
+pub mod bigint;
+mod checks;
+pub mod debug;
 pub mod defs;
 pub mod error;
+pub mod escapes;
 pub mod exceptions;
+pub mod interrupt;
+mod native_docs;
+pub mod output;
 pub mod predefined;
+pub mod print_hooks;
+pub mod print_shared;
+pub mod source_locations;
+pub mod stats;
+pub mod streams;
 pub mod symbols;
+pub mod test_registry;
+pub mod threads;
+pub mod values;
+
+pub use stats::stats;
@@ -0,0 +1,150 @@
+// The docstrings of the natives, for `unlisp doc`. Natives are registered
+// without one, so they're kept here by name, sorted.
+const DOCS: &[(&str, &str)] = &[
+    ("*", "Multiplies the numbers, 1 without arguments."),
+    ("+", "Adds the numbers, 0 without arguments."),
+    ("-", "Subtracts the other numbers from x, or negates x when it's alone."),
+    ("<", "t when the number x is less than y, nil otherwise."),
+    (">", "t when the number x is greater than y, nil otherwise."),
+    (
+        "all-symbols",
+        "The interned symbols sorted by name, with a string only the ones starting with it.",
+    ),
+    (
+        "apply",
+        "Calls f with the other arguments followed by the elements of the last one, a proper list.",
+    ),
+    ("boundp", "Whether the symbol has a value."),
+    ("call-collecting-values", "Calls f and returns all the values it returns as a list."),
+    ("call-ignoring-errors", "Calls f and returns nil instead if it raises an error."),
+    ("call-with-block", "Calls f with the tag return-from-block exits it with."),
+    ("call-with-cleanup", "Calls f and then cleanup, however f exits."),
+    (
+        "call-with-error-handler",
+        "Calls f, handler is called with the message of every error raised in it.",
+    ),
+    (
+        "call-with-restarts",
+        "Calls f with the restarts of names active, invoking one calls its handler instead.",
+    ),
+    ("char<", "t when the char x comes before y, nil otherwise."),
+    ("char=", "t when the chars are the same, nil otherwise."),
+    ("char>", "t when the char x comes after y, nil otherwise."),
+    ("close", "Flushes and closes the stream."),
+    ("coerce", "Converts x to the type of a designator, e.g. a char to an integer."),
+    ("compiler-macro-function", "The compiler macro of the symbol, nil without one."),
+    ("compute-restarts", "The names of the active restarts, innermost first."),
+    ("concat", "The strings joined together."),
+    ("cons", "A new cell with x as its first element and list as its rest."),
+    (
+        "declare-pure",
+        "Lets calls of the function with constant arguments be folded at compile time.",
+    ),
+    ("equal", "x when x and y are equal, nil otherwise."),
+    ("error", "Raises an error with the message format makes of control and the arguments."),
+    ("fboundp", "Whether the symbol has a function."),
+    ("first", "The first element of the list, nil for nil."),
+    ("format", "Formats control with the arguments to a string (nil), stdout (t) or a stream."),
+    ("fresh-line", "Writes a newline unless the output is at the start of a line, t if it did."),
+    ("gc", "A placeholder for a collector, frees nothing and returns 0."),
+    ("gensym", "A new uninterned symbol."),
+    (
+        "get-internal-real-time",
+        "The time since the runtime started in internal-time-units-per-second.",
+    ),
+    (
+        "invoke-restart",
+        "Exits to the active restart of the name, calling its handler with the arguments.",
+    ),
+    ("join", "The strings of the list joined with sep in between."),
+    ("join-thread", "Waits for the thread and returns what its function returned."),
+    ("list-cells", "The number of distinct cells reachable from x."),
+    ("listp", "Whether x is a list, nil included."),
+    ("macroexpand-1", "Expands the macro call form once."),
+    ("make-channel", "A queue that any thread can send to and receive from."),
+    ("make-record", "A record of the type with the slots."),
+    ("number->string", "The number written in the radix, 10 by default, 16 writes floats in hex."),
+    ("open", "Opens a file for reading, or for writing with :direction :output."),
+    ("parse-integer", "Parses a fixnum in the string, in the radix given with :radix."),
+    ("points-to", "Prints the addresses of the first cells of x."),
+    ("prin1", "Writes x readably and returns it."),
+    ("princ", "Writes x without escapes and returns it."),
+    ("print", "Writes a newline and x readably, and returns x."),
+    ("println", "Writes x without escapes and a newline, and returns x."),
+    ("random", "A random integer from 0 below bound."),
+    ("read", "Reads the first form of the string as data."),
+    ("read-all", "Reads all forms of the string as data, into a list."),
+    ("read-char", "The next char of the stream, nil at its end."),
+    ("read-line", "The next line of the stream without its newline, nil at its end."),
+    ("record-ref", "Slot i of a record of the type."),
+    ("record-set!", "Sets slot i of a record of the type to val and returns val."),
+    ("record-type", "The type of the record, a symbol."),
+    ("recv", "Waits for a value sent to the channel and returns it."),
+    ("register-test", "Registers f as the test of the name, for run-tests."),
+    ("reset-room", "Sets the allocation counters of room back to zero."),
+    ("rest", "The list without its first element, nil for nil."),
+    ("return-from-block", "Exits the block of the tag with the value."),
+    ("room", "Prints the allocation counters and returns them as an association list."),
+    (
+        "run-tests",
+        "Runs the registered tests, prints the results and returns the counts passed and run.",
+    ),
+    ("send", "Adds the value to the channel's queue."),
+    ("set-compiler-macro!", "Makes func the compiler macro of the symbol."),
+    (
+        "set-definition-failed!",
+        "Makes calls of the function raise an error that its definition failed to compile.",
+    ),
+    ("set-first!", "Replaces the first element of the cell with x."),
+    ("set-macro!", "Makes the function a macro."),
+    (
+        "set-print-hook!",
+        "Prints records of the type with the string f returns, nil goes back to the default.",
+    ),
+    (
+        "set-print-shared!",
+        "Prints shared structure with labels when enabled, returns the previous setting.",
+    ),
+    ("set-rest!", "Replaces the rest of the cell with x."),
+    ("set-symbol-function!", "Makes func the function of the symbol."),
+    ("set-symbol-value!", "Sets the value of the symbol to val."),
+    ("shared-structure?", "t when the spines of the lists have a cell in common."),
+    ("sleep", "Waits for the number of seconds."),
+    ("spawn-thread", "Calls f on a new thread and returns a handle for join-thread."),
+    ("split-string", "The parts of the string between occurrences of sep."),
+    ("srand", "Seeds the random generator, so random returns the same sequence."),
+    ("stdout-write", "Writes the string to stdout."),
+    ("string->number", "The number the string is in the radix, 10 by default, or nil."),
+    ("string-downcase", "The string in lowercase."),
+    ("string-upcase", "The string in uppercase."),
+    ("string<", "t when the string x sorts before y, nil otherwise."),
+    ("string=", "t when the strings are the same, nil otherwise."),
+    ("string>", "t when the string x sorts after y, nil otherwise."),
+    ("symbol-function", "The function of the symbol."),
+    ("symbol-value", "The value of the symbol."),
+    ("symbolp", "Whether x is a symbol."),
+    ("terpri", "Writes a newline."),
+    ("typep", "Whether x is of the type a designator like integer or list names."),
+    ("values", "Returns the arguments as multiple values, the first one to a single-value caller."),
+    (
+        "warn",
+        "Writes a warning with the message format makes of control and the arguments to stderr.",
+    ),
+    ("write-char", "Writes the char to the stream."),
+];
+
+pub(crate) fn native_doc(name: &str) -> Option<&'static str> {
+    DOCS.binary_search_by(|(native, _)| native.cmp(&name))
+        .ok()
+        .map(|i| DOCS[i].1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sorted_by_name() {
+        assert!(DOCS.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+}
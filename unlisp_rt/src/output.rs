@@ -0,0 +1,51 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Printing natives write to the process-wide stdout handle, which buffers up
+// to a newline. Rust flushes it only when its own main returns, so it has to
+// be flushed explicitly before exiting the process, before unwinding out of
+// an error and when a compiled binary's main returns.
+
+// characters written since the last newline, for fresh-line
+static COLUMN: AtomicUsize = AtomicUsize::new(0);
+
+// set by send_stdout_to_stderr
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Makes output written with `write_stdout` go to stderr, e.g. while stdout
+/// carries an object file.
+pub fn send_stdout_to_stderr(to_stderr: bool) {
+    flush_stdout();
+    TO_STDERR.store(to_stderr, Ordering::SeqCst);
+}
+
+pub fn write_stdout(args: fmt::Arguments) -> io::Result<()> {
+    let text = fmt::format(args);
+
+    if TO_STDERR.load(Ordering::SeqCst) {
+        io::stderr().lock().write_all(text.as_bytes())?;
+    } else {
+        io::stdout().lock().write_all(text.as_bytes())?;
+    }
+
+    match text.rfind('\n') {
+        Some(i) => COLUMN.store(text[i + 1..].chars().count(), Ordering::SeqCst),
+        None => {
+            COLUMN.fetch_add(text.chars().count(), Ordering::SeqCst);
+        }
+    }
+
+    Ok(())
+}
+
+/// The column output written with `write_stdout` is at, 0 right after a
+/// newline.
+pub fn stdout_column() -> usize {
+    COLUMN.load(Ordering::SeqCst)
+}
+
+pub fn flush_stdout() {
+    // nothing sensible can be done if stdout is gone
+    let _ = io::stdout().flush();
+}
@@ -1,14 +1,37 @@
+use crate::bigint::BigInt;
+use crate::checks;
+use crate::debug;
 use crate::defs::*;
 use crate::error::RuntimeError;
+use crate::escapes::{self, ParsedNumber};
 use crate::exceptions;
+use crate::interrupt;
+use crate::native_docs;
+use crate::output;
+use crate::print_hooks;
+use crate::print_shared;
+use crate::source_locations::{self, SourceLocation};
+use crate::stats;
+use crate::streams::{Direction, Stream};
 use crate::symbols;
+use crate::test_registry;
+use crate::threads;
+use crate::values;
 
 use unlisp_internal_macros::trivial_apply;
 
 use libc::{c_char, c_void};
+use std::cmp::Ordering;
 use std::ffi::{CStr, CString};
-use std::io::Write;
+use std::fmt;
+use std::iter;
 use std::mem;
+use std::rc::Rc;
+use std::slice;
+use std::sync::atomic::{self, AtomicBool, AtomicU64};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 fn arr_to_raw(arr: &[&str]) -> *const *const c_char {
     let vec: Vec<_> = arr
@@ -22,7 +45,7 @@ fn arr_to_raw(arr: &[&str]) -> *const *const c_char {
     ptr as *const *const c_char
 }
 
-fn init_symbol_fn(
+pub(crate) fn init_symbol_fn(
     invoke_fn: *const c_void,
     apply_to_fn: *const c_void,
     name: &str,
@@ -43,31 +66,218 @@ fn init_symbol_fn(
     };
 
     let func = to_heap(func);
+    natives().push(func as usize);
 
     unsafe { (*sym).function = func };
 }
 
-unsafe extern "C" fn native_add_invoke(_: *const Function, n: u64, mut args: ...) -> Object {
-    let args = va_list_to_obj_array(n, args.as_va_list());
-    let mut sum = 0;
+// every function registered by init, objects rather than names like the
+// pure functions below
+static NATIVES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+fn natives() -> MutexGuard<'static, Vec<usize>> {
+    NATIVES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A function the runtime defines, as opposed to one compiled from source.
+pub struct NativeFunction {
+    pub name: String,
+    pub arglist: Vec<String>,
+    pub has_restarg: bool,
+    pub doc: Option<&'static str>,
+}
+
+/// The natives registered by the runtime, sorted by name. Redefining a
+/// name doesn't remove its native from the list.
+pub fn native_functions() -> Vec<NativeFunction> {
+    let mut found: Vec<_> = natives()
+        .iter()
+        .map(|f| unsafe {
+            let f = *f as *const Function;
+            let to_string = |s: *const c_char| CStr::from_ptr(s).to_str().unwrap().to_string();
+
+            let name = to_string((*f).name);
+
+            NativeFunction {
+                doc: native_docs::native_doc(&name),
+                name,
+                arglist: (0..(*f).arg_count as usize)
+                    .map(|i| to_string(*(*f).arglist.add(i)))
+                    .collect(),
+                has_restarg: (*f).has_restarg,
+            }
+        })
+        .collect();
+
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    found
+}
+
+#[derive(Clone)]
+enum Number {
+    Int(i64),
+    Big(BigInt),
+    Float(f64),
+}
+
+impl Number {
+    fn from_object(o: &Object) -> Self {
+        if o.ty == ObjType::Box {
+            return Self::from_object(&o.unpack_underlying());
+        }
+
+        match o.ty {
+            ObjType::Float64 => Number::Float(o.unpack_float()),
+            ObjType::BigInt => Number::Big(unsafe { (*o.unpack_bigint()).clone() }),
+            _ => Number::Int(o.unpack_int()),
+        }
+    }
+
+    fn from_bigint(i: BigInt) -> Self {
+        match i.to_i64() {
+            Some(i) => Number::Int(i),
+            None => Number::Big(i),
+        }
+    }
+
+    fn to_float(&self) -> f64 {
+        match self {
+            Number::Int(i) => *i as f64,
+            Number::Big(i) => i.to_f64(),
+            Number::Float(f) => *f,
+        }
+    }
+
+    fn to_bigint(&self) -> BigInt {
+        match self {
+            Number::Int(i) => BigInt::from_i64(*i),
+            Number::Big(i) => i.clone(),
+            Number::Float(_) => unreachable!("float passed as an integer"),
+        }
+    }
+
+    // integer arithmetic stays integer, any float operand makes the result
+    // float. Fixnum results that overflow are redone on bignums, bignum
+    // results are demoted back to fixnums when they fit.
+    fn combine(
+        self,
+        rhs: Self,
+        int_op: fn(i64, i64) -> Option<i64>,
+        big_op: fn(&BigInt, &BigInt) -> BigInt,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Self {
+        match (self, rhs) {
+            (Number::Int(x), Number::Int(y)) if int_op(x, y).is_some() => {
+                Number::Int(int_op(x, y).unwrap())
+            }
+            (Number::Float(x), y) => Number::Float(float_op(x, y.to_float())),
+            (x, Number::Float(y)) => Number::Float(float_op(x.to_float(), y)),
+            (x, y) => Number::from_bigint(big_op(&x.to_bigint(), &y.to_bigint())),
+        }
+    }
 
-    for i in 0..n {
-        sum += (*args.offset(i as isize)).unpack_int();
+    fn compare(&self, rhs: &Self) -> Option<Ordering> {
+        match (self, rhs) {
+            (Number::Int(x), Number::Int(y)) => Some(x.cmp(y)),
+            (Number::Float(_), _) | (_, Number::Float(_)) => {
+                self.to_float().partial_cmp(&rhs.to_float())
+            }
+            (x, y) => Some(x.to_bigint().cmp(&y.to_bigint())),
+        }
+    }
+
+    fn into_object(self) -> Object {
+        match self {
+            Number::Int(i) => Object::from_int(i),
+            Number::Big(i) => Object::from_bigint(i),
+            Number::Float(f) => Object::from_float(f),
+        }
     }
+}
 
-    Object::from_int(sum)
+fn add_numbers(x: Number, y: Number) -> Number {
+    x.combine(y, i64::checked_add, |x, y| x + y, |x, y| x + y)
 }
 
-unsafe extern "C" fn native_add_apply(_: *const Function, args: ListLike) -> Object {
-    let mut sum = 0;
-    let mut cur_args = args;
+fn sub_numbers(x: Number, y: Number) -> Number {
+    x.combine(y, i64::checked_sub, |x, y| x - y, |x, y| x - y)
+}
+
+fn mul_numbers(x: Number, y: Number) -> Number {
+    x.combine(y, i64::checked_mul, |x, y| x * y, |x, y| x * y)
+}
+
+// Fixnums are folded in a tight loop, the first argument that isn't a fixnum
+// (or a result that overflows) switches the rest of the fold to the
+// polymorphic path, which gives the same results on any input.
+fn fold_numbers(
+    init: Number,
+    mut args: impl Iterator<Item = Object>,
+    int_op: fn(i64, i64) -> Option<i64>,
+    op: fn(Number, Number) -> Number,
+) -> Number {
+    let mut acc = match init {
+        Number::Int(i) => i,
+        init => return args.fold(init, |acc, arg| op(acc, Number::from_object(&arg))),
+    };
+    let mut slow_acc = None;
+
+    for arg in args.by_ref() {
+        if arg.ty == ObjType::Int64 {
+            if let Some(result) = int_op(acc, arg.unpack_int()) {
+                acc = result;
+                continue;
+            }
+        }
+
+        slow_acc = Some(op(Number::Int(acc), Number::from_object(&arg)));
+        break;
+    }
 
-    while !cur_args.is_nil() {
-        sum += cur_args.car().unpack_int();
-        cur_args = cur_args.cdr();
+    match slow_acc {
+        Some(init) => args.fold(init, |acc, arg| op(acc, Number::from_object(&arg))),
+        None => Number::Int(acc),
     }
+}
+
+unsafe fn va_args_iter(n: u64, args: *mut Object) -> impl Iterator<Item = Object> {
+    (0..n).map(move |i| (*args.offset(i as isize)).clone())
+}
+
+fn list_iter(list: ListLike) -> impl Iterator<Item = Object> {
+    let mut cur = list;
+
+    iter::from_fn(move || {
+        if cur.is_nil() {
+            None
+        } else {
+            let car = cur.car();
+            cur = cur.cdr();
+            Some(car)
+        }
+    })
+}
+
+unsafe extern "C" fn native_add_invoke(_: *const Function, n: u64, mut args: ...) -> Object {
+    let args = va_list_to_obj_array(n, args.as_va_list());
 
-    Object::from_int(sum)
+    fold_numbers(
+        Number::Int(0),
+        va_args_iter(n, args),
+        i64::checked_add,
+        add_numbers,
+    )
+    .into_object()
+}
+
+unsafe extern "C" fn native_add_apply(_: *const Function, args: ListLike) -> Object {
+    fold_numbers(
+        Number::Int(0),
+        list_iter(args),
+        i64::checked_add,
+        add_numbers,
+    )
+    .into_object()
 }
 
 unsafe extern "C" fn native_sub_invoke(
@@ -77,144 +287,1477 @@ unsafe extern "C" fn native_sub_invoke(
     mut args: ...
 ) -> Object {
     let args = va_list_to_obj_array(n, args.as_va_list());
-    let mut result = x.unpack_int();
-
-    for i in 0..n {
-        result -= (*args.offset(i as isize)).unpack_int();
-    }
 
-    Object::from_int(result)
+    fold_numbers(
+        Number::from_object(&x),
+        va_args_iter(n, args),
+        i64::checked_sub,
+        sub_numbers,
+    )
+    .into_object()
 }
 
 unsafe extern "C" fn native_sub_apply(_: *const Function, args: ListLike) -> Object {
-    let mut result = args.car().unpack_int();
+    fold_numbers(
+        Number::from_object(&args.car()),
+        list_iter(args.cdr()),
+        i64::checked_sub,
+        sub_numbers,
+    )
+    .into_object()
+}
+
+unsafe extern "C" fn native_mul_invoke(_: *const Function, n: u64, mut args: ...) -> Object {
+    let args = va_list_to_obj_array(n, args.as_va_list());
 
-    let mut cur_args = args.cdr();
+    fold_numbers(
+        Number::Int(1),
+        va_args_iter(n, args),
+        i64::checked_mul,
+        mul_numbers,
+    )
+    .into_object()
+}
+
+unsafe extern "C" fn native_mul_apply(_: *const Function, args: ListLike) -> Object {
+    fold_numbers(
+        Number::Int(1),
+        list_iter(args),
+        i64::checked_mul,
+        mul_numbers,
+    )
+    .into_object()
+}
 
-    while !cur_args.is_nil() {
-        result -= cur_args.car().unpack_int();
-        cur_args = cur_args.cdr();
+// comparisons involving NaN are false
+fn compare_numbers(x: &Object, y: &Object, expected: Ordering) -> Object {
+    match Number::from_object(x).compare(&Number::from_object(y)) {
+        Some(ordering) if ordering == expected => Object::t(),
+        _ => Object::nil(),
     }
+}
 
-    Object::from_int(result)
+#[trivial_apply]
+extern "C" fn native_lt_invoke(_: *const Function, x: Object, y: Object) -> Object {
+    compare_numbers(&x, &y, Ordering::Less)
 }
 
 #[trivial_apply]
-extern "C" fn native_equal_invoke(_: *const Function, x: Object, y: Object) -> Object {
-    if x == y {
-        x
+extern "C" fn native_gt_invoke(_: *const Function, x: Object, y: Object) -> Object {
+    compare_numbers(&x, &y, Ordering::Greater)
+}
+
+fn ordering_to_object(ordering: Ordering, expected: Ordering) -> Object {
+    if ordering == expected {
+        Object::t()
     } else {
         Object::nil()
     }
 }
 
+fn compare_chars(x: &Object, y: &Object, expected: Ordering) -> Object {
+    ordering_to_object(x.unpack_char().cmp(&y.unpack_char()), expected)
+}
+
+// byte-wise, so it's lexicographic on code points for UTF-8 strings
+unsafe fn compare_strings(x: &Object, y: &Object, expected: Ordering) -> Object {
+    let x = CStr::from_ptr(x.unpack_string()).to_bytes();
+    let y = CStr::from_ptr(y.unpack_string()).to_bytes();
+    ordering_to_object(x.cmp(y), expected)
+}
+
 #[trivial_apply]
-extern "C" fn native_set_fn_invoke(_: *const Function, sym: Object, func: Object) -> Object {
-    let sym = sym.unpack_symbol();
-    let func = func.unpack_function();
+extern "C" fn native_char_lt_invoke(_: *const Function, x: Object, y: Object) -> Object {
+    compare_chars(&x, &y, Ordering::Less)
+}
 
-    unsafe { (*sym).function = func };
+#[trivial_apply]
+extern "C" fn native_char_gt_invoke(_: *const Function, x: Object, y: Object) -> Object {
+    compare_chars(&x, &y, Ordering::Greater)
+}
 
-    Object::nil()
+#[trivial_apply]
+extern "C" fn native_char_eq_invoke(_: *const Function, x: Object, y: Object) -> Object {
+    compare_chars(&x, &y, Ordering::Equal)
 }
 
 #[trivial_apply]
-extern "C" fn native_cons_invoke(_: *const Function, x: Object, y: Object) -> Object {
-    Object::from_cons(to_heap(Cons::new(x, y)))
+unsafe extern "C" fn native_string_lt_invoke(_: *const Function, x: Object, y: Object) -> Object {
+    compare_strings(&x, &y, Ordering::Less)
 }
 
 #[trivial_apply]
-extern "C" fn native_rest_invoke(_: *const Function, list_like: Object) -> Object {
-    list_like.unpack_list_like().cdr_as_object()
+unsafe extern "C" fn native_string_gt_invoke(_: *const Function, x: Object, y: Object) -> Object {
+    compare_strings(&x, &y, Ordering::Greater)
 }
 
 #[trivial_apply]
-unsafe extern "C" fn native_first_invoke(_: *const Function, list_like: Object) -> Object {
-    list_like.unpack_list_like().car()
+unsafe extern "C" fn native_string_eq_invoke(_: *const Function, x: Object, y: Object) -> Object {
+    compare_strings(&x, &y, Ordering::Equal)
+}
+
+unsafe fn unpack_str<'a>(s: &Object) -> &'a str {
+    match CStr::from_ptr(s.unpack_string()).to_str() {
+        Ok(s) => s,
+        Err(_) => exceptions::raise_error(format!("string {} is not valid UTF-8", s)),
+    }
+}
+
+unsafe fn concat_strings(strings: impl Iterator<Item = Object>, sep: &str) -> Object {
+    let mut result = String::new();
+
+    for (i, s) in strings.enumerate() {
+        if i > 0 {
+            result.push_str(sep);
+        }
+        result.push_str(unpack_str(&s));
+    }
+
+    string_to_object(result)
+}
+
+unsafe extern "C" fn native_concat_invoke(_: *const Function, n: u64, mut args: ...) -> Object {
+    let args = va_list_to_obj_array(n, args.as_va_list());
+    concat_strings(va_args_iter(n, args), "")
+}
+
+unsafe extern "C" fn native_concat_apply(_: *const Function, args: ListLike) -> Object {
+    concat_strings(list_iter(args), "")
+}
+
+// An empty separator splits the string into one-character strings, an empty
+// string always splits into a list of one empty string.
+#[trivial_apply]
+unsafe extern "C" fn native_split_string_invoke(
+    _: *const Function,
+    s: Object,
+    sep: Object,
+) -> Object {
+    let s = unpack_str(&s);
+    let sep = unpack_str(&sep);
+
+    let parts: Vec<_> = if s.is_empty() {
+        vec![String::new()]
+    } else if sep.is_empty() {
+        s.chars().map(|c| c.to_string()).collect()
+    } else {
+        s.split(sep).map(|part| part.to_string()).collect()
+    };
+
+    parts
+        .into_iter()
+        .rev()
+        .fold(ListLike::from_nil(), |acc, part| {
+            acc.cons(string_to_object(part))
+        })
+        .to_object()
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_join_invoke(_: *const Function, list: Object, sep: Object) -> Object {
+    concat_strings(list_iter(list.unpack_list_like()), unpack_str(&sep))
+}
+
+// Unicode case mapping, so a string can change its length (e.g. "ß" upcases
+// to "SS")
+#[trivial_apply]
+unsafe extern "C" fn native_string_upcase_invoke(_: *const Function, s: Object) -> Object {
+    string_to_object(unpack_str(&s).to_uppercase())
 }
 
-unsafe fn apply_to_list_like(f: *const Function, args: ListLike) -> Object {
-    let len = args.len();
-    if !unlisp_rt_check_arity(f, len) {
-        exceptions::unlisp_rt_raise_arity_error((*f).name, (*f).arg_count, len);
+#[trivial_apply]
+unsafe extern "C" fn native_string_downcase_invoke(_: *const Function, s: Object) -> Object {
+    string_to_object(unpack_str(&s).to_lowercase())
+}
+
+// Surrounding whitespace is allowed, positions in errors count characters
+// from the start of the string.
+fn parse_integer(s: &str, radix: u32) -> Result<i64, String> {
+    let mut chars = s.chars().enumerate().peekable();
+
+    while chars.peek().map_or(false, |(_, c)| c.is_whitespace()) {
+        chars.next();
+    }
+
+    let negative = match chars.peek() {
+        Some((_, '-')) => true,
+        _ => false,
+    };
+    if let Some((_, '-')) | Some((_, '+')) = chars.peek() {
+        chars.next();
+    }
+
+    let mut result: Option<i64> = None;
+
+    while let Some(&(pos, c)) = chars.peek() {
+        let digit = match c.to_digit(radix) {
+            Some(digit) => digit as i64,
+            None => break,
+        };
+
+        let acc = result.unwrap_or(0).checked_mul(radix as i64);
+        let acc = if negative {
+            acc.and_then(|acc| acc.checked_sub(digit))
+        } else {
+            acc.and_then(|acc| acc.checked_add(digit))
+        };
+
+        match acc {
+            Some(acc) => result = Some(acc),
+            None => return Err(format!("integer overflow at position {}", pos)),
+        }
+
+        chars.next();
+    }
+
+    while chars.peek().map_or(false, |(_, c)| c.is_whitespace()) {
+        chars.next();
+    }
+
+    match (chars.next(), result) {
+        (Some((pos, c)), _) => Err(format!("junk {:?} at position {}", c, pos)),
+        (None, None) => Err("no digits".to_string()),
+        (None, Some(result)) => Ok(result),
+    }
+}
+
+unsafe fn parse_integer_with_options(s: Object, options: ListLike) -> Object {
+    let mut radix = 10;
+    let mut options = list_iter(options);
+
+    while let Some(key) = options.next() {
+        let value = match options.next() {
+            Some(value) => value,
+            None => exceptions::raise_error(format!("parse-integer: no value for option {}", key)),
+        };
+
+        if key.ty != ObjType::Symbol
+            || CStr::from_ptr((*key.unpack_symbol()).name).to_bytes() != b":radix"
+        {
+            exceptions::raise_error(format!("parse-integer: unknown option {}", key));
+        }
+
+        radix = value.unpack_int();
+    }
+
+    if radix < 2 || radix > 36 {
+        exceptions::raise_error(format!(
+            "parse-integer: radix must be between 2 and 36, got {}",
+            radix
+        ));
+    }
+
+    let s = unpack_str(&s);
+
+    match parse_integer(s, radix as u32) {
+        Ok(i) => Object::from_int(i),
+        Err(msg) => exceptions::raise_error(format!("parse-integer: {} in {:?}", msg, s)),
+    }
+}
+
+unsafe extern "C" fn native_parse_integer_invoke(
+    _: *const Function,
+    n: u64,
+    s: Object,
+    mut args: ...
+) -> Object {
+    let args_arr = va_list_to_obj_array(n, args.as_va_list());
+    let options = obj_array_to_list_like(n, args_arr, ListLike::from_nil());
+
+    parse_integer_with_options(s, options)
+}
+
+unsafe extern "C" fn native_parse_integer_apply(_: *const Function, args: ListLike) -> Object {
+    parse_integer_with_options(args.car(), args.cdr())
+}
+
+// the optional radix of number->string and string->number
+unsafe fn radix_arg(fn_name: &str, radix: Option<Object>) -> u32 {
+    let radix = match radix {
+        Some(radix) => radix,
+        None => return 10,
+    };
+
+    if radix.ty != ObjType::Int64 || radix.unpack_int() < 2 || radix.unpack_int() > 36 {
+        exceptions::raise_error(format!(
+            "{}: radix must be an integer between 2 and 36, got {}",
+            fn_name, radix
+        ));
+    }
+
+    radix.unpack_int() as u32
+}
+
+fn is_number(o: &Object) -> bool {
+    [ObjType::Int64, ObjType::BigInt, ObjType::Float64].contains(&o.ty)
+}
+
+// Floats are written in radix 10 like the printer does, or as hex floats in
+// radix 16, integers in any radix.
+unsafe fn number_to_string(n: Object, radix: Option<Object>) -> Object {
+    let radix = radix_arg("number->string", radix);
+
+    if !is_number(&n) {
+        exceptions::raise_error(format!("number->string: expected a number, got {}", n));
+    }
+
+    let s = match Number::from_object(&n) {
+        Number::Float(f) if radix == 10 => format_float(f),
+        Number::Float(f) if radix == 16 => format_hex_float(f),
+        Number::Float(_) => exceptions::raise_error(format!(
+            "number->string: floats are written in radix 10 or 16, not {}",
+            radix
+        )),
+        integer => integer.to_bigint().to_string_radix(radix),
+    };
+
+    string_to_object(s)
+}
+
+unsafe extern "C" fn native_number_to_string_invoke(
+    _: *const Function,
+    n: u64,
+    number: Object,
+    mut args: ...
+) -> Object {
+    let args = va_list_to_obj_array(n, args.as_va_list());
+    let mut args = va_args_iter(n, args);
+    let radix = args.next();
+
+    if args.next().is_some() {
+        exceptions::raise_error("number->string: expected at most a radix".to_string());
+    }
+
+    number_to_string(number, radix)
+}
+
+unsafe extern "C" fn native_number_to_string_apply(_: *const Function, args: ListLike) -> Object {
+    if args.len() > 2 {
+        exceptions::raise_error("number->string: expected at most a radix".to_string());
+    }
+
+    number_to_string(args.car(), list_iter(args.cdr()).next())
+}
+
+// In radix 10 a string is a number when the reader would read it as one,
+// hex floats included, in other radixes it's an integer. Nil otherwise.
+unsafe fn string_to_number(s: Object, radix: Option<Object>) -> Object {
+    let radix = radix_arg("string->number", radix);
+
+    if s.ty != ObjType::String {
+        exceptions::raise_error(format!("string->number: expected a string, got {}", s));
+    }
+
+    let s = unpack_str(&s);
+    let number = if radix == 10 {
+        escapes::parse_number(s)
+    } else {
+        BigInt::parse_radix(s, radix).map(ParsedNumber::BigInt)
+    };
+
+    match number {
+        Some(ParsedNumber::Int(i)) => Object::from_int(i),
+        Some(ParsedNumber::BigInt(i)) => Number::from_bigint(i).into_object(),
+        Some(ParsedNumber::Float(f)) => Object::from_float(f),
+        None => Object::nil(),
+    }
+}
+
+unsafe extern "C" fn native_string_to_number_invoke(
+    _: *const Function,
+    n: u64,
+    s: Object,
+    mut args: ...
+) -> Object {
+    let args = va_list_to_obj_array(n, args.as_va_list());
+    let mut args = va_args_iter(n, args);
+    let radix = args.next();
+
+    if args.next().is_some() {
+        exceptions::raise_error("string->number: expected at most a radix".to_string());
+    }
+
+    string_to_number(s, radix)
+}
+
+unsafe extern "C" fn native_string_to_number_apply(_: *const Function, args: ListLike) -> Object {
+    if args.len() > 2 {
+        exceptions::raise_error("string->number: expected at most a radix".to_string());
+    }
+
+    string_to_number(args.car(), list_iter(args.cdr()).next())
+}
+
+#[trivial_apply]
+extern "C" fn native_equal_invoke(_: *const Function, x: Object, y: Object) -> Object {
+    if x == y {
+        x
+    } else {
+        Object::nil()
+    }
+}
+
+static NAMESPACE_CONFLICTS: AtomicU64 = AtomicU64::new(0);
+static NAMESPACE_CONFLICTS_ARE_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Makes redefining a function as a macro (or a macro as a function) an
+/// error instead of a warning. Used for AOT compilation, where call sites
+/// compiled before the redefinition would disagree with the ones after it.
+pub fn set_namespace_conflicts_are_errors(are_errors: bool) {
+    NAMESPACE_CONFLICTS_ARE_ERRORS.store(are_errors, atomic::Ordering::SeqCst);
+}
+
+pub fn namespace_conflicts() -> u64 {
+    NAMESPACE_CONFLICTS.load(atomic::Ordering::SeqCst)
+}
+
+// Where each symbol's function was last set, by symbol. Only known for
+// definitions made by code compiled with source locations.
+static DEFINITION_SITES: Mutex<Vec<(usize, SourceLocation)>> = Mutex::new(Vec::new());
+
+fn definition_sites() -> MutexGuard<'static, Vec<(usize, SourceLocation)>> {
+    DEFINITION_SITES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// records where sym's function is being set, returning where it was set before
+fn record_definition_site(sym: *mut Symbol) -> Option<SourceLocation> {
+    let mut sites = definition_sites();
+    let i = sites.iter().position(|(s, _)| *s == sym as usize);
+    let old = i.map(|i| sites.remove(i).1);
+
+    if let Some(site) = source_locations::source_location() {
+        sites.push((sym as usize, site));
+    }
+
+    old
+}
+
+fn describe_site(site: &Option<SourceLocation>) -> String {
+    match site {
+        Some(site) => format!("{}:{}", site.file, site.line),
+        None => "an unknown location".to_string(),
+    }
+}
+
+unsafe fn check_namespace_conflict(sym: *mut Symbol, new_func: *mut Function) {
+    let old_site = record_definition_site(sym);
+
+    let old_func = (*sym).function;
+    if old_func.is_null() || (*old_func).is_macro == (*new_func).is_macro {
+        return;
+    }
+
+    let name = CStr::from_ptr((*sym).name).to_str().unwrap();
+    let msg = if (*new_func).is_macro {
+        format!(
+            "function {} redefined as a macro, call sites compiled earlier still call the function",
+            name
+        )
+    } else {
+        format!(
+            "macro {} redefined as a function, call sites expanded earlier keep the macro expansion",
+            name
+        )
+    };
+    let msg = format!(
+        "{} (defined at {}, redefined at {})",
+        msg,
+        describe_site(&old_site),
+        describe_site(&source_locations::source_location())
+    );
+
+    NAMESPACE_CONFLICTS.fetch_add(1, atomic::Ordering::SeqCst);
+
+    if NAMESPACE_CONFLICTS_ARE_ERRORS.load(atomic::Ordering::SeqCst) {
+        exceptions::raise_error(msg);
+    } else {
+        eprintln!("warning: {}", msg);
+    }
+}
+
+#[trivial_apply]
+extern "C" fn native_set_fn_invoke(_: *const Function, sym: Object, func: Object) -> Object {
+    let sym = sym.unpack_symbol();
+    let func = func.unpack_function();
+
+    unsafe {
+        check_namespace_conflict(sym, func);
+        (*sym).function = func;
+    }
+
+    Object::nil()
+}
+
+// Function objects whose calls the compiler may evaluate at compile time.
+// Objects rather than names are recorded, so redefining a name makes calls
+// to it opaque again.
+static PURE_FUNCTIONS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+fn pure_functions() -> MutexGuard<'static, Vec<usize>> {
+    PURE_FUNCTIONS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn is_pure_function(f: *const Function) -> bool {
+    pure_functions().contains(&(f as usize))
+}
+
+unsafe fn declare_pure(sym: *mut Symbol) {
+    let f = (*sym).function;
+    if f.is_null() {
+        let name = CStr::from_ptr((*sym).name).to_str().unwrap();
+        exceptions::raise_error(format!("declare-pure: undefined function {}", name));
+    }
+
+    pure_functions().push(f as usize);
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_declare_pure_invoke(_: *const Function, sym: Object) -> Object {
+    declare_pure(sym.unpack_symbol());
+    Object::nil()
+}
+
+#[trivial_apply]
+extern "C" fn native_cons_invoke(_: *const Function, x: Object, y: Object) -> Object {
+    Object::from_cons(cons_to_heap(Cons::new(x, y)))
+}
+
+#[trivial_apply]
+extern "C" fn native_rest_invoke(_: *const Function, list_like: Object) -> Object {
+    list_like.unpack_list_like().cdr_as_object()
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_first_invoke(_: *const Function, list_like: Object) -> Object {
+    list_like.unpack_list_like().car()
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_set_first_invoke(
+    _: *const Function,
+    cons: Object,
+    x: Object,
+) -> Object {
+    (*cons.unpack_cons()).car = to_heap(x.clone());
+    x
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_set_rest_invoke(_: *const Function, cons: Object, x: Object) -> Object {
+    (*cons.unpack_cons()).cdr = to_heap(x.clone());
+    x
+}
+
+static GENSYM_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// symbols are always interned, so uniqueness comes from a prefix the reader
+// doesn't produce in ordinary code
+extern "C" fn native_gensym_invoke(_: *const Function) -> Object {
+    let i = GENSYM_COUNTER.fetch_add(1, atomic::Ordering::SeqCst);
+    Object::from_symbol(symbols::get_or_intern_symbol(format!("--gensym-{}", i)))
+}
+
+unsafe extern "C" fn native_gensym_apply(f: *const Function, _: ListLike) -> Object {
+    native_gensym_invoke(f)
+}
+
+unsafe fn all_symbols(prefix: Option<Object>) -> Object {
+    let prefix = prefix.as_ref().map_or("", |prefix| unpack_str(prefix));
+
+    symbols::interned_symbols_with_prefix(prefix)
+        .into_iter()
+        .rev()
+        .fold(ListLike::from_nil(), |acc, sym| {
+            acc.cons(Object::from_symbol(sym))
+        })
+        .to_object()
+}
+
+unsafe extern "C" fn native_all_symbols_invoke(
+    _: *const Function,
+    n: u64,
+    mut args: ...
+) -> Object {
+    let args = va_list_to_obj_array(n, args.as_va_list());
+    let mut args = va_args_iter(n, args);
+    let prefix = args.next();
+
+    if args.next().is_some() {
+        exceptions::raise_error("all-symbols: expected at most one prefix".to_string());
+    }
+
+    all_symbols(prefix)
+}
+
+unsafe extern "C" fn native_all_symbols_apply(_: *const Function, args: ListLike) -> Object {
+    if args.len() > 1 {
+        exceptions::raise_error("all-symbols: expected at most one prefix".to_string());
+    }
+
+    all_symbols(list_iter(args).next())
+}
+
+/// Parses up to `limit` forms of a string into data, the error message says
+/// where in the string parsing failed.
+pub type ReadFn = fn(src: &str, limit: usize) -> Result<Vec<Object>, String>;
+
+// the reader is part of the compiler, so compiled binaries don't have one
+static READER: Mutex<Option<ReadFn>> = Mutex::new(None);
+
+/// Sets the parser behind `read` and `read-all`.
+pub fn set_reader(read: ReadFn) {
+    *READER.lock().unwrap_or_else(|e| e.into_inner()) = Some(read);
+}
+
+unsafe fn read_forms(fn_name: &str, s: Object, limit: usize) -> Vec<Object> {
+    let s = unpack_str(&s);
+    let reader = *READER.lock().unwrap_or_else(|e| e.into_inner());
+
+    let read = match reader {
+        Some(read) => read,
+        None => exceptions::raise_error(format!("{}: no reader in compiled programs", fn_name)),
+    };
+
+    match read(s, limit) {
+        Ok(forms) => forms,
+        Err(msg) => exceptions::raise_error(format!("{}: {}", fn_name, msg)),
+    }
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_read_invoke(_: *const Function, s: Object) -> Object {
+    match read_forms("read", s, 1).pop() {
+        Some(form) => form,
+        None => exceptions::raise_error("read: no form in string".to_string()),
+    }
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_read_all_invoke(_: *const Function, s: Object) -> Object {
+    read_forms("read-all", s, usize::max_value())
+        .into_iter()
+        .rev()
+        .fold(ListLike::from_nil(), |acc, form| acc.cons(form))
+        .to_object()
+}
+
+pub const INTERNAL_TIME_UNITS_PER_SECOND: i64 = 1_000_000;
+
+// real time is counted from runtime initialization
+static START_TIME: Mutex<Option<Instant>> = Mutex::new(None);
+
+fn start_time() -> Instant {
+    let mut start = START_TIME.lock().unwrap_or_else(|e| e.into_inner());
+    *start.get_or_insert_with(Instant::now)
+}
+
+extern "C" fn native_get_internal_real_time_invoke(_: *const Function) -> Object {
+    Object::from_int(start_time().elapsed().as_micros() as i64)
+}
+
+unsafe extern "C" fn native_get_internal_real_time_apply(
+    f: *const Function,
+    _: ListLike,
+) -> Object {
+    native_get_internal_real_time_invoke(f)
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_sleep_invoke(_: *const Function, seconds: Object) -> Object {
+    let seconds = if seconds.ty == ObjType::Float64 {
+        seconds.unpack_float()
+    } else {
+        seconds.unpack_int() as f64
+    };
+
+    // negative, NaN and too long for a Duration
+    let duration = match Duration::try_from_secs_f64(seconds) {
+        Ok(duration) => duration,
+        Err(_) => {
+            exceptions::raise_error(format!("sleep: invalid duration {}", format_float(seconds)))
+        }
+    };
+
+    thread::sleep(duration);
+    Object::nil()
+}
+
+// splitmix64, the state only ever advances by a constant, so it can be an
+// atomic shared by all threads
+const RANDOM_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+static RANDOM_STATE: AtomicU64 = AtomicU64::new(0);
+
+fn next_random() -> u64 {
+    let mut z = RANDOM_STATE
+        .fetch_add(RANDOM_GAMMA, atomic::Ordering::SeqCst)
+        .wrapping_add(RANDOM_GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn seed_random(seed: u64) {
+    RANDOM_STATE.store(seed, atomic::Ordering::SeqCst);
+}
+
+#[trivial_apply]
+extern "C" fn native_random_invoke(_: *const Function, bound: Object) -> Object {
+    let bound = bound.unpack_int();
+    if bound <= 0 {
+        unsafe {
+            exceptions::raise_error(format!("random: bound must be positive, got {}", bound))
+        };
+    }
+
+    // rejects the top values that would make the lower results more likely
+    let bound = bound as u64;
+    let zone = u64::max_value() - u64::max_value() % bound;
+    loop {
+        let r = next_random();
+        if r < zone {
+            return Object::from_int((r % bound) as i64);
+        }
+    }
+}
+
+#[trivial_apply]
+extern "C" fn native_srand_invoke(_: *const Function, seed: Object) -> Object {
+    seed_random(seed.unpack_int() as u64);
+    Object::nil()
+}
+
+pub(crate) unsafe fn apply_to_list_like(f: *const Function, args: ListLike) -> Object {
+    checks::check_function("apply_to_list_like", f);
+
+    let len = args.len();
+    if !unlisp_rt_check_arity(f, len) {
+        exceptions::unlisp_rt_raise_arity_error((*f).name, (*f).arg_count, len);
+    }
+
+    let apply_fn: unsafe extern "C" fn(*const Function, ListLike) -> Object =
+        mem::transmute((*f).apply_to_f_ptr);
+    apply_fn(f, args)
+}
+
+unsafe fn raise_apply_without_list() -> ! {
+    exceptions::raise_error("wrong number of arguments (1) passed to apply".to_string())
+}
+
+// checks apply's last argument itself, so a missing list isn't reported as
+// a generic cast error and the tail of a dotted one isn't dropped
+unsafe fn apply_args_tail(f: *const Function, last_arg: &Object) -> ListLike {
+    let last_arg = if last_arg.ty == ObjType::Box {
+        last_arg.unpack_underlying()
+    } else {
+        last_arg.clone()
+    };
+
+    let f_name = || CStr::from_ptr((*f).name).to_str().unwrap();
+
+    if last_arg.ty != ObjType::Cons && !last_arg.is_nil() {
+        exceptions::raise_error(format!(
+            "apply: last argument must be a list, got {} (applying {})",
+            last_arg.ty,
+            f_name()
+        ))
+    }
+
+    let mut cur = last_arg.clone();
+    while cur.ty == ObjType::Cons {
+        cur = (*cur.unpack_cons()).cdr();
+    }
+
+    if !cur.is_nil() {
+        exceptions::raise_error(format!(
+            "apply: last argument must be a proper list, got {} (applying {})",
+            last_arg,
+            f_name()
+        ))
+    }
+
+    last_arg.unpack_list_like()
+}
+
+// Calls f with the leading arguments followed by the elements of tail. When
+// they fit an invoke call they're copied to the stack and nothing is
+// allocated, the list apply functions take is only built for more.
+unsafe fn apply_spread(f: *const Function, leading: &[Object], tail: ListLike) -> Object {
+    checks::check_function("apply_spread", f);
+
+    let len = leading.len() as u64 + tail.len();
+    if len as usize > MAX_INVOKE_ARGS {
+        let args = leading
+            .iter()
+            .rev()
+            .fold(tail, |acc, arg| acc.cons(arg.clone()));
+        return apply_to_list_like(f, args);
+    }
+
+    if !unlisp_rt_check_arity(f, len) {
+        exceptions::unlisp_rt_raise_arity_error((*f).name, (*f).arg_count, len);
+    }
+
+    let mut args = InvokeArgs::uninit();
+    let args_ptr = args.as_mut_ptr() as *mut Object;
+
+    for (i, arg) in leading.iter().enumerate() {
+        args_ptr.add(i).write(arg.clone());
+    }
+
+    // only the cons cells are elements, like tail.len() counts them
+    let mut cur = tail.to_object();
+    for i in leading.len()..len as usize {
+        let cons = cur.unpack_cons();
+        args_ptr.add(i).write((*cons).car());
+        cur = (*cons).cdr();
+    }
+
+    invoke_args_array(f, invoke_args_slice(&mut args, len as usize))
+}
+
+unsafe extern "C" fn native_apply_invoke(
+    _: *const Function,
+    n: u64,
+    f: Object,
+    mut args: ...
+) -> Object {
+    let f = f.unpack_function();
+    if n == 0 {
+        raise_apply_without_list();
+    }
+
+    // few arguments are read to the stack rather than a malloc'ed array
+    let mut stack_args = InvokeArgs::uninit();
+    let args_arr = if n as usize <= MAX_INVOKE_ARGS {
+        let stack_ptr = stack_args.as_mut_ptr() as *mut Object;
+        va_list_into_obj_array(n, args.as_va_list(), stack_ptr);
+        invoke_args_slice(&mut stack_args, n as usize)
+    } else {
+        slice::from_raw_parts(va_list_to_obj_array(n, args.as_va_list()), n as usize)
+    };
+
+    let (last_arg, leading) = args_arr.split_last().unwrap();
+    apply_spread(f, leading, apply_args_tail(f, last_arg))
+}
+
+unsafe extern "C" fn native_apply_apply(_: *const Function, args: ListLike) -> Object {
+    let f = args.car().unpack_function();
+
+    let mut leading = vec![];
+    let mut f_args = args.cdr();
+
+    if f_args.is_nil() {
+        raise_apply_without_list();
+    }
+
+    loop {
+        let f_cdr = f_args.cdr();
+        if f_cdr.is_nil() {
+            break;
+        }
+        leading.push(f_args.car());
+        f_args = f_cdr;
+    }
+
+    apply_spread(f, &leading, apply_args_tail(f, &f_args.car()))
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_call_ignoring_errors_invoke(_: *const Function, f: Object) -> Object {
+    let f = f.unpack_function();
+
+    exceptions::run_with_global_ex_handler(|| apply_to_list_like(f, ListLike::from_nil()))
+        .unwrap_or_else(|_| {
+            // the interrupt goes on to the code evaluating
+            interrupt::unlisp_rt_check_interrupt();
+            Object::nil()
+        })
+}
+
+// f is the body of a block, called with the tag return-from-block exits to
+#[trivial_apply]
+unsafe extern "C" fn native_call_with_block_invoke(_: *const Function, f: Object) -> Object {
+    let f = f.unpack_function();
+
+    exceptions::run_block(|tag| {
+        apply_spread(f, &[Object::from_int(tag as i64)], ListLike::from_nil())
+    })
+}
+
+unsafe extern "C" fn native_return_from_block_invoke(
+    _: *const Function,
+    tag: Object,
+    value: Object,
+) -> ! {
+    exceptions::exit_block(tag.unpack_int() as u64, value)
+}
+
+unsafe extern "C" fn native_return_from_block_apply(_: *const Function, args: ListLike) -> ! {
+    exceptions::exit_block(args.car().unpack_int() as u64, args.cdr().car())
+}
+
+// handler is called with the message of every error raised in f, see
+// exceptions::run_with_error_handler
+#[trivial_apply]
+unsafe extern "C" fn native_call_with_error_handler_invoke(
+    _: *const Function,
+    handler: Object,
+    f: Object,
+) -> Object {
+    let handler = handler.unpack_function();
+    let f = f.unpack_function();
+
+    exceptions::run_with_error_handler(
+        Rc::new(move |msg: &str| {
+            let args = ListLike::from_nil().cons(string_to_object(msg.to_string()));
+            apply_to_list_like(handler, args);
+        }),
+        || apply_to_list_like(f, ListLike::from_nil()),
+    )
+}
+
+// f is the form of a restart-case, handlers are the bodies of the restarts
+// named by names, in the same order
+#[trivial_apply]
+unsafe extern "C" fn native_call_with_restarts_invoke(
+    _: *const Function,
+    names: Object,
+    handlers: Object,
+    f: Object,
+) -> Object {
+    let f = f.unpack_function();
+    let names: Vec<_> = list_iter(names.unpack_list_like())
+        .map(|name| name.unpack_symbol())
+        .collect();
+    let handlers: Vec<_> = list_iter(handlers.unpack_list_like()).collect();
+
+    if names.len() != handlers.len() {
+        exceptions::raise_error(format!(
+            "call-with-restarts: {} restart names for {} handlers",
+            names.len(),
+            handlers.len()
+        ));
+    }
+
+    match exceptions::run_with_restarts(&names, || apply_to_list_like(f, ListLike::from_nil())) {
+        Ok(value) => value,
+        Err((index, args)) => {
+            apply_to_list_like(handlers[index].unpack_function(), args.unpack_list_like())
+        }
+    }
+}
+
+unsafe extern "C" fn native_invoke_restart_invoke(
+    _: *const Function,
+    n: u64,
+    name: Object,
+    mut args: ...
+) -> ! {
+    let args_arr = va_list_to_obj_array(n, args.as_va_list());
+    let args = obj_array_to_list_like(n, args_arr, ListLike::from_nil());
+
+    exceptions::invoke_restart(name.unpack_symbol(), args.to_object())
+}
+
+unsafe extern "C" fn native_invoke_restart_apply(_: *const Function, args: ListLike) -> ! {
+    exceptions::invoke_restart(args.car().unpack_symbol(), args.cdr().to_object())
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_compute_restarts_invoke(_: *const Function) -> Object {
+    exceptions::active_restarts()
+        .into_iter()
+        .rev()
+        .fold(ListLike::from_nil(), |acc, name| {
+            acc.cons(Object::from_symbol(name))
+        })
+        .to_object()
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_register_test_invoke(
+    _: *const Function,
+    name: Object,
+    f: Object,
+) -> Object {
+    let name = CStr::from_ptr((*name.unpack_symbol()).name)
+        .to_string_lossy()
+        .into_owned();
+
+    test_registry::register_test(name, f.unpack_function());
+
+    Object::nil()
+}
+
+unsafe extern "C" fn native_run_tests_invoke(_: *const Function) -> Object {
+    let outcomes = test_registry::run_registered_tests();
+    let mut passed = 0;
+
+    for outcome in outcomes.iter() {
+        match &outcome.error {
+            None => {
+                passed += 1;
+                write_stdout_or_raise(format_args!("PASS {}\n", outcome.name));
+            }
+            Some(e) => write_stdout_or_raise(format_args!("FAIL {}: {}\n", outcome.name, e)),
+        }
+    }
+
+    write_stdout_or_raise(format_args!("{}/{} tests passed\n", passed, outcomes.len()));
+
+    ListLike::from_nil()
+        .cons(Object::from_count(outcomes.len() as u64))
+        .cons(Object::from_int(passed))
+        .to_object()
+}
+
+unsafe extern "C" fn native_run_tests_apply(f: *const Function, _: ListLike) -> Object {
+    native_run_tests_invoke(f)
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_symbol_fn_invoke(_: *const Function, sym: Object) -> Object {
+    let sym = sym.unpack_symbol();
+    let f = unlisp_rt_symbol_function(sym);
+    Object::from_function(f)
+}
+
+#[trivial_apply]
+extern "C" fn native_set_compiler_macro_invoke(
+    _: *const Function,
+    sym: Object,
+    func: Object,
+) -> Object {
+    symbols::set_compiler_macro(sym.unpack_symbol(), func.unpack_function());
+
+    Object::nil()
+}
+
+#[trivial_apply]
+extern "C" fn native_compiler_macro_fn_invoke(_: *const Function, sym: Object) -> Object {
+    let f = symbols::compiler_macro(sym.unpack_symbol());
+
+    if f.is_null() {
+        Object::nil()
+    } else {
+        Object::from_function(f)
+    }
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_set_macro_invoke(_: *const Function, f: Object) -> Object {
+    let f = f.unpack_function();
+
+    (*f).is_macro = true;
+
+    Object::nil()
+}
+
+// Stands in for a function whose definition failed to compile, it has the
+// name of that definition and takes any arguments.
+unsafe extern "C" fn native_failed_definition_invoke(f: *const Function, _: u64, _args: ...) -> ! {
+    native_failed_definition_apply(f, ListLike::from_nil())
+}
+
+unsafe extern "C" fn native_failed_definition_apply(f: *const Function, _: ListLike) -> ! {
+    let name = CStr::from_ptr((*f).name).to_string_lossy();
+    exceptions::raise_error(format!("definition of {} failed to compile", name))
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_set_definition_failed_invoke(
+    _: *const Function,
+    sym: Object,
+) -> Object {
+    let sym = sym.unpack_symbol();
+
+    let func = Function {
+        ty: FunctionType::Function,
+        name: (*sym).name,
+        arglist: arr_to_raw(&[]),
+        arg_count: 0,
+        is_macro: false,
+        invoke_f_ptr: native_failed_definition_invoke as *const c_void,
+        apply_to_f_ptr: native_failed_definition_apply as *const c_void,
+        has_restarg: true,
+    };
+
+    (*sym).function = to_heap(func);
+
+    Object::nil()
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_listp_invoke(_: *const Function, x: Object) -> Object {
+    if x.is_nil() || x.ty == ObjType::Cons {
+        Object::t()
+    } else {
+        Object::nil()
+    }
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_symbolp_invoke(_: *const Function, x: Object) -> Object {
+    if x.ty == ObjType::Symbol {
+        Object::t()
+    } else {
+        Object::nil()
+    }
+}
+
+// The designators cover the types of objects, `integer` includes big
+// integers, `list` nil and conses alike. None for unknown designators.
+fn type_matches(x: &Object, designator: &str) -> Option<bool> {
+    let matches = match designator {
+        "integer" => x.ty == ObjType::Int64 || x.ty == ObjType::BigInt,
+        "float" => x.ty == ObjType::Float64,
+        "number" => [ObjType::Int64, ObjType::BigInt, ObjType::Float64].contains(&x.ty),
+        "char" => x.ty == ObjType::Char,
+        "string" => x.ty == ObjType::String,
+        "symbol" => x.ty == ObjType::Symbol,
+        "function" => x.ty == ObjType::Function,
+        "list" => x.ty == ObjType::Cons || x.is_nil(),
+        "cons" => x.ty == ObjType::Cons,
+        "null" => x.is_nil(),
+        "record" => x.ty == ObjType::Record,
+        "stream" => x.ty == ObjType::Stream,
+        _ => return None,
+    };
+
+    Some(matches)
+}
+
+fn unboxed(x: Object) -> Object {
+    if x.ty == ObjType::Box {
+        x.unpack_underlying()
+    } else {
+        x
+    }
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_typep_invoke(_: *const Function, x: Object, ty: Object) -> Object {
+    let designator = CStr::from_ptr((*ty.unpack_symbol()).name).to_string_lossy();
+
+    match type_matches(&unboxed(x), &designator) {
+        Some(true) => Object::t(),
+        Some(false) => Object::nil(),
+        None => exceptions::raise_error(format!("typep: unknown type designator {}", designator)),
+    }
+}
+
+// Objects already of the type are returned as they are, the conversions are
+// a char to its code, an integer to a float and a string to a list of its
+// chars.
+#[trivial_apply]
+unsafe extern "C" fn native_coerce_invoke(_: *const Function, x: Object, ty: Object) -> Object {
+    let x = unboxed(x);
+    let designator = CStr::from_ptr((*ty.unpack_symbol()).name).to_string_lossy();
+
+    match type_matches(&x, &designator) {
+        Some(true) => return x,
+        Some(false) => (),
+        None => exceptions::raise_error(format!("coerce: unknown type designator {}", designator)),
+    }
+
+    match (&x.ty, designator.as_ref()) {
+        (ObjType::Char, "integer") | (ObjType::Char, "number") => {
+            Object::from_int(i64::from(u32::from(x.unpack_char())))
+        }
+        (ObjType::Int64, "float") | (ObjType::BigInt, "float") => {
+            Object::from_float(Number::from_object(&x).to_float())
+        }
+        (ObjType::String, "list") => unpack_str(&x)
+            .chars()
+            .rev()
+            .fold(ListLike::from_nil(), |acc, c| {
+                acc.cons(Object::from_char(c))
+            })
+            .to_object(),
+        _ => exceptions::raise_error(format!("coerce: cannot coerce {} to {}", x.ty, designator)),
+    }
+}
+
+unsafe fn make_record(ty: Object, slots: impl Iterator<Item = Object>) -> Object {
+    let slots: Vec<_> = slots.collect();
+    stats::record_bytes(slots.len() * mem::size_of::<Object>());
+
+    Object::from_record(to_heap(Record {
+        ty: ty.unpack_symbol(),
+        slots,
+    }))
+}
+
+unsafe extern "C" fn native_make_record_invoke(
+    _: *const Function,
+    n: u64,
+    ty: Object,
+    mut slots: ...
+) -> Object {
+    let slots = va_list_to_obj_array(n, slots.as_va_list());
+    make_record(ty, va_args_iter(n, slots))
+}
+
+unsafe extern "C" fn native_make_record_apply(_: *const Function, args: ListLike) -> Object {
+    make_record(args.car(), list_iter(args.cdr()))
+}
+
+unsafe fn record_type(x: &Object) -> Option<*mut Symbol> {
+    let x = if x.ty == ObjType::Box {
+        x.unpack_underlying()
+    } else {
+        x.clone()
+    };
+
+    if x.ty == ObjType::Record {
+        Some((*x.unpack_record()).ty)
+    } else {
+        None
+    }
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_record_type_invoke(_: *const Function, x: Object) -> Object {
+    record_type(&x).map_or_else(Object::nil, Object::from_symbol)
+}
+
+// the slot of a record of type ty, accessors of one structure don't work on
+// instances of another
+unsafe fn record_slot(record: &Object, ty: &Object, i: &Object) -> *mut Object {
+    let ty = ty.unpack_symbol();
+    let type_name = CStr::from_ptr((*ty).name).to_string_lossy();
+
+    if record_type(record) != Some(ty) {
+        exceptions::raise_error(format!("expected a {}, got {}", type_name, record))
+    }
+
+    let record = record.unpack_record();
+    let i = i.unpack_int();
+    if i < 0 || i as usize >= (*record).slots.len() {
+        exceptions::raise_error(format!("{} has no slot {}", type_name, i))
+    }
+
+    &mut (*record).slots[i as usize]
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_record_ref_invoke(
+    _: *const Function,
+    record: Object,
+    ty: Object,
+    i: Object,
+) -> Object {
+    (*record_slot(&record, &ty, &i)).clone()
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_record_set_invoke(
+    _: *const Function,
+    record: Object,
+    ty: Object,
+    i: Object,
+    val: Object,
+) -> Object {
+    *record_slot(&record, &ty, &i) = val.clone();
+    val
+}
+
+// nil for f removes the hook
+#[trivial_apply]
+unsafe extern "C" fn native_set_print_hook_invoke(
+    _: *const Function,
+    ty: Object,
+    f: Object,
+) -> Object {
+    let f = if f.is_nil() {
+        None
+    } else {
+        Some(f.unpack_function())
+    };
+    print_hooks::set_print_hook(ty.unpack_symbol(), f);
+
+    ty
+}
+
+// returns the setting it replaces, so it can be restored
+#[trivial_apply]
+unsafe extern "C" fn native_set_print_shared_invoke(_: *const Function, enabled: Object) -> Object {
+    let was_enabled = print_shared::print_shared();
+    print_shared::set_print_shared(!enabled.is_nil());
+
+    if was_enabled {
+        Object::t()
+    } else {
+        Object::nil()
+    }
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_spawn_thread_invoke(_: *const Function, f: Object) -> Object {
+    threads::spawn_thread(f.unpack_function())
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_join_thread_invoke(_: *const Function, thread: Object) -> Object {
+    threads::join_thread(&thread)
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_make_channel_invoke(_: *const Function) -> Object {
+    threads::make_channel()
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_send_invoke(
+    _: *const Function,
+    channel: Object,
+    value: Object,
+) -> Object {
+    threads::send(&channel, value.clone());
+
+    value
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_recv_invoke(_: *const Function, channel: Object) -> Object {
+    threads::recv(&channel)
+}
+
+// f is the protected form of unwind-protect, cleanup runs however f exits
+#[trivial_apply]
+unsafe extern "C" fn native_call_with_cleanup_invoke(
+    _: *const Function,
+    f: Object,
+    cleanup: Object,
+) -> Object {
+    let f = f.unpack_function();
+    let cleanup = cleanup.unpack_function();
+
+    // the cleanup runs to the end also when f was interrupted
+    exceptions::run_with_cleanup(
+        || apply_to_list_like(f, ListLike::from_nil()),
+        || {
+            interrupt::run_masked(|| apply_to_list_like(cleanup, ListLike::from_nil()));
+        },
+    )
+}
+
+unsafe extern "C" fn native_values_invoke(_: *const Function, n: u64, mut args: ...) -> Object {
+    let args_arr = va_list_to_obj_array(n, args.as_va_list());
+    values::set_values(obj_array_to_list_like(n, args_arr, ListLike::from_nil()))
+}
+
+unsafe extern "C" fn native_values_apply(_: *const Function, args: ListLike) -> Object {
+    values::set_values(args)
+}
+
+// the values f returns as a list, what multiple-value-list expands to
+#[trivial_apply]
+unsafe extern "C" fn native_call_collecting_values_invoke(_: *const Function, f: Object) -> Object {
+    let f = f.unpack_function();
+
+    values::clear_values();
+    let result = apply_to_list_like(f, ListLike::from_nil());
+
+    values::take_values(result).to_object()
+}
+
+unsafe fn open_with_options(path: Object, options: ListLike) -> Object {
+    let mut direction = Direction::Input;
+    let mut options = list_iter(options);
+
+    while let Some(key) = options.next() {
+        let value = match options.next() {
+            Some(value) => value,
+            None => exceptions::raise_error(format!("open: no value for option {}", key)),
+        };
+
+        if key.ty != ObjType::Symbol
+            || CStr::from_ptr((*key.unpack_symbol()).name).to_bytes() != b":direction"
+        {
+            exceptions::raise_error(format!("open: unknown option {}", key));
+        }
+
+        direction = match CStr::from_ptr((*value.unpack_symbol()).name).to_bytes() {
+            b":input" => Direction::Input,
+            b":output" => Direction::Output,
+            _ => exceptions::raise_error(format!(
+                "open: direction must be :input or :output, got {}",
+                value
+            )),
+        };
     }
 
-    let apply_fn: unsafe extern "C" fn(*const Function, ListLike) -> Object =
-        mem::transmute((*f).apply_to_f_ptr);
-    apply_fn(f, args)
+    let path = unpack_str(&path);
+
+    match Stream::open(path, direction) {
+        Ok(stream) => Object::from_stream(to_heap(stream)),
+        Err(e) => exceptions::raise_error(format!("open: can't open {:?}: {}", path, e)),
+    }
 }
 
-unsafe extern "C" fn native_apply_invoke(
+unsafe extern "C" fn native_open_invoke(
     _: *const Function,
     n: u64,
-    f: Object,
+    path: Object,
     mut args: ...
 ) -> Object {
-    let f = f.unpack_function();
     let args_arr = va_list_to_obj_array(n, args.as_va_list());
-    let last_arg = (*args_arr.offset((n as isize) - 1)).unpack_list_like();
-    let args = obj_array_to_list_like(n - 1, args_arr, last_arg);
+    let options = obj_array_to_list_like(n, args_arr, ListLike::from_nil());
 
-    apply_to_list_like(f, args)
+    open_with_options(path, options)
 }
 
-unsafe extern "C" fn native_apply_apply(_: *const Function, args: ListLike) -> Object {
-    let f = args.car().unpack_function();
-
-    let mut to_cons = vec![];
-    let mut f_args = args.cdr();
+unsafe extern "C" fn native_open_apply(_: *const Function, args: ListLike) -> Object {
+    open_with_options(args.car(), args.cdr())
+}
 
-    loop {
-        let f_cdr = f_args.cdr();
-        if f_cdr.is_nil() {
-            break;
-        }
-        to_cons.push(f_args.car());
-        f_args = f_cdr;
+unsafe fn with_stream<R>(
+    fn_name: &str,
+    stream: &Object,
+    op: impl FnOnce(&mut Stream) -> Result<R, String>,
+) -> R {
+    match op(&mut *stream.unpack_stream()) {
+        Ok(result) => result,
+        Err(msg) => exceptions::raise_error(format!("{}: {}", fn_name, msg)),
     }
-
-    let cons_base = f_args.car().unpack_list_like();
-
-    let reconsed_args = to_cons
-        .into_iter()
-        .rev()
-        .fold(cons_base, |acc, item| acc.cons(item));
-
-    apply_to_list_like(f, reconsed_args)
 }
 
 #[trivial_apply]
-unsafe extern "C" fn native_symbol_fn_invoke(_: *const Function, sym: Object) -> Object {
-    let sym = sym.unpack_symbol();
-    let f = unlisp_rt_symbol_function(sym);
-    Object::from_function(f)
+unsafe extern "C" fn native_close_invoke(_: *const Function, stream: Object) -> Object {
+    with_stream("close", &stream, Stream::close);
+
+    Object::t()
 }
 
 #[trivial_apply]
-unsafe extern "C" fn native_set_macro_invoke(_: *const Function, f: Object) -> Object {
-    let f = f.unpack_function();
-
-    (*f).is_macro = true;
-
-    Object::nil()
+unsafe extern "C" fn native_read_char_invoke(_: *const Function, stream: Object) -> Object {
+    match with_stream("read-char", &stream, Stream::read_char) {
+        Some(c) => Object::from_char(c),
+        None => Object::nil(),
+    }
 }
 
 #[trivial_apply]
-unsafe extern "C" fn native_listp_invoke(_: *const Function, x: Object) -> Object {
-    if x.is_nil() || x.ty == ObjType::Cons {
-        Object::t()
-    } else {
-        Object::nil()
+unsafe extern "C" fn native_read_line_invoke(_: *const Function, stream: Object) -> Object {
+    match with_stream("read-line", &stream, Stream::read_line) {
+        Some(line) => string_to_object(line),
+        None => Object::nil(),
     }
 }
 
 #[trivial_apply]
-unsafe extern "C" fn native_symbolp_invoke(_: *const Function, x: Object) -> Object {
-    if x.ty == ObjType::Symbol {
-        Object::t()
-    } else {
-        Object::nil()
-    }
+unsafe extern "C" fn native_write_char_invoke(
+    _: *const Function,
+    c: Object,
+    stream: Object,
+) -> Object {
+    with_stream("write-char", &stream, |stream| {
+        stream.write_char(c.unpack_char())
+    });
+
+    c
 }
 
 pub unsafe fn call_macro(f: *mut Function, args: ListLike) -> Result<Object, RuntimeError> {
@@ -259,31 +1802,244 @@ unsafe extern "C" fn native_macroexpand_1_invoke(_: *const Function, form: Objec
     }
 }
 
+unsafe fn format_to_string(control: Object, args: ListLike) -> String {
+    let control = CStr::from_ptr(control.unpack_string()).to_str().unwrap();
+
+    let mut result = String::new();
+    let mut args = args;
+    let mut chars = control.chars();
+
+    let mut next_arg = |directive: char| {
+        if args.is_nil() {
+            exceptions::raise_error(format!("format: no argument for directive ~{}", directive))
+        }
+        let arg = args.car();
+        args = args.cdr();
+        arg
+    };
+
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('a') | Some('A') => {
+                let arg = next_arg('a');
+                result.push_str(&format!("{}", arg.printed(PrintMode::Aesthetically)));
+            }
+            Some('s') | Some('S') => result.push_str(&format!("{}", next_arg('s'))),
+            Some('d') | Some('D') => {
+                let arg = next_arg('d');
+                if arg.ty == ObjType::BigInt {
+                    result.push_str(&format!("{}", arg));
+                } else {
+                    result.push_str(&format!("{}", arg.unpack_int()));
+                }
+            }
+            Some('%') => result.push('\n'),
+            Some('~') => result.push('~'),
+            Some(d) => exceptions::raise_error(format!("format: unknown directive ~{}", d)),
+            None => exceptions::raise_error("format: control string ends with ~".to_string()),
+        }
+    }
+
+    result
+}
+
+fn string_to_object(s: String) -> Object {
+    stats::record_string(s.len() + 1);
+    Object::from_string(CString::new(s).unwrap().into_raw())
+}
+
+unsafe extern "C" fn native_room_invoke(_: *const Function) -> Object {
+    let entries = stats::stats().entries();
+
+    for (name, count) in entries.iter() {
+        write_stdout_or_raise(format_args!("{:>10} {:>12}\n", name, count));
+    }
+
+    entries
+        .iter()
+        .rev()
+        .fold(ListLike::from_nil(), |acc, (name, count)| {
+            let key = Object::from_symbol(symbols::get_or_intern_symbol(name.to_string()));
+            acc.cons(Object::from_cons(cons_to_heap(Cons::new(
+                key,
+                Object::from_count(*count),
+            ))))
+        })
+        .to_object()
+}
+
+unsafe extern "C" fn native_room_apply(f: *const Function, _: ListLike) -> Object {
+    native_room_invoke(f)
+}
+
+extern "C" fn native_reset_room_invoke(_: *const Function) -> Object {
+    stats::reset();
+    Object::nil()
+}
+
+unsafe extern "C" fn native_reset_room_apply(f: *const Function, _: ListLike) -> Object {
+    native_reset_room_invoke(f)
+}
+
+// There's no collector yet, so nothing is ever freed. Returns the number of
+// objects freed to keep the interface stable once one exists.
+extern "C" fn native_gc_invoke(_: *const Function) -> Object {
+    Object::from_int(0)
+}
+
+unsafe extern "C" fn native_gc_apply(f: *const Function, _: ListLike) -> Object {
+    native_gc_invoke(f)
+}
+
+// Formats to a string that's returned when the destination is nil, to stdout
+// when it's t and to a stream otherwise, the last two return nil. Without a
+// destination, when the first argument is the control string, the string is
+// returned too.
+unsafe fn format_to(destination: Object, args: ListLike) -> Object {
+    let destination = unboxed(destination);
+
+    if destination.ty == ObjType::String {
+        return string_to_object(format_to_string(destination, args));
+    }
+
+    if args.is_nil() {
+        exceptions::raise_error("format: no control string".to_string())
+    }
+
+    let formatted = format_to_string(args.car(), args.cdr());
+
+    if destination.is_nil() {
+        string_to_object(formatted)
+    } else if destination == Object::t() {
+        write_stdout_or_raise(format_args!("{}", formatted));
+        Object::nil()
+    } else if destination.ty == ObjType::Stream {
+        with_stream("format", &destination, |stream| {
+            stream.write_str(&formatted)
+        });
+        Object::nil()
+    } else {
+        exceptions::raise_error(format!(
+            "format: expected nil, t or a stream as the destination, got {}",
+            destination
+        ))
+    }
+}
+
+unsafe extern "C" fn native_format_invoke(
+    _: *const Function,
+    n: u64,
+    destination: Object,
+    mut args: ...
+) -> Object {
+    let args_arr = va_list_to_obj_array(n, args.as_va_list());
+    let args = obj_array_to_list_like(n, args_arr, ListLike::from_nil());
+
+    format_to(destination, args)
+}
+
+unsafe extern "C" fn native_format_apply(_: *const Function, args: ListLike) -> Object {
+    format_to(args.car(), args.cdr())
+}
+
+unsafe extern "C" fn native_error_invoke(
+    _: *const Function,
+    n: u64,
+    control: Object,
+    mut args: ...
+) -> ! {
+    let args_arr = va_list_to_obj_array(n, args.as_va_list());
+    let args = obj_array_to_list_like(n, args_arr, ListLike::from_nil());
+
+    exceptions::raise_error(format_to_string(control, args))
+}
+
+unsafe extern "C" fn native_error_apply(_: *const Function, args: ListLike) -> ! {
+    exceptions::raise_error(format_to_string(args.car(), args.cdr()))
+}
+
+unsafe extern "C" fn native_warn_invoke(
+    _: *const Function,
+    n: u64,
+    control: Object,
+    mut args: ...
+) -> Object {
+    let args_arr = va_list_to_obj_array(n, args.as_va_list());
+    let args = obj_array_to_list_like(n, args_arr, ListLike::from_nil());
+
+    eprintln!("warning: {}", format_to_string(control, args));
+    Object::nil()
+}
+
+unsafe extern "C" fn native_warn_apply(_: *const Function, args: ListLike) -> Object {
+    eprintln!("warning: {}", format_to_string(args.car(), args.cdr()));
+    Object::nil()
+}
+
+// the stdout lock is released before raising
+pub(crate) unsafe fn write_stdout_or_raise(args: fmt::Arguments) {
+    if let Err(e) = output::write_stdout(args) {
+        exceptions::raise_error(format!("{}", e));
+    }
+}
+
+#[trivial_apply]
+unsafe extern "C" fn native_prin1_invoke(_: *const Function, x: Object) -> Object {
+    write_stdout_or_raise(format_args!("{}", x));
+    x
+}
+
 #[trivial_apply]
-unsafe extern "C" fn native_error_invoke(_: *const Function, msg: Object) -> ! {
-    let s = msg.unpack_string();
-    let rust_str = CStr::from_ptr(s).to_str().unwrap().to_string();
-    exceptions::raise_error(rust_str)
+unsafe extern "C" fn native_princ_invoke(_: *const Function, x: Object) -> Object {
+    write_stdout_or_raise(format_args!("{}", x.printed(PrintMode::Aesthetically)));
+    x
 }
 
 #[trivial_apply]
 unsafe extern "C" fn native_print_invoke(_: *const Function, x: Object) -> Object {
-    print!("{}", x);
+    write_stdout_or_raise(format_args!("\n{}", x));
     x
 }
 
 #[trivial_apply]
 unsafe extern "C" fn native_println_invoke(_: *const Function, x: Object) -> Object {
-    println!("{}", x);
+    write_stdout_or_raise(format_args!("{}\n", x));
     x
 }
 
+unsafe extern "C" fn native_terpri_invoke(_: *const Function) -> Object {
+    write_stdout_or_raise(format_args!("\n"));
+    Object::nil()
+}
+
+unsafe extern "C" fn native_terpri_apply(f: *const Function, _: ListLike) -> Object {
+    native_terpri_invoke(f)
+}
+
+// t if a newline was written
+unsafe extern "C" fn native_fresh_line_invoke(_: *const Function) -> Object {
+    if output::stdout_column() == 0 {
+        return Object::nil();
+    }
+
+    write_stdout_or_raise(format_args!("\n"));
+    Object::t()
+}
+
+unsafe extern "C" fn native_fresh_line_apply(f: *const Function, _: ListLike) -> Object {
+    native_fresh_line_invoke(f)
+}
+
 #[trivial_apply]
 unsafe extern "C" fn native_stdout_write_invoke(_: *const Function, s: Object) -> Object {
     let s = s.unpack_string();
-    let rust_str = CStr::from_ptr(s).to_str().unwrap().to_string();
-    let _ = write!(std::io::stdout(), "{}", rust_str)
-        .map_err(|e| exceptions::raise_error(format!("{}", e)));
+    let rust_str = CStr::from_ptr(s).to_str().unwrap();
+    write_stdout_or_raise(format_args!("{}", rust_str));
     Object::nil()
 }
 
@@ -310,33 +2066,239 @@ unsafe extern "C" fn native_boundp_invoke(_: *const Function, sym: Object) -> Ob
     }
 }
 
-#[trivial_apply]
-unsafe extern "C" fn native_fboundp_invoke(_: *const Function, sym: Object) -> Object {
-    let sym = sym.unpack_symbol();
-    if (*sym).function.is_null() {
-        Object::nil()
-    } else {
-        Object::from_symbol(symbols::get_or_intern_symbol("true".to_string()))
-    }
-}
+#[trivial_apply]
+unsafe extern "C" fn native_fboundp_invoke(_: *const Function, sym: Object) -> Object {
+    let sym = sym.unpack_symbol();
+    if (*sym).function.is_null() {
+        Object::nil()
+    } else {
+        Object::from_symbol(symbols::get_or_intern_symbol("true".to_string()))
+    }
+}
+
+pub fn init() {
+    NAMESPACE_CONFLICTS.store(0, atomic::Ordering::SeqCst);
+    pure_functions().clear();
+    natives().clear();
+    *START_TIME.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+    unsafe {
+        let units = symbols::get_or_intern_symbol("internal-time-units-per-second".to_string());
+        (*units).value = to_heap(Object::from_int(INTERNAL_TIME_UNITS_PER_SECOND));
+    }
+    seed_random(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64),
+    );
+
+    init_symbol_fn(
+        native_add_invoke as *const c_void,
+        native_add_apply as *const c_void,
+        "+",
+        &[],
+        true,
+    );
+
+    init_symbol_fn(
+        native_sub_invoke as *const c_void,
+        native_sub_apply as *const c_void,
+        "-",
+        &["x"],
+        true,
+    );
+
+    init_symbol_fn(
+        native_mul_invoke as *const c_void,
+        native_mul_apply as *const c_void,
+        "*",
+        &[],
+        true,
+    );
+
+    init_symbol_fn(
+        native_lt_invoke as *const c_void,
+        native_lt_apply as *const c_void,
+        "<",
+        &["x", "y"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_gt_invoke as *const c_void,
+        native_gt_apply as *const c_void,
+        ">",
+        &["x", "y"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_char_lt_invoke as *const c_void,
+        native_char_lt_apply as *const c_void,
+        "char<",
+        &["x", "y"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_char_gt_invoke as *const c_void,
+        native_char_gt_apply as *const c_void,
+        "char>",
+        &["x", "y"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_char_eq_invoke as *const c_void,
+        native_char_eq_apply as *const c_void,
+        "char=",
+        &["x", "y"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_string_lt_invoke as *const c_void,
+        native_string_lt_apply as *const c_void,
+        "string<",
+        &["x", "y"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_string_gt_invoke as *const c_void,
+        native_string_gt_apply as *const c_void,
+        "string>",
+        &["x", "y"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_string_eq_invoke as *const c_void,
+        native_string_eq_apply as *const c_void,
+        "string=",
+        &["x", "y"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_concat_invoke as *const c_void,
+        native_concat_apply as *const c_void,
+        "concat",
+        &[],
+        true,
+    );
+
+    init_symbol_fn(
+        native_split_string_invoke as *const c_void,
+        native_split_string_apply as *const c_void,
+        "split-string",
+        &["s", "sep"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_join_invoke as *const c_void,
+        native_join_apply as *const c_void,
+        "join",
+        &["list", "sep"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_string_upcase_invoke as *const c_void,
+        native_string_upcase_apply as *const c_void,
+        "string-upcase",
+        &["s"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_string_downcase_invoke as *const c_void,
+        native_string_downcase_apply as *const c_void,
+        "string-downcase",
+        &["s"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_parse_integer_invoke as *const c_void,
+        native_parse_integer_apply as *const c_void,
+        "parse-integer",
+        &["s"],
+        true,
+    );
+
+    init_symbol_fn(
+        native_number_to_string_invoke as *const c_void,
+        native_number_to_string_apply as *const c_void,
+        "number->string",
+        &["n"],
+        true,
+    );
 
-pub fn init() {
     init_symbol_fn(
-        native_add_invoke as *const c_void,
-        native_add_apply as *const c_void,
-        "+",
-        &[],
+        native_string_to_number_invoke as *const c_void,
+        native_string_to_number_apply as *const c_void,
+        "string->number",
+        &["s"],
         true,
     );
 
     init_symbol_fn(
-        native_sub_invoke as *const c_void,
-        native_sub_apply as *const c_void,
-        "-",
-        &["x"],
+        native_all_symbols_invoke as *const c_void,
+        native_all_symbols_apply as *const c_void,
+        "all-symbols",
+        &[],
         true,
     );
 
+    init_symbol_fn(
+        native_read_invoke as *const c_void,
+        native_read_apply as *const c_void,
+        "read",
+        &["s"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_read_all_invoke as *const c_void,
+        native_read_all_apply as *const c_void,
+        "read-all",
+        &["s"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_get_internal_real_time_invoke as *const c_void,
+        native_get_internal_real_time_apply as *const c_void,
+        "get-internal-real-time",
+        &[],
+        false,
+    );
+
+    init_symbol_fn(
+        native_sleep_invoke as *const c_void,
+        native_sleep_apply as *const c_void,
+        "sleep",
+        &["seconds"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_random_invoke as *const c_void,
+        native_random_apply as *const c_void,
+        "random",
+        &["bound"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_srand_invoke as *const c_void,
+        native_srand_apply as *const c_void,
+        "srand",
+        &["seed"],
+        false,
+    );
+
     init_symbol_fn(
         native_equal_invoke as *const c_void,
         native_equal_apply as *const c_void,
@@ -353,6 +2315,22 @@ pub fn init() {
         false,
     );
 
+    init_symbol_fn(
+        native_set_compiler_macro_invoke as *const c_void,
+        native_set_compiler_macro_apply as *const c_void,
+        "set-compiler-macro!",
+        &["sym", "func"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_compiler_macro_fn_invoke as *const c_void,
+        native_compiler_macro_fn_apply as *const c_void,
+        "compiler-macro-function",
+        &["sym"],
+        false,
+    );
+
     init_symbol_fn(
         native_symbol_fn_invoke as *const c_void,
         native_symbol_fn_apply as *const c_void,
@@ -368,6 +2346,14 @@ pub fn init() {
         &["x", "list"],
         false,
     );
+
+    init_symbol_fn(
+        native_declare_pure_invoke as *const c_void,
+        native_declare_pure_apply as *const c_void,
+        "declare-pure",
+        &["sym"],
+        false,
+    );
     init_symbol_fn(
         native_rest_invoke as *const c_void,
         native_rest_apply as *const c_void,
@@ -382,6 +2368,27 @@ pub fn init() {
         &["list"],
         false,
     );
+    init_symbol_fn(
+        native_set_first_invoke as *const c_void,
+        native_set_first_apply as *const c_void,
+        "set-first!",
+        &["cons", "x"],
+        false,
+    );
+    init_symbol_fn(
+        native_set_rest_invoke as *const c_void,
+        native_set_rest_apply as *const c_void,
+        "set-rest!",
+        &["cons", "x"],
+        false,
+    );
+    init_symbol_fn(
+        native_gensym_invoke as *const c_void,
+        native_gensym_apply as *const c_void,
+        "gensym",
+        &[],
+        false,
+    );
 
     init_symbol_fn(
         native_apply_invoke as *const c_void,
@@ -391,6 +2398,102 @@ pub fn init() {
         true,
     );
 
+    init_symbol_fn(
+        native_call_ignoring_errors_invoke as *const c_void,
+        native_call_ignoring_errors_apply as *const c_void,
+        "call-ignoring-errors",
+        &["f"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_call_with_block_invoke as *const c_void,
+        native_call_with_block_apply as *const c_void,
+        "call-with-block",
+        &["f"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_return_from_block_invoke as *const c_void,
+        native_return_from_block_apply as *const c_void,
+        "return-from-block",
+        &["tag", "value"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_call_with_error_handler_invoke as *const c_void,
+        native_call_with_error_handler_apply as *const c_void,
+        "call-with-error-handler",
+        &["handler", "f"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_call_with_restarts_invoke as *const c_void,
+        native_call_with_restarts_apply as *const c_void,
+        "call-with-restarts",
+        &["names", "handlers", "f"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_invoke_restart_invoke as *const c_void,
+        native_invoke_restart_apply as *const c_void,
+        "invoke-restart",
+        &["name"],
+        true,
+    );
+
+    init_symbol_fn(
+        native_compute_restarts_invoke as *const c_void,
+        native_compute_restarts_apply as *const c_void,
+        "compute-restarts",
+        &[],
+        false,
+    );
+
+    init_symbol_fn(
+        native_room_invoke as *const c_void,
+        native_room_apply as *const c_void,
+        "room",
+        &[],
+        false,
+    );
+
+    init_symbol_fn(
+        native_reset_room_invoke as *const c_void,
+        native_reset_room_apply as *const c_void,
+        "reset-room",
+        &[],
+        false,
+    );
+
+    init_symbol_fn(
+        native_gc_invoke as *const c_void,
+        native_gc_apply as *const c_void,
+        "gc",
+        &[],
+        false,
+    );
+
+    init_symbol_fn(
+        native_register_test_invoke as *const c_void,
+        native_register_test_apply as *const c_void,
+        "register-test",
+        &["name", "f"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_run_tests_invoke as *const c_void,
+        native_run_tests_apply as *const c_void,
+        "run-tests",
+        &[],
+        false,
+    );
+
     init_symbol_fn(
         native_set_macro_invoke as *const c_void,
         native_set_macro_apply as *const c_void,
@@ -399,6 +2502,14 @@ pub fn init() {
         false,
     );
 
+    init_symbol_fn(
+        native_set_definition_failed_invoke as *const c_void,
+        native_set_definition_failed_apply as *const c_void,
+        "set-definition-failed!",
+        &["sym"],
+        false,
+    );
+
     init_symbol_fn(
         native_listp_invoke as *const c_void,
         native_listp_apply as *const c_void,
@@ -415,6 +2526,174 @@ pub fn init() {
         false,
     );
 
+    init_symbol_fn(
+        native_typep_invoke as *const c_void,
+        native_typep_apply as *const c_void,
+        "typep",
+        &["x", "type"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_coerce_invoke as *const c_void,
+        native_coerce_apply as *const c_void,
+        "coerce",
+        &["x", "type"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_make_record_invoke as *const c_void,
+        native_make_record_apply as *const c_void,
+        "make-record",
+        &["type"],
+        true,
+    );
+
+    init_symbol_fn(
+        native_record_type_invoke as *const c_void,
+        native_record_type_apply as *const c_void,
+        "record-type",
+        &["x"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_record_ref_invoke as *const c_void,
+        native_record_ref_apply as *const c_void,
+        "record-ref",
+        &["record", "type", "i"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_record_set_invoke as *const c_void,
+        native_record_set_apply as *const c_void,
+        "record-set!",
+        &["record", "type", "i", "val"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_set_print_hook_invoke as *const c_void,
+        native_set_print_hook_apply as *const c_void,
+        "set-print-hook!",
+        &["type", "f"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_set_print_shared_invoke as *const c_void,
+        native_set_print_shared_apply as *const c_void,
+        "set-print-shared!",
+        &["enabled"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_spawn_thread_invoke as *const c_void,
+        native_spawn_thread_apply as *const c_void,
+        "spawn-thread",
+        &["f"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_join_thread_invoke as *const c_void,
+        native_join_thread_apply as *const c_void,
+        "join-thread",
+        &["thread"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_make_channel_invoke as *const c_void,
+        native_make_channel_apply as *const c_void,
+        "make-channel",
+        &[],
+        false,
+    );
+
+    init_symbol_fn(
+        native_send_invoke as *const c_void,
+        native_send_apply as *const c_void,
+        "send",
+        &["channel", "value"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_recv_invoke as *const c_void,
+        native_recv_apply as *const c_void,
+        "recv",
+        &["channel"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_call_with_cleanup_invoke as *const c_void,
+        native_call_with_cleanup_apply as *const c_void,
+        "call-with-cleanup",
+        &["f", "cleanup"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_values_invoke as *const c_void,
+        native_values_apply as *const c_void,
+        "values",
+        &[],
+        true,
+    );
+
+    init_symbol_fn(
+        native_call_collecting_values_invoke as *const c_void,
+        native_call_collecting_values_apply as *const c_void,
+        "call-collecting-values",
+        &["f"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_open_invoke as *const c_void,
+        native_open_apply as *const c_void,
+        "open",
+        &["path"],
+        true,
+    );
+
+    init_symbol_fn(
+        native_close_invoke as *const c_void,
+        native_close_apply as *const c_void,
+        "close",
+        &["stream"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_read_char_invoke as *const c_void,
+        native_read_char_apply as *const c_void,
+        "read-char",
+        &["stream"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_read_line_invoke as *const c_void,
+        native_read_line_apply as *const c_void,
+        "read-line",
+        &["stream"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_write_char_invoke as *const c_void,
+        native_write_char_apply as *const c_void,
+        "write-char",
+        &["c", "stream"],
+        false,
+    );
+
     init_symbol_fn(
         native_macroexpand_1_invoke as *const c_void,
         native_macroexpand_1_apply as *const c_void,
@@ -423,11 +2702,43 @@ pub fn init() {
         false,
     );
 
+    init_symbol_fn(
+        native_format_invoke as *const c_void,
+        native_format_apply as *const c_void,
+        "format",
+        &["destination"],
+        true,
+    );
+
     init_symbol_fn(
         native_error_invoke as *const c_void,
         native_error_apply as *const c_void,
         "error",
-        &["msg"],
+        &["control"],
+        true,
+    );
+
+    init_symbol_fn(
+        native_warn_invoke as *const c_void,
+        native_warn_apply as *const c_void,
+        "warn",
+        &["control"],
+        true,
+    );
+
+    init_symbol_fn(
+        native_prin1_invoke as *const c_void,
+        native_prin1_apply as *const c_void,
+        "prin1",
+        &["x"],
+        false,
+    );
+
+    init_symbol_fn(
+        native_princ_invoke as *const c_void,
+        native_princ_apply as *const c_void,
+        "princ",
+        &["x"],
         false,
     );
 
@@ -447,6 +2758,22 @@ pub fn init() {
         false,
     );
 
+    init_symbol_fn(
+        native_terpri_invoke as *const c_void,
+        native_terpri_apply as *const c_void,
+        "terpri",
+        &[],
+        false,
+    );
+
+    init_symbol_fn(
+        native_fresh_line_invoke as *const c_void,
+        native_fresh_line_apply as *const c_void,
+        "fresh-line",
+        &[],
+        false,
+    );
+
     init_symbol_fn(
         native_stdout_write_invoke as *const c_void,
         native_stdout_write_apply as *const c_void,
@@ -486,4 +2813,13 @@ pub fn init() {
         &["sym"],
         false,
     );
+
+    // natives the compiler may fold, see unlispc::fold
+    for name in &["cons", "+", "-", "*", "<", ">", "equal"] {
+        unsafe { declare_pure(symbols::get_or_intern_symbol(name.to_string())) };
+    }
+
+    if debug::debug_natives_enabled() {
+        debug::init();
+    }
 }
@@ -0,0 +1,103 @@
+use crate::defs::{self, Function, ObjType, Object, Symbol};
+use crate::exceptions;
+use crate::interrupt;
+
+use std::cell::Cell;
+use std::ffi::CStr;
+use std::sync::{Mutex, MutexGuard};
+
+/// Hooks whose output prints objects with hooks of their own are called
+/// this many levels deep, below that objects are printed the default way.
+pub const MAX_HOOK_DEPTH: usize = 16;
+
+#[derive(Clone, Copy)]
+struct PrintHook {
+    ty: *mut Symbol,
+    f: *mut Function,
+}
+
+// symbols and functions are never freed, so the pointers stay valid on any
+// thread
+unsafe impl Send for PrintHook {}
+
+static PRINT_HOOKS: Mutex<Vec<PrintHook>> = Mutex::new(Vec::new());
+
+thread_local! {
+    // hooks being called on this thread
+    static HOOK_DEPTH: Cell<usize> = Cell::new(0);
+}
+
+fn print_hooks() -> MutexGuard<'static, Vec<PrintHook>> {
+    PRINT_HOOKS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Makes records of type `ty` print as the string `f` returns for them,
+/// none removes the hook.
+pub fn set_print_hook(ty: *mut Symbol, f: Option<*mut Function>) {
+    let mut hooks = print_hooks();
+    hooks.retain(|hook| hook.ty != ty);
+
+    if let Some(f) = f {
+        hooks.push(PrintHook { ty, f });
+    }
+}
+
+fn print_hook(ty: *mut Symbol) -> Option<*mut Function> {
+    print_hooks()
+        .iter()
+        .find(|hook| hook.ty == ty)
+        .map(|hook| hook.f)
+}
+
+unsafe fn call_hook(f: *mut Function, record: &Object) -> Object {
+    if !defs::unlisp_rt_check_arity(f, 1) {
+        exceptions::unlisp_rt_raise_arity_error((*f).name, (*f).arg_count, 1);
+    }
+
+    let printed = defs::invoke_args_array(f, &[record.clone()]);
+    if printed.ty != ObjType::String {
+        exceptions::raise_error(format!("print hook returned {}, not a string", printed));
+    }
+
+    printed
+}
+
+/// What the hook of the record's type prints it as, none without a hook or
+/// when hooks are already nested `MAX_HOOK_DEPTH` deep. An error raised by
+/// the hook is caught and printed as `#<print-error for TYPE: MESSAGE>`, so
+/// the rest of the output is still printed, the interrupt isn't caught.
+pub unsafe fn hooked_print(record: &Object, ty: *mut Symbol) -> Option<String> {
+    let f = print_hook(ty)?;
+
+    let depth = HOOK_DEPTH.with(Cell::get);
+    if depth >= MAX_HOOK_DEPTH {
+        return None;
+    }
+
+    // the depth is restored also when the hook exits to a block or a
+    // restart around the print
+    HOOK_DEPTH.with(|d| d.set(depth + 1));
+    let result = exceptions::run_with_global_ex_handler(|| {
+        exceptions::run_with_cleanup(|| call_hook(f, record), || HOOK_DEPTH.with(|d| d.set(depth)))
+    });
+
+    Some(match result {
+        Ok(printed) => CStr::from_ptr(printed.unpack_string())
+            .to_string_lossy()
+            .into_owned(),
+        Err(e) => {
+            // the interrupt isn't printed, it goes on to the code evaluating
+            interrupt::unlisp_rt_check_interrupt();
+
+            format!(
+                "#<print-error for {}: {}>",
+                CStr::from_ptr((*ty).name).to_string_lossy(),
+                e
+            )
+        }
+    })
+}
+
+pub fn init() {
+    print_hooks().clear();
+}
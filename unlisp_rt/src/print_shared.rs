@@ -0,0 +1,159 @@
+use crate::defs::{Cons, ObjType, Object, PrintMode};
+use crate::print_hooks;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static PRINT_SHARED: AtomicBool = AtomicBool::new(false);
+
+/// Makes the printer write cells reachable more than once, shared tails and
+/// cycles alike, once with a `#n=` label and `#n#` for the other
+/// references, which the reader reads back into the same sharing. Off, shared
+/// structure is printed expanded.
+pub fn set_print_shared(enabled: bool) {
+    PRINT_SHARED.store(enabled, Ordering::SeqCst);
+}
+
+pub fn print_shared() -> bool {
+    PRINT_SHARED.load(Ordering::SeqCst)
+}
+
+pub fn init() {
+    set_print_shared(false);
+}
+
+fn unboxed(obj: &Object) -> Object {
+    if obj.ty == ObjType::Box {
+        obj.unpack_underlying()
+    } else {
+        obj.clone()
+    }
+}
+
+// cells reached more than once from `root`, through conses and the slots of
+// records, which aren't labeled themselves
+unsafe fn shared_cells(root: &Object) -> HashSet<*const Cons> {
+    let mut seen = HashSet::new();
+    let mut seen_records = HashSet::new();
+    let mut shared = HashSet::new();
+    let mut stack = vec![root.clone()];
+
+    while let Some(obj) = stack.pop() {
+        let obj = unboxed(&obj);
+
+        match obj.ty {
+            ObjType::Cons => {
+                let cons = obj.unpack_cons();
+                if !seen.insert(cons as *const Cons) {
+                    shared.insert(cons as *const Cons);
+                    continue;
+                }
+
+                stack.push((*(*cons).car).clone());
+                stack.push((*(*cons).cdr).clone());
+            }
+            ObjType::Record => {
+                let record = obj.unpack_record();
+                if seen_records.insert(record) {
+                    stack.extend((*record).slots.iter().cloned());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    shared
+}
+
+struct SharedPrinter {
+    mode: PrintMode,
+    shared: HashSet<*const Cons>,
+    labels: HashMap<*const Cons, usize>,
+}
+
+impl SharedPrinter {
+    unsafe fn write(&mut self, obj: &Object, f: &mut fmt::Formatter) -> fmt::Result {
+        let obj = unboxed(obj);
+
+        match obj.ty {
+            ObjType::Cons => self.write_cons(obj.unpack_cons(), f),
+            ObjType::Record => {
+                let record = &*obj.unpack_record();
+                if let Some(printed) = print_hooks::hooked_print(&obj, record.ty) {
+                    return write!(f, "{}", printed);
+                }
+
+                write!(f, "#<{}", Object::from_symbol(record.ty).printed(self.mode))?;
+                for slot in record.slots.iter() {
+                    write!(f, " ")?;
+                    self.write(slot, f)?;
+                }
+                write!(f, ">")
+            }
+            _ => write!(f, "{}", obj.printed(self.mode)),
+        }
+    }
+
+    // writes the label of a shared cell, false when the cell was written
+    // before and only its reference is
+    fn write_label(
+        &mut self,
+        cons: *const Cons,
+        f: &mut fmt::Formatter,
+    ) -> Result<bool, fmt::Error> {
+        if !self.shared.contains(&cons) {
+            return Ok(true);
+        }
+
+        if let Some(label) = self.labels.get(&cons) {
+            write!(f, "#{}#", label)?;
+            return Ok(false);
+        }
+
+        let label = self.labels.len() + 1;
+        self.labels.insert(cons, label);
+        write!(f, "#{}=", label)?;
+
+        Ok(true)
+    }
+
+    // like display_cons, a shared cell in the spine is written as the tail
+    // of a dotted list so it can be labeled
+    unsafe fn write_cons(&mut self, cons: *mut Cons, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.write_label(cons, f)? {
+            return Ok(());
+        }
+
+        write!(f, "(")?;
+        self.write(&*(*cons).car, f)?;
+
+        let mut obj = unboxed(&*(*cons).cdr);
+        while obj.ty == ObjType::Cons && !self.shared.contains(&(obj.unpack_cons() as _)) {
+            let cons = obj.unpack_cons();
+            write!(f, " ")?;
+            self.write(&*(*cons).car, f)?;
+            obj = unboxed(&*(*cons).cdr);
+        }
+
+        if obj.is_nil() {
+            write!(f, ")")
+        } else {
+            write!(f, " . ")?;
+            self.write(&obj, f)?;
+            write!(f, ")")
+        }
+    }
+}
+
+/// Writes a cons or a record with the cells it shares labeled, see
+/// `set_print_shared`. Labels count from 1 in the order they're written.
+pub unsafe fn display(obj: &Object, mode: PrintMode, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut printer = SharedPrinter {
+        mode,
+        shared: shared_cells(obj),
+        labels: HashMap::new(),
+    };
+
+    printer.write(obj, f)
+}
@@ -0,0 +1,68 @@
+use std::cell::Cell;
+use std::fmt;
+use std::sync::Mutex;
+
+use unlisp_internal_macros::runtime_fn;
+
+// names of the files code was read from, indexed by the ids compiled into it
+static FILES: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+thread_local! {
+    // (file id, line, column) of the call compiled code on this thread
+    // started last, line 0 when there's none
+    static LOCATION: Cell<(u32, u32, u32)> = Cell::new((0, 0, 0));
+}
+
+/// A position in source code, lines and columns are counted from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+    pub col: u32,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+/// The id compiled code refers to the file by, the same for every call with
+/// the same name.
+pub fn intern_file(name: &str) -> u32 {
+    let mut files = FILES.lock().unwrap();
+
+    match files.iter().position(|file| file == name) {
+        Some(id) => id as u32,
+        None => {
+            files.push(name.to_string());
+            (files.len() - 1) as u32
+        }
+    }
+}
+
+// called by JIT compiled code before every call it has the location of
+#[runtime_fn]
+pub extern "C" fn unlisp_rt_set_source_location(file: u32, line: u32, col: u32) {
+    LOCATION.with(|location| location.set((file, line, col)));
+}
+
+/// Forgets the location of the last call, so errors raised before the next
+/// one have none.
+pub fn clear_source_location() {
+    LOCATION.with(|location| location.set((0, 0, 0)));
+}
+
+/// Where the call compiled code started last on this thread is in the
+/// source, which is the innermost one running unless it returned since.
+pub fn source_location() -> Option<SourceLocation> {
+    let (file, line, col) = LOCATION.with(Cell::get);
+
+    if line == 0 {
+        return None;
+    }
+
+    let file = FILES.lock().unwrap().get(file as usize)?.clone();
+
+    Some(SourceLocation { file, line, col })
+}
@@ -0,0 +1,84 @@
+use libc::c_char;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use unlisp_internal_macros::runtime_fn;
+
+// counters are only informational, so relaxed ordering is enough
+static CONSES: AtomicU64 = AtomicU64::new(0);
+static CLOSURES: AtomicU64 = AtomicU64::new(0);
+static STRINGS: AtomicU64 = AtomicU64::new(0);
+static SYMBOLS: AtomicU64 = AtomicU64::new(0);
+static BYTES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub conses: u64,
+    pub closures: u64,
+    pub strings: u64,
+    pub symbols: u64,
+    pub bytes: u64,
+}
+
+impl Stats {
+    pub fn entries(&self) -> [(&'static str, u64); 5] {
+        [
+            ("conses", self.conses),
+            ("closures", self.closures),
+            ("strings", self.strings),
+            ("symbols", self.symbols),
+            ("bytes", self.bytes),
+        ]
+    }
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        conses: CONSES.load(Ordering::Relaxed),
+        closures: CLOSURES.load(Ordering::Relaxed),
+        strings: STRINGS.load(Ordering::Relaxed),
+        symbols: SYMBOLS.load(Ordering::Relaxed),
+        bytes: BYTES.load(Ordering::Relaxed),
+    }
+}
+
+pub fn reset() {
+    for counter in [&CONSES, &CLOSURES, &STRINGS, &SYMBOLS, &BYTES].iter() {
+        counter.store(0, Ordering::Relaxed);
+    }
+}
+
+pub fn record_bytes(n: usize) {
+    BYTES.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+pub fn record_cons() {
+    CONSES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_string(len: usize) {
+    STRINGS.fetch_add(1, Ordering::Relaxed);
+    record_bytes(len);
+}
+
+pub fn record_symbol() {
+    SYMBOLS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[repr(u32)]
+pub enum AllocKind {
+    Other = 0,
+    Closure = 1,
+}
+
+// allocation entry point for compiled code, malloc calls emitted by codegen
+// go through here
+#[runtime_fn]
+pub unsafe extern "C" fn unlisp_rt_allocate(kind: u32, size: u64) -> *mut c_char {
+    if kind == AllocKind::Closure as u32 {
+        CLOSURES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    record_bytes(size as usize);
+
+    libc::malloc(size as usize) as *mut c_char
+}
@@ -0,0 +1,160 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::str;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Direction::Input => write!(f, "input"),
+            Direction::Output => write!(f, "output"),
+        }
+    }
+}
+
+enum Handle {
+    Input(BufReader<File>),
+    Output(BufWriter<File>),
+}
+
+/// A file opened for reading or writing. A closed stream keeps its path
+/// and direction for printing, reading or writing it is an error.
+pub struct Stream {
+    path: String,
+    direction: Direction,
+    handle: Option<Handle>,
+}
+
+fn io_error(e: io::Error) -> String {
+    e.to_string()
+}
+
+// the length of the UTF-8 sequence starting with the byte, invalid leading
+// bytes are left to the decoder
+fn utf8_len(first: u8) -> usize {
+    match first {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1,
+    }
+}
+
+impl Stream {
+    /// Output streams create the file or truncate it.
+    pub fn open(path: &str, direction: Direction) -> io::Result<Self> {
+        let handle = match direction {
+            Direction::Input => Handle::Input(BufReader::new(File::open(path)?)),
+            Direction::Output => Handle::Output(BufWriter::new(File::create(path)?)),
+        };
+
+        Ok(Self {
+            path: path.to_string(),
+            direction,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.handle.is_some()
+    }
+
+    /// Flushes what was written, closing a closed stream does nothing.
+    pub fn close(&mut self) -> Result<(), String> {
+        match self.handle.take() {
+            Some(Handle::Output(mut writer)) => writer.flush().map_err(io_error),
+            _ => Ok(()),
+        }
+    }
+
+    fn reader(&mut self) -> Result<&mut BufReader<File>, String> {
+        // the errors print the stream, which can't be borrowed while the
+        // handle is
+        match &self.handle {
+            Some(Handle::Input(_)) => (),
+            Some(Handle::Output(_)) => return Err(format!("{} is not an input stream", self)),
+            None => return Err(format!("{} is closed", self)),
+        }
+
+        match &mut self.handle {
+            Some(Handle::Input(reader)) => Ok(reader),
+            _ => unreachable!(),
+        }
+    }
+
+    fn writer(&mut self) -> Result<&mut BufWriter<File>, String> {
+        // the errors print the stream, which can't be borrowed while the
+        // handle is
+        match &self.handle {
+            Some(Handle::Output(_)) => (),
+            Some(Handle::Input(_)) => return Err(format!("{} is not an output stream", self)),
+            None => return Err(format!("{} is closed", self)),
+        }
+
+        match &mut self.handle {
+            Some(Handle::Output(writer)) => Ok(writer),
+            _ => unreachable!(),
+        }
+    }
+
+    /// The next character, none at the end of the file.
+    pub fn read_char(&mut self) -> Result<Option<char>, String> {
+        let reader = self.reader()?;
+        let mut buf = [0; 4];
+
+        if reader.read(&mut buf[..1]).map_err(io_error)? == 0 {
+            return Ok(None);
+        }
+
+        let len = utf8_len(buf[0]);
+        reader.read_exact(&mut buf[1..len]).map_err(io_error)?;
+
+        match str::from_utf8(&buf[..len]) {
+            Ok(s) => Ok(s.chars().next()),
+            Err(_) => Err(format!("invalid UTF-8 in {}", self.path)),
+        }
+    }
+
+    /// The rest of the current line without the line ending, none at the end
+    /// of the file.
+    pub fn read_line(&mut self) -> Result<Option<String>, String> {
+        let mut line = String::new();
+
+        if self.reader()?.read_line(&mut line).map_err(io_error)? == 0 {
+            return Ok(None);
+        }
+
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+
+        Ok(Some(line))
+    }
+
+    pub fn write_char(&mut self, c: char) -> Result<(), String> {
+        let mut buf = [0; 4];
+
+        self.write_str(c.encode_utf8(&mut buf))
+    }
+
+    pub fn write_str(&mut self, s: &str) -> Result<(), String> {
+        self.writer()?.write_all(s.as_bytes()).map_err(io_error)
+    }
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let state = if self.is_open() { "" } else { "closed " };
+
+        write!(f, "#<{}{} stream {:?}>", state, self.direction, self.path)
+    }
+}
@@ -1,29 +1,38 @@
-use crate::defs::Symbol;
+use crate::defs::{to_heap, Function, Object, Symbol};
+use crate::stats;
 use libc::c_char;
 use std::collections::HashMap;
 use std::ffi::{CStr, CString};
+use std::ptr;
+use std::sync::{Mutex, MutexGuard};
 
-type InternedSymbols = HashMap<String, *mut Symbol>;
+struct SymbolPtr(*mut Symbol);
 
-static mut INTERNED_SYMBOLS: Option<InternedSymbols> = None;
+// symbols are never freed, so the pointers stay valid on any thread
+unsafe impl Send for SymbolPtr {}
 
-pub fn interned_symbols_ref() -> &'static InternedSymbols {
-    unsafe { INTERNED_SYMBOLS.as_ref().unwrap() }
-}
+type InternedSymbols = HashMap<String, SymbolPtr>;
+
+// The table is shared by all contexts in the process. Interning takes the
+// lock, but the symbol cells themselves aren't synchronized, so concurrent
+// definitions of the same name race and the last one wins.
+static INTERNED_SYMBOLS: Mutex<Option<InternedSymbols>> = Mutex::new(None);
 
-pub fn interned_symbols_mut() -> &'static mut InternedSymbols {
-    unsafe { INTERNED_SYMBOLS.as_mut().unwrap() }
+fn interned_symbols() -> MutexGuard<'static, Option<InternedSymbols>> {
+    INTERNED_SYMBOLS.lock().unwrap_or_else(|e| e.into_inner())
 }
 
 fn get_or_intern_symbol_impl(name_raw: *const c_char, name: String) -> *mut Symbol {
-    interned_symbols_ref().get(&name).map_or_else(
-        || {
-            let sym_pointer = Box::into_raw(Box::new(Symbol::new(name_raw)));
-            interned_symbols_mut().insert(name.clone(), sym_pointer);
-            sym_pointer
-        },
-        |p| *p,
-    )
+    let mut symbols = interned_symbols();
+    let symbols = symbols.as_mut().expect("runtime isn't initialized");
+
+    symbols
+        .entry(name)
+        .or_insert_with(|| {
+            stats::record_symbol();
+            SymbolPtr(to_heap(Symbol::new(name_raw)))
+        })
+        .0
 }
 
 pub fn get_or_intern_symbol_by_ptr(name: *const c_char) -> *mut Symbol {
@@ -40,8 +49,158 @@ pub fn get_or_intern_symbol(name: String) -> *mut Symbol {
     get_or_intern_symbol_impl(c_ptr, name)
 }
 
-pub fn init() {
-    unsafe {
-        INTERNED_SYMBOLS = Some(HashMap::new());
+/// Whether the symbol of the name has a function or a value, false when it
+/// isn't interned or the runtime isn't initialized.
+pub fn is_bound_name(name: &str) -> bool {
+    let table = interned_symbols();
+
+    match table.as_ref().and_then(|symbols| symbols.get(name)) {
+        Some(sym) => unsafe { !(*sym.0).function.is_null() || !(*sym.0).value.is_null() },
+        None => false,
+    }
+}
+
+/// A symbol that isn't in the table, interning its name gives a different
+/// symbol.
+pub fn make_uninterned_symbol(name: &str) -> *mut Symbol {
+    let c_str = CString::new(name).expect("string conversion failed");
+
+    stats::record_symbol();
+    to_heap(Symbol::new(c_str.into_raw()))
+}
+
+/// All interned symbols whose names start with `prefix`, sorted by name, so
+/// the result doesn't depend on the hash table's iteration order.
+pub fn interned_symbols_with_prefix(prefix: &str) -> Vec<*mut Symbol> {
+    let table = interned_symbols();
+    let mut found: Vec<_> = table
+        .iter()
+        .flat_map(|symbols| symbols.iter())
+        .filter(|(name, _)| name.starts_with(prefix))
+        .map(|(name, sym)| (name.as_str(), sym.0))
+        .collect();
+
+    found.sort_by_key(|(name, _)| *name);
+    found.into_iter().map(|(_, sym)| sym).collect()
+}
+
+/// A line for every interned symbol, sorted by name, with what its function
+/// binding is, e.g. `when: macro, 1+ args` or `x: no function, bound`.
+pub unsafe fn describe_interned_symbols() -> String {
+    let mut description = String::new();
+
+    for sym in interned_symbols_with_prefix("") {
+        let name = CStr::from_ptr((*sym).name).to_string_lossy();
+        let f = (*sym).function;
+
+        let binding = if f.is_null() {
+            "no function".to_string()
+        } else {
+            format!(
+                "{}, {}{} args",
+                if (*f).is_macro { "macro" } else { "function" },
+                (*f).arg_count,
+                if (*f).has_restarg { "+" } else { "" }
+            )
+        };
+        let bound = if (*sym).value.is_null() {
+            ""
+        } else {
+            ", bound"
+        };
+
+        description.push_str(&format!("{}: {}{}\n", name, binding, bound));
+    }
+
+    description
+}
+
+// Functions rewriting calls at compile time, by the address of the symbol
+// naming the function whose calls they rewrite. They're kept apart from the
+// symbols, whose layout compiled code relies on.
+static COMPILER_MACROS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+fn compiler_macros() -> MutexGuard<'static, Vec<(usize, usize)>> {
+    COMPILER_MACROS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The compiler macro of the function `sym` names, null when it has none.
+pub fn compiler_macro(sym: *mut Symbol) -> *mut Function {
+    compiler_macros()
+        .iter()
+        .find(|(s, _)| *s == sym as usize)
+        .map_or(ptr::null_mut(), |(_, f)| *f as *mut Function)
+}
+
+/// Makes `f` the compiler macro of `sym` in place of the one it had, a null
+/// `f` removes it.
+pub fn set_compiler_macro(sym: *mut Symbol, f: *mut Function) {
+    let mut macros = compiler_macros();
+    macros.retain(|(s, _)| *s != sym as usize);
+
+    if !f.is_null() {
+        macros.push((sym as usize, f as usize));
     }
 }
+
+// a symbol's function, value cell and what the cell held
+type SymbolSlots = (*mut Function, *mut Object, Option<Object>);
+
+/// The function, value and compiler macro of every interned symbol at one
+/// point in time. Holding on to it keeps the function objects and value
+/// cells it refers to around, though the runtime doesn't free those anyway.
+pub struct Bindings {
+    slots: HashMap<usize, SymbolSlots>,
+    compiler_macros: Vec<(usize, usize)>,
+}
+
+/// Records what every interned symbol is bound to, see `restore_bindings`.
+pub fn save_bindings() -> Bindings {
+    let slots = interned_symbols_with_prefix("")
+        .into_iter()
+        .map(|sym| unsafe {
+            let value = (*sym).value;
+            let contents = if value.is_null() {
+                None
+            } else {
+                Some((*value).clone())
+            };
+
+            (sym as usize, ((*sym).function, value, contents))
+        })
+        .collect();
+
+    Bindings {
+        slots,
+        compiler_macros: compiler_macros().clone(),
+    }
+}
+
+/// Binds every interned symbol back to the function, value and compiler
+/// macro it had when `bindings` were saved. Symbols interned since lose their function and
+/// value. Code compiled in between isn't freed, so it stays valid while
+/// nothing refers to it anymore.
+pub unsafe fn restore_bindings(bindings: &Bindings) {
+    for sym in interned_symbols_with_prefix("") {
+        match bindings.slots.get(&(sym as usize)) {
+            Some((function, value, contents)) => {
+                (*sym).function = *function;
+                (*sym).value = *value;
+                if let Some(contents) = contents {
+                    **value = contents.clone();
+                }
+            }
+            None => {
+                (*sym).function = ptr::null_mut();
+                (*sym).value = ptr::null_mut();
+            }
+        }
+    }
+
+    *compiler_macros() = bindings.compiler_macros.clone();
+}
+
+pub fn init() {
+    *interned_symbols() = Some(HashMap::new());
+    compiler_macros().clear();
+}
@@ -0,0 +1,66 @@
+use crate::defs::{Function, ListLike};
+use crate::exceptions;
+use crate::interrupt;
+use crate::predefined::apply_to_list_like;
+
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Clone, Copy)]
+struct FunctionPtr(*mut Function);
+
+// functions are never freed, so the pointers stay valid on any thread
+unsafe impl Send for FunctionPtr {}
+
+type RegisteredTests = Vec<(String, FunctionPtr)>;
+
+static REGISTERED_TESTS: Mutex<RegisteredTests> = Mutex::new(Vec::new());
+
+fn registered_tests() -> MutexGuard<'static, RegisteredTests> {
+    REGISTERED_TESTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// redefining a test replaces it in place, so reloading a file doesn't run
+// its tests twice
+pub fn register_test(name: String, f: *mut Function) {
+    let mut tests = registered_tests();
+    let f = FunctionPtr(f);
+
+    match tests.iter_mut().find(|(existing, _)| *existing == name) {
+        Some(entry) => entry.1 = f,
+        None => tests.push((name, f)),
+    }
+}
+
+pub struct TestOutcome {
+    pub name: String,
+    pub error: Option<String>,
+}
+
+// every test runs under its own exception handler, so a failing test
+// doesn't stop the rest from running. The registry isn't locked while tests
+// run, so tests can define tests of their own.
+pub unsafe fn run_registered_tests() -> Vec<TestOutcome> {
+    let tests = registered_tests().clone();
+
+    tests
+        .into_iter()
+        .map(|(name, f)| {
+            let result = exceptions::run_with_global_ex_handler(|| {
+                apply_to_list_like(f.0, ListLike::from_nil())
+            });
+            // the interrupt stops the run rather than failing a test
+            if result.is_err() {
+                interrupt::unlisp_rt_check_interrupt();
+            }
+
+            TestOutcome {
+                name: name,
+                error: result.err().map(|e| e.to_string()),
+            }
+        })
+        .collect()
+}
+
+pub fn init() {
+    registered_tests().clear();
+}
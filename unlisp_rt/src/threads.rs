@@ -0,0 +1,164 @@
+use crate::defs::{self, Function, ObjType, Object, Record};
+use crate::exceptions;
+use crate::stats;
+use crate::symbols;
+
+use std::mem;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+const THREAD_TYPE: &str = "thread";
+const CHANNEL_TYPE: &str = "channel";
+
+// Objects are pointers into memory shared by all threads, nothing is copied
+// when they're passed to another thread. Races on cells both threads
+// mutate are the program's problem.
+struct SharedObject(Object);
+
+unsafe impl Send for SharedObject {}
+
+// what the function of a thread returned, or the message of the error it
+// raised
+type ThreadResult = Result<SharedObject, String>;
+
+struct Channel {
+    sender: Sender<SharedObject>,
+    // shared, so a thread waiting in recv doesn't hold the registry's lock
+    receiver: Arc<Mutex<Receiver<SharedObject>>>,
+}
+
+// Handles are records with the index into one of these, a joined thread
+// leaves none behind.
+static THREADS: Mutex<Vec<Option<JoinHandle<ThreadResult>>>> = Mutex::new(Vec::new());
+static CHANNELS: Mutex<Vec<Channel>> = Mutex::new(Vec::new());
+
+// Errors are raised with longjmp, which doesn't drop the guards of the
+// frames it leaves, so nothing is raised while a lock is held.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+unsafe fn make_handle(ty: &str, id: usize) -> Object {
+    stats::record_bytes(mem::size_of::<Object>());
+
+    Object::from_record(defs::to_heap(Record {
+        ty: symbols::get_or_intern_symbol(ty.to_string()),
+        slots: vec![Object::from_int(id as i64)],
+    }))
+}
+
+// the index in a handle made by make_handle with the type
+unsafe fn handle_id(handle: &Object, ty: &str) -> usize {
+    let record = if handle.ty == ObjType::Box {
+        handle.unpack_underlying()
+    } else {
+        handle.clone()
+    };
+
+    if record.ty == ObjType::Record {
+        let record = record.unpack_record();
+
+        if (*record).ty == symbols::get_or_intern_symbol(ty.to_string()) {
+            if let [id] = (*record).slots.as_slice() {
+                if id.ty == ObjType::Int64 && id.unpack_int() >= 0 {
+                    return id.unpack_int() as usize;
+                }
+            }
+        }
+    }
+
+    exceptions::raise_error(format!("expected a {}, got {}", ty, handle))
+}
+
+unsafe fn call_thread_fn(f: *mut Function) -> Object {
+    if !defs::unlisp_rt_check_arity(f, 0) {
+        exceptions::unlisp_rt_raise_arity_error((*f).name, (*f).arg_count, 0);
+    }
+
+    defs::invoke_args_array(f, &[])
+}
+
+/// Calls `f` without arguments on a new thread, which has its own error
+/// handler, and returns the handle `join_thread` takes.
+pub unsafe fn spawn_thread(f: *mut Function) -> Object {
+    // a pointer to a function object, which is never freed
+    let f = f as usize;
+
+    let handle = thread::spawn(move || {
+        exceptions::run_with_global_ex_handler(|| call_thread_fn(f as *mut Function))
+            .map(SharedObject)
+            .map_err(|e| e.to_string())
+    });
+
+    let mut threads = lock(&THREADS);
+    threads.push(Some(handle));
+
+    make_handle(THREAD_TYPE, threads.len() - 1)
+}
+
+/// Waits for the thread of a handle to finish and returns what its function
+/// returned, or raises the error it raised. A thread is joined once.
+pub unsafe fn join_thread(handle: &Object) -> Object {
+    let id = handle_id(handle, THREAD_TYPE);
+
+    let join_handle = lock(&THREADS).get_mut(id).map(Option::take);
+
+    let join_handle = match join_handle {
+        Some(Some(join_handle)) => join_handle,
+        Some(None) => exceptions::raise_error(format!("{} was already joined", handle)),
+        None => exceptions::raise_error(format!("expected a thread, got {}", handle)),
+    };
+
+    match join_handle.join() {
+        Ok(Ok(SharedObject(result))) => result,
+        Ok(Err(message)) => exceptions::raise_error(message),
+        Err(_) => exceptions::raise_error(format!("{} panicked", handle)),
+    }
+}
+
+pub unsafe fn make_channel() -> Object {
+    let (sender, receiver) = mpsc::channel();
+
+    let mut channels = lock(&CHANNELS);
+    channels.push(Channel {
+        sender,
+        receiver: Arc::new(Mutex::new(receiver)),
+    });
+
+    make_handle(CHANNEL_TYPE, channels.len() - 1)
+}
+
+unsafe fn channel<R>(handle: &Object, f: impl FnOnce(&Channel) -> R) -> R {
+    let id = handle_id(handle, CHANNEL_TYPE);
+    let found = lock(&CHANNELS).get(id).map(f);
+
+    match found {
+        Some(found) => found,
+        None => exceptions::raise_error(format!("expected a channel, got {}", handle)),
+    }
+}
+
+/// Queues `value` on the channel, without waiting for a receiver.
+pub unsafe fn send(handle: &Object, value: Object) {
+    let sender = channel(handle, |channel| channel.sender.clone());
+
+    // the receiver is kept with the sender, so sending can't fail
+    sender.send(SharedObject(value)).unwrap();
+}
+
+/// The oldest value queued on the channel, waiting for one when there's
+/// none.
+pub unsafe fn recv(handle: &Object) -> Object {
+    let receiver = channel(handle, |channel| channel.receiver.clone());
+    let received = lock(&receiver).recv();
+
+    // the sender is kept with the receiver, so receiving can't fail
+    received.unwrap().0
+}
+
+/// Forgets threads and channels. Threads still running go on, detached.
+pub fn init() {
+    lock(&THREADS).clear();
+    lock(&CHANNELS).clear();
+}
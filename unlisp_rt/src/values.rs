@@ -0,0 +1,62 @@
+use crate::defs::{ListLike, ObjType, Object};
+
+use std::cell::RefCell;
+
+use unlisp_internal_macros::runtime_fn;
+
+thread_local! {
+    // the primary value and all the values of the last `values` call on this
+    // thread, until they're collected
+    static VALUES: RefCell<Option<(Object, ListLike)>> = RefCell::new(None);
+}
+
+// conses are compared by identity, a fresh list equal to the values' primary
+// is still a single value
+fn same(x: &Object, y: &Object) -> bool {
+    if x.ty == ObjType::Cons && y.ty == ObjType::Cons {
+        x.unpack_cons() == y.unpack_cons()
+    } else {
+        x == y
+    }
+}
+
+/// Records `values` as the values of the form being evaluated and returns
+/// the primary one, the first or nil, which is what callers that don't
+/// collect values see.
+pub fn set_values(values: ListLike) -> Object {
+    let primary = if values.is_nil() {
+        Object::nil()
+    } else {
+        values.car()
+    };
+
+    VALUES.with(|v| *v.borrow_mut() = Some((primary.clone(), values)));
+
+    primary
+}
+
+/// Forgets the recorded values, so a form that doesn't call `values` is
+/// seen to return a single one.
+pub fn clear_values() {
+    VALUES.with(|v| *v.borrow_mut() = None);
+}
+
+// called by JIT compiled code after every call that isn't in tail position,
+// the values of the call end there, e.g. in (progn (values 1 2) 1)
+#[runtime_fn]
+pub extern "C" fn unlisp_rt_clear_values() {
+    clear_values();
+}
+
+/// The values of a form that returned `result`: those recorded by the last
+/// `values` call when `result` is their primary value, otherwise just
+/// `result`. The recorded values are collected only once. Compiled code
+/// clears them after calls whose value isn't returned, so they are those of
+/// the form's last call; comparing with `result` still catches natives that
+/// call functions and return something else.
+pub fn take_values(result: Object) -> ListLike {
+    match VALUES.with(|v| v.borrow_mut().take()) {
+        Some((primary, values)) if same(&primary, &result) => values,
+        _ => ListLike::from_nil().cons(result),
+    }
+}
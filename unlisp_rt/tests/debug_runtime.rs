@@ -0,0 +1,85 @@
+//! Runs only against the debug runtime:
+//! `cargo test -p unlisp_rt --features debug_runtime`.
+#![cfg(feature = "debug_runtime")]
+
+use unlisp_rt::defs::{self, Function, FunctionType, Object};
+use unlisp_rt::symbols;
+
+use std::env;
+use std::mem;
+use std::process::Command;
+use std::ptr;
+
+// set in the child process that runs into the corruption, the checks abort
+const CORRUPTION_ENV: &str = "UNLISP_RT_CORRUPTION";
+
+fn stderr_of_child(test: &str, corruption: &str) -> String {
+    let output = Command::new(env::current_exe().unwrap())
+        .args(&[test, "--exact", "--nocapture"])
+        .env(CORRUPTION_ENV, corruption)
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn corrupted_object(corruption: &str) -> Object {
+    match corruption {
+        "tag" => {
+            let mut o = Object::from_int(1);
+            unsafe { *(&mut o as *mut Object as *mut u32) = 42 };
+            o
+        }
+        "null-cons" => Object::from_cons(ptr::null_mut()),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn corrupted_argument_aborts() {
+    if let Ok(corruption) = env::var(CORRUPTION_ENV) {
+        defs::reset_runtime();
+        unsafe {
+            let f = (*symbols::get_or_intern_symbol("equal".to_string())).function;
+            let invoke: extern "C" fn(*const Function, Object, Object) -> Object =
+                mem::transmute((*f).invoke_f_ptr);
+            invoke(f, Object::from_int(1), corrupted_object(&corruption));
+        }
+        return;
+    }
+
+    assert!(
+        stderr_of_child("corrupted_argument_aborts", "tag").contains(
+            "unlisp debug runtime: invalid type tag 42 in argument 2 of native_equal_invoke"
+        )
+    );
+    assert!(stderr_of_child("corrupted_argument_aborts", "null-cons")
+        .contains("unlisp debug runtime: null cons pointer in argument 2 of native_equal_invoke"));
+}
+
+#[test]
+fn function_without_apply_pointer_aborts() {
+    if env::var(CORRUPTION_ENV).is_ok() {
+        defs::reset_runtime();
+        let f = Function {
+            ty: FunctionType::Function,
+            name: ptr::null(),
+            arglist: ptr::null(),
+            arg_count: 0,
+            is_macro: false,
+            invoke_f_ptr: function_without_apply_pointer_aborts as *const _,
+            apply_to_f_ptr: ptr::null(),
+            has_restarg: false,
+        };
+        defs::unlisp_rt_check_arity(&f, 0);
+        return;
+    }
+
+    assert!(
+        stderr_of_child("function_without_apply_pointer_aborts", "apply").contains(
+            "unlisp debug runtime: function without an apply pointer called by \
+             unlisp_rt_check_arity"
+        )
+    );
+}
@@ -0,0 +1,44 @@
+#![feature(test)]
+
+extern crate test;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+use test::Bencher;
+use unlispc::codegen::context::CodegenContext;
+use unlispc::{reader, repr};
+
+fn bench_sum(b: &mut Bencher, list_src: &str) {
+    with_stdlib_ctx(|ctx: &mut CodegenContext| {
+        let defvar = format!("(defvar xs {})", list_src);
+        for result in eval_in_ctx(ctx, &defvar) {
+            result.unwrap();
+        }
+
+        let mut input = "(apply (symf +) xs)".as_bytes();
+        let form = reader::Reader::create(&mut input)
+            .read_form()
+            .unwrap()
+            .unwrap();
+        let hir = repr::form_to_hir_with_transforms(&form).unwrap();
+
+        ctx.reinitialize();
+        let sum = ctx.compile_hirs(&[hir]).unwrap();
+
+        b.iter(|| unsafe {
+            unlisp_rt::exceptions::run_with_global_ex_handler(|| sum.call()).unwrap()
+        });
+    });
+}
+
+#[bench]
+fn bench_sum_int_list(b: &mut Bencher) {
+    bench_sum(b, "(range 100000)");
+}
+
+#[bench]
+fn bench_sum_mixed_list(b: &mut Bencher) {
+    bench_sum(b, "(cons 0.5 (range 100000))");
+}
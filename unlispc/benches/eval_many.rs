@@ -0,0 +1,51 @@
+#![feature(test)]
+
+extern crate test;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+use test::Bencher;
+use unlispc::codegen::context::CodegenContext;
+use unlispc::repr::HIR;
+use unlispc::{reader, repr};
+
+// evaluates 1,000 small top-level forms with `eval`
+fn bench_small_forms(b: &mut Bencher, eval: unsafe fn(&mut CodegenContext, &[HIR]) -> usize) {
+    with_stdlib_ctx(|ctx: &mut CodegenContext| {
+        let src: String = (0..1000).map(|i| format!("(list {} 1) ", i)).collect();
+        let hirs: Vec<_> = reader::Reader::from_str(&src)
+            .read_all_forms()
+            .unwrap()
+            .iter()
+            .map(|(_, form)| repr::form_to_hir_with_transforms(form).unwrap())
+            .collect();
+
+        b.iter(|| unsafe { eval(ctx, &hirs) });
+    });
+}
+
+unsafe fn one_at_a_time(ctx: &mut CodegenContext, hirs: &[HIR]) -> usize {
+    ctx.eval_hir_batch(hirs)
+        .into_iter()
+        .filter(Result::is_ok)
+        .count()
+}
+
+unsafe fn batched(ctx: &mut CodegenContext, hirs: &[HIR]) -> usize {
+    ctx.eval_many(hirs)
+        .into_iter()
+        .filter(Result::is_ok)
+        .count()
+}
+
+#[bench]
+fn bench_small_forms_one_at_a_time(b: &mut Bencher) {
+    bench_small_forms(b, one_at_a_time);
+}
+
+#[bench]
+fn bench_small_forms_batched(b: &mut Bencher) {
+    bench_small_forms(b, batched);
+}
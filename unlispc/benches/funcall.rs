@@ -0,0 +1,55 @@
+#![feature(test)]
+
+extern crate test;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+use test::Bencher;
+use unlisp_rt::exceptions::run_with_global_ex_handler;
+use unlispc::codegen::context::CodegenContext;
+use unlispc::{reader, repr};
+
+// a loop calling a 3-argument function through a variable 10M times, in the
+// way the call form given builds the call
+fn bench_call_loop(b: &mut Bencher, call: &str) {
+    with_stdlib_ctx(|ctx: &mut CodegenContext| {
+        let results = eval_in_ctx(
+            ctx,
+            &format!(
+                "(defun add3 (a b c) (+ a (+ b c)))
+                 (defun call-loop (f n acc)
+                   (if (equal n 0) acc (call-loop f (- n 1) {})))",
+                call
+            ),
+        );
+
+        for result in results {
+            result.unwrap();
+        }
+
+        let form = reader::Reader::from_str("(call-loop (symf add3) 10000000 0)")
+            .read_form()
+            .unwrap()
+            .unwrap();
+        let hir = repr::form_to_hir_with_transforms(&form).unwrap();
+
+        ctx.reinitialize();
+        let call_loop = ctx.compile_hirs(&[hir]).unwrap();
+
+        unlisp_rt::stats::reset();
+        b.iter(|| unsafe { run_with_global_ex_handler(|| call_loop.call()).unwrap() });
+        assert_eq!(unlisp_rt::stats().conses, 0);
+    });
+}
+
+#[bench]
+fn bench_funcall(b: &mut Bencher) {
+    bench_call_loop(b, "(funcall f acc 1 0)");
+}
+
+#[bench]
+fn bench_apply_spread(b: &mut Bencher) {
+    bench_call_loop(b, "(apply f acc 1 0 nil)");
+}
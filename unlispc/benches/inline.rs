@@ -0,0 +1,52 @@
+#![feature(test)]
+
+extern crate test;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+use test::Bencher;
+use unlisp_rt::exceptions::run_with_global_ex_handler;
+use unlispc::codegen::context::CodegenContext;
+use unlispc::{inline, reader, repr};
+
+// a loop calling a one-line function declared inline on every iteration,
+// compiled with the given inline threshold
+fn bench_count_up(b: &mut Bencher, threshold: usize) {
+    with_stdlib_ctx(|ctx: &mut CodegenContext| {
+        inline::set_inline_threshold(threshold);
+        let results = eval_in_ctx(
+            ctx,
+            "(defun bump (x) (declare (inline)) (+ x 1))
+             (defun count-up (n acc)
+               (if (equal n 0) acc (count-up (- n 1) (bump acc))))",
+        );
+        inline::set_inline_threshold(inline::DEFAULT_INLINE_THRESHOLD);
+
+        for result in results {
+            result.unwrap();
+        }
+
+        let form = reader::Reader::from_str("(count-up 100000 0)")
+            .read_form()
+            .unwrap()
+            .unwrap();
+        let hir = repr::form_to_hir_with_transforms(&form).unwrap();
+
+        ctx.reinitialize();
+        let count_up = ctx.compile_hirs(&[hir]).unwrap();
+
+        b.iter(|| unsafe { run_with_global_ex_handler(|| count_up.call()).unwrap() });
+    });
+}
+
+#[bench]
+fn bench_tiny_function_inlined(b: &mut Bencher) {
+    bench_count_up(b, inline::DEFAULT_INLINE_THRESHOLD);
+}
+
+#[bench]
+fn bench_tiny_function_called(b: &mut Bencher) {
+    bench_count_up(b, 0);
+}
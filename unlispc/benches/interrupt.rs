@@ -0,0 +1,51 @@
+#![feature(test)]
+
+extern crate test;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+use test::Bencher;
+use unlisp_rt::exceptions::run_with_global_ex_handler;
+use unlispc::codegen::context::CodegenContext;
+use unlispc::{reader, repr};
+
+// a tail recursive loop of 10M iterations, compiled with or without the
+// interrupt check every function entry makes
+fn bench_loop(b: &mut Bencher, interrupt_checks: bool) {
+    with_stdlib_ctx(|ctx: &mut CodegenContext| {
+        ctx.set_interrupt_checks(interrupt_checks);
+
+        let results = eval_in_ctx(
+            ctx,
+            "(defun count-down (n acc)
+               (if (equal n 0) acc (count-down (- n 1) (+ acc 1))))",
+        );
+
+        for result in results {
+            result.unwrap();
+        }
+
+        let form = reader::Reader::from_str("(count-down 10000000 0)")
+            .read_form()
+            .unwrap()
+            .unwrap();
+        let hir = repr::form_to_hir_with_transforms(&form).unwrap();
+
+        ctx.reinitialize();
+        let count_down = ctx.compile_hirs(&[hir]).unwrap();
+
+        b.iter(|| unsafe { run_with_global_ex_handler(|| count_down.call()).unwrap() });
+    });
+}
+
+#[bench]
+fn bench_loop_with_interrupt_checks(b: &mut Bencher) {
+    bench_loop(b, true);
+}
+
+#[bench]
+fn bench_loop_without_interrupt_checks(b: &mut Bencher) {
+    bench_loop(b, false);
+}
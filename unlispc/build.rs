@@ -0,0 +1,45 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+// the compiler and the runtime it generates calls to, a change to either may
+// change the code compiled from the same source
+const SOURCE_DIRS: &[&str] = &["src", "../unlisp_rt/src"];
+
+fn source_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = fs::read_dir(dir).expect("couldn't read source directory");
+
+    for entry in entries {
+        let path = entry.expect("couldn't read source directory").path();
+
+        if path.is_dir() {
+            source_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+// Sets UNLISPC_BUILD_HASH to a hash of the sources this build is made from,
+// which object file fingerprints include, see fingerprint::fingerprint_hirs.
+fn main() {
+    let mut hasher = DefaultHasher::new();
+
+    for dir in SOURCE_DIRS {
+        println!("cargo:rerun-if-changed={}", dir);
+
+        let mut files = vec![];
+        source_files(Path::new(dir), &mut files);
+        files.sort();
+
+        for file in files {
+            file.hash(&mut hasher);
+            fs::read(&file)
+                .expect("couldn't read source file")
+                .hash(&mut hasher);
+        }
+    }
+
+    println!("cargo:rustc-env=UNLISPC_BUILD_HASH={:016x}", hasher.finish());
+}
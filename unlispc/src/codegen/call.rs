@@ -1,7 +1,11 @@
 use super::common::*;
 use super::context::CodegenContext;
+use super::static_value::compile_static_symbol_value;
 use super::top_level::compile_hir;
-use crate::repr::Call;
+use crate::error::{Error, ErrorType};
+use crate::repr::{Call, HIR};
+use unlisp_rt::defs::MAX_INVOKE_ARGS;
+use unlisp_rt::symbols;
 
 use inkwell::types::BasicType;
 use inkwell::values::{BasicValueEnum, PointerValue};
@@ -10,12 +14,50 @@ use inkwell::{AddressSpace, IntPredicate};
 use std::iter;
 use std::rc::Rc;
 
+// Calls with more than MAX_INVOKE_ARGS arguments pass them in an array and
+// go through the apply function. Long invoke argument lists end up on the
+// stack, where LLVM and the C ABI of natives and va_arg don't lay objects out
+// alike. The runtime's apply uses the same limit.
+fn codegen_array_call(
+    ctx: &mut CodegenContext,
+    fn_obj_ptr: PointerValue,
+    compiled_args: Vec<BasicValueEnum>,
+) -> BasicValueEnum {
+    let object_ty = ctx.lookup_known_type("unlisp_rt_object");
+    let i64_ty = ctx.llvm_ctx.i64_type();
+    let n_args = i64_ty.const_int(compiled_args.len() as u64, false);
+
+    let args_array = ctx
+        .builder
+        .build_array_alloca(object_ty, n_args, "args_array");
+
+    for (i, arg) in compiled_args.into_iter().enumerate() {
+        let arg_ptr = unsafe {
+            ctx.builder
+                .build_gep(args_array, &[i64_ty.const_int(i as u64, false)], "arg_ptr")
+        };
+        ctx.builder.build_store(arg_ptr, arg);
+    }
+
+    // never a tail call, the array is in this frame
+    ctx.builder
+        .build_call(
+            ctx.lookup_known_fn("unlisp_rt_apply_args_array"),
+            &[fn_obj_ptr.into(), n_args.into(), args_array.into()],
+            "array_call_result",
+        )
+        .try_as_basic_value()
+        .left()
+        .unwrap()
+}
+
 fn codegen_simple_call(
     ctx: &mut CodegenContext,
     call: &Call,
     fn_obj_ptr: PointerValue,
     invoke_ptr: PointerValue,
     mut compiled_args: Vec<BasicValueEnum>,
+    is_tail: bool,
 ) -> BasicValueEnum {
     let function_ptr_ty = ctx
         .lookup_known_type("unlisp_rt_function")
@@ -39,15 +81,14 @@ fn codegen_simple_call(
     compiled_args.push(fn_obj_ptr.into());
     compiled_args.reverse();
 
-    ctx.builder
-        .build_call(
-            invoke_ptr_cast.into_pointer_value(),
-            compiled_args.as_slice(),
-            "invoke_result",
-        )
-        .try_as_basic_value()
-        .left()
-        .unwrap()
+    let call_site = ctx.builder.build_call(
+        invoke_ptr_cast.into_pointer_value(),
+        compiled_args.as_slice(),
+        "invoke_result",
+    );
+    call_site.set_tail_call(is_tail);
+
+    call_site.try_as_basic_value().left().unwrap()
 }
 
 fn codegen_vararg_call(
@@ -57,6 +98,7 @@ fn codegen_vararg_call(
     arity: BasicValueEnum,
     invoke_ptr: PointerValue,
     mut compiled_args: Vec<BasicValueEnum>,
+    is_tail: bool,
 ) -> BasicValueEnum {
     let function_ptr_ty = ctx
         .lookup_known_type("unlisp_rt_function")
@@ -90,15 +132,39 @@ fn codegen_vararg_call(
     compiled_args.push(fn_obj_ptr.into());
     compiled_args.reverse();
 
-    ctx.builder
-        .build_call(
-            invoke_ptr_cast.into_pointer_value(),
-            compiled_args.as_slice(),
-            "vararg_invoke_result",
-        )
-        .try_as_basic_value()
-        .left()
-        .unwrap()
+    let call_site = ctx.builder.build_call(
+        invoke_ptr_cast.into_pointer_value(),
+        compiled_args.as_slice(),
+        "vararg_invoke_result",
+    );
+    call_site.set_tail_call(is_tail);
+
+    call_site.try_as_basic_value().left().unwrap()
+}
+
+// literals, quotes and closures are compiled without calls
+fn makes_calls(hir: &HIR) -> bool {
+    !matches!(hir, HIR::Literal(_) | HIR::Quote(_) | HIR::Closure(_))
+}
+
+// Records the call's span for the error raised by the call or its checks,
+// again right before it's made when its arguments made calls of their own.
+fn codegen_set_source_location(ctx: &mut CodegenContext, call: &Call) {
+    let span = match call.span {
+        Some(span) if ctx.emits_source_locations() => span,
+        _ => return,
+    };
+
+    let i32_ty = ctx.llvm_ctx.i32_type();
+    ctx.builder.build_call(
+        ctx.lookup_known_fn("unlisp_rt_set_source_location"),
+        &[
+            i32_ty.const_int(span.file as u64, false).into(),
+            i32_ty.const_int(span.line as u64, false).into(),
+            i32_ty.const_int(span.col as u64, false).into(),
+        ],
+        "set_source_location",
+    );
 }
 
 fn codegen_ok_arity_block(
@@ -106,7 +172,22 @@ fn codegen_ok_arity_block(
     call: &Call,
     fn_obj_ptr: PointerValue,
     arity: BasicValueEnum,
+    is_tail: bool,
 ) -> CompileResult {
+    let compiled_args = call
+        .args
+        .iter()
+        .map(|arg| compile_hir(ctx, arg))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if call.args.iter().any(makes_calls) {
+        codegen_set_source_location(ctx, call);
+    }
+
+    if compiled_args.len() > MAX_INVOKE_ARGS {
+        return Ok(codegen_array_call(ctx, fn_obj_ptr, compiled_args));
+    }
+
     let invoke_ptr_ptr = unsafe {
         ctx.builder
             .build_struct_gep(fn_obj_ptr, 5, "invoke_ptr_ptr")
@@ -117,12 +198,6 @@ fn codegen_ok_arity_block(
         .build_load(invoke_ptr_ptr, "invoke_ptr")
         .into_pointer_value();
 
-    let compiled_args = call
-        .args
-        .iter()
-        .map(|arg| compile_hir(ctx, arg))
-        .collect::<Result<Vec<_>, _>>()?;
-
     let has_restarg_ptr = unsafe {
         ctx.builder
             .build_struct_gep(fn_obj_ptr, 7, "has_restarg_ptr")
@@ -139,13 +214,20 @@ fn codegen_ok_arity_block(
         arity,
         invoke_ptr,
         compiled_args.clone(),
+        is_tail,
     );
     ctx.builder.build_unconditional_branch(&merge_vararg_block);
     ctx.exit_block();
 
     let no_vararg_block = ctx.enter_block();
-    let no_vararg_result =
-        codegen_simple_call(ctx, call, fn_obj_ptr, invoke_ptr, compiled_args.clone());
+    let no_vararg_result = codegen_simple_call(
+        ctx,
+        call,
+        fn_obj_ptr,
+        invoke_ptr,
+        compiled_args.clone(),
+        is_tail,
+    );
     ctx.builder.build_unconditional_branch(&merge_vararg_block);
     ctx.exit_block();
 
@@ -173,7 +255,8 @@ fn codegen_fn_exists_block(
     ctx: &mut CodegenContext,
     call: &Call,
     fn_obj_ptr: PointerValue,
-    sym_name_ptr: BasicValueEnum,
+    name_ptr: BasicValueEnum,
+    is_tail: bool,
 ) -> CompileResult {
     let args_count = call.args.len() as u64;
 
@@ -200,7 +283,7 @@ fn codegen_fn_exists_block(
     ctx.builder.build_call(
         ctx.lookup_known_fn("unlisp_rt_raise_arity_error"),
         &[
-            sym_name_ptr,
+            name_ptr,
             arity.into(),
             ctx.llvm_ctx.i64_type().const_int(args_count, false).into(),
         ],
@@ -210,7 +293,7 @@ fn codegen_fn_exists_block(
     ctx.exit_block();
 
     let enter_ok_arity_block = ctx.enter_block();
-    let result = codegen_ok_arity_block(ctx, call, fn_obj_ptr, arity)?;
+    let result = codegen_ok_arity_block(ctx, call, fn_obj_ptr, arity, is_tail)?;
     let exit_ok_arity_block = ctx.exit_block();
 
     ctx.builder.build_conditional_branch(
@@ -220,10 +303,87 @@ fn codegen_fn_exists_block(
     );
     ctx.replace_cur_block(exit_ok_arity_block);
 
+    // values of a call end unless it's returned, see unlisp_rt::values
+    if !is_tail {
+        ctx.builder
+            .build_call(ctx.lookup_known_fn("unlisp_rt_clear_values"), &[], "clear_values");
+    }
+
     Ok(result)
 }
 
-pub fn compile_call(ctx: &mut CodegenContext, call: &Call) -> CompileResult {
+// The head is a lexical variable, so the function is taken from its value
+// (raising a cast error for non-functions) and arity errors name the function
+// itself rather than the variable.
+fn compile_lexical_call(ctx: &mut CodegenContext, call: &Call, is_tail: bool) -> CompileResult {
+    codegen_set_source_location(ctx, call);
+
+    let fn_obj = ctx.lookup_local_name(&call.fn_name).ok_or_else(|| {
+        Error::new(
+            ErrorType::Compilation,
+            format!("undefined symbol: {}", call.fn_name),
+        )
+    })?;
+
+    let fn_obj_ptr = ctx
+        .builder
+        .build_call(
+            ctx.lookup_known_fn("unlisp_rt_function_from_obj"),
+            &[fn_obj],
+            "fn_obj_ptr",
+        )
+        .try_as_basic_value()
+        .left()
+        .unwrap()
+        .into_pointer_value();
+
+    let name_ptr_ptr = unsafe { ctx.builder.build_struct_gep(fn_obj_ptr, 1, "name_ptr_ptr") };
+    let name_ptr = ctx.builder.build_load(name_ptr_ptr, "name_ptr");
+
+    codegen_fn_exists_block(ctx, call, fn_obj_ptr, name_ptr, is_tail)
+}
+
+// A call that doesn't fit the function's current definition is reported at
+// compile time. It still checks the arity when it runs, the function may be
+// redefined by then. Recursive calls of a function being (re)defined aren't
+// checked against its old definition.
+fn warn_on_wrong_arity(ctx: &mut CodegenContext, call: &Call) {
+    if ctx.is_defining(&call.fn_name) {
+        return;
+    }
+
+    let f = unsafe { (*symbols::get_or_intern_symbol(call.fn_name.clone())).function };
+    if f.is_null() {
+        return;
+    }
+
+    let (arity, has_restarg) = unsafe { ((*f).arg_count, (*f).has_restarg) };
+    let args_count = call.args.len() as u64;
+
+    if args_count < arity || (!has_restarg && args_count != arity) {
+        let warning = format!(
+            "wrong number of arguments ({}) passed to {}, which takes {}{}",
+            args_count,
+            call.fn_name,
+            if has_restarg { "at least " } else { "" },
+            arity
+        );
+        ctx.warn_at(warning, call.span);
+    }
+}
+
+pub fn compile_call(ctx: &mut CodegenContext, call: &Call, is_tail: bool) -> CompileResult {
+    if call.is_lexical {
+        return compile_lexical_call(ctx, call, is_tail);
+    }
+
+    if let Some(result) = compile_static_symbol_value(ctx, call) {
+        return result;
+    }
+
+    warn_on_wrong_arity(ctx, call);
+    codegen_set_source_location(ctx, call);
+
     let sym_name_ptr = ctx.str_literal_as_i8_ptr(call.fn_name.as_str());
 
     let intern_fn = ctx.lookup_known_fn("unlisp_rt_intern_sym");
@@ -266,7 +426,7 @@ pub fn compile_call(ctx: &mut CodegenContext, call: &Call) -> CompileResult {
     ctx.exit_block();
 
     let fn_exists_block = ctx.enter_block();
-    let result = codegen_fn_exists_block(ctx, call, fn_obj_ptr, sym_name_ptr)?;
+    let result = codegen_fn_exists_block(ctx, call, fn_obj_ptr, sym_name_ptr, is_tail)?;
     let exit_fn_exists_block = ctx.exit_block();
 
     ctx.builder
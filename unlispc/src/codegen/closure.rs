@@ -1,28 +1,129 @@
 use crate::error::{Error, ErrorType};
-use crate::repr::Closure;
-use unlisp_rt::defs::Function;
+use crate::inline;
+use crate::repr::{ArgType, Closure};
+use unlisp_rt::defs::{Function, ObjType};
+use unlisp_rt::stats::AllocKind;
 
 use inkwell::types::{BasicType, StructType};
-use inkwell::values::{BasicValueEnum, FunctionValue};
-use inkwell::AddressSpace;
+use inkwell::values::{BasicValueEnum, FunctionValue, IntValue, PointerValue};
+use inkwell::{AddressSpace, IntPredicate};
 
 use super::common::*;
 use super::context::CodegenContext;
-use super::top_level::compile_hirs;
+use super::top_level::compile_hirs_in_tail_pos;
 
 use std::iter;
+use std::rc::Rc;
 
 fn is_global_name(ctx: &CodegenContext, name: &String) -> bool {
     ctx.lookup_local_name(name).is_none() && ctx.is_global_var(name)
 }
 
+fn codegen_type_tag_check(ctx: &mut CodegenContext, arg: BasicValueEnum, ty: ArgType) -> IntValue {
+    let tag = ctx
+        .builder
+        .build_extract_value(arg.into_struct_value(), 0, "ty_tag")
+        .unwrap()
+        .into_int_value();
+
+    let tag_is = |ctx: &CodegenContext, obj_ty: ObjType| {
+        ctx.builder.build_int_compare(
+            IntPredicate::EQ,
+            tag,
+            ctx.llvm_ctx.i32_type().const_int(obj_ty as u64, false),
+            "tag_matches",
+        )
+    };
+
+    match ty {
+        ArgType::Integer => ctx.builder.build_or(
+            tag_is(ctx, ObjType::Int64),
+            tag_is(ctx, ObjType::BigInt),
+            "is_integer",
+        ),
+        ArgType::Symbol => tag_is(ctx, ObjType::Symbol),
+        ArgType::Function => tag_is(ctx, ObjType::Function),
+        ArgType::String => tag_is(ctx, ObjType::String),
+        ArgType::List => {
+            let is_nil = ctx
+                .builder
+                .build_call(
+                    ctx.lookup_known_fn("unlisp_rt_object_is_nil"),
+                    &[arg],
+                    "is_nil",
+                )
+                .try_as_basic_value()
+                .left()
+                .unwrap()
+                .into_int_value();
+
+            ctx.builder
+                .build_or(tag_is(ctx, ObjType::Cons), is_nil, "is_list")
+        }
+    }
+}
+
+// unannotated parameters don't get any code emitted for them
+fn codegen_arg_type_checks(
+    ctx: &mut CodegenContext,
+    closure: &Closure,
+    fn_name: &str,
+    args: &[BasicValueEnum],
+) {
+    let typed_args = closure
+        .lambda
+        .arglist
+        .iter()
+        .zip(closure.lambda.arg_types.iter())
+        .zip(args.iter());
+
+    for ((arg_name, ty), arg) in typed_args {
+        let ty = match ty {
+            Some(ty) => *ty,
+            None => continue,
+        };
+
+        let type_ok = codegen_type_tag_check(ctx, *arg, ty);
+        let ok_block = ctx.append_block();
+
+        let type_error_block = ctx.enter_block();
+
+        let actual_ty_name = ctx
+            .builder
+            .build_call(
+                ctx.lookup_known_fn("unlisp_rt_object_type_name"),
+                &[*arg],
+                "actual_type",
+            )
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+
+        let arg_name_ptr = ctx.str_literal_as_i8_ptr(arg_name.as_str());
+        let fn_name_ptr = ctx.str_literal_as_i8_ptr(fn_name);
+        let expected_ty_name = ctx.str_literal_as_i8_ptr(ty.name());
+
+        ctx.builder.build_call(
+            ctx.lookup_known_fn("unlisp_rt_raise_type_error"),
+            &[arg_name_ptr, fn_name_ptr, expected_ty_name, actual_ty_name],
+            "raise_type_err",
+        );
+        ctx.builder.build_unreachable();
+        ctx.exit_block();
+
+        ctx.builder
+            .build_conditional_branch(type_ok, &ok_block, &type_error_block);
+        ctx.replace_cur_block(Rc::new(ok_block));
+    }
+}
+
 fn codegen_raw_fn(ctx: &mut CodegenContext, closure: &Closure) -> Result<FunctionValue, Error> {
-    let fn_name = closure
+    let display_name = closure
         .lambda
         .name
         .as_ref()
         .map_or("lambda", |n| n.as_str());
-    let fn_name = ctx.mangle_str(fn_name);
+    let fn_name = ctx.mangle_nested_str(display_name);
 
     let free_vars_no_globals: Vec<_> = closure
         .free_vars
@@ -46,6 +147,15 @@ fn codegen_raw_fn(ctx: &mut CodegenContext, closure: &Closure) -> Result<Functio
     ctx.push_env();
     ctx.enter_fn_block(&function);
 
+    // loops are tail calls, which come back through here too
+    if ctx.emits_interrupt_checks() {
+        ctx.builder.build_call(
+            ctx.lookup_known_fn("unlisp_rt_check_interrupt"),
+            &[],
+            "check_interrupt",
+        );
+    }
+
     let args: Vec<_> = free_vars_no_globals
         .into_iter()
         .map(|n| (n, true))
@@ -54,18 +164,30 @@ fn codegen_raw_fn(ctx: &mut CodegenContext, closure: &Closure) -> Result<Functio
         .collect();
 
     let param_iter = function.get_param_iter();
+    let mut own_params = vec![];
 
     for (arg, (arg_name, is_free)) in param_iter.zip(args.into_iter()) {
         arg.as_struct_value().set_name(arg_name);
         ctx.save_env_mapping(arg_name.clone(), arg, is_free);
+
+        if !is_free {
+            own_params.push(arg);
+        }
     }
 
-    let val = compile_hirs(ctx, closure.lambda.body.as_slice())?;
+    codegen_arg_type_checks(ctx, closure, display_name, &own_params);
+
+    ctx.push_definition_name(display_name);
+    let val = compile_hirs_in_tail_pos(ctx, closure.lambda.body.as_slice(), true);
+    ctx.pop_definition_name();
+    let val = val?;
 
     ctx.builder.build_return(Some(&val));
 
     ctx.verify_or_panic(&function, "raw");
-    ctx.pass_manager.run_on(&function);
+    if closure.lambda.declarations.optimize {
+        ctx.pass_manager.run_on(&function);
+    }
 
     ctx.exit_block();
     ctx.pop_env();
@@ -79,7 +201,7 @@ fn codegen_closure_struct(ctx: &mut CodegenContext, closure: &Closure) -> Struct
         |n| format!("{}_closure_struct", n),
     );
 
-    let struct_name = ctx.mangle_str(struct_name);
+    let struct_name = ctx.mangle_nested_str(struct_name);
 
     let struct_ty = ctx.llvm_ctx.opaque_struct_type(struct_name.as_str());
 
@@ -161,7 +283,7 @@ fn codegen_invoke_fn(
         .as_ref()
         .map_or_else(|| "invoke_closure".to_string(), |n| format!("invoke_{}", n));
 
-    let fn_name = ctx.mangle_str(fn_name);
+    let fn_name = ctx.mangle_nested_str(fn_name);
     let obj_struct_ty = ctx.lookup_known_type("unlisp_rt_object");
     let has_restarg = closure.lambda.restarg.is_some();
 
@@ -226,23 +348,52 @@ fn codegen_invoke_fn(
         raw_fn_args.push(codegen_valist_into_list_conversion(ctx, n_vararg.unwrap()));
     }
 
-    let raw_call = ctx
+    let raw_call_site = ctx
         .builder
-        .build_call(raw_fn, raw_fn_args.as_slice(), "raw_fn_call")
-        .try_as_basic_value()
-        .left()
-        .unwrap();
+        .build_call(raw_fn, raw_fn_args.as_slice(), "raw_fn_call");
+    raw_call_site.set_tail_call(true);
+
+    let raw_call = raw_call_site.try_as_basic_value().left().unwrap();
 
     ctx.builder.build_return(Some(&raw_call));
 
     ctx.verify_or_panic(&function, "invoke");
-    ctx.pass_manager.run_on(&function);
+    if closure.lambda.declarations.optimize {
+        ctx.pass_manager.run_on(&function);
+    }
 
     ctx.exit_block();
 
     function
 }
 
+// The first element of a list passed to an apply function, read from the
+// cons cell in place. The list is long enough, the arity was checked before
+// the call.
+fn codegen_list_car(ctx: &mut CodegenContext, list: BasicValueEnum) -> BasicValueEnum {
+    let cons_ptr_ty = ctx
+        .lookup_known_type("unlisp_rt_cons")
+        .as_struct_type()
+        .ptr_type(AddressSpace::Generic);
+    let object_ptr_ty = ctx
+        .lookup_known_type("unlisp_rt_object")
+        .as_struct_type()
+        .ptr_type(AddressSpace::Generic);
+
+    let cons_ptr = ctx
+        .builder
+        .build_bitcast(list, cons_ptr_ty, "cons_ptr")
+        .into_pointer_value();
+    let car_ptr_ptr = unsafe { ctx.builder.build_struct_gep(cons_ptr, 0, "car_ptr_ptr") };
+    let car_ptr = ctx.builder.build_load(car_ptr_ptr, "car_ptr");
+    let car_ptr = ctx
+        .builder
+        .build_bitcast(car_ptr, object_ptr_ty, "car_obj_ptr")
+        .into_pointer_value();
+
+    ctx.builder.build_load(car_ptr, "arg")
+}
+
 fn codegen_apply_to_fn(
     ctx: &mut CodegenContext,
     closure: &Closure,
@@ -254,7 +405,7 @@ fn codegen_apply_to_fn(
         .name
         .as_ref()
         .map_or_else(|| "apply_closure".to_string(), |n| format!("apply_{}", n));
-    let fn_name = ctx.mangle_str(fn_name);
+    let fn_name = ctx.mangle_nested_str(fn_name);
     let list_ty = ctx.llvm_ctx.i8_type().ptr_type(AddressSpace::Generic);
     let obj_struct_ty = ctx.lookup_known_type("unlisp_rt_object");
     let has_restarg = closure.lambda.restarg.is_some();
@@ -294,18 +445,12 @@ fn codegen_apply_to_fn(
         raw_fn_args.push(arg);
     }
 
-    let list_first_fn = ctx.lookup_known_fn("unlisp_rt_list_car");
     let list_rest_fn = ctx.lookup_known_fn("unlisp_rt_list_cdr");
 
     let mut cur_list = list_param;
 
     for _ in closure.lambda.arglist.iter() {
-        let arg = ctx
-            .builder
-            .build_call(list_first_fn, &[cur_list], "arg")
-            .try_as_basic_value()
-            .left()
-            .unwrap();
+        let arg = codegen_list_car(ctx, cur_list);
 
         raw_fn_args.push(arg);
         cur_list = ctx
@@ -327,47 +472,184 @@ fn codegen_apply_to_fn(
         raw_fn_args.push(obj);
     }
 
-    let raw_call = ctx
+    let raw_call_site = ctx
         .builder
-        .build_call(raw_fn, raw_fn_args.as_slice(), "raw_fn_call")
-        .try_as_basic_value()
-        .left()
-        .unwrap();
+        .build_call(raw_fn, raw_fn_args.as_slice(), "raw_fn_call");
+    raw_call_site.set_tail_call(true);
+
+    let raw_call = raw_call_site.try_as_basic_value().left().unwrap();
 
     ctx.builder.build_return(Some(&raw_call));
 
     ctx.verify_or_panic(&function, "apply");
-    ctx.pass_manager.run_on(&function);
+    if closure.lambda.declarations.optimize {
+        ctx.pass_manager.run_on(&function);
+    }
 
     ctx.exit_block();
 
     function
 }
 
+// names of the required parameters, same layout as the arglists of natives
+fn codegen_arglist_global(ctx: &mut CodegenContext, closure: &Closure) -> PointerValue {
+    let i8_ptr_ty = ctx.llvm_ctx.i8_type().ptr_type(AddressSpace::Generic);
+
+    let names: Vec<_> = closure
+        .lambda
+        .arglist
+        .iter()
+        .map(|name| {
+            ctx.get_or_globalize_str_literal(name.as_str())
+                .as_pointer_value()
+                .const_cast(i8_ptr_ty)
+        })
+        .collect();
+
+    let global_name = closure.lambda.name.as_ref().map_or_else(
+        || "arglist".to_string(),
+        |n| format!("{}_arglist", n),
+    );
+    let global_name = ctx.mangle_nested_str(global_name);
+
+    let array_ty = i8_ptr_ty.array_type(names.len() as u32);
+    let global = ctx.get_module().add_global(array_ty, None, &global_name);
+    global.set_initializer(&i8_ptr_ty.const_array(names.as_slice()));
+
+    global
+        .as_pointer_value()
+        .const_cast(i8_ptr_ty.ptr_type(AddressSpace::Generic))
+}
+
+// what --dump-closures prints, captured globals aren't stored but still
+// have a field
+fn describe_closure(ctx: &CodegenContext, closure: &Closure, name: &str) -> String {
+    let mut out = format!("closure {}\n", name);
+
+    out.push_str(&format!(
+        "  arglist: ({})\n",
+        closure.lambda.arglist.join(" ")
+    ));
+    let restarg = closure.lambda.restarg.as_ref();
+    out.push_str(&format!(
+        "  restarg: {}\n",
+        restarg.map_or("none", |r| r.as_str())
+    ));
+
+    if closure.free_vars.is_empty() {
+        out.push_str("  free variables: none\n");
+    } else {
+        out.push_str("  free variables:\n");
+        for (var, site) in closure.free_vars.iter().zip(closure.free_var_sites.iter()) {
+            out.push_str(&format!("    {}: {}\n", var, site.name()));
+        }
+    }
+
+    out.push_str("  fields:\n");
+    out.push_str(&format!(
+        "    0-{}: function header\n",
+        Function::FIELDS_COUNT - 1
+    ));
+
+    let stored: Vec<_> = closure
+        .free_vars
+        .iter()
+        .filter(|n| !is_global_name(ctx, n))
+        .collect();
+    let unused = closure.free_vars.len() - stored.len();
+    let field_names = stored
+        .iter()
+        .map(|n| n.as_str())
+        .chain(iter::repeat("unused").take(unused));
+
+    for (i, field) in field_names.enumerate() {
+        out.push_str(&format!(
+            "    {}: {}\n",
+            Function::FIELDS_COUNT as usize + i,
+            field
+        ));
+    }
+
+    out
+}
+
 pub fn compile_closure(ctx: &mut CodegenContext, closure: &Closure) -> CompileResult {
+    // anonymous lambdas are named after the definitions they're nested in,
+    // e.g. outer/lambda, named ones keep their own name for error messages
+    let name = closure
+        .lambda
+        .name
+        .clone()
+        .unwrap_or_else(|| ctx.qualified_name("lambda"));
+
+    // before the body, so enclosing closures come before nested ones
+    if ctx.dumps_closures() {
+        let description = describe_closure(ctx, closure, &name);
+        ctx.dump_closure(&description);
+    }
+
+    for option in closure.lambda.declarations.unknown.iter() {
+        let warning = format!("{}: unknown declare option {}, ignored", name, option);
+        ctx.warn(warning);
+    }
+
+    for option in closure.lambda.declarations.unparenthesized.iter() {
+        let warning = format!(
+            "{}: declare option {} isn't a list, ignored (write ({}))",
+            name, option, option
+        );
+        ctx.warn(warning);
+    }
+
+    if let Some(warning) = inline::declared_inline_warning(&closure.lambda) {
+        ctx.warn(warning);
+    }
+
     let raw_fn = codegen_raw_fn(ctx, closure)?;
     let struct_ty = codegen_closure_struct(ctx, closure);
     let invoke_fn = codegen_invoke_fn(ctx, closure, struct_ty, raw_fn);
     let apply_to_fn = codegen_apply_to_fn(ctx, closure, struct_ty, raw_fn);
 
-    let struct_ptr = ctx.builder.build_malloc(struct_ty, "closure_ptr");
+    let alloc_size = struct_ty.size_of().expect("closure struct should be sized");
+    let allocated = ctx
+        .builder
+        .build_call(
+            ctx.lookup_known_fn("unlisp_rt_allocate"),
+            &[
+                ctx.llvm_ctx
+                    .i32_type()
+                    .const_int(AllocKind::Closure as u64, false)
+                    .into(),
+                alloc_size.into(),
+            ],
+            "closure_mem",
+        )
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+
+    let struct_ptr = ctx
+        .builder
+        .build_bitcast(
+            allocated,
+            struct_ty.ptr_type(AddressSpace::Generic),
+            "closure_ptr",
+        )
+        .into_pointer_value();
 
     let struct_ty_ptr = unsafe { ctx.builder.build_struct_gep(struct_ptr, 0, "ty_ptr") };
 
     ctx.builder
         .build_store(struct_ty_ptr, ctx.llvm_ctx.i32_type().const_int(1, false));
 
-    let name = closure
-        .lambda
-        .name
-        .as_ref()
-        .map_or("lambda", |n| n.as_str());
     let name_ptr = ctx.str_literal_as_i8_ptr(name);
 
     let struct_name_ptr = unsafe { ctx.builder.build_struct_gep(struct_ptr, 1, "name_ptr") };
     ctx.builder.build_store(struct_name_ptr, name_ptr);
 
-    //TODO: arglist
+    let arglist_ptr = codegen_arglist_global(ctx, closure);
+    let struct_arglist_ptr = unsafe { ctx.builder.build_struct_gep(struct_ptr, 2, "arglist_ptr") };
+    ctx.builder.build_store(struct_arglist_ptr, arglist_ptr);
 
     let struct_arg_count_ptr =
         unsafe { ctx.builder.build_struct_gep(struct_ptr, 3, "arg_count_ptr") };
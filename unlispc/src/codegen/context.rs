@@ -1,29 +1,94 @@
 use crate::error;
-use crate::repr::HIR;
+use crate::inline;
+use crate::reader;
+use crate::repr::{self, Form, Span, HIR};
 use crate::runtime_defs;
 
+use super::common::CompileResult;
+use super::ir_dump;
+use super::literal::const_object;
 use super::top_level::compile_top_level_hirs;
+use super::unit::{CompilationUnit, SourceDescriptor, UnitError, UnitReport};
 
 use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
 use inkwell::execution_engine::{ExecutionEngine, JitFunction};
-use inkwell::module::Module;
+use inkwell::module::{Linkage, Module};
 use inkwell::passes::PassManager;
 use inkwell::targets::*;
-use inkwell::types::BasicTypeEnum;
+use inkwell::types::{BasicType, BasicTypeEnum};
 use inkwell::values::{BasicValueEnum, FunctionValue, GlobalValue, PointerValue};
 use inkwell::AddressSpace;
 use inkwell::OptimizationLevel;
 
 use unlisp_rt;
+use unlisp_rt::defs::{ObjType, Object};
 
 use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::iter;
+use std::mem;
 use std::path::Path;
 use std::rc::Rc;
+use std::slice;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+const CODEGEN_OPT_LEVEL: OptimizationLevel = OptimizationLevel::Less;
+
+const MANGLING_MARKER: &str = "__unlisp_";
+
+// Run on every compiled function, in order.
+const FUNCTION_PASSES: &[(&str, fn(&PassManager<FunctionValue>))] = &[
+    // ("instcombine", PassManager::add_instruction_combining_pass),
+    // ("reassociate", PassManager::add_reassociate_pass),
+    // ("gvn", PassManager::add_gvn_pass),
+    // ("simplifycfg", PassManager::add_cfg_simplification_pass),
+    // ("basicaa", PassManager::add_basic_alias_analysis_pass),
+    // ("mem2reg", PassManager::add_promote_memory_to_register_pass),
+    // ("instcombine", PassManager::add_instruction_combining_pass),
+    // ("reassociate", PassManager::add_reassociate_pass),
+];
+
+/// Describes the optimization pipeline, one step per line: the function
+/// passes followed by the codegen optimization level used for both JIT and
+/// object code.
+pub fn pass_pipeline() -> Vec<String> {
+    let mut steps: Vec<_> = FUNCTION_PASSES
+        .iter()
+        .map(|(name, _)| format!("function pass: {}", name))
+        .collect();
+
+    if steps.is_empty() {
+        steps.push("function passes: none".to_string());
+    }
+
+    let opt_level = match CODEGEN_OPT_LEVEL {
+        OptimizationLevel::None => "none (-O0)",
+        OptimizationLevel::Less => "less (-O1)",
+        OptimizationLevel::Default => "default (-O2)",
+        OptimizationLevel::Aggressive => "aggressive (-O3)",
+    };
+    steps.push(format!("codegen opt level: {}", opt_level));
+
+    steps
+}
 
 pub type CompiledFn = JitFunction<unsafe extern "C" fn() -> unlisp_rt::defs::Object>;
 
+/// Identifies a snapshot taken by `CodegenContext::begin_snapshot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotId(usize);
+
+// what rolling back to a snapshot restores
+struct Snapshot {
+    bindings: unlisp_rt::symbols::Bindings,
+    declared_syms: HashSet<String>,
+}
+
 struct EnvValue {
     val: BasicValueEnum,
     is_captured: bool,
@@ -36,13 +101,36 @@ pub struct CodegenContext {
     pub pass_manager: PassManager<FunctionValue>,
 
     execution_engine: ExecutionEngine,
+    opt_level: OptimizationLevel,
     counter: u64,
+    // the last id mangle_str gave out for each name
+    mangled_ids: HashMap<String, u64>,
     module: Module,
     blocks_stack: Vec<Rc<BasicBlock>>,
     envs: Vec<HashMap<String, EnvValue>>,
+    definition_names: Vec<String>,
     declared_syms: HashSet<String>,
     defined_str_literals: HashSet<String>,
     str_literal_globals: HashMap<String, GlobalValue>,
+    // the current block of the module's constructor, see module_constant
+    module_constants: Option<Rc<BasicBlock>>,
+    module_has_code: bool,
+    closure_dump_hook: Option<Box<dyn FnMut(&str)>>,
+    ir_dump_hook: Option<Box<dyn FnMut(&str)>>,
+    ir_dump_filter: Option<String>,
+    // functions with a body added by the current codegen_hirs call
+    new_functions: Vec<FunctionValue>,
+    warnings: Vec<String>,
+    // parallel to warnings, the call a warning is about when it's known
+    warning_spans: Vec<Option<Span>>,
+    warnings_are_errors: bool,
+    interrupt_checks: bool,
+    source_locations: bool,
+    static_values: bool,
+    // symbols whose value a module's constructor sets, see static_value.rs
+    static_symbols: HashSet<String>,
+    // active snapshots, oldest first
+    snapshots: Vec<Snapshot>,
 }
 
 impl CodegenContext {
@@ -51,21 +139,113 @@ impl CodegenContext {
         self.counter
     }
 
+    /// Makes a name unique in this context as `NAME__unlisp_N`, where `N`
+    /// counts from 1 the times `NAME` was mangled. Names depend only on what
+    /// the context compiled before, so the same input gets the same names
+    /// in every run, and a new function doesn't renumber the others.
     pub fn mangle_str(&mut self, s: impl Into<String>) -> String {
-        format!("{}__unlisp_{}", s.into(), self.gen_unique_int())
+        let s = s.into();
+        let id = self.mangled_ids.entry(s.clone()).or_insert(0);
+        *id += 1;
+
+        format!("{}{}{}", s, MANGLING_MARKER, id)
+    }
+
+    /// The name `mangle_str` made a mangled name from, other names are
+    /// returned as they are.
+    pub fn demangle(mangled: &str) -> &str {
+        let is_id = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+
+        match mangled.rfind(MANGLING_MARKER) {
+            Some(i) if is_id(&mangled[i + MANGLING_MARKER.len()..]) => &mangled[..i],
+            _ => mangled,
+        }
+    }
+
+    /// Like `mangle_str`, but prefixes the name with the enclosing function
+    /// definitions, e.g. `outer/lambda__unlisp_3`, so that generated symbols
+    /// show where they came from in profiles and IR dumps.
+    pub fn mangle_nested_str(&mut self, s: impl Into<String>) -> String {
+        let sanitized: String = self
+            .qualified_name(&s.into())
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "_./-".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+
+        self.mangle_str(sanitized)
+    }
+
+    pub fn qualified_name(&self, name: &str) -> String {
+        self.definition_names
+            .iter()
+            .map(|n| n.as_str())
+            .chain(iter::once(name))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    pub fn push_definition_name(&mut self, name: impl Into<String>) {
+        self.definition_names.push(name.into())
+    }
+
+    pub fn pop_definition_name(&mut self) {
+        self.definition_names.pop();
+    }
+
+    /// Whether code of a function with this name is being compiled.
+    pub fn is_defining(&self, name: &str) -> bool {
+        self.definition_names.iter().any(|n| n == name)
+    }
+
+    /// Records a warning about the code being compiled, the code is compiled
+    /// anyway.
+    pub fn warn(&mut self, warning: impl Into<String>) {
+        self.warn_at(warning, None);
+    }
+
+    /// Like `warn`, about the call at `span`.
+    pub fn warn_at(&mut self, warning: impl Into<String>, span: Option<Span>) {
+        self.warnings.push(warning.into());
+        self.warning_spans.push(span);
+    }
+
+    /// Makes forms that get warnings while they're compiled fail with a
+    /// compilation error instead of being run, the warnings are still
+    /// returned by `take_warnings`.
+    pub fn set_warnings_are_errors(&mut self, warnings_are_errors: bool) {
+        self.warnings_are_errors = warnings_are_errors;
+    }
+
+    /// Warnings recorded since the last call, in the order they were found.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        self.take_located_warnings()
+            .into_iter()
+            .map(|(warning, _)| warning)
+            .collect()
+    }
+
+    /// Like `take_warnings`, with the span of the call each warning is about
+    /// when it's known.
+    pub fn take_located_warnings(&mut self) -> Vec<(String, Option<Span>)> {
+        let spans = mem::replace(&mut self.warning_spans, vec![]);
+        mem::replace(&mut self.warnings, vec![])
+            .into_iter()
+            .zip(spans)
+            .collect()
     }
 
     fn make_pass_manager(module: &Module) -> PassManager<FunctionValue> {
         let fpm = PassManager::<FunctionValue>::create(module);
 
-        // fpm.add_instruction_combining_pass();
-        // fpm.add_reassociate_pass();
-        // fpm.add_gvn_pass();
-        // fpm.add_cfg_simplification_pass();
-        // fpm.add_basic_alias_analysis_pass();
-        // fpm.add_promote_memory_to_register_pass();
-        // fpm.add_instruction_combining_pass();
-        // fpm.add_reassociate_pass();
+        for (_, add_pass) in FUNCTION_PASSES.iter() {
+            add_pass(&fpm);
+        }
 
         fpm.initialize();
 
@@ -73,33 +253,55 @@ impl CodegenContext {
     }
 
     pub fn new() -> Self {
+        Self::with_opt_level(CODEGEN_OPT_LEVEL)
+    }
+
+    /// Like `new`, with JIT and object code generated at `opt_level`
+    /// instead of the default one `pass_pipeline` describes. Codegen below
+    /// Less doesn't turn tail calls into jumps.
+    pub fn with_opt_level(opt_level: OptimizationLevel) -> Self {
+        // JIT compiled code can parse data with read and read-all
+        unlisp_rt::predefined::set_reader(reader::read_objects);
+
         let llvm_ctx = Context::create();
         let module = llvm_ctx.create_module("mod_0");
         let ee = module
-            .create_jit_execution_engine(OptimizationLevel::None)
+            .create_jit_execution_engine(opt_level)
             .expect("couldn't create execution engine");
         let builder = llvm_ctx.create_builder();
 
         runtime_defs::gen_defs(&llvm_ctx, &module);
 
-        let mut s = Self {
+        Self {
             counter: 0,
+            mangled_ids: HashMap::new(),
             llvm_ctx: llvm_ctx,
             pass_manager: Self::make_pass_manager(&module),
             module: module,
             execution_engine: ee,
+            opt_level,
             builder: builder,
             blocks_stack: vec![],
             envs: vec![],
+            definition_names: vec![],
             defined_str_literals: HashSet::new(),
             str_literal_globals: HashMap::new(),
+            module_constants: None,
             declared_syms: HashSet::new(),
-        };
-
-        s.declare_global_var(&"nil".to_string());
-        s.declare_global_var(&"t".to_string());
-
-        s
+            module_has_code: false,
+            closure_dump_hook: None,
+            ir_dump_hook: None,
+            ir_dump_filter: None,
+            new_functions: vec![],
+            warnings: vec![],
+            warning_spans: vec![],
+            warnings_are_errors: false,
+            interrupt_checks: true,
+            source_locations: true,
+            static_values: false,
+            static_symbols: HashSet::new(),
+            snapshots: vec![],
+        }
     }
 
     pub fn reinitialize(&mut self) {
@@ -118,8 +320,101 @@ impl CodegenContext {
         self.pass_manager = Self::make_pass_manager(&module);
 
         self.blocks_stack = vec![];
+        self.definition_names = vec![];
         self.module = module;
         self.str_literal_globals = HashMap::new();
+        self.module_constants = None;
+        self.module_has_code = false;
+    }
+
+    /// Whether compiled functions check on entry if the evaluation was
+    /// interrupted, which `eval_str_with_timeout` relies on. The check is a
+    /// runtime call on every entry, see `benches/interrupt.rs` for its cost.
+    /// On by default, code compiled ahead of time can't be interrupted and
+    /// goes without.
+    pub fn set_interrupt_checks(&mut self, interrupt_checks: bool) {
+        self.interrupt_checks = interrupt_checks;
+    }
+
+    pub fn emits_interrupt_checks(&self) -> bool {
+        self.interrupt_checks
+    }
+
+    /// Whether calls with a span record it before they're made, so runtime
+    /// errors carry the location of the call that raised them. On by
+    /// default, the file names are known only to the process compiling the
+    /// code, so code compiled ahead of time goes without.
+    pub fn set_source_locations(&mut self, source_locations: bool) {
+        self.source_locations = source_locations;
+    }
+
+    pub fn emits_source_locations(&self) -> bool {
+        self.source_locations
+    }
+
+    /// Whether constant initial values of global variables are compiled into
+    /// the module as data and set by its constructor, rather than stored by
+    /// code at run time. Off by default, for code compiled ahead of time.
+    pub fn set_static_values(&mut self, static_values: bool) {
+        self.static_values = static_values;
+    }
+
+    pub fn compiles_static_values(&self) -> bool {
+        self.static_values
+    }
+
+    /// Whether the module's constructor can set the value of `name`, which
+    /// is true for the first form setting it only: constructors run before
+    /// any code, so code between the forms would see the value of the later
+    /// one.
+    pub fn claim_static_symbol(&mut self, name: &str) -> bool {
+        self.static_symbols.insert(name.to_string())
+    }
+
+    /// Passes a description of every closure compiled from now on to `hook`:
+    /// its parameters, the variables it captures with where they're bound,
+    /// and the fields of the closure struct. `--dump-closures` prints them.
+    pub fn set_closure_dump_hook(&mut self, hook: Option<Box<dyn FnMut(&str)>>) {
+        self.closure_dump_hook = hook;
+    }
+
+    /// Passes the IR of the functions compiled for every top-level form, or
+    /// for all of a file compiled ahead of time, to `hook`, one function at
+    /// a time after a summary line. With a filter, only functions whose
+    /// readable name contains it are passed.
+    pub fn set_ir_dump_hook(&mut self, hook: Option<Box<dyn FnMut(&str)>>, filter: Option<String>) {
+        self.ir_dump_hook = hook;
+        self.ir_dump_filter = filter;
+    }
+
+    fn dump_new_functions(&mut self) {
+        let hook = match self.ir_dump_hook.as_mut() {
+            Some(hook) => hook,
+            None => return,
+        };
+
+        for function in self.new_functions.iter() {
+            let mangled = function.get_name().to_string_lossy();
+            let name = Self::demangle(&mangled);
+
+            if let Some(filter) = &self.ir_dump_filter {
+                if !name.contains(filter.as_str()) {
+                    continue;
+                }
+            }
+
+            hook(&ir_dump::dump_function(function));
+        }
+    }
+
+    pub fn dumps_closures(&self) -> bool {
+        self.closure_dump_hook.is_some()
+    }
+
+    pub fn dump_closure(&mut self, description: &str) {
+        if let Some(hook) = self.closure_dump_hook.as_mut() {
+            hook(description);
+        }
     }
 
     pub fn declare_global_var(&mut self, name: &String) {
@@ -139,7 +434,7 @@ impl CodegenContext {
     }
 
     fn define_str_literal(&mut self, lit: String) -> GlobalValue {
-        let mut charcodes: Vec<_> = lit.clone().chars().map(|c| c as u8).collect();
+        let mut charcodes: Vec<_> = lit.bytes().collect();
         charcodes.push(0);
 
         let array_ty = self.llvm_ctx.i8_type().array_type(charcodes.len() as u32);
@@ -191,6 +486,80 @@ impl CodegenContext {
             .into_pointer_value()
     }
 
+    /// Compiles code into the constructor of the module, which runs once
+    /// before any other code of the module: right after the module is
+    /// compiled by the JIT, or, ahead of time, by `main` before the
+    /// top-level code of any unit.
+    pub fn in_module_constructor<T>(&mut self, compile: impl FnOnce(&mut Self) -> T) -> T {
+        let block = match self.module_constants.take() {
+            Some(block) => block,
+            None => {
+                let fn_ty = self.llvm_ctx.void_type().fn_type(&[], false);
+                let fn_name = self.mangle_str("__module_constants");
+                let function = self.module.add_function(&fn_name, fn_ty, None);
+
+                Rc::new(self.llvm_ctx.append_basic_block(&function, "entry"))
+            }
+        };
+
+        self.builder.position_at_end(&block);
+        self.blocks_stack.push(block);
+
+        let result = compile(self);
+        self.module_constants = Some(self.exit_block());
+
+        result
+    }
+
+    /// A global of the module holding the object `build` compiles, built
+    /// once by the module's constructor, so the code using it loads the
+    /// object instead of building it every time it runs. The global is 0
+    /// until the constructor ran.
+    pub fn module_constant(
+        &mut self,
+        name: &str,
+        build: impl FnOnce(&mut Self) -> CompileResult,
+    ) -> Result<GlobalValue, error::Error> {
+        let global_name = self.mangle_str(name);
+        let object_ty = self.lookup_known_type("unlisp_rt_object");
+        let global = self.module.add_global(object_ty, None, &global_name);
+        global.set_initializer(&const_object(self, ObjType::Int64, 0));
+        global.set_linkage(Linkage::Private);
+
+        self.in_module_constructor(|ctx| {
+            let object = build(ctx)?;
+            ctx.builder.build_store(global.as_pointer_value(), object);
+
+            Ok(global)
+        })
+    }
+
+    // Ends the constructor of the module and returns it, if the module has
+    // one. Constants compiled after this go into another constructor.
+    fn finish_module_constants(&mut self) -> Option<FunctionValue> {
+        let block = self.module_constants.take()?;
+
+        self.builder.position_at_end(&block);
+        self.builder.build_return(None);
+
+        let function = block.get_parent().unwrap();
+        self.verify_or_panic(&function, "module constants");
+
+        Some(function)
+    }
+
+    unsafe fn run_module_constants(&mut self) {
+        if let Some(function) = self.finish_module_constants() {
+            let fn_name = function.get_name().to_string_lossy().into_owned();
+            let constants_fn: JitFunction<unsafe extern "C" fn()> = self
+                .execution_engine
+                .get_function(&fn_name)
+                .expect("couldn't find module constants in execution engine");
+
+            constants_fn.call();
+        }
+    }
+
     pub fn lookup_known_type(&self, name: &str) -> BasicTypeEnum {
         self.module
             .get_type(name)
@@ -317,6 +686,10 @@ impl CodegenContext {
     pub fn enter_fn_block(&mut self, function: &FunctionValue) -> Rc<BasicBlock> {
         let block = self.llvm_ctx.append_basic_block(&function, "entry");
         let block_rc = Rc::new(block);
+        // entered again for the blocks of ifs
+        if !self.new_functions.contains(function) {
+            self.new_functions.push(*function);
+        }
 
         self.builder.position_at_end(&block_rc);
         self.blocks_stack.push(block_rc.clone());
@@ -346,12 +719,39 @@ impl CodegenContext {
     }
 
     pub fn codegen_hirs(&mut self, hirs: &[HIR]) -> Result<String, error::Error> {
-        compile_top_level_hirs(self, hirs)
+        self.module_has_code = true;
+        self.new_functions.clear();
+
+        let top_level_fn_name = compile_top_level_hirs(self, hirs)?;
+        self.dump_new_functions();
+
+        Ok(top_level_fn_name)
     }
 
     pub fn compile_hirs_with_main(&mut self, hirs: &[HIR]) -> Result<(), error::Error> {
         let code_init_fn_name = self.codegen_hirs(hirs)?;
+        let constants_fn_names = self.finished_constants_fn_names();
 
+        self.compile_main(&constants_fn_names, slice::from_ref(&code_init_fn_name))
+    }
+
+    // the constructor of the module, if it has one, for a `main` in the same
+    // module
+    fn finished_constants_fn_names(&mut self) -> Vec<String> {
+        self.finish_module_constants()
+            .map(|function| function.get_name().to_string_lossy().into_owned())
+            .into_iter()
+            .collect()
+    }
+
+    // `main` initializes the runtime, calls the constructors of the modules,
+    // then the functions generated for the top-level code in order, then
+    // calls `-main`
+    fn compile_main(
+        &mut self,
+        constants_fn_names: &[String],
+        code_init_fn_names: &[String],
+    ) -> Result<(), error::Error> {
         let sym = unlisp_rt::symbols::get_or_intern_symbol("-main".to_string());
 
         unsafe {
@@ -376,10 +776,17 @@ impl CodegenContext {
         self.enter_fn_block(&main_fn);
 
         let init_rt_fn = self.lookup_known_fn("unlisp_rt_init_runtime");
-        let code_init_fn = self.lookup_known_fn(&code_init_fn_name);
-
         self.builder.build_call(init_rt_fn, &[], "init_rt");
-        self.builder.build_call(code_init_fn, &[], "init_code");
+
+        for constants_fn_name in constants_fn_names {
+            let constants_fn = self.lookup_known_fn(constants_fn_name);
+            self.builder.build_call(constants_fn, &[], "init_constants");
+        }
+
+        for code_init_fn_name in code_init_fn_names {
+            let code_init_fn = self.lookup_known_fn(code_init_fn_name);
+            self.builder.build_call(code_init_fn, &[], "init_code");
+        }
 
         let main_sym_ptr = self.get_interned_sym("-main");
 
@@ -410,6 +817,8 @@ impl CodegenContext {
         let top_level_fn_name = self.codegen_hirs(hirs)?;
 
         unsafe {
+            self.run_module_constants();
+
             Ok(self
                 .execution_engine
                 .get_function(top_level_fn_name.as_str())
@@ -421,35 +830,481 @@ impl CodegenContext {
         &mut self,
         hirs: &[HIR],
     ) -> Result<unlisp_rt::defs::Object, error::Error> {
+        let warnings_before = self.warnings.len();
         let compiled_fn = self.compile_hirs(hirs)?;
+        self.check_warnings(warnings_before)?;
+
+        Self::run_compiled(&compiled_fn)
+    }
+
+    fn check_warnings(&self, warnings_before: usize) -> Result<(), error::Error> {
+        if self.warnings_are_errors && self.warnings.len() > warnings_before {
+            return Err(error::Error::new(
+                error::ErrorType::Compilation,
+                "the form has warnings, which are treated as errors (see --werror)",
+            ));
+        }
+
+        Ok(())
+    }
+
+    unsafe fn run_compiled(compiled_fn: &CompiledFn) -> Result<Object, error::Error> {
+        // an error raised before the first call has no location
+        unlisp_rt::source_locations::clear_source_location();
 
         unlisp_rt::exceptions::run_with_global_ex_handler(|| compiled_fn.call())
             .map_err(error::Error::rt_error)
     }
 
-    pub fn compile_hirs_to_file(&mut self, file: &str, hirs: &[HIR]) -> Result<(), error::Error> {
+    /// Evaluates one top-level form that's already been through
+    /// `repr::form_to_hir_with_transforms`. Code of the previous form is
+    /// moved out of the way first, while the module of this one stays
+    /// available (e.g. for dumping IR) until the next call.
+    pub unsafe fn eval_top_level_hir(&mut self, hir: &HIR) -> Result<Object, error::Error> {
+        if self.module_has_code {
+            self.reinitialize();
+        }
+
+        let result = self.eval_hirs(slice::from_ref(hir));
+        if result.is_ok() {
+            inline::note_definition(hir);
+        }
+
+        result
+    }
+
+    /// Evaluates top-level HIRs in order, each one seeing definitions made by
+    /// the previous ones. Results are per form, so an error doesn't stop the
+    /// batch.
+    pub unsafe fn eval_hir_batch(&mut self, hirs: &[HIR]) -> Vec<Result<Object, error::Error>> {
+        hirs.iter()
+            .map(|hir| self.eval_top_level_hir(hir))
+            .collect()
+    }
+
+    /// Evaluates top-level HIRs like `eval_hir_batch`, but compiles runs of
+    /// them into one module, so the JIT compiles machine code once per run
+    /// rather than once per form. A run ends after a function definition, as
+    /// the forms after it are checked against its arity. Forms still fail on
+    /// their own, an error in one doesn't stop the others.
+    pub unsafe fn eval_many(&mut self, hirs: &[HIR]) -> Vec<Result<Object, error::Error>> {
+        let mut results = Vec::with_capacity(hirs.len());
+        let mut rest = hirs;
+
+        while !rest.is_empty() {
+            let run_len = rest
+                .iter()
+                .position(inline::is_function_definition)
+                .map_or(rest.len(), |i| i + 1);
+            let (run, after) = rest.split_at(run_len);
+
+            results.extend(self.eval_run(run));
+            rest = after;
+        }
+
+        results
+    }
+
+    // every form of the run gets a top-level function of its own in the
+    // same module, they're called once all of them are compiled
+    unsafe fn eval_run(&mut self, run: &[HIR]) -> Vec<Result<Object, error::Error>> {
+        if self.module_has_code {
+            self.reinitialize();
+        }
+
+        let mut fn_names: Vec<Result<String, error::Error>> = vec![];
+        for (i, hir) in run.iter().enumerate() {
+            let warnings_before = self.warnings.len();
+            let fn_name = self.codegen_hirs(slice::from_ref(hir));
+
+            if fn_name.is_err() {
+                // the failed form leaves an unfinished function behind, so the
+                // ones before it go into a new module
+                self.recompile_run(&run[..i], &mut fn_names);
+            }
+
+            fn_names.push(fn_name.and_then(|name| {
+                self.check_warnings(warnings_before)?;
+                Ok(name)
+            }));
+        }
+
+        self.run_module_constants();
+
+        run.iter()
+            .zip(fn_names)
+            .map(|(hir, fn_name)| {
+                let compiled_fn: CompiledFn = self
+                    .execution_engine
+                    .get_function(fn_name?.as_str())
+                    .expect("couldn't find top-level function in execution engine");
+
+                let result = Self::run_compiled(&compiled_fn);
+                if result.is_ok() {
+                    inline::note_definition(hir);
+                }
+
+                result
+            })
+            .collect()
+    }
+
+    // Compiles the forms of the run that compiled before into a new module,
+    // updating the names of their functions. Warnings and dumps they made
+    // the first time aren't repeated.
+    fn recompile_run(&mut self, run: &[HIR], fn_names: &mut [Result<String, error::Error>]) {
+        let warnings_len = self.warnings.len();
+        let closure_dump_hook = self.closure_dump_hook.take();
+        let ir_dump_hook = self.ir_dump_hook.take();
+
+        self.reinitialize();
+        for (hir, fn_name) in run.iter().zip(fn_names.iter_mut()) {
+            if let Ok(name) = fn_name {
+                *name = self
+                    .codegen_hirs(slice::from_ref(hir))
+                    .expect("form failed to compile the second time");
+            }
+        }
+
+        self.warnings.truncate(warnings_len);
+        self.warning_spans.truncate(warnings_len);
+        self.closure_dump_hook = closure_dump_hook;
+        self.ir_dump_hook = ir_dump_hook;
+    }
+
+    /// The supported entry point for embedding the compiler. Forms are
+    /// macroexpanded one at a time right before evaluation, so a macro or
+    /// function defined by a form is visible to all the forms after it.
+    pub unsafe fn eval_forms(&mut self, forms: &[Form]) -> Vec<Result<Object, error::Error>> {
+        forms
+            .iter()
+            .map(|form| {
+                repr::form_to_hir_with_transforms(form)
+                    .and_then(|hir| self.eval_top_level_hir(&hir))
+            })
+            .collect()
+    }
+
+    /// Evaluates the forms of `src` like `eval_forms` until one of them
+    /// fails, returning the value of the last one. When all of it takes
+    /// longer than `timeout`, evaluation is interrupted with a `Timeout`
+    /// error and the context can go on evaluating. Compiled functions check
+    /// for the interrupt on entry, tail recursive loops included, and that's
+    /// the only place it's checked: a loop inside a native, e.g. a long
+    /// `sleep` or `read-all`, can't be interrupted, the timeout is noticed
+    /// after it returns. Cleanups of `unwind-protect` run to the end, and
+    /// `ignore-errors` doesn't catch the interrupt. Only the calling thread
+    /// is interrupted, threads spawned by the code aren't.
+    pub unsafe fn eval_str_with_timeout(
+        &mut self,
+        src: &str,
+        timeout: Duration,
+    ) -> Result<Object, error::Error> {
+        let forms = reader::Reader::from_str(src)
+            .read_all_forms()
+            .map_err(|e| reader_error(e.as_ref()))?;
+
+        let flag = unlisp_rt::interrupt::interrupt_flag();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let watchdog = thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(timeout) {
+                flag.store(true, Ordering::SeqCst);
+            }
+        });
+
+        let mut result = Ok(Object::nil());
+        for (_, form) in forms.iter() {
+            result = repr::form_to_hir_with_transforms(form)
+                .and_then(|hir| self.eval_top_level_hir(&hir));
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        // wakes the watchdog up if the deadline hasn't passed yet
+        drop(done_tx);
+        watchdog.join().expect("timeout watchdog panicked");
+
+        let interrupted = unlisp_rt::interrupt::is_interrupted();
+        unlisp_rt::interrupt::clear_interrupt();
+
+        match result {
+            // the deadline can pass after the last form succeeded
+            Err(_) if interrupted => Err(error::Error::new(
+                error::ErrorType::Timeout,
+                format!("evaluation took longer than {:?}", timeout),
+            )),
+            result => result,
+        }
+    }
+
+    /// Records the environment forms see: the function and value of every
+    /// symbol, macros included, and the global variables declared. Rolling
+    /// back to the snapshot restores it, so definitions made since are
+    /// undone. Machine code compiled since isn't freed by the rollback, nor
+    /// by anything else.
+    pub fn begin_snapshot(&mut self) -> SnapshotId {
+        self.snapshots.push(Snapshot {
+            bindings: unlisp_rt::symbols::save_bindings(),
+            declared_syms: self.declared_syms.clone(),
+        });
+
+        SnapshotId(self.snapshots.len() - 1)
+    }
+
+    /// Restores the environment recorded by the snapshot, which ends it and
+    /// the ones taken after it.
+    pub fn rollback_to(&mut self, id: SnapshotId) {
+        assert!(id.0 < self.snapshots.len(), "snapshot was already ended");
+        self.snapshots.truncate(id.0 + 1);
+        let snapshot = self.snapshots.pop().unwrap();
+
+        unsafe { unlisp_rt::symbols::restore_bindings(&snapshot.bindings) };
+        self.declared_syms = snapshot.declared_syms;
+    }
+
+    /// Keeps the definitions made since the snapshot, ending it and the ones
+    /// taken after it.
+    pub fn commit_snapshot(&mut self, id: SnapshotId) {
+        assert!(id.0 < self.snapshots.len(), "snapshot was already ended");
+        self.snapshots.truncate(id.0);
+    }
+
+    /// Evaluates the forms of `src` like `eval_forms` until one of them
+    /// fails, returning the value of the last one. When one fails, the
+    /// definitions made by the ones before it are rolled back, and its error
+    /// is returned.
+    pub unsafe fn eval_str_transactional(&mut self, src: &str) -> Result<Object, error::Error> {
+        let forms = reader::Reader::from_str(src)
+            .read_all_forms()
+            .map_err(|e| reader_error(e.as_ref()))?;
+
+        let snapshot = self.begin_snapshot();
+        let mut result = Ok(Object::nil());
+        for (_, form) in forms.iter() {
+            result = repr::form_to_hir_with_transforms(form)
+                .and_then(|hir| self.eval_top_level_hir(&hir));
+
+            if result.is_err() {
+                self.rollback_to(snapshot);
+                return result;
+            }
+        }
+
+        self.commit_snapshot(snapshot);
+        result
+    }
+
+    fn create_target_machine(opt_level: OptimizationLevel) -> TargetMachine {
         Target::initialize_all(&InitializationConfig::default());
-        self.compile_hirs_with_main(hirs)?;
 
         let triple = TargetMachine::get_default_triple().to_string();
         let target = Target::from_triple(triple.as_str())
             .expect("couldn't create target from target triple");
 
-        let target_machine = target
+        target
             .create_target_machine(
                 triple.as_str(),
                 "generic",
                 "",
-                OptimizationLevel::None,
+                opt_level,
                 RelocMode::Default,
                 CodeModel::Default,
             )
-            .expect("couldn't create target machine");
+            .expect("couldn't create target machine")
+    }
+
+    pub fn compile_hirs_to_file(&mut self, file: &str, hirs: &[HIR]) -> Result<(), error::Error> {
+        let units: Vec<_> = hirs
+            .iter()
+            .map(|hir| CompilationUnit {
+                hir: hir.clone(),
+                source: SourceDescriptor::unknown(),
+            })
+            .collect();
+
+        self.compile_units_to_object(&units, Path::new(file))
+            .map(|_| ())
+            .map_err(|e| e.error)
+    }
+
+    /// Compiles the units, in order, to an object file with a `main` like
+    /// `compile_hirs_to_file` does, and reports what each of them added.
+    /// Compilation stops at the first unit that fails, its error carries the
+    /// unit's source and nothing is written.
+    pub fn compile_units_to_object(
+        &mut self,
+        units: &[CompilationUnit],
+        out: &Path,
+    ) -> Result<Vec<UnitReport>, UnitError> {
+        let target_machine = Self::create_target_machine(self.opt_level);
+        let mut code_init_fn_names = vec![];
+        let mut reports = vec![];
+
+        for unit in units {
+            let warnings_before = self.warnings.len();
+            let code_init_fn_name = self
+                .codegen_hirs(slice::from_ref(&unit.hir))
+                .map_err(|e| UnitError::new(Some(&unit.source), e))?;
+
+            let symbols = self
+                .new_functions
+                .iter()
+                .map(|function| function.get_name().to_string_lossy().into_owned())
+                .collect();
+
+            code_init_fn_names.push(code_init_fn_name);
+            reports.push(UnitReport {
+                source: unit.source.clone(),
+                symbols,
+                warnings: self.warnings[warnings_before..].to_vec(),
+            });
+        }
+
+        let constants_fn_names = self.finished_constants_fn_names();
+        self.compile_main(&constants_fn_names, &code_init_fn_names)
+            .map_err(|e| UnitError::new(None, e))?;
 
         target_machine
-            .write_to_file(self.get_module(), FileType::Object, Path::new(file))
-            .expect("couldn't write module to file");
+            .write_to_file(self.get_module(), FileType::Object, out)
+            .map_err(|e| {
+                let message = format!("couldn't write object file {}: {}", out.display(), e);
+                UnitError::new(
+                    None,
+                    error::Error::new(error::ErrorType::Compilation, message),
+                )
+            })?;
+
+        Ok(reports)
+    }
 
-        Ok(())
+    fn write_object_to_memory(&self) -> Vec<u8> {
+        let buffer = Self::create_target_machine(self.opt_level)
+            .write_to_memory_buffer(self.get_module(), FileType::Object)
+            .expect("couldn't write module to memory buffer");
+
+        buffer.as_slice().to_vec()
+    }
+
+    pub fn compile_hirs_to_memory(&mut self, hirs: &[HIR]) -> Result<Vec<u8>, error::Error> {
+        self.compile_hirs_with_main(hirs)?;
+
+        Ok(self.write_object_to_memory())
+    }
+
+    /// Compiles the HIRs of a unit, e.g. the forms of a file, to an object
+    /// file of its own, so units can be compiled separately and linked
+    /// together. Everything the object defines is local to it, except for
+    /// the function `init_fn_name`, which runs the unit's top-level code,
+    /// and the unit's constructor, which builds its constants. The `main` of
+    /// `compile_main_to_memory` calls both.
+    pub fn compile_unit_to_memory(
+        &mut self,
+        hirs: &[HIR],
+        init_fn_name: &str,
+    ) -> Result<Vec<u8>, error::Error> {
+        if self.module_has_code {
+            self.reinitialize();
+        }
+        // literals defined by other units aren't in this unit's object
+        self.defined_str_literals.clear();
+
+        let top_level_fn_name = self.codegen_hirs(hirs)?;
+        let constants_fn = self.finish_module_constants();
+
+        for function in self.new_functions.iter().chain(constants_fn.iter()) {
+            function.set_linkage(Linkage::Internal);
+        }
+
+        let mut global = self.module.get_first_global();
+        while let Some(defined) = global {
+            if defined.get_initializer().is_some() {
+                defined.set_linkage(Linkage::Internal);
+            }
+            global = defined.get_next_global();
+        }
+
+        let fn_ty = self
+            .lookup_known_type("unlisp_rt_object")
+            .fn_type(&[], false);
+        let init_fn = self.module.add_function(init_fn_name, fn_ty, None);
+        self.enter_fn_block(&init_fn);
+
+        let top_level_fn = self.lookup_known_fn(&top_level_fn_name);
+        let val = self
+            .builder
+            .build_call(top_level_fn, &[], "unit_val")
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+        self.builder.build_return(Some(&val));
+
+        self.verify_or_panic(&init_fn, "unit init");
+
+        // defined even when the unit has no constants, so main can call it
+        let unit_constants_fn_ty = self.llvm_ctx.void_type().fn_type(&[], false);
+        let unit_constants_fn = self.module.add_function(
+            &unit_constants_fn_name(init_fn_name),
+            unit_constants_fn_ty,
+            None,
+        );
+        self.enter_fn_block(&unit_constants_fn);
+
+        if let Some(constants_fn) = constants_fn {
+            self.builder.build_call(constants_fn, &[], "constants");
+        }
+        self.builder.build_return(None);
+
+        self.verify_or_panic(&unit_constants_fn, "unit constants");
+
+        Ok(self.write_object_to_memory())
+    }
+
+    /// Compiles the `main` of units compiled with `compile_unit_to_memory`
+    /// to an object file of its own, their init functions are called in the
+    /// order given.
+    pub fn compile_main_to_memory(
+        &mut self,
+        init_fn_names: &[String],
+    ) -> Result<Vec<u8>, error::Error> {
+        if self.module_has_code {
+            self.reinitialize();
+        }
+        self.module_has_code = true;
+        self.defined_str_literals.clear();
+
+        let mut constants_fn_names = vec![];
+        for init_fn_name in init_fn_names {
+            let fn_ty = self
+                .lookup_known_type("unlisp_rt_object")
+                .fn_type(&[], false);
+            self.module.add_function(init_fn_name, fn_ty, None);
+
+            let constants_fn_name = unit_constants_fn_name(init_fn_name);
+            let constants_fn_ty = self.llvm_ctx.void_type().fn_type(&[], false);
+            self.module
+                .add_function(&constants_fn_name, constants_fn_ty, None);
+            constants_fn_names.push(constants_fn_name);
+        }
+
+        self.compile_main(&constants_fn_names, init_fn_names)?;
+
+        Ok(self.write_object_to_memory())
+    }
+}
+
+// the function of a unit compiled by `compile_unit_to_memory` that runs its
+// constructor
+fn unit_constants_fn_name(init_fn_name: &str) -> String {
+    format!("{}_constants", init_fn_name)
+}
+
+// reader errors that aren't `error::Error`s are I/O errors, e.g. input that
+// ends in the middle of a form
+fn reader_error(e: &(dyn Error + 'static)) -> error::Error {
+    match e.downcast_ref::<error::Error>() {
+        Some(e) => e.clone(),
+        None => error::Error::new(error::ErrorType::Reader, e.to_string()),
     }
 }
@@ -1,25 +1,45 @@
+use crate::fold;
 use crate::repr::If;
 
 use super::common::CompileResult;
 use super::context::CodegenContext;
 use super::literal::compile_nil_t_literal;
-use super::top_level::compile_hir;
+use super::top_level::{compile_hir, compile_hir_in_tail_pos};
 
 use std::rc::Rc;
 
-pub fn compile_if(ctx: &mut CodegenContext, if_hir: &If) -> CompileResult {
+// only the branch that's taken is compiled, so it's fine for the other one
+// to refer to names that aren't defined
+fn compile_constant_if(
+    ctx: &mut CodegenContext,
+    if_hir: &If,
+    cond: bool,
+    is_tail: bool,
+) -> CompileResult {
+    match (cond, if_hir.else_hir.as_ref()) {
+        (true, _) => compile_hir_in_tail_pos(ctx, &if_hir.then_hir, is_tail),
+        (false, Some(hir)) => compile_hir_in_tail_pos(ctx, hir, is_tail),
+        (false, None) => Ok(compile_nil_t_literal(ctx, false)),
+    }
+}
+
+pub fn compile_if(ctx: &mut CodegenContext, if_hir: &If, is_tail: bool) -> CompileResult {
+    if let Some(cond) = fold::constant_condition(&if_hir.cond) {
+        return compile_constant_if(ctx, if_hir, cond, is_tail);
+    }
+
     let merge_block = ctx.append_block();
 
     let compiled_cond = compile_hir(ctx, &if_hir.cond)?;
 
     let enter_then_block = ctx.enter_block();
-    let compiled_then = compile_hir(ctx, &if_hir.then_hir)?;
+    let compiled_then = compile_hir_in_tail_pos(ctx, &if_hir.then_hir, is_tail)?;
     ctx.builder.build_unconditional_branch(&merge_block);
     let exit_then_block = ctx.exit_block();
 
     let enter_else_block = ctx.enter_block();
     let compiled_else = match if_hir.else_hir.as_ref() {
-        Some(hir) => compile_hir(ctx, hir)?,
+        Some(hir) => compile_hir_in_tail_pos(ctx, hir, is_tail)?,
         None => compile_nil_t_literal(ctx, false),
     };
     ctx.builder.build_unconditional_branch(&merge_block);
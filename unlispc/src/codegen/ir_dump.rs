@@ -0,0 +1,68 @@
+use super::context::CodegenContext;
+
+use inkwell::values::FunctionValue;
+
+use std::iter;
+
+fn instruction_count(function: &FunctionValue) -> usize {
+    function
+        .get_basic_blocks()
+        .iter()
+        .map(|block| {
+            iter::successors(block.get_first_instruction(), |i| i.get_next_instruction()).count()
+        })
+        .sum()
+}
+
+/// The IR of a single function after a comment line with its readable name
+/// and size.
+pub fn dump_function(function: &FunctionValue) -> String {
+    let mangled = function.get_name().to_string_lossy();
+
+    format!(
+        "; {}: {} basic blocks, {} instructions\n{}",
+        CodegenContext::demangle(&mangled),
+        function.count_basic_blocks(),
+        instruction_count(function),
+        function.print_to_string().to_string()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use inkwell::context::Context;
+
+    #[test]
+    fn test_golden_dump() {
+        let context = Context::create();
+        let module = context.create_module("dump");
+        let i64_ty = context.i64_type();
+        let function = module.add_function(
+            "double__unlisp_7",
+            i64_ty.fn_type(&[i64_ty.into()], false),
+            None,
+        );
+
+        let x = function.get_nth_param(0).unwrap().into_int_value();
+        x.set_name("x");
+
+        let builder = context.create_builder();
+        let entry = context.append_basic_block(&function, "entry");
+        builder.position_at_end(&entry);
+        let doubled = builder.build_int_add(x, x, "doubled");
+        builder.build_return(Some(&doubled));
+
+        assert_eq!(
+            dump_function(&function),
+            "; double: 1 basic blocks, 2 instructions
+
+define i64 @double__unlisp_7(i64 %x) {
+entry:
+  %doubled = add i64 %x, %x
+  ret i64 %doubled
+}
+"
+        );
+    }
+}
@@ -2,16 +2,20 @@ use crate::repr::LetBlock;
 
 use super::common::*;
 use super::context::CodegenContext;
-use super::top_level::{compile_hir, compile_hirs};
+use super::top_level::{compile_hir, compile_hirs_in_tail_pos};
 
-pub fn compile_let_block(ctx: &mut CodegenContext, let_block: &LetBlock) -> CompileResult {
+pub fn compile_let_block(
+    ctx: &mut CodegenContext,
+    let_block: &LetBlock,
+    is_tail: bool,
+) -> CompileResult {
     for (name, hir) in let_block.bindings.iter() {
         let val = compile_hir(ctx, hir)?;
         ctx.push_env();
         ctx.save_env_mapping(name.clone(), val, false);
     }
 
-    let result = compile_hirs(ctx, let_block.body.as_slice())?;
+    let result = compile_hirs_in_tail_pos(ctx, let_block.body.as_slice(), is_tail)?;
 
     for _ in let_block.bindings.iter() {
         ctx.pop_env();
@@ -1,22 +1,77 @@
 use crate::repr::Literal;
-use inkwell::values::BasicValueEnum;
+use inkwell::values::{BasicValueEnum, StructValue};
+use inkwell::AddressSpace;
+use unlisp_rt::bigint::BigInt;
+use unlisp_rt::defs::ObjType;
 
 use super::common::*;
 use super::context::CodegenContext;
 use crate::error;
 
+// An object built as a constant from its tag and the bits of its payload.
+pub fn const_object(ctx: &CodegenContext, ty: ObjType, payload: u64) -> StructValue {
+    let object_ty = ctx.lookup_known_type("unlisp_rt_object").into_struct_type();
+    let tag = ctx.llvm_ctx.i32_type().const_int(ty as u64, false);
+    let payload = ctx
+        .llvm_ctx
+        .i64_type()
+        .const_int(payload, false)
+        .const_to_pointer(ctx.llvm_ctx.i8_type().ptr_type(AddressSpace::Generic));
+
+    object_ty.const_named_struct(&[tag.into(), payload.into()])
+}
+
+/// Ints, floats and chars as constant objects, other literals need the
+/// runtime to build them.
+pub fn constant_object(ctx: &CodegenContext, literal: &Literal) -> Option<StructValue> {
+    match literal {
+        Literal::IntegerLiteral(i) => Some(const_object(ctx, ObjType::Int64, *i as u64)),
+        Literal::FloatLiteral(f) => Some(const_object(ctx, ObjType::Float64, f.to_bits())),
+        Literal::CharLiteral(c) => Some(const_object(ctx, ObjType::Char, *c as u64)),
+        _ => None,
+    }
+}
+
+// Boxed ints are built as constants rather than through a call to
+// unlisp_rt_object_from_int. Arithmetic on them still calls the natives,
+// which unbox their arguments themselves.
 fn compile_integer(ctx: &mut CodegenContext, i: i64) -> BasicValueEnum {
-    let ty = ctx.llvm_ctx.i64_type();
-    let int_val = ty.const_int(i as u64, true);
+    const_object(ctx, ObjType::Int64, i as u64).into()
+}
+
+fn compile_float(ctx: &mut CodegenContext, f: f64) -> BasicValueEnum {
+    let float_val = ctx.llvm_ctx.f64_type().const_float(f);
+    let call = ctx.builder.build_call(
+        ctx.lookup_known_fn("unlisp_rt_object_from_float"),
+        &[float_val.into()],
+        "call",
+    );
+
+    call.try_as_basic_value().left().unwrap()
+}
+
+fn compile_char(ctx: &mut CodegenContext, c: char) -> BasicValueEnum {
+    let code = ctx.llvm_ctx.i32_type().const_int(c as u64, false);
     let call = ctx.builder.build_call(
-        ctx.lookup_known_fn("unlisp_rt_object_from_int"),
-        &[int_val.into()],
+        ctx.lookup_known_fn("unlisp_rt_object_from_char"),
+        &[code.into()],
         "call",
     );
 
     call.try_as_basic_value().left().unwrap()
 }
 
+fn compile_big_integer(ctx: &mut CodegenContext, i: &BigInt) -> BasicValueEnum {
+    let to_obj_fn = ctx.lookup_known_fn("unlisp_rt_object_from_bigint_str");
+    let literal_ptr = ctx.str_literal_as_i8_ptr(&i.to_string());
+
+    ctx.builder
+        .build_call(to_obj_fn, &[literal_ptr.into()], "bigint_obj")
+        .try_as_basic_value()
+        .left()
+        .unwrap()
+}
+
 pub fn compile_nil_t_literal(ctx: &CodegenContext, t: bool) -> BasicValueEnum {
     let fn_name = if t {
         "unlisp_rt_t_object"
@@ -44,8 +99,13 @@ fn compile_string_literal(ctx: &mut CodegenContext, s: &String) -> BasicValueEnu
 pub fn compile_literal(ctx: &mut CodegenContext, literal: &Literal) -> CompileResult {
     match literal {
         Literal::ListLiteral(vec) if vec.is_empty() => Ok(compile_nil_t_literal(ctx, false)),
-        Literal::ListLiteral(_) => panic!("cannot compile unquoted list literal"),
+        Literal::ListLiteral(_) | Literal::DottedListLiteral(..) => {
+            panic!("cannot compile unquoted list literal")
+        }
         Literal::IntegerLiteral(i) => Ok(compile_integer(ctx, *i)),
+        Literal::BigIntegerLiteral(i) => Ok(compile_big_integer(ctx, i)),
+        Literal::FloatLiteral(f) => Ok(compile_float(ctx, *f)),
+        Literal::CharLiteral(c) => Ok(compile_char(ctx, *c)),
         Literal::StringLiteral(s) => Ok(compile_string_literal(ctx, s)),
         Literal::SymbolLiteral(s) => {
             let val = ctx.lookup_name_or_gen_global_access(s).ok_or_else(|| {
@@ -1,11 +1,14 @@
 pub mod context;
+pub mod unit;
 
 mod call;
 mod set_expr;
 mod closure;
 mod common;
 mod if_codegen;
+mod ir_dump;
 mod let_block;
 mod literal;
 mod quote;
+mod static_value;
 mod top_level;
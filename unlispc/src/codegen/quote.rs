@@ -1,6 +1,8 @@
 use crate::repr::Literal;
 use inkwell::values::BasicValueEnum;
-use inkwell::AddressSpace;
+
+use std::iter;
+use std::slice;
 
 use super::common::CompileResult;
 use super::context::CodegenContext;
@@ -17,44 +19,136 @@ fn compile_quoted_symbol(ctx: &mut CodegenContext, name: &String) -> BasicValueE
         .unwrap()
 }
 
-fn compile_quoted_list(ctx: &mut CodegenContext, list: &Vec<Literal>) -> CompileResult {
-    let cons_fn = ctx.lookup_known_fn("unlisp_rt_list_cons");
-    let object_form_list_fn = ctx.lookup_known_fn("unlisp_rt_object_from_list");
+// A list constant being built. Elements are consed on from the last one, onto
+// nil or the compiled tail of a dotted list.
+struct QuotedList<'a> {
+    items: iter::Rev<slice::Iter<'a, Literal>>,
+    result: BasicValueEnum,
+    is_dotted: bool,
+}
+
+impl<'a> QuotedList<'a> {
+    fn new(ctx: &mut CodegenContext, list: &'a [Literal]) -> Self {
+        let result = ctx
+            .builder
+            .build_call(ctx.lookup_known_fn("unlisp_rt_nil_list"), &[], "nil_list")
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+
+        Self {
+            items: list.iter().rev(),
+            result: result,
+            is_dotted: false,
+        }
+    }
 
-    let nil_sym = ctx.get_interned_sym("nil");
+    fn dotted(list: &'a [Literal], tail: BasicValueEnum) -> Self {
+        Self {
+            items: list.iter().rev(),
+            result: tail,
+            is_dotted: true,
+        }
+    }
 
-    let mut result = ctx.builder.build_bitcast(
-        nil_sym,
-        ctx.llvm_ctx.i8_type().ptr_type(AddressSpace::Generic),
-        "nil_as_i8*",
-    );
+    fn cons(&mut self, ctx: &mut CodegenContext, el: BasicValueEnum) {
+        let cons_fn = if self.is_dotted {
+            ctx.lookup_known_fn("unlisp_rt_object_cons")
+        } else {
+            ctx.lookup_known_fn("unlisp_rt_list_cons")
+        };
 
-    for el in list.iter().rev() {
-        let compiled = compile_quoted_literal(ctx, el)?;
-        result = ctx
+        self.result = ctx
             .builder
-            .build_call(cons_fn, &[compiled, result], "result")
+            .build_call(cons_fn, &[el, self.result], "result")
             .try_as_basic_value()
             .left()
             .unwrap();
     }
 
-    let result_obj = ctx
-        .builder
-        .build_call(object_form_list_fn, &[result], "result_obj")
-        .try_as_basic_value()
-        .left()
-        .unwrap();
+    fn finish(self, ctx: &mut CodegenContext) -> BasicValueEnum {
+        if self.is_dotted {
+            return self.result;
+        }
 
-    Ok(result_obj)
+        ctx.builder
+            .build_call(
+                ctx.lookup_known_fn("unlisp_rt_object_from_list"),
+                &[self.result],
+                "result_obj",
+            )
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+    }
 }
 
+// Nested lists are kept on an explicit stack rather than compiled
+// recursively, so deeply nested constants don't overflow the stack.
 pub fn compile_quoted_literal(ctx: &mut CodegenContext, literal: &Literal) -> CompileResult {
-    match literal {
-        Literal::SymbolLiteral(s) => Ok(compile_quoted_symbol(ctx, s)),
-        Literal::ListLiteral(list) => Ok(compile_quoted_list(ctx, list)?),
-        Literal::IntegerLiteral(_) | Literal::StringLiteral(_) | Literal::T => {
-            compile_literal(ctx, literal)
+    let mut stack: Vec<QuotedList> = vec![];
+    let mut next = Some(literal);
+
+    loop {
+        let mut compiled = match next.take() {
+            Some(Literal::ListLiteral(list)) => {
+                stack.push(QuotedList::new(ctx, list));
+                None
+            }
+            // tails are never lists, so this doesn't nest
+            Some(Literal::DottedListLiteral(list, tail)) => {
+                let tail = compile_quoted_literal(ctx, tail)?;
+                stack.push(QuotedList::dotted(list, tail));
+                None
+            }
+            Some(Literal::SymbolLiteral(s)) => Some(compile_quoted_symbol(ctx, s)),
+            Some(literal) => Some(compile_literal(ctx, literal)?),
+            None => None,
+        };
+
+        while next.is_none() {
+            let list = match stack.last_mut() {
+                Some(list) => list,
+                None => return Ok(compiled.unwrap()),
+            };
+
+            if let Some(el) = compiled.take() {
+                list.cons(ctx, el);
+            }
+
+            match list.items.next() {
+                Some(item) => next = Some(item),
+                None => compiled = Some(stack.pop().unwrap().finish(ctx)),
+            }
         }
     }
 }
+
+/// Quoted symbols and lists are built once per module, by its constructor,
+/// and loaded from a global where they're used. A list is copied on every
+/// evaluation, so it's still fresh and can be mutated, other quoted data is
+/// compiled like the literal.
+pub fn compile_quote(ctx: &mut CodegenContext, literal: &Literal) -> CompileResult {
+    let is_list = match literal {
+        Literal::ListLiteral(list) => !list.is_empty(),
+        Literal::DottedListLiteral(..) => true,
+        Literal::SymbolLiteral(_) => false,
+        _ => return compile_quoted_literal(ctx, literal),
+    };
+
+    let global = ctx.module_constant("quoted", |ctx| compile_quoted_literal(ctx, literal))?;
+    let constant = ctx.builder.build_load(global.as_pointer_value(), "constant");
+
+    if !is_list {
+        return Ok(constant);
+    }
+
+    let copy = ctx
+        .builder
+        .build_call(ctx.lookup_known_fn("unlisp_rt_copy_constant"), &[constant], "copy")
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+
+    Ok(copy)
+}
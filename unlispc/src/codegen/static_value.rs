@@ -0,0 +1,89 @@
+use crate::repr::{Call, Literal, HIR};
+
+use inkwell::module::Linkage;
+use inkwell::values::{GlobalValue, StructValue};
+
+use super::common::*;
+use super::context::CodegenContext;
+use super::literal::{compile_literal, compile_nil_t_literal, constant_object};
+use super::quote::compile_quoted_literal;
+
+const SET_SYMBOL_VALUE: &str = "set-symbol-value!";
+
+// literals the module's constructor can build, symbols are variables
+fn is_static_literal(literal: &Literal) -> bool {
+    match literal {
+        Literal::SymbolLiteral(_) => false,
+        Literal::ListLiteral(list) => list.is_empty(),
+        Literal::DottedListLiteral(..) => false,
+        _ => true,
+    }
+}
+
+/// Compiles `(set-symbol-value! (quote sym) value)`, which `defvar` expands
+/// to, without the native when the value is a literal or quoted data (after
+/// folding) and the context compiles constant values statically. The value
+/// is a private global of the module: ints, floats and chars are
+/// initialized in the object file, other values are built by the module's
+/// constructor. The constructor also points the symbol at the global, so
+/// the variable is set before any code runs and the form itself compiles
+/// to nothing. Only the first form setting a symbol is compiled this way,
+/// later ones call the native in order. None for other calls.
+pub fn compile_static_symbol_value(ctx: &mut CodegenContext, call: &Call) -> Option<CompileResult> {
+    if !ctx.compiles_static_values() || call.fn_name != SET_SYMBOL_VALUE {
+        return None;
+    }
+
+    let (sym_name, value) = match call.args.as_slice() {
+        [HIR::Quote(quote), value] => match (&quote.body, value) {
+            (Literal::SymbolLiteral(sym_name), HIR::Literal(literal))
+                if is_static_literal(literal) =>
+            {
+                (sym_name, value)
+            }
+            (Literal::SymbolLiteral(sym_name), HIR::Quote(_)) => (sym_name, value),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    if !ctx.claim_static_symbol(sym_name) {
+        return None;
+    }
+
+    Some(compile_static_value(ctx, sym_name, value))
+}
+
+fn compile_static_value(ctx: &mut CodegenContext, sym_name: &str, value: &HIR) -> CompileResult {
+    let global_name = format!("{}_value", sym_name);
+
+    let global = match value {
+        HIR::Literal(literal) => match constant_object(ctx, literal) {
+            Some(constant) => constant_global(ctx, &global_name, constant),
+            None => ctx.module_constant(&global_name, |ctx| compile_literal(ctx, literal))?,
+        },
+        HIR::Quote(quote) => {
+            ctx.module_constant(&global_name, |ctx| compile_quoted_literal(ctx, &quote.body))?
+        }
+        _ => unreachable!("not a static value"),
+    };
+
+    ctx.in_module_constructor(|ctx| {
+        let sym = ctx.get_interned_sym(sym_name);
+        let value_ptr_ptr = unsafe { ctx.builder.build_struct_gep(sym, 2, "value_ptr_ptr") };
+        ctx.builder
+            .build_store(value_ptr_ptr, global.as_pointer_value());
+    });
+
+    Ok(compile_nil_t_literal(ctx, false))
+}
+
+fn constant_global(ctx: &mut CodegenContext, name: &str, constant: StructValue) -> GlobalValue {
+    let object_ty = ctx.lookup_known_type("unlisp_rt_object");
+    let global_name = ctx.mangle_str(name);
+    let global = ctx.get_module().add_global(object_ty, None, &global_name);
+    global.set_initializer(&constant);
+    global.set_linkage(Linkage::Private);
+
+    global
+}
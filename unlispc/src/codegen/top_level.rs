@@ -11,18 +11,28 @@ use super::context::CodegenContext;
 use super::if_codegen::compile_if;
 use super::let_block::compile_let_block;
 use super::literal::{compile_literal, compile_nil_t_literal};
-use super::quote::compile_quoted_literal;
+use super::quote::compile_quote;
 
 pub fn compile_hir(ctx: &mut CodegenContext, hir: &HIR) -> CompileResult {
+    compile_hir_in_tail_pos(ctx, hir, false)
+}
+
+// `is_tail` is set when the value of `hir` is returned from the enclosing
+// function right away, calls in tail position are marked as tail calls
+pub fn compile_hir_in_tail_pos(
+    ctx: &mut CodegenContext,
+    hir: &HIR,
+    is_tail: bool,
+) -> CompileResult {
     match hir {
         HIR::Literal(literal) => compile_literal(ctx, literal),
         HIR::SetExpr(e) => compile_set_expr(ctx, e),
-        HIR::Call(call) => compile_call(ctx, call),
+        HIR::Call(call) => compile_call(ctx, call, is_tail),
         HIR::Closure(closure) => compile_closure(ctx, closure),
         HIR::Lambda(_) => panic!("cannot compile raw lambda"),
-        HIR::If(if_hir) => compile_if(ctx, if_hir),
-        HIR::Quote(quote) => compile_quoted_literal(ctx, &quote.body),
-        HIR::LetBlock(let_block) => compile_let_block(ctx, let_block),
+        HIR::If(if_hir) => compile_if(ctx, if_hir, is_tail),
+        HIR::Quote(quote) => compile_quote(ctx, &quote.body),
+        HIR::LetBlock(let_block) => compile_let_block(ctx, let_block, is_tail),
         HIR::DeclareVar(decl_var) => {
             ctx.declare_global_var(&decl_var.var_name);
             Ok(compile_nil_t_literal(ctx, false))
@@ -31,10 +41,19 @@ pub fn compile_hir(ctx: &mut CodegenContext, hir: &HIR) -> CompileResult {
 }
 
 pub fn compile_hirs(ctx: &mut CodegenContext, hirs: &[HIR]) -> CompileResult {
+    compile_hirs_in_tail_pos(ctx, hirs, false)
+}
+
+pub fn compile_hirs_in_tail_pos(
+    ctx: &mut CodegenContext,
+    hirs: &[HIR],
+    is_tail: bool,
+) -> CompileResult {
     let mut val_opt = None;
 
-    for hir in hirs {
-        val_opt = Some(compile_hir(ctx, hir)?);
+    for (i, hir) in hirs.iter().enumerate() {
+        let is_last = i + 1 == hirs.len();
+        val_opt = Some(compile_hir_in_tail_pos(ctx, hir, is_tail && is_last)?);
     }
 
     let val_or_nil = val_opt.unwrap_or_else(|| compile_nil_t_literal(ctx, false));
@@ -0,0 +1,89 @@
+use crate::error;
+use crate::repr::HIR;
+
+use std::fmt;
+use std::ops::Range;
+
+/// Where the code of a unit comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    User,
+    Stdlib,
+    Preload,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceDescriptor {
+    pub file: String,
+    /// Byte offsets of the form in the file, when it was read from one.
+    pub span: Option<Range<usize>>,
+    pub origin: Origin,
+}
+
+impl SourceDescriptor {
+    /// For code handed over without its source, e.g. by
+    /// `CodegenContext::compile_hirs_to_file`.
+    pub fn unknown() -> Self {
+        Self {
+            file: "<unknown>".to_string(),
+            span: None,
+            origin: Origin::User,
+        }
+    }
+}
+
+impl fmt::Display for SourceDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.file)?;
+
+        if let Some(span) = &self.span {
+            write!(f, ":{}..{}", span.start, span.end)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A top-level form, already expanded, with the source it was read from.
+#[derive(Debug, Clone)]
+pub struct CompilationUnit {
+    pub hir: HIR,
+    pub source: SourceDescriptor,
+}
+
+/// What compiling a unit added to the object file.
+#[derive(Debug, Clone)]
+pub struct UnitReport {
+    pub source: SourceDescriptor,
+    /// The mangled names of the functions the unit defined, in the order
+    /// they were generated.
+    pub symbols: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// An error compiling units to an object file, with the source of the unit
+/// that failed. Errors that aren't about a single unit, like a missing
+/// `-main`, have none.
+#[derive(Debug, Clone)]
+pub struct UnitError {
+    pub source: Option<SourceDescriptor>,
+    pub error: error::Error,
+}
+
+impl UnitError {
+    pub fn new(source: Option<&SourceDescriptor>, error: error::Error) -> Self {
+        Self {
+            source: source.cloned(),
+            error,
+        }
+    }
+}
+
+impl fmt::Display for UnitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match &self.source {
+            Some(source) => write!(f, "{}: {}", source, self.error),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
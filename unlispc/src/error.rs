@@ -1,4 +1,5 @@
 use unlisp_rt::error::RuntimeError;
+use unlisp_rt::source_locations::SourceLocation;
 
 use std::error;
 use std::fmt;
@@ -9,12 +10,16 @@ pub enum ErrorType {
     Compilation,
     Macroexpansion,
     Runtime,
+    // evaluation interrupted by eval_str_with_timeout
+    Timeout,
 }
 
 #[derive(Debug, Clone)]
 pub struct Error {
     pub message: String,
     pub ty: ErrorType,
+    // of the call that raised a runtime error, see repr::Span
+    pub location: Option<SourceLocation>,
 }
 
 impl Error {
@@ -22,6 +27,7 @@ impl Error {
         Self {
             message: message.into(),
             ty: ty,
+            location: None,
         }
     }
 
@@ -29,14 +35,12 @@ impl Error {
         Self {
             message: format!("{}", rt_err),
             ty: ErrorType::Runtime,
+            location: rt_err.location().cloned(),
         }
     }
 
     pub fn convert(self, new_ty: ErrorType) -> Self {
-        Self {
-            message: self.message,
-            ty: new_ty,
-        }
+        Self { ty: new_ty, ..self }
     }
 }
 
@@ -50,6 +54,7 @@ impl fmt::Display for Error {
                 ErrorType::Compilation => "compilation error",
                 ErrorType::Macroexpansion => "macroexpansion error",
                 ErrorType::Runtime => "runtime error",
+                ErrorType::Timeout => "timeout",
             },
             self.message
         )
@@ -0,0 +1,236 @@
+use crate::fold;
+use crate::inline;
+use crate::repr::*;
+
+use unlisp_rt::escapes;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+// Fingerprints are computed over expanded HIR rather than source text, so a
+// changed macro invalidates every unit that expands it.
+
+// a hash of the sources of the compiler and the runtime, see build.rs, so a
+// rebuilt compiler doesn't reuse objects of an older one with the same version
+const BUILD_HASH: &str = env!("UNLISPC_BUILD_HASH");
+
+// pre-order with an explicit stack, so deeply nested constants don't
+// overflow the call stack
+fn hash_literal<H: Hasher>(literal: &Literal, state: &mut H) {
+    let mut stack = vec![literal];
+
+    while let Some(literal) = stack.pop() {
+        match literal {
+            Literal::T => 0.hash(state),
+            Literal::SymbolLiteral(s) => (1, s).hash(state),
+            Literal::IntegerLiteral(i) => (2, i).hash(state),
+            Literal::BigIntegerLiteral(i) => (7, i).hash(state),
+            Literal::FloatLiteral(f) => (3, f.to_bits()).hash(state),
+            Literal::CharLiteral(c) => (8, c).hash(state),
+            Literal::StringLiteral(s) => (4, s).hash(state),
+            Literal::ListLiteral(list) => {
+                (5, list.len()).hash(state);
+                stack.extend(list.iter().rev());
+            }
+            Literal::DottedListLiteral(list, tail) => {
+                (6, list.len()).hash(state);
+                stack.push(tail);
+                stack.extend(list.iter().rev());
+            }
+        }
+    }
+}
+
+fn hash_hirs<H: Hasher>(hirs: &[HIR], state: &mut H) {
+    hirs.len().hash(state);
+    hirs.iter().for_each(|h| hash_hir(h, state));
+}
+
+fn hash_lambda<H: Hasher>(lambda: &Lambda, state: &mut H) {
+    lambda.name.hash(state);
+    lambda.arglist.hash(state);
+    lambda
+        .arg_types
+        .iter()
+        .map(|t| t.map(|t| t.name()))
+        .collect::<Vec<_>>()
+        .hash(state);
+    lambda.restarg.hash(state);
+    lambda.declarations.hash(state);
+    hash_hirs(&lambda.body, state);
+}
+
+fn hash_hir<H: Hasher>(hir: &HIR, state: &mut H) {
+    match hir {
+        HIR::DeclareVar(decl) => (0, &decl.var_name).hash(state),
+        HIR::Literal(literal) => {
+            1.hash(state);
+            hash_literal(literal, state);
+        }
+        HIR::Lambda(lambda) => {
+            2.hash(state);
+            hash_lambda(lambda, state);
+        }
+        HIR::Closure(closure) => {
+            3.hash(state);
+            closure.free_vars.hash(state);
+            hash_lambda(&closure.lambda, state);
+        }
+        HIR::Call(call) => {
+            (4, &call.fn_name, call.is_lexical).hash(state);
+            hash_hirs(&call.args, state);
+        }
+        HIR::LetBlock(let_block) => {
+            (5, let_block.bindings.len()).hash(state);
+            for (name, val) in let_block.bindings.iter() {
+                name.hash(state);
+                hash_hir(val, state);
+            }
+            hash_hirs(&let_block.body, state);
+        }
+        HIR::Quote(quote) => {
+            6.hash(state);
+            hash_literal(&quote.body, state);
+        }
+        HIR::If(if_hir) => {
+            7.hash(state);
+            hash_hir(&if_hir.cond, state);
+            hash_hir(&if_hir.then_hir, state);
+            if_hir.else_hir.is_some().hash(state);
+            if let Some(else_hir) = if_hir.else_hir.as_ref() {
+                hash_hir(else_hir, state);
+            }
+        }
+        HIR::SetExpr(set_expr) => {
+            (8, &set_expr.name).hash(state);
+            hash_hir(&set_expr.val, state);
+        }
+    }
+}
+
+/// Options that change the code compiled from the same HIR, or the HIR
+/// expanded from the same source, to pass to `fingerprint_hirs`.
+pub fn codegen_flags() -> Vec<String> {
+    vec![
+        format!("fold={}", fold::constant_folding()),
+        format!("inline-threshold={}", inline::inline_threshold()),
+        format!("symbol-case={:?}", escapes::symbol_case()),
+    ]
+}
+
+/// Global variables declared anywhere in the HIRs, in the order they're
+/// declared. Code compiled after them refers to these as globals, so they
+/// belong in the fingerprints of the units that follow.
+pub fn declared_vars(hirs: &[HIR]) -> Vec<String> {
+    let mut vars = vec![];
+    let mut stack: Vec<&HIR> = hirs.iter().rev().collect();
+
+    while let Some(hir) = stack.pop() {
+        let len = stack.len();
+
+        match hir {
+            HIR::DeclareVar(decl) => vars.push(decl.var_name.clone()),
+            HIR::Literal(_) | HIR::Quote(_) => (),
+            HIR::Lambda(lambda) => stack.extend(lambda.body.iter()),
+            HIR::Closure(closure) => stack.extend(closure.lambda.body.iter()),
+            HIR::Call(call) => stack.extend(call.args.iter()),
+            HIR::LetBlock(let_block) => {
+                stack.extend(let_block.bindings.iter().map(|(_, val)| val));
+                stack.extend(let_block.body.iter());
+            }
+            HIR::If(if_hir) => {
+                stack.push(&if_hir.cond);
+                stack.push(&if_hir.then_hir);
+                stack.extend(if_hir.else_hir.as_ref().map(|else_hir| else_hir.as_ref()));
+            }
+            HIR::SetExpr(set_expr) => stack.push(&set_expr.val),
+        }
+
+        // children are visited in order
+        stack[len..].reverse();
+    }
+
+    vars
+}
+
+/// Fingerprint of a compilation unit, covers the expanded HIR, the build of
+/// the compiler and any flags that affect the emitted object.
+pub fn fingerprint_hirs(hirs: &[HIR], flags: &[String]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    BUILD_HASH.hash(&mut hasher);
+    std::env::consts::ARCH.hash(&mut hasher);
+    std::env::consts::OS.hash(&mut hasher);
+    flags.hash(&mut hasher);
+    hash_hirs(hirs, &mut hasher);
+
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::Reader;
+    use std::sync::Once;
+
+    static RUNTIME: Once = Once::new();
+
+    fn hirs_of(src: &str) -> Vec<HIR> {
+        // converting to HIR interns symbols
+        RUNTIME.call_once(unlisp_rt::symbols::init);
+
+        let mut reader = Reader::from_str(src);
+        let mut hirs = vec![];
+
+        while let Some(form) = reader.read_form().unwrap() {
+            hirs.push(form_to_hir_with_transforms(&form).unwrap());
+        }
+
+        hirs
+    }
+
+    fn fingerprint_src(src: &str) -> u64 {
+        fingerprint_hirs(&hirs_of(src), &[])
+    }
+
+    #[test]
+    fn test_fingerprint_is_stable() {
+        let src = "(set-symbol-function! (quote f) (lambda (x) (let ((a 1) (b 2)) (lambda () (+ a b x)))))";
+
+        assert_eq!(fingerprint_src(src), fingerprint_src(src));
+    }
+
+    #[test]
+    fn test_fingerprint_changes_with_code() {
+        assert_ne!(fingerprint_src("(+ 1 2)"), fingerprint_src("(+ 1 3)"));
+        assert_ne!(fingerprint_src("(quote (1 2))"), fingerprint_src("(quote (1 . 2))"));
+        assert_ne!(
+            fingerprint_src("(lambda ((x integer)) x)"),
+            fingerprint_src("(lambda (x) x)")
+        );
+        assert_ne!(
+            fingerprint_src("(lambda (x) (declare (notinline)) x)"),
+            fingerprint_src("(lambda (x) x)")
+        );
+    }
+
+    #[test]
+    fn test_declared_vars_in_order() {
+        let hirs = hirs_of(
+            "(declare-var a) (lambda () (if 1 (declare-var b) (declare-var c))) (declare-var d)",
+        );
+
+        assert_eq!(declared_vars(&hirs), vec!["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn test_fingerprint_changes_with_flags() {
+        let flags = vec!["inline-threshold=0".to_string()];
+
+        assert_ne!(
+            fingerprint_hirs(&hirs_of("(+ 1 2)"), &flags),
+            fingerprint_src("(+ 1 2)")
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use crate::repr::{Call, Literal, Quote, HIR};
+use unlisp_rt::defs::Function;
+use unlisp_rt::predefined;
+
+use std::cmp::Ordering;
+use std::sync::atomic::{self, AtomicBool};
+
+static CONSTANT_FOLDING: AtomicBool = AtomicBool::new(true);
+
+/// Turns folding of calls with constant arguments on or off (`--no-fold`).
+pub fn set_constant_folding(enabled: bool) {
+    CONSTANT_FOLDING.store(enabled, atomic::Ordering::SeqCst);
+}
+
+pub fn constant_folding() -> bool {
+    CONSTANT_FOLDING.load(atomic::Ordering::SeqCst)
+}
+
+fn constant_arg(hir: &HIR) -> Option<Literal> {
+    match hir {
+        HIR::Quote(quote) => Some(quote.body.clone()),
+        HIR::Literal(Literal::SymbolLiteral(_)) => None,
+        HIR::Literal(literal) => Some(literal.clone()),
+        _ => None,
+    }
+}
+
+// elements and the dotted tail, if the literal is a list at all
+fn list_parts(literal: Literal) -> Result<(Vec<Literal>, Option<Literal>), Literal> {
+    match literal {
+        Literal::ListLiteral(items) => Ok((items, None)),
+        Literal::DottedListLiteral(items, tail) => Ok((items, Some(*tail))),
+        literal => Err(literal),
+    }
+}
+
+fn make_list(items: Vec<Literal>, tail: Option<Literal>) -> Literal {
+    match tail {
+        Some(tail) if !items.is_empty() => Literal::DottedListLiteral(items, Box::new(tail)),
+        Some(tail) => tail,
+        None => Literal::ListLiteral(items),
+    }
+}
+
+fn proper_list(literal: Literal) -> Option<Vec<Literal>> {
+    match list_parts(literal) {
+        Ok((items, None)) => Some(items),
+        _ => None,
+    }
+}
+
+fn fold_cons(car: Literal, cdr: Literal) -> Literal {
+    let (mut items, tail) = match list_parts(cdr) {
+        Ok((items, tail)) => (items, tail),
+        Err(atom) => (vec![], Some(atom)),
+    };
+
+    items.insert(0, car);
+    make_list(items, tail)
+}
+
+fn fold_append(x: Literal, y: Literal) -> Option<Literal> {
+    let mut items = proper_list(x)?;
+
+    let tail = match list_parts(y) {
+        Ok((rest, tail)) => {
+            items.extend(rest);
+            tail
+        }
+        Err(atom) => Some(atom),
+    };
+
+    Some(make_list(items, tail))
+}
+
+fn fold_reverse(x: Literal) -> Option<Literal> {
+    let mut items = proper_list(x)?;
+    items.reverse();
+    Some(Literal::ListLiteral(items))
+}
+
+// The numbers the folder computes with. Bignums are left to the runtime.
+#[derive(Clone, Copy)]
+enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    fn to_float(self) -> f64 {
+        match self {
+            Number::Int(i) => i as f64,
+            Number::Float(f) => f,
+        }
+    }
+
+    fn is_nan(self) -> bool {
+        match self {
+            Number::Int(_) => false,
+            Number::Float(f) => f.is_nan(),
+        }
+    }
+
+    fn into_literal(self) -> Literal {
+        match self {
+            Number::Int(i) => Literal::IntegerLiteral(i),
+            Number::Float(f) => Literal::FloatLiteral(f),
+        }
+    }
+}
+
+fn number_args(args: &[Literal]) -> Option<Vec<Number>> {
+    args.iter()
+        .map(|arg| match arg {
+            Literal::IntegerLiteral(i) => Some(Number::Int(*i)),
+            Literal::FloatLiteral(f) => Some(Number::Float(*f)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn boolean(b: bool) -> Literal {
+    if b {
+        Literal::T
+    } else {
+        Literal::ListLiteral(vec![])
+    }
+}
+
+// The same steps as the runtime's arithmetic: fixnums stay fixnums, a float
+// operand converts the other operand with `as f64` and the rest is computed
+// in f64 from left to right, so results are the same bit for bit, signed
+// zeros included. Overflowing fixnums (bignums at runtime) aren't folded.
+fn combine(fn_name: &str, x: Number, y: Number) -> Option<Number> {
+    let (int_op, float_op): (fn(i64, i64) -> Option<i64>, fn(f64, f64) -> f64) = match fn_name {
+        "+" => (i64::checked_add, |x, y| x + y),
+        "*" => (i64::checked_mul, |x, y| x * y),
+        _ => (i64::checked_sub, |x, y| x - y),
+    };
+
+    match (x, y) {
+        (Number::Int(x), Number::Int(y)) => int_op(x, y).map(Number::Int),
+        (x, y) => Some(Number::Float(float_op(x.to_float(), y.to_float()))),
+    }
+}
+
+// Like the natives, `-` subtracts the rest from its first argument. NaNs
+// aren't folded, neither as arguments nor as results: their payloads aren't
+// specified, and compiled binaries may run on another machine.
+fn fold_number_call(fn_name: &str, args: &[Number]) -> Option<Literal> {
+    if args.iter().any(|x| x.is_nan()) {
+        return None;
+    }
+
+    let (init, rest) = match (fn_name, args) {
+        ("+", _) => (Number::Int(0), args),
+        ("*", _) => (Number::Int(1), args),
+        ("-", _) if !args.is_empty() => (args[0], &args[1..]),
+        ("<", [x, y]) => return Some(boolean(compare(*x, *y) == Ordering::Less)),
+        (">", [x, y]) => return Some(boolean(compare(*x, *y) == Ordering::Greater)),
+        // equal returns its first argument, floats are equal when their bits
+        // are and never equal to integers
+        ("equal", [Number::Int(x), Number::Int(y)]) if x == y => {
+            return Some(Literal::IntegerLiteral(*x))
+        }
+        ("equal", [Number::Float(x), Number::Float(y)]) if x.to_bits() == y.to_bits() => {
+            return Some(Literal::FloatLiteral(*x))
+        }
+        ("equal", [_, _]) => return Some(boolean(false)),
+        _ => return None,
+    };
+
+    let result = rest
+        .iter()
+        .try_fold(init, |acc, x| combine(fn_name, acc, *x))?;
+
+    if result.is_nan() {
+        None
+    } else {
+        Some(result.into_literal())
+    }
+}
+
+// for numbers that aren't NaN
+fn compare(x: Number, y: Number) -> Ordering {
+    match (x, y) {
+        (Number::Int(x), Number::Int(y)) => x.cmp(&y),
+        (x, y) => x.to_float().partial_cmp(&y.to_float()).unwrap(),
+    }
+}
+
+/// Whether a condition is known to be non-nil (or nil) at compile time, that
+/// is when it's a literal or quoted data, possibly after folding. Always
+/// `None` with `--no-fold`, so both branches are compiled.
+pub fn constant_condition(cond: &HIR) -> Option<bool> {
+    if !constant_folding() {
+        return None;
+    }
+
+    constant_arg(cond).map(|literal| literal != Literal::ListLiteral(vec![]))
+}
+
+/// Evaluates a call at compile time when all arguments are constants and
+/// the function is one of the list constructors `cons`, `list`, `append` and
+/// `reverse`, or arithmetic and comparisons (`+`, `-`, `*`, `<`, `>` and
+/// `equal` on integer and float literals). Quoted lists are built once per
+/// module and copied on every evaluation, so folded lists are still fresh
+/// and can be mutated. Calls are
+/// folded only while the function is the one declared pure by the runtime or
+/// stdlib, a redefined `list` is called.
+pub fn fold_constant_call(call: &Call, f: *const Function) -> Option<HIR> {
+    if !constant_folding() || call.is_lexical || f.is_null() || !predefined::is_pure_function(f) {
+        return None;
+    }
+
+    let args = call
+        .args
+        .iter()
+        .map(constant_arg)
+        .collect::<Option<Vec<_>>>()?;
+
+    if let Some(numbers) = number_args(&args) {
+        if let Some(literal) = fold_number_call(call.fn_name.as_str(), &numbers) {
+            return Some(HIR::Literal(literal));
+        }
+    }
+
+    let mut args = args.into_iter();
+
+    let folded = match (call.fn_name.as_str(), call.args.len()) {
+        ("cons", 2) => fold_cons(args.next()?, args.next()?),
+        ("list", _) => Literal::ListLiteral(args.collect()),
+        ("append", 2) => fold_append(args.next()?, args.next()?)?,
+        ("reverse", 1) => fold_reverse(args.next()?)?,
+        _ => return None,
+    };
+
+    Some(HIR::Quote(Quote { body: folded }))
+}
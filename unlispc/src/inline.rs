@@ -0,0 +1,313 @@
+use crate::macro_env;
+use crate::repr::{Call, If, Inlining, Lambda, LetBlock, Literal, Quote, SetExpr, HIR};
+use unlisp_rt::defs::Function;
+use unlisp_rt::symbols;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// Default of `--inline-threshold`, the largest body of a function declared
+/// `(inline)` that's inlined.
+pub const DEFAULT_INLINE_THRESHOLD: usize = 48;
+
+static INLINE_THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_INLINE_THRESHOLD);
+
+/// Sets the size, in HIR nodes, up to which bodies of functions declared
+/// `(inline)` are inlined (`--inline-threshold`), 0 turns inlining off.
+pub fn set_inline_threshold(threshold: usize) {
+    INLINE_THRESHOLD.store(threshold, Ordering::SeqCst);
+}
+
+pub fn inline_threshold() -> usize {
+    INLINE_THRESHOLD.load(Ordering::SeqCst)
+}
+
+// functions that can be inlined, by the address of their function object
+static INLINABLE_FUNCTIONS: Mutex<Vec<(usize, Lambda)>> = Mutex::new(Vec::new());
+
+fn inlinable_functions() -> MutexGuard<'static, Vec<(usize, Lambda)>> {
+    INLINABLE_FUNCTIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+// functions declared (notinline), by the address of their function object
+static NOTINLINE_FUNCTIONS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+fn notinline_functions() -> MutexGuard<'static, Vec<usize>> {
+    NOTINLINE_FUNCTIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+// numbers the parameters of inlined bodies apart
+static INLINED_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+// lambdas aren't walked into, bodies containing them aren't inlined
+fn walk<'a>(hir: &'a HIR, visit: &mut dyn FnMut(&'a HIR)) {
+    visit(hir);
+
+    match hir {
+        HIR::Call(call) => {
+            for arg in call.args.iter() {
+                walk(arg, visit);
+            }
+        }
+        HIR::LetBlock(let_block) => {
+            for (_, val) in let_block.bindings.iter() {
+                walk(val, visit);
+            }
+            for hir in let_block.body.iter() {
+                walk(hir, visit);
+            }
+        }
+        HIR::If(if_hir) => {
+            walk(&if_hir.cond, visit);
+            walk(&if_hir.then_hir, visit);
+            if let Some(else_hir) = if_hir.else_hir.as_ref() {
+                walk(else_hir, visit);
+            }
+        }
+        HIR::SetExpr(e) => walk(&e.val, visit),
+        _ => (),
+    }
+}
+
+fn body_size(lambda: &Lambda) -> usize {
+    let mut size = 0;
+    for hir in lambda.body.iter() {
+        walk(hir, &mut |_| size += 1);
+    }
+
+    size
+}
+
+// The size up to which the body of a function is inlined, none when its
+// calls are always kept. Only functions declared `(inline)` are inlined,
+// the calls of others see their redefinitions.
+fn size_limit(lambda: &Lambda, threshold: usize) -> Option<usize> {
+    match lambda.declarations.inlining {
+        Inlining::Inline if threshold > 0 => Some(threshold),
+        _ => None,
+    }
+}
+
+// Parameters with type annotations are checked on entry, so those functions
+// are always called. So are ones that call themselves, which would be
+// inlined into their own body over and over, ones creating closures and
+// ones with a restarg.
+fn obstacle(name: &str, lambda: &Lambda, limit: usize) -> Option<&'static str> {
+    if lambda.restarg.is_some() {
+        return Some("it has a restarg");
+    }
+    if lambda.arg_types.iter().any(Option::is_some) {
+        return Some("it has annotated parameters");
+    }
+    if lambda.body.is_empty() {
+        return Some("its body is empty");
+    }
+    if body_size(lambda) > limit {
+        return Some("its body is too big");
+    }
+
+    let mut found = None;
+    for hir in lambda.body.iter() {
+        walk(hir, &mut |hir| match hir {
+            HIR::Lambda(_) | HIR::Closure(_) | HIR::DeclareVar(_) => {
+                found = found.or(Some("it creates closures or declares variables"))
+            }
+            HIR::Call(call) if !call.is_lexical && call.fn_name == name => {
+                found = Some("it calls itself")
+            }
+            _ => (),
+        });
+    }
+
+    found
+}
+
+fn is_inlinable(name: &str, lambda: &Lambda, threshold: usize) -> bool {
+    size_limit(lambda, threshold).map_or(false, |limit| obstacle(name, lambda, limit).is_none())
+}
+
+/// A warning for a function declared `(inline)` whose calls are kept all
+/// the same, e.g. because it calls itself.
+pub fn declared_inline_warning(lambda: &Lambda) -> Option<String> {
+    let name = lambda.name.as_ref()?;
+    if lambda.declarations.inlining != Inlining::Inline {
+        return None;
+    }
+
+    let limit = size_limit(lambda, inline_threshold())?;
+    obstacle(name, lambda, limit)
+        .map(|reason| format!("{} is declared inline but isn't inlined: {}", name, reason))
+}
+
+// Variables the body refers to besides its parameters. They're looked up
+// where the body ends up, so none of them may be bound lexically there.
+fn free_names(lambda: &Lambda) -> Vec<&str> {
+    let mut names = vec![];
+    for hir in lambda.body.iter() {
+        walk(hir, &mut |hir| match hir {
+            HIR::Literal(Literal::SymbolLiteral(name)) => names.push(name.as_str()),
+            HIR::SetExpr(e) => names.push(e.name.as_str()),
+            HIR::Call(call) if call.is_lexical => names.push(call.fn_name.as_str()),
+            _ => (),
+        });
+    }
+
+    names.retain(|name| !lambda.arglist.iter().any(|arg| arg == name));
+    names
+}
+
+fn renamed(name: &str, renames: &[(String, String)]) -> String {
+    renames
+        .iter()
+        .find(|(from, _)| from == name)
+        .map_or_else(|| name.to_string(), |(_, to)| to.clone())
+}
+
+// every binding and use of a parameter's name gets the new name, so
+// shadowing inside the body stays the same
+fn rename(hir: &HIR, renames: &[(String, String)]) -> HIR {
+    let rename_all =
+        |hirs: &[HIR]| -> Vec<HIR> { hirs.iter().map(|hir| rename(hir, renames)).collect() };
+
+    match hir {
+        HIR::Literal(Literal::SymbolLiteral(s)) => {
+            HIR::Literal(Literal::SymbolLiteral(renamed(s, renames)))
+        }
+        HIR::SetExpr(e) => HIR::SetExpr(SetExpr {
+            name: renamed(&e.name, renames),
+            val: Box::new(rename(&e.val, renames)),
+        }),
+        HIR::Call(call) => HIR::Call(Call {
+            fn_name: if call.is_lexical {
+                renamed(&call.fn_name, renames)
+            } else {
+                call.fn_name.clone()
+            },
+            is_lexical: call.is_lexical,
+            args: rename_all(&call.args),
+            span: call.span,
+        }),
+        HIR::LetBlock(let_block) => HIR::LetBlock(LetBlock {
+            bindings: let_block
+                .bindings
+                .iter()
+                .map(|(name, val)| (renamed(name, renames), rename(val, renames)))
+                .collect(),
+            body: rename_all(&let_block.body),
+        }),
+        HIR::If(if_hir) => HIR::If(If {
+            cond: Box::new(rename(&if_hir.cond, renames)),
+            then_hir: Box::new(rename(&if_hir.then_hir, renames)),
+            else_hir: if_hir
+                .else_hir
+                .as_ref()
+                .map(|else_hir| Box::new(rename(else_hir, renames))),
+        }),
+        hir => hir.clone(),
+    }
+}
+
+/// Whether `hir` is a top-level `(set-symbol-function! ...)`, which is what
+/// `defun` expands to.
+pub fn is_function_definition(hir: &HIR) -> bool {
+    match hir {
+        HIR::Call(call) => !call.is_lexical && call.fn_name == "set-symbol-function!",
+        _ => false,
+    }
+}
+
+/// Remembers the function a top-level `(set-symbol-function! (quote name)
+/// (lambda ...))` has just defined, if it's declared `(inline)` and can be
+/// inlined or declared `(notinline)`. Called once the form was evaluated, so the
+/// function object is the new one.
+pub fn note_definition(hir: &HIR) {
+    let call = match hir {
+        HIR::Call(call)
+            if !call.is_lexical
+                && call.fn_name == "set-symbol-function!"
+                && call.args.len() == 2 =>
+        {
+            call
+        }
+        _ => return,
+    };
+
+    let name = match &call.args[0] {
+        HIR::Quote(Quote {
+            body: Literal::SymbolLiteral(name),
+        }) => name,
+        _ => return,
+    };
+
+    let lambda = match &call.args[1] {
+        HIR::Closure(closure) => &closure.lambda,
+        _ => return,
+    };
+
+    let f = unsafe { (*symbols::get_or_intern_symbol(name.clone())).function };
+    if f.is_null() {
+        return;
+    }
+
+    if lambda.declarations.inlining == Inlining::NotInline {
+        notinline_functions().push(f as usize);
+    } else if is_inlinable(name, lambda, inline_threshold()) {
+        inlinable_functions().push((f as usize, lambda.clone()));
+    }
+}
+
+/// Whether `f` was defined at top level with a `(declare (notinline))`,
+/// whose calls are kept as they are, compiler macros included.
+pub fn is_declared_notinline(f: *const Function) -> bool {
+    notinline_functions().contains(&(f as usize))
+}
+
+/// Replaces a call of a function declared `(inline)` at top level by its
+/// body, with the parameters bound to the arguments by a `let`. Like folding
+/// this depends on the function's definition when the call is compiled,
+/// redefining it only affects code compiled afterwards.
+pub fn inline_call(call: &Call, f: *const Function) -> Option<HIR> {
+    let threshold = inline_threshold();
+    if threshold == 0 || call.is_lexical || f.is_null() {
+        return None;
+    }
+
+    let lambda = inlinable_functions()
+        .iter()
+        .rev()
+        .find(|(g, _)| *g == f as usize)
+        .map(|(_, lambda)| lambda.clone())?;
+
+    if lambda.arglist.len() != call.args.len()
+        || size_limit(&lambda, threshold).map_or(true, |limit| body_size(&lambda) > limit)
+        || free_names(&lambda)
+            .into_iter()
+            .any(macro_env::is_lexical_variable)
+    {
+        return None;
+    }
+
+    let n = INLINED_CALLS.fetch_add(1, Ordering::SeqCst);
+    let renames: Vec<_> = lambda
+        .arglist
+        .iter()
+        .map(|arg| (arg.clone(), format!("--inline-{}-{}", n, arg)))
+        .collect();
+
+    Some(HIR::LetBlock(LetBlock {
+        bindings: renames
+            .iter()
+            .map(|(_, to)| to.clone())
+            .zip(call.args.iter().cloned())
+            .collect(),
+        body: lambda
+            .body
+            .iter()
+            .map(|hir| rename(hir, &renames))
+            .collect(),
+    }))
+}
@@ -1,18 +1,36 @@
 use crate::error;
-use crate::pushback_reader::PushbackReader;
+use crate::pushback_reader::{IoSource, PushbackReader, Source, StrSource};
 use std::error::Error;
 use std::io;
 use std::io::Read;
+use unlisp_rt::bigint::BigInt;
+use unlisp_rt::defs::CHAR_NAMES;
+use unlisp_rt::escapes::{self, ParsedNumber, SymbolCase};
 
 type GenResult<T> = Result<T, Box<dyn Error>>;
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     LeftPar,
     RightPar,
     IntegerLiteral(i64),
+    // integer literal outside of i64 range
+    BigIntegerLiteral(BigInt),
+    FloatLiteral(f64),
+    CharLiteral(char),
     StringLiteral(String),
     Symbol(String),
+    // a symbol written with bars or backslashes, it's never read as nil, t or
+    // a number
+    EscapedSymbol(String),
+    Dot,
+    // `#n=`, labels the form after it so that `#n#` can refer to it
+    Label(u64),
+    // `#n#`, the form labeled with `#n=`
+    LabelRef(u64),
+    // a ';' comment up to the end of its line, only returned after
+    // `set_keep_comments`
+    Comment(String),
 }
 
 pub fn is_eof<T>(result: &io::Result<T>) -> bool {
@@ -22,23 +40,173 @@ pub fn is_eof<T>(result: &io::Result<T>) -> bool {
     }
 }
 
-pub struct Lexer<'a, T: Read + 'a> {
-    pbr: PushbackReader<'a, T>,
+/// Where the lines of an input start. `\n`, `\r\n` and a lone `\r` all end a
+/// line, so files with Windows or old Mac line endings are numbered the same.
+#[derive(Default)]
+pub(crate) struct Lines {
+    // starts of the lines after the first one
+    starts: Vec<usize>,
+    // bytes seen so far, ones read again after being unread are skipped
+    scanned: usize,
+    prev: u8,
 }
 
-impl<'a, T: Read> Lexer<'a, T> {
-    pub fn create(r: &'a mut T) -> Lexer<'a, T> {
+impl Lines {
+    pub(crate) fn scan(&mut self, pos: usize, byte: u8) {
+        if pos < self.scanned {
+            return;
+        }
+        self.scanned = pos + 1;
+
+        match byte {
+            b'\n' if self.prev == b'\r' => *self.starts.last_mut().unwrap() = pos + 1,
+            b'\n' | b'\r' => self.starts.push(pos + 1),
+            _ => (),
+        }
+        self.prev = byte;
+    }
+
+    pub(crate) fn line_at(&self, pos: usize) -> usize {
+        match self.starts.binary_search(&pos) {
+            Ok(i) => i + 2,
+            Err(i) => i + 1,
+        }
+    }
+
+    pub(crate) fn column_at(&self, pos: usize) -> usize {
+        let line_start = match self.line_at(pos) {
+            1 => 0,
+            line => self.starts[line - 2],
+        };
+        pos - line_start + 1
+    }
+
+    // forgets the bytes after the first len, which were scanned and end
+    // with last
+    pub(crate) fn truncate(&mut self, len: usize, last: Option<u8>) {
+        while self.starts.last().map_or(false, |start| *start > len) {
+            self.starts.pop();
+        }
+
+        self.prev = last.unwrap_or(0);
+        // a '\r' whose '\n' was cut off ends its line again
+        if self.prev == b'\r' && self.starts.last() != Some(&len) {
+            self.starts.push(len);
+        }
+        self.scanned = len;
+    }
+}
+
+/// The line, counted from 1, of the byte at `pos` in `src`. Line terminators
+/// belong to the line they end.
+pub fn line_at(src: &[u8], pos: usize) -> usize {
+    let mut lines = Lines::default();
+    // with the byte at pos scanned too, the '\n' of a "\r\n" stays on its line
+    for (i, byte) in src.iter().enumerate().take(pos + 1) {
+        lines.scan(i, *byte);
+    }
+
+    lines.line_at(pos)
+}
+
+pub(crate) const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// chars that can start or continue an atom, non-ASCII bytes are taken as part
+// of a symbol and decoded once the whole atom is read
+pub(crate) fn is_atom_char(c: char) -> bool {
+    escapes::is_symbol_char(c) || c == '.' || c == '|' || c == '\\' || !c.is_ascii()
+}
+
+pub struct Lexer<S: Source> {
+    pbr: PushbackReader<S>,
+    at_start: bool,
+    token_start: usize,
+    lines: Lines,
+    keep_comments: bool,
+    symbol_case: SymbolCase,
+}
+
+impl<'a, T: Read + 'a> Lexer<IoSource<'a, T>> {
+    pub fn create(r: &'a mut T) -> Self {
+        Self::with_reader(PushbackReader::create(r))
+    }
+}
+
+impl<'a> Lexer<StrSource<'a>> {
+    /// Lexes a string in memory, tokens are the same as with `create` over
+    /// its bytes.
+    pub fn from_str(s: &'a str) -> Self {
+        Self::with_reader(PushbackReader::from_str(s))
+    }
+
+    /// Lexes bytes from the middle of an input, where a token or the
+    /// whitespace before one starts. The BOM and a `#!` line are only skipped
+    /// `at_start`.
+    pub(crate) fn resume(bytes: &'a [u8], at_start: bool, symbol_case: SymbolCase) -> Self {
+        let mut lexer = Self::with_reader(PushbackReader::from_bytes(bytes));
+        lexer.at_start = at_start;
+        lexer.symbol_case = symbol_case;
+        lexer
+    }
+}
+
+impl<S: Source> Lexer<S> {
+    fn with_reader(pbr: PushbackReader<S>) -> Self {
         Lexer {
-            pbr: PushbackReader::create(r),
+            pbr,
+            at_start: true,
+            token_start: 0,
+            lines: Lines::default(),
+            keep_comments: false,
+            symbol_case: escapes::symbol_case(),
         }
     }
 
-    fn valid_symbol_char(c: char) -> bool {
-        c.is_alphanumeric()
-            || vec!['&', '*', '-', '?', '+', '<', '>', '_', '!']
-                .into_iter()
-                .find(|x| c == *x)
-                .is_some()
+    /// Return comments as `Token::Comment` instead of skipping them, for
+    /// tools that write the source back like `unlisp fmt`. The reader doesn't
+    /// expect them.
+    pub fn set_keep_comments(&mut self, keep: bool) {
+        self.keep_comments = keep;
+    }
+
+    /// Overrides the mode set with `escapes::set_symbol_case` for the rest of
+    /// the input.
+    pub fn set_symbol_case(&mut self, case: SymbolCase) {
+        self.symbol_case = case;
+    }
+
+    /// Number of bytes of the input consumed so far.
+    pub fn position(&self) -> usize {
+        self.pbr.position()
+    }
+
+    /// Number of bytes of the input looked at so far, which includes the
+    /// ones after a token read to tell where it ends.
+    pub fn furthest(&self) -> usize {
+        self.pbr.furthest()
+    }
+
+    /// Where the last token returned by `next_token` starts, after the
+    /// whitespace and comments before it.
+    pub fn token_start(&self) -> usize {
+        self.token_start
+    }
+
+    /// The line of the last byte consumed, after an error it's the line
+    /// reading stopped on.
+    pub fn line(&self) -> usize {
+        self.line_of(self.position().saturating_sub(1))
+    }
+
+    /// The line of a byte that was already consumed.
+    pub fn line_of(&self, pos: usize) -> usize {
+        self.lines.line_at(pos)
+    }
+
+    /// The column, counted in bytes from 1, of a byte that was already
+    /// consumed.
+    pub fn column_of(&self, pos: usize) -> usize {
+        self.lines.column_at(pos)
     }
 
     fn next_char(&mut self) -> io::Result<char> {
@@ -51,6 +219,7 @@ impl<'a, T: Read> Lexer<'a, T> {
                 e
             }
         })?;
+        self.lines.scan(self.position() - 1, one_byte[0]);
         Ok(one_byte[0] as char)
     }
 
@@ -58,23 +227,144 @@ impl<'a, T: Read> Lexer<'a, T> {
         self.pbr.unread_byte(c as u8);
     }
 
-    fn read_string_literal(&mut self) -> io::Result<String> {
-        let mut buf = Vec::new();
+    fn read_string_literal(&mut self) -> GenResult<String> {
+        let mut text = Vec::new();
         loop {
             let c = self.next_char()?;
 
-            // TODO: handle escaping
             if c == '"' {
                 break;
             }
-            buf.push(c);
+            // an escaped quote doesn't end the literal
+            if c == '\\' {
+                text.push(c as u8);
+                text.push(self.next_char()? as u8);
+                continue;
+            }
+            // next_char reads single bytes, so this is the original byte
+            text.push(c as u8);
         }
 
-        Ok(buf.into_iter().collect())
+        let bytes = escapes::unescape_string(&text)
+            .map_err(|msg| error::Error::new(error::ErrorType::Reader, msg))?;
+
+        Ok(String::from_utf8(bytes).map_err(|_| {
+            error::Error::new(
+                error::ErrorType::Reader,
+                "string literal is not valid UTF-8",
+            )
+        })?)
+    }
+
+    // reads what follows '#', a label like `#1=` or `#1#` or a character
+    fn read_dispatch(&mut self) -> GenResult<Token> {
+        let c = self.next_char()?;
+        self.unread_char(c);
+
+        if c.is_ascii_digit() {
+            self.read_label()
+        } else {
+            Ok(Token::CharLiteral(self.read_char_literal()?))
+        }
     }
 
-    fn read_integer_literal(&mut self) -> io::Result<i64> {
+    fn read_label(&mut self) -> GenResult<Token> {
+        let mut digits = String::new();
+        let mut c = self.next_char()?;
+
+        while c.is_ascii_digit() {
+            digits.push(c);
+            c = self.next_char()?;
+        }
+
+        let label = digits.parse::<u64>().map_err(|_| {
+            error::Error::new(
+                error::ErrorType::Reader,
+                format!("label #{} is too large", digits),
+            )
+        })?;
+
+        match c {
+            '=' => Ok(Token::Label(label)),
+            '#' => Ok(Token::LabelRef(label)),
+            _ => Err(error::Error::new(
+                error::ErrorType::Reader,
+                format!("expected = or # after #{}", digits),
+            ))?,
+        }
+    }
+
+    // reads what follows '#' for a single character like `#\a` or a named
+    // one like `#\space`
+    fn read_char_literal(&mut self) -> GenResult<char> {
+        if self.next_char()? != '\\' {
+            Err(error::Error::new(
+                error::ErrorType::Reader,
+                "expected \\ after #",
+            ))?
+        }
+
+        // bytes of multi-byte characters are read as the atom they're in
+        let c = self.next_char()?;
+        if c.is_ascii() && !c.is_alphanumeric() {
+            return Ok(c);
+        }
+
+        self.unread_char(c);
+        let (name, _) = self.read_atom()?;
+
+        let mut chars = name.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(c);
+        }
+
+        match CHAR_NAMES.iter().find(|(n, _)| *n == name) {
+            Some((_, named)) => Ok(*named),
+            None => Err(error::Error::new(
+                error::ErrorType::Reader,
+                format!("unknown character name {}", name),
+            ))?,
+        }
+    }
+
+    fn parse_number(atom: &str) -> Option<Token> {
+        escapes::parse_number(atom).map(|number| match number {
+            ParsedNumber::Int(i) => Token::IntegerLiteral(i),
+            ParsedNumber::BigInt(i) => Token::BigIntegerLiteral(i),
+            ParsedNumber::Float(f) => Token::FloatLiteral(f),
+        })
+    }
+
+    // reads up to the closing bar, a doubled bar stands for a bar in the name
+    fn read_bar_quoted(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        loop {
+            let c = self.next_char()?;
+
+            if c != '|' {
+                buf.push(c as u8);
+                continue;
+            }
+
+            let next = self.next_char();
+            if is_eof(&next) {
+                return Ok(());
+            }
+
+            match next? {
+                '|' => buf.push(b'|'),
+                next => {
+                    self.unread_char(next);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // the name of the atom and whether any part of it was escaped
+    fn read_atom(&mut self) -> GenResult<(String, bool)> {
         let mut buf = Vec::new();
+        let mut escaped = false;
+
         loop {
             let c = self.next_char();
 
@@ -82,52 +372,131 @@ impl<'a, T: Read> Lexer<'a, T> {
                 break;
             }
 
-            let c = c?;
-
-            if c.is_numeric() {
-                buf.push(c);
-            } else {
-                self.unread_char(c);
-                break;
+            match c? {
+                '|' => {
+                    escaped = true;
+                    self.read_bar_quoted(&mut buf)?;
+                }
+                '\\' => {
+                    escaped = true;
+                    buf.push(self.next_char()? as u8);
+                }
+                c if is_atom_char(c) => buf.push(c as u8),
+                c => {
+                    self.unread_char(c);
+                    break;
+                }
             }
         }
 
-        let s: String = buf.into_iter().collect();
-        Ok(s.parse::<i64>().unwrap())
+        // symbols are interned as C strings
+        if buf.contains(&0) {
+            Err(error::Error::new(
+                error::ErrorType::Reader,
+                "symbol name can't contain a NUL character",
+            ))?
+        }
+
+        let name = String::from_utf8(buf).map_err(|_| {
+            error::Error::new(error::ErrorType::Reader, "symbol name is not valid UTF-8")
+        })?;
+
+        Ok((name, escaped))
     }
+    fn skip_line(&mut self) -> io::Result<()> {
+        let mut next_char = self.next_char()?;
+        while next_char != '\n' && next_char != '\r' {
+            next_char = self.next_char()?;
+        }
 
-    fn read_symbol(&mut self) -> io::Result<String> {
-        let mut buf = Vec::new();
+        Ok(())
+    }
+
+    // the rest of a line after ';', without the line terminator
+    fn read_comment(&mut self) -> io::Result<String> {
+        let mut buf = vec![b';'];
         loop {
             let c = self.next_char();
-
             if is_eof(&c) {
                 break;
             }
 
-            let c = c?;
+            match c? {
+                '\n' | '\r' => break,
+                c => buf.push(c as u8),
+            }
+        }
 
-            if Self::valid_symbol_char(c) {
-                buf.push(c);
-            } else {
-                self.unread_char(c);
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    // a UTF-8 byte order mark is skipped at the start of the input
+    fn skip_bom(&mut self) -> io::Result<()> {
+        let mut read = vec![];
+
+        for expected in BOM.iter() {
+            let c = self.next_char();
+            if is_eof(&c) {
                 break;
             }
+
+            let c = c? as u8;
+            read.push(c);
+            if c != *expected {
+                break;
+            }
+        }
+
+        if read != BOM {
+            self.pbr.unread(&read);
         }
 
-        Ok(buf.into_iter().collect())
+        Ok(())
     }
 
-    fn skip_line(&mut self) -> io::Result<()> {
-        let mut next_char = self.next_char()?;
-        while next_char != '\n' {
-            next_char = self.next_char()?;
+    // a '#!' line is only skipped when it's the very first line of the input,
+    // so scripts can be made executable
+    fn skip_shebang(&mut self) -> io::Result<()> {
+        let first = self.next_char();
+        if is_eof(&first) {
+            return Ok(());
         }
+        let first = first?;
 
-        Ok(())
+        if first != '#' {
+            self.unread_char(first);
+            return Ok(());
+        }
+
+        let second = self.next_char();
+        match second {
+            Ok('!') => {
+                let skipped = self.skip_line();
+                if is_eof(&skipped) {
+                    return Ok(());
+                }
+                skipped
+            }
+            Ok(c) => {
+                self.unread_char(c);
+                self.unread_char(first);
+                Ok(())
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                self.unread_char(first);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
     }
 
     pub fn next_token(&mut self) -> GenResult<Option<Token>> {
+        if self.at_start {
+            self.at_start = false;
+            self.skip_bom()?;
+            self.skip_shebang()?;
+        }
+
         let c = self.next_char();
 
         if is_eof(&c) {
@@ -140,7 +509,11 @@ impl<'a, T: Read> Lexer<'a, T> {
             return self.next_token();
         }
 
+        // c is a single byte
+        self.token_start = self.position() - 1;
+
         let tok = match c {
+            ';' if self.keep_comments => Token::Comment(self.read_comment()?),
             ';' => {
                 self.skip_line()?;
                 return self.next_token();
@@ -148,17 +521,24 @@ impl<'a, T: Read> Lexer<'a, T> {
             '(' => Token::LeftPar,
             ')' => Token::RightPar,
 
-            c if c.is_numeric() => {
+            c if is_atom_char(c) => {
                 self.unread_char(c);
-                Token::IntegerLiteral(self.read_integer_literal()?)
-            }
+                let (atom, escaped) = self.read_atom()?;
 
-            c if Self::valid_symbol_char(c) => {
-                self.unread_char(c);
-                Token::Symbol(self.read_symbol()?)
+                if escaped {
+                    Token::EscapedSymbol(atom)
+                } else if atom == "." {
+                    Token::Dot
+                } else {
+                    match Self::parse_number(&atom) {
+                        Some(number) => number,
+                        None => Token::Symbol(self.symbol_case.normalize(&atom)),
+                    }
+                }
             }
 
             '"' => Token::StringLiteral(self.read_string_literal()?),
+            '#' => self.read_dispatch()?,
             _ => Err(error::Error::new(
                 error::ErrorType::Reader,
                 format!("unexpexted char {}", c),
@@ -197,6 +577,96 @@ mod tests {
         assert_eq!(lexer.next_token().unwrap(), None);
     }
 
+    #[test]
+    fn test_shebang_is_skipped() {
+        let mut input = "#!/usr/bin/env unlisp\nfoo".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::Symbol("foo".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap(), None);
+
+        let mut input = "#!/usr/bin/env unlisp".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+
+        assert_eq!(lexer.next_token().unwrap(), None);
+    }
+
+    #[test]
+    fn test_shebang_not_on_first_line() {
+        let mut input = "foo\n#!/usr/bin/env unlisp".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+
+        let _ = lexer.next_token().unwrap().unwrap();
+        assert!(lexer.next_token().is_err());
+
+        let mut input = " #!/usr/bin/env unlisp".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+
+        assert!(lexer.next_token().is_err());
+    }
+
+    #[test]
+    fn test_char_literal() {
+        let mut input = "#\\a #\\( #\\space #\\1)".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+
+        for c in &['a', '(', ' ', '1'] {
+            assert_eq!(lexer.next_token().unwrap().unwrap(), Token::CharLiteral(*c));
+        }
+        assert_eq!(lexer.next_token().unwrap().unwrap(), Token::RightPar);
+
+        let mut input = "#\\bogus".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+
+        assert!(lexer.next_token().is_err());
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut lexer = Lexer::from_str("#1=(a . #1#) #12#x");
+
+        assert_eq!(lexer.next_token().unwrap().unwrap(), Token::Label(1));
+        assert_eq!(lexer.next_token().unwrap().unwrap(), Token::LeftPar);
+        let _ = lexer.next_token().unwrap().unwrap();
+        assert_eq!(lexer.next_token().unwrap().unwrap(), Token::Dot);
+        assert_eq!(lexer.next_token().unwrap().unwrap(), Token::LabelRef(1));
+        assert_eq!(lexer.next_token().unwrap().unwrap(), Token::RightPar);
+        assert_eq!(lexer.next_token().unwrap().unwrap(), Token::LabelRef(12));
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::Symbol("x".to_string())
+        );
+
+        for src in &["#1", "#1x", "#99999999999999999999="] {
+            assert!(Lexer::from_str(src).next_token().is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_dot() {
+        let mut input = "(a . b) a.b".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+
+        assert_eq!(lexer.next_token().unwrap().unwrap(), Token::LeftPar);
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::Symbol("a".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().unwrap(), Token::Dot);
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::Symbol("b".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().unwrap(), Token::RightPar);
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::Symbol("a.b".to_string())
+        );
+    }
+
     #[test]
     fn test_integer_literal() {
         let mut input = "1 12 1000 2019".as_bytes();
@@ -220,6 +690,153 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_negative_integer_literal() {
+        let mut input = "-1 +2 - 1+".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::IntegerLiteral(-1)
+        );
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::IntegerLiteral(2)
+        );
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::Symbol("-".to_string())
+        );
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::Symbol("1+".to_string())
+        );
+    }
+
+    #[test]
+    fn test_big_integer_literal() {
+        let mut input = "99999999999999999999 -9223372036854775809 9223372036854775807".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::BigIntegerLiteral(BigInt::parse("99999999999999999999").unwrap())
+        );
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::BigIntegerLiteral(BigInt::parse("-9223372036854775809").unwrap())
+        );
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::IntegerLiteral(std::i64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_float_literal() {
+        let mut input = "1.5 1e10 1.5e-3 -0.0 +inf.0 -inf.0 2.5E+2".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+
+        let expected = [1.5, 1e10, 1.5e-3, -0.0, std::f64::INFINITY, std::f64::NEG_INFINITY, 250.0];
+
+        for f in expected.iter() {
+            match lexer.next_token().unwrap().unwrap() {
+                Token::FloatLiteral(read) => assert_eq!(read.to_bits(), f.to_bits()),
+                tok => panic!("expected float, got {:?}", tok),
+            }
+        }
+
+        let mut input = "+nan.0 1. 1e 1.e5".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+
+        match lexer.next_token().unwrap().unwrap() {
+            Token::FloatLiteral(read) => assert!(read.is_nan()),
+            tok => panic!("expected float, got {:?}", tok),
+        }
+
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::Symbol("1.".to_string())
+        );
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::Symbol("1e".to_string())
+        );
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::Symbol("1.e5".to_string())
+        );
+    }
+
+    #[test]
+    fn test_float_print_read_round_trip() {
+        // xorshift, to cover arbitrary bit patterns without extra dependencies
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+
+        for _ in 0..10000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            let f = f64::from_bits(state);
+            let printed = unlisp_rt::defs::format_float(f);
+            let hex = unlisp_rt::defs::format_hex_float(f);
+
+            for printed in [printed, hex].iter() {
+                let mut input = printed.as_bytes();
+                let mut lexer = Lexer::create(&mut input);
+
+                match lexer.next_token().unwrap().unwrap() {
+                    Token::FloatLiteral(read) if f.is_nan() => assert!(read.is_nan()),
+                    Token::FloatLiteral(read) => {
+                        assert_eq!(read.to_bits(), f.to_bits(), "{}", printed)
+                    }
+                    tok => panic!("{} read back as {:?}", printed, tok),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_hex_float_literal() {
+        let cases = [
+            ("0x1.8p3", 12.0),
+            ("-0x1p-1", -0.5),
+            ("0X1.8P+1", 3.0),
+            ("-0x0p0", -0.0),
+            ("0x1p-1074", 5e-324),
+            ("0x1.fffffffffffffp1023", std::f64::MAX),
+            // rounded to nearest, ties to even, like decimal floats
+            ("0x1.00000000000008p0", 1.0),
+            ("0x1.000000000000080001p0", 1.0 + std::f64::EPSILON),
+            ("0x1p-1075", 0.0),
+            ("0x1p1024", std::f64::INFINITY),
+        ];
+
+        for (src, expected) in cases.iter() {
+            let mut input = src.as_bytes();
+            let mut lexer = Lexer::create(&mut input);
+
+            match lexer.next_token().unwrap().unwrap() {
+                Token::FloatLiteral(read) => {
+                    assert_eq!(read.to_bits(), expected.to_bits(), "{}", src)
+                }
+                tok => panic!("expected float for {}, got {:?}", src, tok),
+            }
+        }
+
+        // no exponent, digits missing around the point, or not hex
+        let mut input = "0x10 0x1p 0x.8p1 0x1.p1 0x1gp1".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+
+        for symbol in ["0x10", "0x1p", "0x.8p1", "0x1.p1", "0x1gp1"].iter() {
+            assert_eq!(
+                lexer.next_token().unwrap().unwrap(),
+                Token::Symbol(symbol.to_string())
+            );
+        }
+    }
+
     #[test]
     fn test_string_literal() {
         let mut input = "\"\" \"foo\" \"bar\"".as_bytes();
@@ -239,6 +856,101 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_string_escapes() {
+        let cases = [
+            (r#""a\"b""#, "a\"b"),
+            (r#""\"""#, "\""),
+            (r#""a\\b""#, "a\\b"),
+            (r#""\\""#, "\\"),
+            (r#""\n\t\r""#, "\n\t\r"),
+            (r#""\x41\x7f\xc3\x9f""#, "A\u{7f}ß"),
+            ("\"raw\nline\"", "raw\nline"),
+        ];
+
+        for (src, expected) in cases.iter() {
+            let mut input = src.as_bytes();
+            let mut lexer = Lexer::create(&mut input);
+
+            assert_eq!(
+                lexer.next_token().unwrap().unwrap(),
+                Token::StringLiteral(expected.to_string()),
+                "{}",
+                src
+            );
+        }
+
+        for src in [r#""\q""#, r#""\x4""#, r#""\x00""#, r#""\xff""#].iter() {
+            let mut input = src.as_bytes();
+            let mut lexer = Lexer::create(&mut input);
+
+            let err = lexer.next_token().unwrap_err();
+            assert!(err.to_string().starts_with("reader error"), "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_escaped_string_print_read_round_trip() {
+        // xorshift, to cover arbitrary contents without extra dependencies
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..2000 {
+            let len = next() % 16;
+            let bytes: Vec<u8> = (0..len).map(|_| (next() % 255) as u8 + 1).collect();
+
+            let escaped = escapes::escape_string(&bytes);
+            assert_eq!(
+                escapes::unescape_string(escaped.as_bytes()),
+                Ok(bytes.clone()),
+                "{}",
+                escaped
+            );
+
+            // only valid UTF-8 can be read as a literal
+            let s = match String::from_utf8(bytes) {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            let printed = format!("\"{}\"", escaped);
+            let mut input = printed.as_bytes();
+            let mut lexer = Lexer::create(&mut input);
+
+            assert_eq!(
+                lexer.next_token().unwrap().unwrap(),
+                Token::StringLiteral(s),
+                "{}",
+                printed
+            );
+        }
+
+        // mostly ASCII with quotes and backslashes, so that most strings are
+        // valid UTF-8
+        let alphabet = ['"', '\\', '\n', '\t', 'a', ' ', 'ß', '\u{1}', '\u{85}'];
+        for _ in 0..2000 {
+            let len = next() % 16;
+            let s: String = (0..len)
+                .map(|_| alphabet[(next() % alphabet.len() as u64) as usize])
+                .collect();
+
+            let printed = format!("\"{}\"", escapes::escape_string(s.as_bytes()));
+            let mut input = printed.as_bytes();
+            let mut lexer = Lexer::create(&mut input);
+
+            assert_eq!(
+                lexer.next_token().unwrap().unwrap(),
+                Token::StringLiteral(s),
+                "{}",
+                printed
+            );
+        }
+    }
+
     #[test]
     fn test_incomplete_string() {
         let mut input = "\"foo".as_bytes();
@@ -249,7 +961,7 @@ mod tests {
 
     #[test]
     fn test_symbol() {
-        let mut input = "x foo bar*".as_bytes();
+        let mut input = "x foo bar* %include".as_bytes();
         let mut lexer = Lexer::create(&mut input);
 
         assert_eq!(
@@ -264,6 +976,103 @@ mod tests {
             lexer.next_token().unwrap().unwrap(),
             Token::Symbol("bar*".to_string())
         );
+        // the reader's directives start with one
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::Symbol("%include".to_string())
+        );
+    }
+
+    #[test]
+    fn test_escaped_symbol() {
+        let cases = [
+            ("|hello world|", "hello world"),
+            ("|(a \"b\" ; c)|", "(a \"b\" ; c)"),
+            ("|a||b|", "a|b"),
+            ("||", ""),
+            ("|12|", "12"),
+            ("|nil|", "nil"),
+            ("foo|bar baz|qux", "foobar bazqux"),
+            ("f\\(o\\ o", "f(o o"),
+            ("\\1", "1"),
+            ("|a\\b|", "a\\b"),
+            ("|λ|", "λ"),
+        ];
+
+        for (src, expected) in cases.iter() {
+            let mut input = src.as_bytes();
+            let mut lexer = Lexer::create(&mut input);
+
+            assert_eq!(
+                lexer.next_token().unwrap().unwrap(),
+                Token::EscapedSymbol(expected.to_string()),
+                "{}",
+                src
+            );
+            assert_eq!(lexer.next_token().unwrap(), None, "{}", src);
+        }
+
+        let mut input = "(|a b|)".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+
+        assert_eq!(lexer.next_token().unwrap().unwrap(), Token::LeftPar);
+        assert_eq!(
+            lexer.next_token().unwrap().unwrap(),
+            Token::EscapedSymbol("a b".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().unwrap(), Token::RightPar);
+    }
+
+    #[test]
+    fn test_unterminated_escaped_symbol() {
+        for src in ["|foo", "|foo bar||", "foo\\"].iter() {
+            let mut input = src.as_bytes();
+            let mut lexer = Lexer::create(&mut input);
+
+            assert!(is_gen_eof(&lexer.next_token()), "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_escaped_symbol_print_read_round_trip() {
+        // xorshift, to cover arbitrary names without extra dependencies
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let alphabet = [
+            'a', 'B', '1', '.', '-', '+', 'e', ' ', '(', ')', '|', '\\', '"', ';', '#', '\'', 'λ',
+        ];
+        let mut names: Vec<String> = ["", ".", "nil", "t", "1", "-1.5e3", "+inf.0", "a.b", "1+"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        for _ in 0..2000 {
+            let len = next() % 8;
+            names.push(
+                (0..len)
+                    .map(|_| alphabet[(next() % alphabet.len() as u64) as usize])
+                    .collect(),
+            );
+        }
+
+        for name in names {
+            let printed = escapes::escape_symbol(&name);
+            let mut input = printed.as_bytes();
+            let mut lexer = Lexer::create(&mut input);
+
+            match lexer.next_token().unwrap().unwrap() {
+                Token::Symbol(read) | Token::EscapedSymbol(read) => {
+                    assert_eq!(read, name, "{}", printed)
+                }
+                tok => panic!("{} read back as {:?}", printed, tok),
+            }
+            assert_eq!(lexer.next_token().unwrap(), None, "{}", printed);
+        }
     }
 
     #[test]
@@ -286,4 +1095,178 @@ mod tests {
             Token::Symbol("foo".to_string())
         );
     }
+
+    #[test]
+    fn test_kept_comments() {
+        let src = "; first\r\n(foo ;; second\n bar) ; last";
+        let mut lexer = Lexer::from_str(src);
+        lexer.set_keep_comments(true);
+
+        let mut comments = vec![];
+        while let Some(tok) = lexer.next_token().unwrap() {
+            if let Token::Comment(text) = tok {
+                comments.push((lexer.token_start(), lexer.line(), text));
+            }
+        }
+
+        assert_eq!(
+            comments,
+            vec![
+                (0, 1, "; first".to_string()),
+                (14, 2, ";; second".to_string()),
+                (30, 3, "; last".to_string())
+            ]
+        );
+    }
+
+    // the same lines with LF, CRLF and CR line endings, and LF after a BOM
+    fn line_ending_variants(lines: &[&str]) -> Vec<(&'static str, String)> {
+        let joined = |term| lines.join(term);
+        vec![
+            ("\n", joined("\n")),
+            ("\r\n", joined("\r\n")),
+            ("\r", joined("\r")),
+            ("\n", format!("\u{feff}{}", joined("\n"))),
+        ]
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let lines = [
+            "; comment on line 1",
+            "(defun f (x)",
+            "  \"two",
+            "lines\" x) ; trailing",
+            "(g 1) )",
+        ];
+
+        let mut streams = vec![];
+        for (term, src) in line_ending_variants(&lines) {
+            let mut lexer = Lexer::from_str(&src);
+            let mut tokens = vec![];
+
+            while let Some(tok) = lexer.next_token().unwrap() {
+                let line = lexer.lines.line_at(lexer.token_start());
+                let tok = match tok {
+                    // string literals keep the line endings as they are
+                    Token::StringLiteral(s) => {
+                        assert_eq!(s, format!("two{}lines", term), "{:?}", src);
+                        Token::StringLiteral(s.replace(term, "\n"))
+                    }
+                    tok => tok,
+                };
+                tokens.push((line, tok));
+            }
+
+            assert_eq!(lexer.line(), 5, "{:?}", src);
+            streams.push(tokens);
+        }
+
+        let sym = |s: &str| Token::Symbol(s.to_string());
+        assert_eq!(
+            streams[0],
+            vec![
+                (2, Token::LeftPar),
+                (2, sym("defun")),
+                (2, sym("f")),
+                (2, Token::LeftPar),
+                (2, sym("x")),
+                (2, Token::RightPar),
+                (3, Token::StringLiteral("two\nlines".to_string())),
+                (4, sym("x")),
+                (4, Token::RightPar),
+                (5, Token::LeftPar),
+                (5, sym("g")),
+                (5, Token::IntegerLiteral(1)),
+                (5, Token::RightPar),
+                (5, Token::RightPar),
+            ]
+        );
+        for tokens in streams.iter().skip(1) {
+            assert_eq!(tokens, &streams[0]);
+        }
+    }
+
+    #[test]
+    fn test_line_at() {
+        let src = b"a\r\nb\rc\nd";
+        let lines: Vec<_> = (0..src.len()).map(|pos| line_at(src, pos)).collect();
+        assert_eq!(lines, [1, 1, 1, 2, 2, 3, 3, 4]);
+    }
+
+    #[test]
+    fn test_bom_only_skipped_at_start() {
+        let mut input = "\u{feff}foo".as_bytes();
+        let mut lexer = Lexer::create(&mut input);
+        assert_eq!(
+            lexer.next_token().unwrap(),
+            Some(Token::Symbol("foo".to_string()))
+        );
+
+        let mut lexer = Lexer::from_str("\u{feff}");
+        assert_eq!(lexer.next_token().unwrap(), None);
+
+        // a partial BOM is read back as it was
+        let mut lexer = Lexer::from_str("\u{fefe}");
+        assert_eq!(
+            lexer.next_token().unwrap(),
+            Some(Token::Symbol("\u{fefe}".to_string()))
+        );
+
+        let mut lexer = Lexer::from_str("foo \u{feff}");
+        assert_eq!(
+            lexer.next_token().unwrap(),
+            Some(Token::Symbol("foo".to_string()))
+        );
+        assert_eq!(
+            lexer.next_token().unwrap(),
+            Some(Token::Symbol("\u{feff}".to_string()))
+        );
+    }
+
+    fn tokens_of<S: Source>(mut lexer: Lexer<S>) -> Vec<Result<(usize, Token), String>> {
+        let mut tokens = vec![];
+        loop {
+            match lexer.next_token() {
+                Ok(Some(tok)) => tokens.push(Ok((lexer.token_start(), tok))),
+                Ok(None) => return tokens,
+                Err(e) => {
+                    tokens.push(Err(e.to_string()));
+                    return tokens;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_str_and_io_sources_agree() {
+        let inputs = [
+            "",
+            "#!/usr/bin/env unlisp\n(foo 1 -2.5 #\\a . \"b\\\"c\") ; comment\nbar",
+            "|a b| \\1 123456789012345678901234567890 +inf.0",
+            "(λ \"héllo\")",
+            "\"unterminated",
+            "#\\bogus",
+        ];
+
+        for src in inputs.iter() {
+            let mut input = src.as_bytes();
+            assert_eq!(
+                tokens_of(Lexer::create(&mut input)),
+                tokens_of(Lexer::from_str(src)),
+                "{}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn test_token_start() {
+        let mut lexer = Lexer::from_str("  foo ; bar\n (");
+
+        let _ = lexer.next_token().unwrap().unwrap();
+        assert_eq!(lexer.token_start(), 2);
+        assert_eq!(lexer.next_token().unwrap().unwrap(), Token::LeftPar);
+        assert_eq!(lexer.token_start(), 13);
+    }
 }
@@ -1,6 +1,10 @@
 pub mod codegen;
 pub mod error;
+pub mod fingerprint;
+pub mod fold;
+pub mod inline;
 pub mod lexer;
+pub mod macro_env;
 pub mod pushback_reader;
 pub mod reader;
 pub mod repr;
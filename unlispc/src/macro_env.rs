@@ -0,0 +1,115 @@
+use crate::codegen::context::CodegenContext;
+use crate::error::Error;
+use crate::repr::{Form, HIR};
+use unlisp_rt::defs::Function;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Local macros established by `macrolet`, innermost scope last. Macro
+// functions are compiled in a separate context which is never dropped, so
+// symbols and strings they intern stay valid after expansion.
+thread_local! {
+    static SCOPES: RefCell<Vec<HashMap<String, *mut Function>>> = RefCell::new(vec![]);
+    static MACRO_CTX: RefCell<Option<CodegenContext>> = RefCell::new(None);
+}
+
+// Symbol macros established by `symbol-macrolet`. A `None` entry is a
+// lexical variable shadowing an outer symbol macro.
+thread_local! {
+    static SYMBOL_SCOPES: RefCell<Vec<HashMap<String, Option<Form>>>> = RefCell::new(vec![]);
+}
+
+pub fn lookup_local_macro(name: &str) -> Option<*mut Function> {
+    SCOPES.with(|scopes| {
+        scopes
+            .borrow()
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).cloned())
+    })
+}
+
+pub fn with_local_macros<T>(macros: HashMap<String, *mut Function>, f: impl FnOnce() -> T) -> T {
+    SCOPES.with(|scopes| scopes.borrow_mut().push(macros));
+    let result = f();
+    SCOPES.with(|scopes| scopes.borrow_mut().pop());
+    result
+}
+
+// `lambda_hir` must evaluate to a function. Global variables aren't declared
+// in the macro context, so local macro bodies can only refer to their own
+// bindings and to global functions.
+pub fn compile_local_macro(lambda_hir: &HIR) -> Result<*mut Function, Error> {
+    MACRO_CTX.with(|ctx| {
+        let mut ctx = ctx.borrow_mut();
+        let ctx = ctx.get_or_insert_with(CodegenContext::new);
+
+        let result = unsafe { ctx.eval_hirs(&[lambda_hir.clone()]) };
+        ctx.reinitialize();
+
+        let f = result?.unpack_function();
+        unsafe { (*f).is_macro = true };
+
+        Ok(f)
+    })
+}
+
+pub fn lookup_symbol_macro(name: &str) -> Option<Form> {
+    SYMBOL_SCOPES.with(|scopes| {
+        scopes
+            .borrow()
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).cloned())
+            .and_then(|expansion| expansion)
+    })
+}
+
+// Pushes a symbol macro scope which is popped when the guard is dropped,
+// including on early returns with an error.
+pub struct SymbolScope;
+
+impl SymbolScope {
+    pub fn enter() -> Self {
+        SYMBOL_SCOPES.with(|scopes| scopes.borrow_mut().push(HashMap::new()));
+        SymbolScope
+    }
+}
+
+impl Drop for SymbolScope {
+    fn drop(&mut self) {
+        SYMBOL_SCOPES.with(|scopes| scopes.borrow_mut().pop());
+    }
+}
+
+// Let and lambda bindings are recorded as shadowing entries, so a name whose
+// innermost entry has no expansion is a lexical variable.
+pub fn is_lexical_variable(name: &str) -> bool {
+    SYMBOL_SCOPES.with(|scopes| {
+        scopes
+            .borrow()
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .map_or(false, |expansion| expansion.is_none())
+    })
+}
+
+pub fn define_symbol_macro(name: &str, expansion: Form) {
+    SYMBOL_SCOPES.with(|scopes| {
+        scopes
+            .borrow_mut()
+            .last_mut()
+            .expect("no symbol scope to define a symbol macro in")
+            .insert(name.to_string(), Some(expansion));
+    })
+}
+
+pub fn shadow_symbol(name: &str) {
+    SYMBOL_SCOPES.with(|scopes| {
+        if let Some(scope) = scopes.borrow_mut().last_mut() {
+            scope.insert(name.to_string(), None);
+        }
+    })
+}
@@ -1,39 +1,106 @@
 use std::io::Read;
 use std::io::Result;
 
-pub struct PushbackReader<'a, T: Read + 'a> {
-    reader: &'a mut T,
+/// Where a `PushbackReader` gets its bytes from. Reading from an `io::Read`
+/// and from a string in memory share everything above this.
+pub trait Source {
+    fn read_bytes(&mut self, dest: &mut [u8]) -> Result<usize>;
+}
+
+pub struct IoSource<'a, T: Read + 'a>(&'a mut T);
+
+impl<'a, T: Read + 'a> Source for IoSource<'a, T> {
+    fn read_bytes(&mut self, dest: &mut [u8]) -> Result<usize> {
+        self.0.read(dest)
+    }
+}
+
+// the bytes not read yet
+pub struct StrSource<'a>(&'a [u8]);
+
+impl<'a> Source for StrSource<'a> {
+    fn read_bytes(&mut self, dest: &mut [u8]) -> Result<usize> {
+        let n = dest.len().min(self.0.len());
+        let (read, rest) = self.0.split_at(n);
+        dest[..n].copy_from_slice(read);
+        self.0 = rest;
+        Ok(n)
+    }
+}
+
+pub struct PushbackReader<S: Source> {
+    source: S,
     buffer: Vec<u8>,
+    // bytes read and not unread
+    position: usize,
+    // the furthest position has been
+    furthest: usize,
 }
 
-impl<'a, T: Read + 'a> Read for PushbackReader<'a, T> {
+impl<S: Source> Read for PushbackReader<S> {
     fn read(&mut self, dest: &mut [u8]) -> Result<usize> {
         let dest_size = dest.len();
         let buf_size = self.buffer.len();
 
-        if buf_size <= dest_size {
+        let read = if buf_size <= dest_size {
             self.buffer.reverse();
             dest[0..buf_size].copy_from_slice(self.buffer.as_slice());
             self.buffer.clear();
-            let read_from_reader = self.reader.read(&mut dest[buf_size..])?;
-            return Ok(read_from_reader + buf_size);
+            let read_from_source = self.source.read_bytes(&mut dest[buf_size..])?;
+            read_from_source + buf_size
         } else {
             let buf_part_iter = self.buffer.drain(buf_size - dest_size..buf_size).rev();
             dest.copy_from_slice(buf_part_iter.collect::<Vec<u8>>().as_slice());
-            Ok(dest_size)
-        }
+            dest_size
+        };
+
+        self.position += read;
+        self.furthest = self.furthest.max(self.position);
+        Ok(read)
+    }
+}
+
+impl<'a, T: Read + 'a> PushbackReader<IoSource<'a, T>> {
+    pub fn create(r: &'a mut T) -> Self {
+        Self::with_source(IoSource(r))
+    }
+}
+
+impl<'a> PushbackReader<StrSource<'a>> {
+    pub fn from_str(s: &'a str) -> Self {
+        Self::from_bytes(s.as_bytes())
+    }
+
+    pub fn from_bytes(s: &'a [u8]) -> Self {
+        Self::with_source(StrSource(s))
     }
 }
 
-impl<'a, T: Read + 'a> PushbackReader<'a, T> {
-    pub fn create(r: &'a mut T) -> PushbackReader<'a, T> {
+impl<S: Source> PushbackReader<S> {
+    fn with_source(source: S) -> Self {
         PushbackReader {
-            reader: r,
+            source,
             buffer: Vec::new(),
+            position: 0,
+            furthest: 0,
         }
     }
 
+    /// Number of bytes consumed so far, unread bytes don't count.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Number of bytes looked at so far, bytes unread and read again count
+    /// once.
+    pub fn furthest(&self) -> usize {
+        self.furthest
+    }
+
     pub fn unread(&mut self, bs: &[u8]) {
+        // bytes that weren't read before can be pushed back too
+        self.position = self.position.saturating_sub(bs.len());
+
         let orig_buf_size = self.buffer.len();
         self.buffer.extend_from_slice(bs);
         let actual_buf_size = self.buffer.len();
@@ -60,6 +127,43 @@ mod tests {
         assert_eq!(buf, [1, 2, 3]);
     }
 
+    #[test]
+    fn position_counts_unread_bytes_back() {
+        let vec: Vec<u8> = vec![1, 2, 3];
+        let mut slice = vec.as_slice();
+        let mut pbr = PushbackReader::create(&mut slice);
+
+        let mut buf: [u8; 2] = [0; 2];
+        pbr.read_exact(&mut buf).unwrap();
+        assert_eq!(pbr.position(), 2);
+
+        pbr.unread_byte(2);
+        assert_eq!(pbr.position(), 1);
+        assert_eq!(pbr.furthest(), 2);
+
+        let mut buf = Vec::new();
+        pbr.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, [2, 3]);
+        assert_eq!(pbr.position(), 3);
+        assert_eq!(pbr.furthest(), 3);
+    }
+
+    #[test]
+    fn str_source_reads_like_a_slice() {
+        let mut slice = "abc".as_bytes();
+        let mut from_io = PushbackReader::create(&mut slice);
+        let mut from_str = PushbackReader::from_str("abc");
+
+        for pbr in &mut [&mut from_io as &mut dyn Read, &mut from_str] {
+            let mut buf: [u8; 2] = [0; 2];
+            assert_eq!(pbr.read(&mut buf).unwrap(), 2);
+            assert_eq!(&buf, b"ab");
+            assert_eq!(pbr.read(&mut buf).unwrap(), 1);
+            assert_eq!(buf[0], b'c');
+            assert_eq!(pbr.read(&mut buf).unwrap(), 0);
+        }
+    }
+
     #[test]
     fn unread_sanity_check() {
         let vec: Vec<u8> = vec![];
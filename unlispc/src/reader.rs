@@ -1,89 +1,885 @@
 use crate::error;
-use crate::lexer::Lexer;
-use crate::lexer::Token;
-use crate::repr::Form;
+use crate::lexer::{self, Lexer, Lines, Token};
+use crate::pushback_reader::{IoSource, PushbackReader, StrSource};
+use crate::repr::{self, Form, FormSpans};
+use std::collections::HashSet;
 use std::error::Error;
 use std::io;
 use std::io::Read;
+use unlisp_rt::defs::Object;
+use unlisp_rt::escapes::{self, SymbolCase, SYMBOL_CASES};
 
-pub struct Reader<'a, T: Read + 'a> {
-    lexer: Lexer<'a, T>,
+pub use crate::pushback_reader::Source;
+
+const READTABLE_CASE: &str = "%readtable-case";
+
+// fewer bytes read than this are kept by `ParserState::discard_read`
+const MIN_DISCARDED: usize = 4096;
+
+type TokenResult = Result<Option<Token>, Box<dyn Error>>;
+
+/// A top-level form read by `ParserState::poll_form`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParsedForm {
+    pub form: Form,
+    /// Byte offsets of the form's first byte and of the one after its last.
+    pub start: usize,
+    pub end: usize,
+    /// Where the form and the forms in it start.
+    pub spans: FormSpans,
 }
 
-impl<'a, T: Read + 'a> Reader<'a, T> {
-    pub fn create(r: &'a mut T) -> Reader<'a, T> {
-        Reader {
-            lexer: Lexer::create(r),
+/// A point of a `ParserState` to go back to with `rollback`, small enough to
+/// take one after every form.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Checkpoint {
+    // where reading resumes
+    offset: usize,
+    // bytes looked at to read what came before offset
+    seen: usize,
+    symbol_case: SymbolCase,
+}
+
+impl Checkpoint {
+    /// Where reading resumes after `rollback`, the forms and errors before
+    /// the checkpoint end before it.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// How many bytes at the start of the input `rollback` keeps, which
+    /// have to be the same in the edited input. This can be one more than
+    /// `offset`, as the end of a symbol is only known from the byte after it.
+    pub fn kept_len(&self) -> usize {
+        self.seen
+    }
+}
+
+// what a token cut off by the end of the input pushed so far waits for
+// before lexing it again is worth it
+#[derive(Clone, Copy)]
+enum Awaiting {
+    Quote,
+    LineEnd,
+    // a byte that can't continue an atom
+    Delimiter,
+    AnyByte,
+}
+
+impl Awaiting {
+    fn is_ended_by(self, byte: u8) -> bool {
+        match self {
+            Awaiting::Quote => byte == b'"',
+            Awaiting::LineEnd => byte == b'\n' || byte == b'\r',
+            Awaiting::Delimiter => !lexer::is_atom_char(byte as char),
+            Awaiting::AnyByte => true,
         }
     }
+}
+
+// a list whose closing paren hasn't been read yet
+struct OpenList {
+    items: Vec<Form>,
+    // the list's own, with those of the items
+    spans: FormSpans,
+    // a '.' was read, the next form is the tail
+    after_dot: bool,
+    // the form after the '.', only the closing paren can follow it
+    tail: Option<(Form, FormSpans)>,
+}
+
+impl OpenList {
+    fn new(spans: FormSpans) -> Self {
+        Self {
+            items: vec![],
+            spans,
+            after_dot: false,
+            tail: None,
+        }
+    }
+}
+
+fn is_eof_error(e: &(dyn Error + 'static)) -> bool {
+    match e.downcast_ref::<io::Error>() {
+        Some(io_err) => io_err.kind() == io::ErrorKind::UnexpectedEof,
+        None => false,
+    }
+}
+
+/// The reader as a state machine fed the input in chunks, for editors that
+/// parse a buffer as it's typed. `Reader` is a loop over it.
+///
+/// Forms are read from the tokens pushed so far, a token cut off by the end
+/// of what was pushed waits for more input, or for `finish` to be read as it
+/// is. A `checkpoint` is a few numbers rather than a copy of the input, after
+/// an edit `rollback` goes back to one taken before it, so the forms read up
+/// to there don't have to be read again.
+///
+/// The input is kept until `discard_read` drops what was read, positions
+/// stay counted from the start of all of it.
+pub struct ParserState {
+    // what was pushed so far, without the first base bytes
+    text: Vec<u8>,
+    base: usize,
+    lines: Lines,
+    // no more input is coming
+    finished: bool,
+    // bytes of the tokens read so far and the whitespace after them
+    offset: usize,
+    // bytes looked at to read them, see `Checkpoint::kept_len`
+    seen: usize,
+    symbol_case: SymbolCase,
+    max_nesting_depth: usize,
+    // Lists being read are kept on an explicit stack rather than read
+    // recursively, so deeply nested input can't overflow the call stack.
+    // Labels wait for the next form finished as deep as they were read, the
+    // ones defined so far can be referred to anywhere in the form.
+    open_lists: Vec<OpenList>,
+    pending_labels: Vec<(usize, u64)>,
+    defined_labels: HashSet<u64>,
+    // lists still open in a form that's too deeply nested, the rest of it is
+    // skipped before the error is returned
+    skipped_depth: usize,
+    // where the form being read starts and what was seen before it
+    form_start: usize,
+    form_seen: usize,
+    // set until more input comes, with how much was pushed when it was
+    awaiting: Option<(Awaiting, usize)>,
+    // the last checkpoint that doesn't rely on the input ending where it
+    // does, once reading did
+    last_checkpoint: Option<Checkpoint>,
+}
+
+impl Default for ParserState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ParserState {
+    pub fn new() -> Self {
+        ParserState {
+            text: vec![],
+            base: 0,
+            lines: Lines::default(),
+            finished: false,
+            offset: 0,
+            seen: 0,
+            symbol_case: escapes::symbol_case(),
+            max_nesting_depth: repr::max_nesting_depth(),
+            open_lists: vec![],
+            pending_labels: vec![],
+            defined_labels: HashSet::new(),
+            skipped_depth: 0,
+            form_start: 0,
+            form_seen: 0,
+            awaiting: None,
+            last_checkpoint: None,
+        }
+    }
+
+    /// Overrides the limit set with `repr::set_max_nesting_depth`.
+    pub fn set_max_nesting_depth(&mut self, depth: usize) {
+        self.max_nesting_depth = depth;
+    }
+
+    /// Overrides the mode set with `escapes::set_symbol_case` for the rest of
+    /// the input, like a `(%readtable-case ...)` directive.
+    pub fn set_symbol_case(&mut self, case: SymbolCase) {
+        self.symbol_case = case;
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        self.push_bytes(s.as_bytes());
+    }
+
+    /// Appends to the input, chunks can end anywhere, even inside of a
+    /// token or a UTF-8 sequence.
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        assert!(!self.finished, "input pushed after finish");
+
+        for byte in bytes {
+            self.lines.scan(self.end(), *byte);
+            self.text.push(*byte);
+        }
+    }
+
+    /// Drops the input before the position once no form is being read, so
+    /// a long stream is read in constant memory, but for the starts of its
+    /// lines. Checkpoints taken before can't be rolled back to anymore.
+    /// Bytes are dropped once they're at least half of those kept, so
+    /// calling it after every form takes linear time.
+    pub fn discard_read(&mut self) {
+        let read = self.offset - self.base;
+
+        if self.in_form() || read < MIN_DISCARDED || read * 2 < self.text.len() {
+            return;
+        }
+
+        self.text.drain(..read);
+        self.base = self.offset;
+        self.last_checkpoint = None;
+    }
+
+    /// Tells that the input ends with what was pushed, so what's left of it
+    /// is read as it is.
+    pub fn finish(&mut self) {
+        self.finished = true;
+        self.awaiting = None;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Number of bytes of the input read so far, after an error it's where
+    /// reading stopped.
+    pub fn position(&self) -> usize {
+        self.offset
+    }
+
+    /// The line, counted from 1, of a byte that was pushed.
+    pub fn line_of(&self, pos: usize) -> usize {
+        self.lines.line_at(pos)
+    }
 
-    fn next_tok_or_eof(&mut self) -> Result<Token, Box<dyn Error>> {
-        let tok = self.lexer.next_token()?;
-        tok.ok_or(Box::new(io::Error::from(io::ErrorKind::UnexpectedEof)))
+    /// The column, counted in bytes from 1, of a byte that was pushed.
+    pub fn column_of(&self, pos: usize) -> usize {
+        self.lines.column_at(pos)
     }
 
-    fn tok_to_trivial_form(&self, tok: &Token) -> Option<Form> {
+    /// Number of lists open in the form being read.
+    pub fn depth(&self) -> usize {
+        self.open_lists.len() + self.skipped_depth
+    }
+
+    /// Whether the input ends inside of a string literal, once `poll_form`
+    /// returned none.
+    pub fn in_string(&self) -> bool {
+        self.byte_at(self.pending_start()) == Some(b'"')
+    }
+
+    /// Whether the input ends inside of a comment, once `poll_form` returned
+    /// none.
+    pub fn in_comment(&self) -> bool {
+        self.byte_at(self.pending_start()) == Some(b';')
+    }
+
+    /// Whether all of the input was read into forms, with nothing but
+    /// whitespace and comments after the last one, once `poll_form` returned
+    /// none.
+    pub fn is_between_forms(&self) -> bool {
+        !self.in_form() && self.pending_start() == self.end()
+    }
+
+    /// Where reading stands, see `rollback`.
+    pub fn checkpoint(&self) -> Checkpoint {
+        if let Some(checkpoint) = self.last_checkpoint {
+            return checkpoint;
+        }
+
+        // a form is read again from its start
+        let (offset, seen) = if self.in_form() {
+            (self.form_start, self.form_seen)
+        } else {
+            (self.offset, self.seen)
+        };
+
+        Checkpoint {
+            offset,
+            seen: seen.max(offset),
+            symbol_case: self.symbol_case,
+        }
+    }
+
+    /// Goes back to a checkpoint taken earlier, for an edit that leaves the
+    /// first `checkpoint.kept_len()` bytes of the input as they were. Only
+    /// those are kept, the rest of the edited input is pushed after them.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        assert!(
+            checkpoint.seen <= self.end(),
+            "checkpoint past the end of the input"
+        );
+        assert!(
+            checkpoint.offset >= self.base,
+            "checkpoint in input dropped by discard_read"
+        );
+
+        self.text.truncate(checkpoint.seen - self.base);
+        self.lines.truncate(checkpoint.seen, self.text.last().cloned());
+        self.offset = checkpoint.offset;
+        self.seen = checkpoint.seen;
+        self.symbol_case = checkpoint.symbol_case;
+        self.finished = false;
+        self.awaiting = None;
+        self.last_checkpoint = None;
+        self.drop_form();
+    }
+
+    // the position after the input pushed so far
+    fn end(&self) -> usize {
+        self.base + self.text.len()
+    }
+
+    fn byte_at(&self, pos: usize) -> Option<u8> {
+        pos.checked_sub(self.base)
+            .and_then(|i| self.text.get(i))
+            .cloned()
+    }
+
+    // the input from pos, which wasn't dropped, on
+    fn text_from(&self, pos: usize) -> &[u8] {
+        &self.text[pos - self.base..]
+    }
+
+    fn in_form(&self) -> bool {
+        !self.open_lists.is_empty() || !self.pending_labels.is_empty() || self.skipped_depth > 0
+    }
+
+    // forgets the form being read, after an error in it
+    fn drop_form(&mut self) {
+        self.open_lists.clear();
+        self.pending_labels.clear();
+        self.defined_labels.clear();
+        self.skipped_depth = 0;
+    }
+
+    // where what's left of the input after the whitespace and complete
+    // comments the lexer would skip starts
+    fn pending_start(&self) -> usize {
+        let mut pos = self.offset;
+
+        while let Some(byte) = self.byte_at(pos) {
+            if byte == b';' {
+                let line_end = self
+                    .text_from(pos)
+                    .iter()
+                    .position(|b| *b == b'\n' || *b == b'\r');
+                match line_end {
+                    Some(len) => pos += len + 1,
+                    None => break,
+                }
+            } else if (byte as char).is_whitespace() {
+                pos += 1;
+            } else {
+                break;
+            }
+        }
+
+        pos
+    }
+
+    // The lexer skips a BOM and a '#!' line at the start of the input, what
+    // it skipped can still turn out to be one until the first bytes and the
+    // line's end were pushed. Only asked before anything was read, so
+    // before anything was dropped.
+    fn in_preamble(&self) -> bool {
+        let after_bom = if self.text.starts_with(lexer::BOM) {
+            &self.text[lexer::BOM.len()..]
+        } else {
+            &self.text[..]
+        };
+
+        self.text.len() < lexer::BOM.len() + 2
+            || (after_bom.starts_with(b"#!")
+                && !after_bom.iter().any(|b| *b == b'\n' || *b == b'\r'))
+    }
+
+    // whether no more input came since the token at offset was found cut off
+    fn still_awaiting(&mut self) -> bool {
+        if let Some((awaiting, pushed)) = self.awaiting {
+            if !self.text_from(pushed).iter().any(|b| awaiting.is_ended_by(*b)) {
+                self.awaiting = Some((awaiting, self.end()));
+                return true;
+            }
+        }
+
+        self.awaiting = None;
+        false
+    }
+
+    // the next token with where it starts, how far the lexer read and how
+    // far it looked
+    fn lex_token(&self) -> (TokenResult, usize, usize, usize) {
+        let mut lexer = Lexer::resume(
+            self.text_from(self.offset),
+            self.offset == 0,
+            self.symbol_case,
+        );
+        let tok = lexer.next_token();
+
+        (
+            tok,
+            self.offset + lexer.token_start(),
+            self.offset + lexer.position(),
+            self.offset + lexer.furthest(),
+        )
+    }
+
+    /// The next top-level form of the input pushed so far, none when it
+    /// needs more input or, after `finish`, at its end. After an error the
+    /// form it was in is dropped and reading goes on after it. Directives
+    /// changing the symbol case are applied and skipped.
+    pub fn poll_form(&mut self) -> Option<Result<ParsedForm, Box<dyn Error>>> {
+        loop {
+            if !self.finished && self.still_awaiting() {
+                return None;
+            }
+
+            let (tok, start, end, furthest) = self.lex_token();
+            let at_end = end == self.end();
+            let cut_off = match &tok {
+                // the atom may go on
+                Ok(Some(_)) => at_end && lexer::is_atom_char(self.text_from(end - 1)[0] as char),
+                // a BOM or a '#!' line is only skipped at the start
+                Ok(None) => self.offset == 0 && self.in_preamble(),
+                Err(e) => is_eof_error(e.as_ref()) || at_end,
+            };
+
+            if cut_off && !self.finished {
+                let awaiting = match (&tok, self.byte_at(self.pending_start())) {
+                    (Ok(Some(_)), _) => Awaiting::Delimiter,
+                    (Err(_), Some(b'"')) => Awaiting::Quote,
+                    (Err(_), Some(b';')) => Awaiting::LineEnd,
+                    _ => Awaiting::AnyByte,
+                };
+                self.awaiting = Some((awaiting, self.end()));
+                return None;
+            }
+
+            if cut_off {
+                self.keep_checkpoint();
+            }
+
+            if !self.in_form() {
+                self.form_start = start;
+                self.form_seen = self.seen;
+            }
+            self.offset = end;
+            self.seen = self.seen.max(furthest);
+
+            let read = match tok {
+                Ok(Some(tok)) => self.read_token(tok, start),
+                Ok(None) if self.finished => match self.end_of_input() {
+                    Some(e) => Err(e),
+                    None => return None,
+                },
+                Ok(None) => return None,
+                Err(e) => Err(e),
+            };
+
+            match read {
+                Ok(Some(form)) => return Some(Ok(form)),
+                Ok(None) => (),
+                Err(e) => {
+                    self.drop_form();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+
+    // Checkpoints taken from here on are the one before reading relied on
+    // the input ending where it does, as it may not after an edit.
+    fn keep_checkpoint(&mut self) {
+        if self.last_checkpoint.is_none() {
+            self.last_checkpoint = Some(self.checkpoint());
+        }
+    }
+
+    // the error for a form the input ends in
+    fn end_of_input(&mut self) -> Option<Box<dyn Error>> {
+        if !self.in_form() {
+            return None;
+        }
+
+        self.keep_checkpoint();
+        if self.skipped_depth > 0 {
+            Some(Box::new(repr::nesting_depth_error(
+                error::ErrorType::Reader,
+                self.max_nesting_depth,
+            )))
+        } else {
+            Some(Box::new(io::Error::from(io::ErrorKind::UnexpectedEof)))
+        }
+    }
+
+    fn spans_at(&self, pos: usize) -> FormSpans {
+        FormSpans {
+            line: self.line_of(pos) as u32,
+            col: self.column_of(pos) as u32,
+            items: vec![],
+        }
+    }
+
+    fn tok_to_trivial_form(tok: &Token) -> Option<Form> {
         match tok {
             Token::Symbol(s) if s == "nil" => Some(Form::List(vec![])),
             Token::Symbol(s) if s == "t" => Some(Form::T),
-            Token::Symbol(s) => Some(Form::Symbol(s.clone())),
+            Token::Symbol(s) | Token::EscapedSymbol(s) => Some(Form::Symbol(s.clone())),
             Token::IntegerLiteral(i) => Some(Form::Integer(*i)),
+            Token::BigIntegerLiteral(i) => Some(Form::BigInteger(i.clone())),
+            Token::FloatLiteral(f) => Some(Form::Float(*f)),
+            Token::CharLiteral(c) => Some(Form::Char(*c)),
             Token::StringLiteral(s) => Some(Form::String(s.to_string())),
             _ => None,
         }
     }
 
-    fn read_list_form(&mut self) -> Result<Form, Box<dyn Error>> {
-        let mut vec = Vec::new();
+    fn reader_error(msg: &str) -> Box<dyn Error> {
+        Box::new(error::Error::new(error::ErrorType::Reader, msg))
+    }
 
-        let mut tok = self.next_tok_or_eof()?;
+    // the spans of a dotted_form, which are spliced the same way
+    fn dotted_spans(mut spans: FormSpans, tail: &Form, tail_spans: FormSpans) -> FormSpans {
+        match tail {
+            Form::List(_) | Form::DottedList(..) => spans.items.extend(tail_spans.items),
+            _ => spans.items.push(tail_spans),
+        }
 
-        while tok != Token::RightPar {
-            let form;
+        spans
+    }
 
-            if let Some(t_form) = self.tok_to_trivial_form(&tok) {
-                form = t_form;
-            } else {
-                form = match tok {
-                    Token::LeftPar => self.read_list_form()?,
-                    Token::RightPar => break,
-                    tok => panic!("unexpected token {:?}", tok),
+    // (a . (b c)) and (a . nil) are proper lists, (a . (b . c)) is (a b . c)
+    fn dotted_form(mut vec: Vec<Form>, tail: Form) -> Form {
+        match tail {
+            Form::List(tail) => {
+                vec.extend(tail);
+                Form::List(vec)
+            }
+            Form::DottedList(tail, tail_end) => {
+                vec.extend(tail);
+                Form::DottedList(vec, tail_end)
+            }
+            tail => Form::DottedList(vec, Box::new(tail)),
+        }
+    }
+
+    // adds a token starting at `start` to the form being read, the form when
+    // it's the last one
+    fn read_token(
+        &mut self,
+        tok: Token,
+        start: usize,
+    ) -> Result<Option<ParsedForm>, Box<dyn Error>> {
+        // the rest of a form that's too deeply nested is consumed, so that
+        // reading continues after it rather than inside of it
+        if self.skipped_depth > 0 {
+            match tok {
+                Token::LeftPar => self.skipped_depth += 1,
+                Token::RightPar => self.skipped_depth -= 1,
+                _ => (),
+            }
+
+            if self.skipped_depth == 0 {
+                return Err(Box::new(repr::nesting_depth_error(
+                    error::ErrorType::Reader,
+                    self.max_nesting_depth,
+                )));
+            }
+            return Ok(None);
+        }
+
+        let spans = self.spans_at(start);
+        let label_pending = self
+            .pending_labels
+            .last()
+            .map_or(false, |(depth, _)| *depth == self.open_lists.len());
+        let after_tail = self
+            .open_lists
+            .last()
+            .map_or(false, |list| list.tail.is_some());
+
+        let form = match tok {
+            Token::RightPar if after_tail => {
+                let list = self.open_lists.pop().unwrap();
+                let (tail, tail_spans) = list.tail.unwrap();
+                let spans = Self::dotted_spans(list.spans, &tail, tail_spans);
+                Some((Self::dotted_form(list.items, tail), spans))
+            }
+            _ if after_tail => {
+                return Err(Self::reader_error("more than one form after '.' in list"))
+            }
+            Token::RightPar | Token::Dot if label_pending => {
+                return Err(Self::reader_error("nothing after label"))
+            }
+            Token::Label(label) if !self.defined_labels.insert(label) => {
+                return Err(Self::reader_error(&format!(
+                    "label #{}= defined twice",
+                    label
+                )))
+            }
+            Token::Label(label) => {
+                self.pending_labels.push((self.open_lists.len(), label));
+                None
+            }
+            Token::LabelRef(label) if !self.defined_labels.contains(&label) => {
+                return Err(Self::reader_error(&format!("undefined label #{}#", label)))
+            }
+            Token::LabelRef(label) => Some((Form::LabelRef(label), spans)),
+            Token::LeftPar if self.open_lists.len() >= self.max_nesting_depth => {
+                self.skipped_depth = self.open_lists.len() + 1;
+                self.open_lists.clear();
+                self.pending_labels.clear();
+                None
+            }
+            Token::LeftPar => {
+                self.open_lists.push(OpenList::new(spans));
+                None
+            }
+            Token::RightPar => match self.open_lists.pop() {
+                None => return Err(Self::reader_error("unbalanced parens")),
+                Some(ref list) if list.after_dot => {
+                    return Err(Self::reader_error("nothing after '.' in list"))
                 }
+                Some(list) => Some((Form::List(list.items), list.spans)),
+            },
+            Token::Dot => match self.open_lists.last_mut() {
+                Some(ref list) if list.after_dot => {
+                    return Err(Self::reader_error("unexpected '.'"))
+                }
+                Some(ref list) if list.items.is_empty() => {
+                    return Err(Self::reader_error("nothing before '.' in list"))
+                }
+                Some(list) => {
+                    list.after_dot = true;
+                    None
+                }
+                None => return Err(Self::reader_error("unexpected '.'")),
+            },
+            tok => match Self::tok_to_trivial_form(&tok) {
+                Some(form) => Some((form, spans)),
+                None => panic!("unexpected token {:?}", tok),
+            },
+        };
+
+        let (mut finished, finished_spans) = match form {
+            Some(form) => form,
+            None => return Ok(None),
+        };
+
+        while let Some(&(depth, label)) = self.pending_labels.last() {
+            if depth != self.open_lists.len() {
+                break;
             }
 
-            vec.push(form);
-            tok = self.next_tok_or_eof()?;
+            self.pending_labels.pop();
+            finished = Form::Labeled(label, Box::new(finished));
         }
 
-        Ok(Form::List(vec))
+        // a finished form goes into the innermost open list, a dotted list
+        // is finished by the closing paren after its tail
+        match self.open_lists.last_mut() {
+            None => self.top_level_form(finished, finished_spans),
+            Some(list) if list.after_dot => {
+                list.tail = Some((finished, finished_spans));
+                Ok(None)
+            }
+            Some(list) => {
+                list.items.push(finished);
+                list.spans.items.push(finished_spans);
+                Ok(None)
+            }
+        }
     }
 
-    pub fn read_form(&mut self) -> Result<Option<Form>, Box<dyn Error>> {
-        let tok = self.lexer.next_token()?;
+    fn top_level_form(
+        &mut self,
+        form: Form,
+        spans: FormSpans,
+    ) -> Result<Option<ParsedForm>, Box<dyn Error>> {
+        self.defined_labels.clear();
 
-        if tok.is_none() {
+        if self.apply_directive(&form)? {
             return Ok(None);
         }
 
-        let tok = tok.unwrap();
+        Ok(Some(ParsedForm {
+            form,
+            start: self.form_start,
+            end: self.offset,
+            spans,
+        }))
+    }
 
-        let trivial_form = self.tok_to_trivial_form(&tok);
-        let form = match trivial_form {
-            Some(form) => form,
-            None => match tok {
-                Token::LeftPar => self.read_list_form()?,
-                Token::RightPar => Err(error::Error::new(
-                    error::ErrorType::Reader,
-                    "unbalanced parens",
-                ))?,
-                tok => panic!("unexpected token {:?}", tok),
-            },
+    // Applies a top-level `(%readtable-case mode)` directive, which is read
+    // with the mode it replaces, so its symbols are matched in any case. False
+    // for other forms.
+    fn apply_directive(&mut self, form: &Form) -> Result<bool, Box<dyn Error>> {
+        let items = match form {
+            Form::List(items) => items,
+            _ => return Ok(false),
         };
 
-        Ok(Some(form))
+        match items.as_slice() {
+            [Form::Symbol(head), ..] if head.eq_ignore_ascii_case(READTABLE_CASE) => (),
+            _ => return Ok(false),
+        }
+
+        let case = match items.as_slice() {
+            [_, Form::Symbol(mode)] => SymbolCase::parse(mode),
+            _ => None,
+        };
+
+        match case {
+            Some(case) => {
+                self.symbol_case = case;
+                Ok(true)
+            }
+            None => Err(Self::reader_error(&format!(
+                "{} takes one of {}",
+                READTABLE_CASE,
+                SYMBOL_CASES.join(", ")
+            ))),
+        }
+    }
+}
+
+pub struct Reader<S: Source> {
+    pbr: PushbackReader<S>,
+    state: ParserState,
+    // where the last form read starts
+    form_start: usize,
+    // where the last form read and its elements start
+    form_spans: FormSpans,
+}
+
+impl<'a, T: Read + 'a> Reader<IoSource<'a, T>> {
+    pub fn create(r: &'a mut T) -> Self {
+        Self::with_reader(PushbackReader::create(r))
+    }
+}
+
+impl<'a> Reader<StrSource<'a>> {
+    /// Reads forms from a string in memory without going through `io::Read`.
+    pub fn from_str(s: &'a str) -> Self {
+        // all of it is pushed at once, nothing is left for the source
+        let mut reader = Self::with_reader(PushbackReader::from_str(""));
+        reader.state.push_str(s);
+        reader.state.finish();
+        reader
     }
 }
 
+impl<S: Source> Reader<S> {
+    fn with_reader(pbr: PushbackReader<S>) -> Self {
+        Reader {
+            pbr,
+            state: ParserState::new(),
+            form_start: 0,
+            form_spans: FormSpans::default(),
+        }
+    }
+
+    /// Overrides the limit set with `repr::set_max_nesting_depth` for this
+    /// reader.
+    pub fn set_max_nesting_depth(&mut self, depth: usize) {
+        self.state.set_max_nesting_depth(depth);
+    }
+
+    /// Overrides the mode set with `escapes::set_symbol_case` for the rest of
+    /// the input, like a `(%readtable-case ...)` directive.
+    pub fn set_symbol_case(&mut self, case: SymbolCase) {
+        self.state.set_symbol_case(case);
+    }
+
+    /// Number of bytes of the input consumed so far, after an error it's
+    /// where reading stopped.
+    pub fn position(&self) -> usize {
+        self.state.position()
+    }
+
+    /// The line, counted from 1, of the last byte consumed, see `position`.
+    pub fn line(&self) -> usize {
+        self.state.line_of(self.position().saturating_sub(1))
+    }
+
+    /// The column, counted in bytes from 1, of the last byte consumed.
+    pub fn column(&self) -> usize {
+        self.state.column_of(self.position().saturating_sub(1))
+    }
+
+    /// The line the last form read by `read_form` starts on.
+    pub fn form_line(&self) -> usize {
+        self.state.line_of(self.form_start)
+    }
+
+    /// The column the last form read by `read_form` starts at.
+    pub fn form_column(&self) -> usize {
+        self.state.column_of(self.form_start)
+    }
+
+    /// Where the last form read by `read_form` and the forms in it start.
+    pub fn form_spans(&self) -> &FormSpans {
+        &self.form_spans
+    }
+
+    // pushes the next byte of the source, bytes are read one at a time so
+    // that nothing after a form is taken from an interactive input
+    fn read_more(&mut self) -> io::Result<()> {
+        let mut byte = [0];
+        let read = self.pbr.read_exact(&mut byte);
+
+        if lexer::is_eof(&read) {
+            self.state.finish();
+        } else {
+            read?;
+            self.state.push_bytes(&byte);
+        }
+
+        Ok(())
+    }
+
+    /// The next top-level form, none at the end of the input. Directives
+    /// changing the symbol case are applied and skipped.
+    pub fn read_form(&mut self) -> Result<Option<Form>, Box<dyn Error>> {
+        loop {
+            match self.state.poll_form() {
+                Some(parsed) => {
+                    self.state.discard_read();
+                    let parsed = parsed?;
+                    self.form_start = parsed.start;
+                    self.form_spans = parsed.spans;
+                    return Ok(Some(parsed.form));
+                }
+                None if self.state.is_finished() => return Ok(None),
+                None => self.read_more()?,
+            }
+        }
+    }
+
+    /// Reads the remaining forms, each with the position it starts at. The
+    /// first error stops reading, `position` tells where.
+    pub fn read_all_forms(&mut self) -> Result<Vec<(usize, Form)>, Box<dyn Error>> {
+        let mut forms = vec![];
+
+        while let Some(form) = self.read_form()? {
+            forms.push((self.form_start, form));
+        }
+
+        Ok(forms)
+    }
+}
+
+/// Reads up to `limit` forms of `src` as runtime data, this is the parser
+/// behind `read` and `read-all`.
+pub fn read_objects(src: &str, limit: usize) -> Result<Vec<Object>, String> {
+    let mut reader = Reader::from_str(src);
+    let mut objects = vec![];
+
+    while objects.len() < limit {
+        let form = match reader.read_form() {
+            Ok(Some(form)) => form,
+            Ok(None) => break,
+            Err(e) => return Err(format!("{} at position {}", e, reader.position())),
+        };
+
+        objects.push(repr::form_to_runtime_object(&form).map_err(|e| e.to_string())?);
+    }
+
+    Ok(objects)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -193,6 +989,22 @@ mod tests {
         assert_eq!(reader.read_form().unwrap().unwrap(), Form::T);
     }
 
+    #[test]
+    fn test_escaped_nil_t_are_symbols() {
+        let mut input = "|nil| |t| n\\il (|nil| . nil)".as_bytes();
+        let mut reader = Reader::create(&mut input);
+
+        let sym = |x: &str| Form::Symbol(x.to_string());
+
+        assert_eq!(reader.read_form().unwrap().unwrap(), sym("nil"));
+        assert_eq!(reader.read_form().unwrap().unwrap(), sym("t"));
+        assert_eq!(reader.read_form().unwrap().unwrap(), sym("nil"));
+        assert_eq!(
+            reader.read_form().unwrap().unwrap(),
+            Form::List(vec![sym("nil")])
+        );
+    }
+
     #[test]
     fn test_incomplete_list() {
         let mut input = "(foo".as_bytes();
@@ -200,5 +1012,589 @@ mod tests {
         assert!(is_gen_eof(&reader.read_form()));
     }
 
+    #[test]
+    fn test_nesting_depth_limit() {
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+
+        let input = format!("{} {} 2", nested(100), nested(101));
+        let mut input = input.as_bytes();
+        let mut reader = Reader::create(&mut input);
+        reader.set_max_nesting_depth(100);
+
+        assert!(reader.read_form().is_ok());
+
+        let err = reader.read_form().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "reader error: form nested deeper than 100 levels (see --max-nesting-depth)"
+        );
+
+        // the rest of the too deep form is skipped
+        assert_eq!(reader.read_form().unwrap().unwrap(), Form::Integer(2));
+    }
+
+    #[test]
+    fn test_pathological_forms() {
+        let input = "(".repeat(100_000);
+        let mut input = input.as_bytes();
+        let mut reader = Reader::create(&mut input);
+
+        assert!(!is_gen_eof(&reader.read_form()));
+        assert_eq!(reader.read_form().unwrap(), None);
+
+        let input = format!("({})", "1 ".repeat(1_000_000));
+        let mut input = input.as_bytes();
+        let mut reader = Reader::create(&mut input);
+
+        match reader.read_form().unwrap().unwrap() {
+            Form::List(items) => assert_eq!(items.len(), 1_000_000),
+            form => panic!("expected a list, got {:?}", form),
+        }
+    }
+
+    #[test]
+    fn test_dotted_pairs() {
+        let mut input = "(a . b) (a b . c) (a . (b c)) (a . (b . c)) (a . nil) ((a . b) . 1)".as_bytes();
+        let mut reader = Reader::create(&mut input);
+
+        let sym = |x: &str| Form::Symbol(x.to_string());
+
+        assert_eq!(
+            reader.read_form().unwrap().unwrap(),
+            Form::DottedList(vec![sym("a")], Box::new(sym("b")))
+        );
+        assert_eq!(
+            reader.read_form().unwrap().unwrap(),
+            Form::DottedList(vec![sym("a"), sym("b")], Box::new(sym("c")))
+        );
+        assert_eq!(
+            reader.read_form().unwrap().unwrap(),
+            Form::List(vec![sym("a"), sym("b"), sym("c")])
+        );
+        assert_eq!(
+            reader.read_form().unwrap().unwrap(),
+            Form::DottedList(vec![sym("a"), sym("b")], Box::new(sym("c")))
+        );
+        assert_eq!(
+            reader.read_form().unwrap().unwrap(),
+            Form::List(vec![sym("a")])
+        );
+        assert_eq!(
+            reader.read_form().unwrap().unwrap(),
+            Form::DottedList(
+                vec![Form::DottedList(vec![sym("a")], Box::new(sym("b")))],
+                Box::new(Form::Integer(1))
+            )
+        );
+    }
+
+    #[test]
+    fn test_malformed_dots() {
+        let cases = [
+            ("(. a)", "nothing before '.' in list"),
+            ("(a .)", "nothing after '.' in list"),
+            ("(a . b c)", "more than one form after '.' in list"),
+            ("(a . . b)", "unexpected '.'"),
+            (". a", "unexpected '.'"),
+        ];
+
+        for (src, message) in cases.iter() {
+            let mut input = src.as_bytes();
+            let mut reader = Reader::create(&mut input);
+
+            let err = reader.read_form().unwrap_err();
+            assert!(
+                err.to_string().contains(message),
+                "{}: unexpected error {}",
+                src,
+                err
+            );
+        }
+
+        let mut input = "(a . b".as_bytes();
+        let mut reader = Reader::create(&mut input);
+        assert!(is_gen_eof(&reader.read_form()));
+    }
+
+    fn forms_of<S: Source>(mut reader: Reader<S>) -> (Vec<Form>, Option<String>, usize) {
+        let mut forms = vec![];
+        loop {
+            match reader.read_form() {
+                Ok(Some(form)) => forms.push(form),
+                Ok(None) => return (forms, None, reader.position()),
+                Err(e) => return (forms, Some(e.to_string()), reader.position()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_str_and_io_sources_agree() {
+        let inputs = [
+            "",
+            "#!/usr/bin/env unlisp\n(foo 1 -2.5 #\\a \"b\\\"c\") ; comment\nx",
+            "(a . b) (a b . c) nil t |a b| \\1",
+            "(λ (ß) \"héllo\")",
+            "(a (b",
+            "(a . . b)",
+            "#\\bogus",
+        ];
+
+        for src in inputs.iter() {
+            let mut input = src.as_bytes();
+            assert_eq!(
+                forms_of(Reader::create(&mut input)),
+                forms_of(Reader::from_str(src)),
+                "{}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_input_is_dropped() {
+        let src = "(print \"line\") ; comment\r\n".repeat(2000);
+        let mut input = src.as_bytes();
+        let mut reader = Reader::create(&mut input);
+        let mut forms = vec![];
+
+        while let Some(form) = reader.read_form().unwrap() {
+            assert!(reader.state.text.len() <= 2 * MIN_DISCARDED);
+            forms.push((reader.form_start, form));
+        }
+
+        assert!(reader.state.base > 0);
+        assert_eq!(reader.form_line(), 2000);
+        assert_eq!(reader.form_column(), 1);
+        assert_eq!(forms, Reader::from_str(&src).read_all_forms().unwrap());
+    }
+
+    #[test]
+    fn test_non_ascii_char_literals() {
+        let forms = Reader::from_str("#\\é #\\λ (#\\ß)").read_all_forms().unwrap();
+
+        assert_eq!(
+            forms,
+            vec![
+                (0, Form::Char('é')),
+                (5, Form::Char('λ')),
+                (10, Form::List(vec![Form::Char('ß')])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_all_forms() {
+        let src = "1 ; one\n  (a\n b) \"s\"\n#\\c";
+        let forms = Reader::from_str(src).read_all_forms().unwrap();
+        let sym = |x: &str| Form::Symbol(x.to_string());
+
+        assert_eq!(
+            forms,
+            vec![
+                (0, Form::Integer(1)),
+                (10, Form::List(vec![sym("a"), sym("b")])),
+                (17, Form::String("s".to_string())),
+                (21, Form::Char('c')),
+            ]
+        );
+
+        let mut reader = Reader::from_str("(a) (b");
+        assert!(is_gen_eof(&reader.read_all_forms()));
+    }
+
+    #[test]
+    fn test_readtable_case_directive() {
+        let src = "Foo |Foo| (%readtable-case downcase) Foo |Foo| NIL :Key
+                   (%READTABLE-CASE UPCASE) foo f|o|o (%readtable-case preserve) Foo";
+        let forms: Vec<_> = Reader::from_str(src)
+            .read_all_forms()
+            .unwrap()
+            .into_iter()
+            .map(|(_, form)| form)
+            .collect();
+        let sym = |x: &str| Form::Symbol(x.to_string());
+
+        assert_eq!(
+            forms,
+            vec![
+                sym("Foo"),
+                sym("Foo"),
+                sym("foo"),
+                sym("Foo"),
+                Form::List(vec![]),
+                sym(":key"),
+                sym("FOO"),
+                sym("foo"),
+                sym("Foo"),
+            ]
+        );
+
+        let mut reader = Reader::from_str("(%readtable-case sideways) x");
+        assert_eq!(
+            reader.read_form().unwrap_err().to_string(),
+            "reader error: %readtable-case takes one of preserve, downcase, upcase"
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let src = "#1=(a #2=(b) . #1#) (#1=x #1#) (#1= 1 #2=#3=#1#)";
+        let forms: Vec<_> = Reader::from_str(src)
+            .read_all_forms()
+            .unwrap()
+            .into_iter()
+            .map(|(_, form)| form)
+            .collect();
+        let sym = |x: &str| Form::Symbol(x.to_string());
+        let labeled = |label, form| Form::Labeled(label, Box::new(form));
+
+        assert_eq!(
+            forms,
+            vec![
+                labeled(
+                    1,
+                    Form::DottedList(
+                        vec![sym("a"), labeled(2, Form::List(vec![sym("b")]))],
+                        Box::new(Form::LabelRef(1))
+                    )
+                ),
+                Form::List(vec![labeled(1, sym("x")), Form::LabelRef(1)]),
+                Form::List(vec![
+                    labeled(1, Form::Integer(1)),
+                    labeled(2, labeled(3, Form::LabelRef(1)))
+                ]),
+            ]
+        );
+
+        for src in &[
+            "(#1#)",
+            "(#1=a #1=b)",
+            "(a #1=)",
+            "(a #1= . b)",
+            "#1=(a) #1#",
+        ] {
+            let mut reader = Reader::from_str(src);
+            let result = reader.read_form().and_then(|_| reader.read_form());
+            assert!(result.is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_form_line() {
+        let mut reader = Reader::from_str("(a\r\n b) ; c\r\n\r\n  (d\r\ne)");
+
+        reader.read_form().unwrap();
+        assert_eq!(reader.form_line(), 1);
+        assert_eq!(reader.form_column(), 1);
+        reader.read_form().unwrap();
+        assert_eq!(reader.form_line(), 4);
+        assert_eq!(reader.form_column(), 3);
+        assert_eq!(reader.line(), 5);
+        assert_eq!(reader.column(), 2);
+    }
+
+    #[test]
+    fn test_error_line_with_any_line_ending() {
+        let lines = ["; line 1", "(a", "  b)", "\"c\"", "(d) )", "(e)"];
+
+        for term in ["\n", "\r\n", "\r"].iter() {
+            for bom in ["", "\u{feff}"].iter() {
+                let src = format!("{}{}", bom, lines.join(term));
+                let mut reader = Reader::from_str(&src);
+                let sym = |x: &str| Form::Symbol(x.to_string());
+
+                assert_eq!(
+                    reader.read_form().unwrap(),
+                    Some(Form::List(vec![sym("a"), sym("b")]))
+                );
+                assert_eq!(reader.line(), 3, "{:?}", src);
+                assert_eq!(
+                    reader.read_form().unwrap(),
+                    Some(Form::String("c".to_string()))
+                );
+                assert_eq!(
+                    reader.read_form().unwrap(),
+                    Some(Form::List(vec![sym("d")]))
+                );
+
+                let err = reader.read_form().unwrap_err();
+                assert_eq!(err.to_string(), "reader error: unbalanced parens");
+                assert_eq!(reader.line(), 5, "{:?}", src);
+            }
+        }
+    }
+
+    #[test]
+    fn test_form_spans() {
+        let mut reader = Reader::from_str("(f\n  (g 1)\n  (a . (b c)))");
+        let at = |line, col, items| FormSpans { line, col, items };
+        let leaf = |line, col| at(line, col, vec![]);
+
+        reader.read_form().unwrap();
+        assert_eq!(
+            reader.form_spans(),
+            &at(
+                1,
+                1,
+                vec![
+                    leaf(1, 2),
+                    at(2, 3, vec![leaf(2, 4), leaf(2, 6)]),
+                    // spliced like the list
+                    at(3, 3, vec![leaf(3, 4), leaf(3, 9), leaf(3, 11)]),
+                ]
+            )
+        );
+    }
+
+    type Polled = Vec<(usize, Result<ParsedForm, String>)>;
+
+    // the forms and errors up to where the state needs more input, each with
+    // the position after it
+    fn poll_all(state: &mut ParserState, polled: &mut Polled) {
+        while let Some(result) = state.poll_form() {
+            polled.push((state.position(), result.map_err(|e| e.to_string())));
+        }
+    }
+
+    fn read_in_chunks(src: &[u8], chunk_size: &mut dyn FnMut() -> usize) -> Polled {
+        let mut state = ParserState::new();
+        let mut polled = vec![];
+
+        for chunk in chunks(src, chunk_size) {
+            state.push_bytes(chunk);
+            poll_all(&mut state, &mut polled);
+        }
+        state.finish();
+        poll_all(&mut state, &mut polled);
+
+        polled
+    }
+
+    fn chunks<'a>(mut src: &'a [u8], chunk_size: &mut dyn FnMut() -> usize) -> Vec<&'a [u8]> {
+        let mut chunks = vec![];
+
+        while !src.is_empty() {
+            let (chunk, rest) = src.split_at(chunk_size().min(src.len()));
+            chunks.push(chunk);
+            src = rest;
+        }
+
+        chunks
+    }
+
+    // xorshift, to pick chunk sizes and edits without extra dependencies
+    fn xorshift(mut state: u64) -> impl FnMut() -> u64 {
+        move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        }
+    }
+
+    const STDLIB: &str = include_str!("../../stdlib.unl");
+
+    fn incremental_inputs() -> Vec<String> {
+        let mut inputs: Vec<String> = [
+            "",
+            "#!/usr/bin/env unlisp\n(foo 1 -2.5 #\\a . \"b\\\"c\") ; comment\nbar",
+            "\u{feff}#!x\r\n(a)",
+            "\u{feff}",
+            "#!",
+            "foo bar\r\n  baz\r(qux)\r\n1",
+            "(a . b) (a b . c) (a . (b c)) nil t |a b| \\1 |a||b| f\\(o",
+            "#1=(a #2=(b) . #1#) (#1=x #1#) #\\space #\\( #\\) #\\a",
+            "(λ (ß) \"héllo\") 123456789012345678901234567890 +inf.0 1.5e3",
+            "(%readtable-case upcase) foo (%readtable-case preserve) Foo",
+            "; only a comment",
+            "(a ; comment (\n b) \"str ; not a comment\" ;; trailing",
+            // errors, reading goes on after them
+            "(a . . b) c",
+            ")) (ok)",
+            "#\\bogus (x)",
+            "(#1#) (#1=a #1=b) y",
+            "(a . b c) d",
+            "(a #1=)",
+            "(a (b",
+            "\"unterminated",
+            "|unterminated",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        inputs.push(STDLIB.to_string());
+
+        inputs
+    }
+
+    #[test]
+    fn test_chunked_input_reads_like_batch() {
+        let mut next = xorshift(0x2545_f491_4f6c_dd1d);
+        let mut inputs = incremental_inputs();
+        let depth = repr::max_nesting_depth() + 1;
+        inputs.push(format!("{}1{} 2", "(".repeat(depth), ")".repeat(depth)));
+
+        for src in inputs {
+            let batch = read_in_chunks(src.as_bytes(), &mut || usize::max_value());
+
+            for _ in 0..4 {
+                let chunked = read_in_chunks(src.as_bytes(), &mut || match next() % 4 {
+                    0 => (next() % 64 + 1) as usize,
+                    _ => (next() % 4 + 1) as usize,
+                });
+                assert_eq!(chunked, batch, "{}", src);
+            }
+        }
+
+        // the same forms as the reader
+        let forms: Vec<_> = read_in_chunks(STDLIB.as_bytes(), &mut || 1)
+            .into_iter()
+            .map(|(_, parsed)| (parsed.as_ref().unwrap().start, parsed.unwrap().form))
+            .collect();
+        assert_eq!(forms, Reader::from_str(STDLIB).read_all_forms().unwrap());
+    }
+
+    #[test]
+    fn test_rollback_reuses_prefix() {
+        let src = "(defun f (x) (g x))\n(defun g (y) y)\n(f 1)";
+        let mut state = ParserState::new();
+        let mut polled = vec![];
+
+        state.push_str(src);
+        state.finish();
+        while let Some(parsed) = state.poll_form() {
+            polled.push(parsed.unwrap().form);
+            if polled.len() == 2 {
+                break;
+            }
+        }
+        let checkpoint = state.checkpoint();
+        assert_eq!(checkpoint.offset(), 35);
+        assert_eq!(checkpoint.kept_len(), 35);
+
+        // (f 1) is edited into (f 2), the two definitions aren't read again
+        state.rollback(checkpoint);
+        state.push_str("\n(f 2)");
+        state.finish();
+        let parsed = state.poll_form().unwrap().unwrap();
+        let sym = |x: &str| Form::Symbol(x.to_string());
+
+        assert_eq!(parsed.form, Form::List(vec![sym("f"), Form::Integer(2)]));
+        assert_eq!((parsed.start, parsed.end), (36, 41));
+        assert_eq!((parsed.spans.line, parsed.spans.col), (3, 1));
+        assert!(state.poll_form().is_none());
+
+        // a symbol at the end is only known to end with the byte after it
+        let mut state = ParserState::new();
+        state.push_str("foo ");
+        assert_eq!(state.poll_form().unwrap().unwrap().form, sym("foo"));
+        assert_eq!(state.checkpoint().offset(), 3);
+        assert_eq!(state.checkpoint().kept_len(), 4);
+
+        // after finish, the end of the input doesn't end it either
+        let mut state = ParserState::new();
+        state.push_str("(a) foo");
+        state.finish();
+        while state.poll_form().is_some() {}
+        assert_eq!(state.checkpoint().offset(), 3);
+
+        state.rollback(state.checkpoint());
+        state.push_str(" foox");
+        state.finish();
+        assert_eq!(state.poll_form().unwrap().unwrap().form, sym("foox"));
+    }
+
+    #[test]
+    fn test_rollback_after_random_edits() {
+        let mut next = xorshift(0x9e37_79b9_7f4a_7c15);
+        let insertions = [
+            "(", ")", " ", "\"", ";", "\n", "\r", "a", "1", ".", "|", "#", "\\", "#1=", "#\\",
+        ];
+
+        for src in incremental_inputs() {
+            let src = src.as_bytes();
+            let edits = if src.len() > 1000 { 20 } else { 50 };
+
+            for _ in 0..edits {
+                let mut chunk_size = || (next() % 8 + 1) as usize;
+                let mut state = ParserState::new();
+                let mut polled = vec![];
+                let mut checkpoints = vec![state.checkpoint()];
+
+                for chunk in chunks(src, &mut chunk_size) {
+                    state.push_bytes(chunk);
+                    poll_all(&mut state, &mut polled);
+                    checkpoints.push(state.checkpoint());
+                }
+                state.finish();
+                poll_all(&mut state, &mut polled);
+                checkpoints.push(state.checkpoint());
+
+                let at = (next() % (src.len() as u64 + 1)) as usize;
+                let removed = ((next() % 4) as usize).min(src.len() - at);
+                let inserted = insertions[(next() % insertions.len() as u64) as usize];
+                let edited = [&src[..at], inserted.as_bytes(), &src[at + removed..]].concat();
+
+                let checkpoint = *checkpoints
+                    .iter()
+                    .rev()
+                    .find(|checkpoint| checkpoint.kept_len() <= at)
+                    .unwrap();
+                state.rollback(checkpoint);
+                state.push_bytes(&edited[checkpoint.kept_len()..]);
+                state.finish();
+
+                let mut reread: Polled = polled
+                    .into_iter()
+                    .filter(|(pos, _)| *pos <= checkpoint.offset())
+                    .collect();
+                poll_all(&mut state, &mut reread);
+
+                let batch = read_in_chunks(&edited, &mut || usize::max_value());
+                assert_eq!(reread, batch, "{}", String::from_utf8_lossy(&edited));
+            }
+        }
+    }
+
+    #[test]
+    fn test_parser_state_status() {
+        let mut state = ParserState::new();
+
+        state.push_str("(defun f (x)\n  \"a (str");
+        assert!(state.poll_form().is_none());
+        assert_eq!(state.depth(), 1);
+        assert!(state.in_string());
+        assert!(!state.in_comment());
+
+        state.push_str("ing\" ; a (comment");
+        assert!(state.poll_form().is_none());
+        assert_eq!(state.depth(), 1);
+        assert!(!state.in_string());
+        assert!(state.in_comment());
+        assert!(!state.is_between_forms());
+
+        state.push_str("\n  (g x)) ; done\n(h");
+        assert!(state.poll_form().unwrap().is_ok());
+        assert!(state.poll_form().is_none());
+        assert_eq!(state.depth(), 1);
+
+        state.push_str(")");
+        assert!(state.poll_form().unwrap().is_ok());
+        assert!(state.poll_form().is_none());
+        assert_eq!(state.depth(), 0);
+        assert!(state.is_between_forms());
+
+        // a symbol waits for what ends it
+        state.push_str(" foo");
+        assert!(state.poll_form().is_none());
+        assert!(!state.is_between_forms());
+        state.push_str("bar\n");
+        assert_eq!(
+            state.poll_form().unwrap().unwrap().form,
+            Form::Symbol("foobar".to_string())
+        );
+    }
+
     //TODO: tests on unbalanced pars
 }
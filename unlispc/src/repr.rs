@@ -1,19 +1,62 @@
 use crate::error::{Error, ErrorType};
+use crate::fold;
+use crate::inline;
+use crate::macro_env;
+use unlisp_rt::bigint::BigInt;
 use unlisp_rt::*;
 
-use std::collections::HashSet;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::ffi::{CStr, CString};
 use std::iter::FromIterator;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use libc::c_char;
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+pub const DEFAULT_MAX_NESTING_DEPTH: usize = 10_000;
+
+static MAX_NESTING_DEPTH: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_NESTING_DEPTH);
+
+/// Sets how deeply lists may be nested in forms the reader produces and the
+/// compiler accepts, deeper forms are reported as errors rather than
+/// overflowing the stack.
+pub fn set_max_nesting_depth(depth: usize) {
+    MAX_NESTING_DEPTH.store(depth, Ordering::SeqCst);
+}
+
+pub fn max_nesting_depth() -> usize {
+    MAX_NESTING_DEPTH.load(Ordering::SeqCst)
+}
+
+/// The error for a form nested deeper than the `depth` levels allowed.
+pub fn nesting_depth_error(ty: ErrorType, depth: usize) -> Error {
+    Error::new(
+        ty,
+        format!(
+            "form nested deeper than {} levels (see --max-nesting-depth)",
+            depth
+        ),
+    )
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum Form {
     T,
     Symbol(String),
     Integer(i64),
+    BigInteger(BigInt),
+    Float(f64),
+    Char(char),
     String(String),
     List(Vec<Form>),
+    // improper list, always has at least one element before the dot and
+    // a tail that's neither a list nor nil
+    DottedList(Vec<Form>, Box<Form>),
+    // `#n=form`, only read as data
+    Labeled(u64, Box<Form>),
+    // `#n#`, the form labeled with n earlier in the same top-level form
+    LabelRef(u64),
 }
 
 macro_rules! define_unwrapper {
@@ -32,65 +75,199 @@ define_unwrapper!(to_list(Form :: List) -> Vec<Form>);
 define_unwrapper!(to_symbol(Form :: Symbol) -> String);
 define_unwrapper!(to_i64(Form :: Integer) -> i64);
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct LetBlock {
     pub bindings: Vec<(String, HIR)>,
     pub body: Vec<HIR>,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ArgType {
+    Integer,
+    List,
+    Symbol,
+    Function,
+    String,
+}
+
+impl ArgType {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "integer" => Some(ArgType::Integer),
+            "list" => Some(ArgType::List),
+            "symbol" => Some(ArgType::Symbol),
+            "function" => Some(ArgType::Function),
+            "string" => Some(ArgType::String),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArgType::Integer => "integer",
+            ArgType::List => "list",
+            ArgType::Symbol => "symbol",
+            ArgType::Function => "function",
+            ArgType::String => "string",
+        }
+    }
+}
+
+/// Most required parameters a function can have, a restarg takes any number
+/// of further arguments.
+pub const MAX_PARAMS: usize = 255;
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Lambda {
     pub name: Option<String>,
     pub arglist: Vec<String>,
+    // parallel to arglist, None for unannotated parameters
+    pub arg_types: Vec<Option<ArgType>>,
     pub restarg: Option<String>,
     pub body: Vec<HIR>,
+    pub declarations: Declarations,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+/// What a `(declare (inline))` or `(declare (notinline))` asks of direct
+/// calls to the function.
+#[derive(Debug, PartialEq, Clone, Copy, Hash)]
+pub enum Inlining {
+    Default,
+    Inline,
+    NotInline,
+}
+
+/// The options of a `(declare ...)` form at the start of a lambda's body.
+#[derive(Debug, PartialEq, Clone, Hash)]
+pub struct Declarations {
+    pub inlining: Inlining,
+    /// False with `(optimize 0)`: the body is compiled without folding,
+    /// inlining or function passes. Nested lambdas inherit it unless they
+    /// declare their own.
+    pub optimize: bool,
+    /// Names of options the compiler doesn't know, which are warned about
+    /// and ignored.
+    pub unknown: Vec<String>,
+    /// Options written as a bare symbol, like `inline` for `(inline)`,
+    /// which are warned about and ignored too.
+    pub unparenthesized: Vec<String>,
+}
+
+impl Default for Declarations {
+    fn default() -> Self {
+        Self {
+            inlining: Inlining::Default,
+            optimize: true,
+            unknown: vec![],
+            unparenthesized: vec![],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Quote {
     pub body: Literal,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct If {
     pub cond: Box<HIR>,
     pub then_hir: Box<HIR>,
     pub else_hir: Option<Box<HIR>>,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+/// Where a variable captured by a closure is bound, relative to the code
+/// creating the closure.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BindingSite {
+    /// A parameter of the enclosing lambda.
+    Parameter,
+    /// A `let` in the enclosing lambda, or around the closure at top level.
+    LetBinding,
+    /// Captured by the enclosing lambda itself, from further out.
+    OuterCapture,
+    /// Not bound lexically, a global variable or an undefined name.
+    Global,
+}
+
+impl BindingSite {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BindingSite::Parameter => "parameter",
+            BindingSite::LetBinding => "let binding",
+            BindingSite::OuterCapture => "outer capture",
+            BindingSite::Global => "global",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Closure {
     pub free_vars: Vec<String>,
+    // parallel to free_vars
+    pub free_var_sites: Vec<BindingSite>,
     pub lambda: Lambda,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Call {
     pub fn_name: String,
+    // the head names a lexical variable holding a function rather than a
+    // global function
+    pub is_lexical: bool,
     pub args: Vec<HIR>,
+    // none for calls that weren't read from source, e.g. from `eval`
+    pub span: Option<Span>,
+}
+
+/// Where the list a call was converted from starts. Calls in a macro
+/// expansion have the span of the macro call.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct Span {
+    /// See `source_locations::intern_file`.
+    pub file: u32,
+    pub line: u32,
+    pub col: u32,
+}
+
+/// Where a form read by the reader starts, with the same for the elements
+/// of a list, in order, and the tail of a dotted list last.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FormSpans {
+    pub line: u32,
+    pub col: u32,
+    pub items: Vec<FormSpans>,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+// `(funcall f args...)` binds f to this variable and calls it as a lexical
+// call head
+pub const FUNCALL_VAR: &str = "--funcall-fn";
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct DeclareVar {
     pub var_name: String,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Literal {
     T,
     SymbolLiteral(String),
     IntegerLiteral(i64),
+    BigIntegerLiteral(BigInt),
+    FloatLiteral(f64),
+    CharLiteral(char),
     StringLiteral(String),
     ListLiteral(Vec<Literal>),
+    DottedListLiteral(Vec<Literal>, Box<Literal>),
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct SetExpr {
     pub name: String,
     pub val: Box<HIR>,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum HIR {
     DeclareVar(DeclareVar),
     Literal(Literal),
@@ -103,17 +280,384 @@ pub enum HIR {
     SetExpr(SetExpr),
 }
 
-fn form_to_literal(form: &Form) -> Literal {
+fn atom_to_literal(form: &Form) -> Literal {
     match form {
         Form::Symbol(s) => Literal::SymbolLiteral(s.clone()),
         Form::Integer(i) => Literal::IntegerLiteral(*i),
+        Form::BigInteger(i) => Literal::BigIntegerLiteral(i.clone()),
+        Form::Float(f) => Literal::FloatLiteral(*f),
+        Form::Char(c) => Literal::CharLiteral(*c),
         Form::String(s) => Literal::StringLiteral(s.clone()),
         Form::T => Literal::T,
-        Form::List(list) => {
-            let literals = list.iter().map(form_to_literal).collect();
-            Literal::ListLiteral(literals)
+        Form::List(_) | Form::DottedList(..) => unreachable!("not an atom"),
+        Form::Labeled(..) | Form::LabelRef(_) => unreachable!("labels aren't compiled"),
+    }
+}
+
+// Converts nested lists with an explicit stack of (remaining elements,
+// converted elements, dotted tail), so that deeply nested quoted data doesn't
+// overflow the call stack.
+fn form_to_literal(form: &Form) -> Literal {
+    let mut stack: Vec<(slice::Iter<Form>, Vec<Literal>, Option<&Form>)> = vec![];
+    let mut next = Some(form);
+
+    loop {
+        let mut converted = match next.take() {
+            Some(Form::List(list)) => {
+                stack.push((list.iter(), vec![], None));
+                None
+            }
+            Some(Form::DottedList(list, tail)) => {
+                stack.push((list.iter(), vec![], Some(tail.as_ref())));
+                None
+            }
+            Some(atom) => Some(atom_to_literal(atom)),
+            None => None,
+        };
+
+        while next.is_none() {
+            let list = match stack.last_mut() {
+                Some(list) => list,
+                None => return converted.unwrap(),
+            };
+
+            list.1.extend(converted.take());
+
+            match list.0.next() {
+                Some(item) => next = Some(item),
+                None => {
+                    let (_, literals, tail) = stack.pop().unwrap();
+                    // tails are never lists, so this doesn't nest
+                    converted = Some(match tail {
+                        Some(tail) => {
+                            Literal::DottedListLiteral(literals, Box::new(form_to_literal(tail)))
+                        }
+                        None => Literal::ListLiteral(literals),
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn labels_error() -> Error {
+    Error::new(
+        ErrorType::Compilation,
+        "#n= and #n# labels can only be read as data",
+    )
+}
+
+fn has_labels(form: &Form) -> bool {
+    let mut stack = vec![form];
+
+    while let Some(form) = stack.pop() {
+        match form {
+            Form::Labeled(..) | Form::LabelRef(_) => return true,
+            Form::List(items) => stack.extend(items.iter()),
+            Form::DottedList(items, tail) => {
+                stack.extend(items.iter());
+                stack.push(tail);
+            }
+            _ => (),
+        }
+    }
+
+    false
+}
+
+// iterative, returns as soon as the limit is exceeded
+fn exceeds_max_nesting_depth(form: &Form) -> bool {
+    let max_depth = max_nesting_depth();
+    let mut stack = vec![(form, 0)];
+
+    while let Some((form, depth)) = stack.pop() {
+        let items = match form {
+            Form::List(items) | Form::DottedList(items, _) => items,
+            _ => continue,
+        };
+
+        if depth + 1 > max_depth {
+            return true;
+        }
+
+        stack.extend(items.iter().map(|item| (item, depth + 1)));
+    }
+
+    false
+}
+
+thread_local! {
+    // spans of the lists of the form form_to_hir_with_spans converts, by the
+    // address of their elements, which stays put while it's borrowed
+    static SPANS: RefCell<HashMap<*const Form, Span>> = RefCell::new(HashMap::new());
+    // of the innermost list being converted that has one
+    static CURRENT_SPAN: Cell<Option<Span>> = Cell::new(None);
+    // spans of the lists passed to macros, by their first cons
+    static CONS_SPANS: RefCell<HashMap<*mut defs::Cons, Span>> = RefCell::new(HashMap::new());
+}
+
+// iterative like exceeds_max_nesting_depth, spans that don't match the
+// form's shape are left out
+fn collect_spans(form: &Form, spans: &FormSpans, file: u32) -> HashMap<*const Form, Span> {
+    let mut collected = HashMap::new();
+    let mut stack = vec![(form, spans)];
+
+    while let Some((form, spans)) = stack.pop() {
+        let items = match form {
+            Form::List(items) if !items.is_empty() => items,
+            _ => continue,
+        };
+
+        let span = Span {
+            file,
+            line: spans.line,
+            col: spans.col,
+        };
+        collected.insert(items.as_ptr(), span);
+
+        stack.extend(items.iter().zip(spans.items.iter()));
+    }
+
+    collected
+}
+
+// Makes the span of a list read from source the one of the calls converted
+// until the guard is dropped, lists from macro expansions keep the span
+// around them.
+struct SpanScope {
+    outer: Option<Span>,
+}
+
+impl SpanScope {
+    fn enter(forms: &[Form]) -> Self {
+        let span = SPANS.with(|spans| spans.borrow().get(&forms.as_ptr()).cloned());
+        let outer = CURRENT_SPAN.with(Cell::get);
+
+        if span.is_some() {
+            CURRENT_SPAN.with(|current| current.set(span));
+        }
+
+        SpanScope { outer }
+    }
+}
+
+impl Drop for SpanScope {
+    fn drop(&mut self) {
+        CURRENT_SPAN.with(|current| current.set(self.outer));
+    }
+}
+
+fn current_span() -> Option<Span> {
+    CURRENT_SPAN.with(Cell::get)
+}
+
+// Calls `f` with the lists of `form` and the first cons of the same list in
+// `obj`, which is `form` converted to a runtime object or the other way
+// around.
+unsafe fn for_each_list(
+    obj: defs::Object,
+    form: &Form,
+    mut f: impl FnMut(*mut defs::Cons, &[Form]),
+) {
+    let mut stack = vec![(obj, form)];
+
+    while let Some((mut obj, form)) = stack.pop() {
+        while obj.ty == defs::ObjType::Box {
+            obj = obj.unpack_underlying();
+        }
+
+        let items = match form {
+            Form::List(items) | Form::DottedList(items, _) if !items.is_empty() => items,
+            _ => continue,
+        };
+
+        if obj.ty != defs::ObjType::Cons {
+            continue;
+        }
+
+        let mut cons = obj.unpack_cons();
+        f(cons, items);
+
+        for item in items.iter() {
+            stack.push(((*cons).car(), item));
+
+            if (*cons).cdr().ty != defs::ObjType::Cons {
+                break;
+            }
+            cons = (*cons).cdr().unpack_cons();
+        }
+    }
+}
+
+// the spans of the lists in a macro argument go with its conses
+unsafe fn note_argument_spans(arg: &Form, obj: defs::Object) {
+    for_each_list(obj, arg, |cons, items| {
+        if let Some(span) = SPANS.with(|spans| spans.borrow().get(&items.as_ptr()).cloned()) {
+            CONS_SPANS.with(|spans| spans.borrow_mut().insert(cons, span));
+        }
+    });
+}
+
+// Macros put the conses of their arguments into the expansion as they are,
+// so the lists converted from them get the spans of the arguments. Returns
+// the lists given a span, which forget_spans takes out again once the
+// expansion is converted and its lists are freed.
+unsafe fn note_expansion_spans(expansion: defs::Object, form: &Form) -> Vec<*const Form> {
+    let mut noted = vec![];
+
+    if CONS_SPANS.with(|spans| spans.borrow().is_empty()) {
+        return noted;
+    }
+
+    for_each_list(expansion, form, |cons, items| {
+        if let Some(span) = CONS_SPANS.with(|spans| spans.borrow().get(&cons).cloned()) {
+            SPANS.with(|spans| spans.borrow_mut().insert(items.as_ptr(), span));
+            noted.push(items.as_ptr());
+        }
+    });
+
+    noted
+}
+
+fn forget_spans(lists: Vec<*const Form>) {
+    SPANS.with(|spans| {
+        let mut spans = spans.borrow_mut();
+        for list in lists {
+            spans.remove(&list);
+        }
+    });
+}
+
+thread_local! {
+    // false while converting the body of a function declared (optimize 0),
+    // calls in it aren't folded or inlined
+    static OPTIMIZE: Cell<bool> = Cell::new(true);
+}
+
+// the options of a leading `(declare ...)` in a lambda body and the forms
+// after it
+fn parse_declarations(body: &[Form]) -> Result<(Declarations, &[Form]), Error> {
+    let mut declarations = Declarations {
+        optimize: OPTIMIZE.with(Cell::get),
+        ..Declarations::default()
+    };
+
+    let declare = body.first().and_then(to_list).filter(|form| {
+        form.first()
+            .and_then(to_symbol)
+            .map_or(false, |s| s == "declare")
+    });
+
+    let options = match declare {
+        Some(declare) => &declare[1..],
+        None => return Ok((declarations, body)),
+    };
+
+    for option in options.iter() {
+        if let Form::Symbol(name) = option {
+            declarations.unparenthesized.push(name.clone());
+            continue;
+        }
+
+        let option = to_list(option)
+            .filter(|o| !o.is_empty())
+            .ok_or_else(|| Error::new(ErrorType::Reader, "declare option is not a list"))?;
+        let name = to_symbol(&option[0])
+            .ok_or_else(|| Error::new(ErrorType::Reader, "not a symbol in declare option"))?;
+        let args = &option[1..];
+
+        match name.as_str() {
+            "inline" | "notinline" if !args.is_empty() => Err(Error::new(
+                ErrorType::Reader,
+                format!("{} declaration takes no arguments", name),
+            ))?,
+            "inline" => declarations.inlining = Inlining::Inline,
+            "notinline" => declarations.inlining = Inlining::NotInline,
+            "optimize" => match args {
+                [Form::Integer(level)] if *level >= 0 => declarations.optimize = *level > 0,
+                _ => Err(Error::new(
+                    ErrorType::Reader,
+                    "optimize declaration takes a level, 0 or more",
+                ))?,
+            },
+            _ => declarations.unknown.push(name.clone()),
         }
     }
+
+    Ok((declarations, &body[1..]))
+}
+
+// Calls the macro or compiler macro `f` with the argument forms of the call
+// `forms`, returning the expansion both as a runtime object and as a form.
+unsafe fn expand_call(
+    f: *mut defs::Function,
+    kind: &str,
+    forms: &[Form],
+) -> Result<(defs::Object, Form), Error> {
+    let arg_count = (forms.len() - 1) as u64;
+    if !defs::unlisp_rt_check_arity(f, arg_count) {
+        Err(Error::new(
+            ErrorType::Macroexpansion,
+            format!(
+                "wrong number of argument forms ({}) passed to {} {}",
+                arg_count,
+                kind,
+                to_symbol(&forms[0]).map_or("", |s| s.as_str())
+            ),
+        ))?
+    }
+
+    let arg_objs = forms[1..]
+        .iter()
+        .map(form_to_runtime_object)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (arg, obj) in forms[1..].iter().zip(arg_objs.iter()) {
+        note_argument_spans(arg, obj.clone());
+    }
+
+    let arg_objs_list = arg_objs
+        .into_iter()
+        .rev()
+        .fold(defs::ListLike::from_nil(), |acc, obj| acc.cons(obj));
+    let expanded = predefined::call_macro(f, arg_objs_list)
+        .map_err(|e| Error::rt_error(e).convert(ErrorType::Macroexpansion))?;
+    let form = runtime_object_to_form(expanded.clone())
+        .map_err(|e| e.convert(ErrorType::Macroexpansion))?;
+
+    Ok((expanded, form))
+}
+
+unsafe fn expansion_to_hir(expanded: defs::Object, form: &Form) -> Result<HIR, Error> {
+    let noted = note_expansion_spans(expanded, form);
+    let hir = form_to_hir(form);
+    forget_spans(noted);
+
+    hir.map_err(|e| e.convert(ErrorType::Macroexpansion))
+}
+
+// What the compiler macro of a global function rewrites the call `forms` to.
+// None when the function has no compiler macro, it's declared (notinline),
+// calls aren't optimized where this one is, or the compiler macro declines
+// by returning the call as it is.
+unsafe fn compiler_macro_expansion(
+    forms: &[Form],
+    call_sym: *mut defs::Symbol,
+    sym_fn: *mut defs::Function,
+) -> Result<Option<HIR>, Error> {
+    let compiler_macro = symbols::compiler_macro(call_sym);
+    if compiler_macro.is_null()
+        || !OPTIMIZE.with(Cell::get)
+        || (!sym_fn.is_null() && inline::is_declared_notinline(sym_fn))
+    {
+        return Ok(None);
+    }
+
+    let (expanded, form) = expand_call(compiler_macro, "compiler macro", forms)?;
+    match &form {
+        Form::List(items) if items.as_slice() == forms => Ok(None),
+        _ => expansion_to_hir(expanded, &form).map(Some),
+    }
 }
 
 fn forms_to_hirs(forms: &[Form]) -> Result<Vec<HIR>, Error> {
@@ -125,13 +669,22 @@ fn forms_to_hir(forms: &Vec<Form>) -> Result<HIR, Error> {
         s1.as_str() == s2
     }
 
+    let _span = SpanScope::enter(forms);
+
     if forms.is_empty() {
         Ok(HIR::Literal(Literal::ListLiteral(vec![])))
     } else {
         match &forms[0] {
-            Form::T | Form::Integer(_) | Form::String(_) | Form::List(_) => {
-                Ok(Err(Error::new(ErrorType::Reader, "illegal function call"))?)
-            }
+            Form::T
+            | Form::Integer(_)
+            | Form::BigInteger(_)
+            | Form::Float(_)
+            | Form::Char(_)
+            | Form::String(_)
+            | Form::List(_)
+            | Form::DottedList(..)
+            | Form::Labeled(..)
+            | Form::LabelRef(_) => Ok(Err(Error::new(ErrorType::Reader, "illegal function call"))?),
 
             Form::Symbol(s) if is(s, "quote") => {
                 let quote = Quote {
@@ -194,6 +747,7 @@ fn forms_to_hir(forms: &Vec<Form>) -> Result<HIR, Error> {
             }
 
             Form::Symbol(s) if is(s, "let") => {
+                let _scope = macro_env::SymbolScope::enter();
                 let bindings = forms
                     .get(1)
                     .ok_or_else(|| (Error::new(ErrorType::Reader, "no bindings in let")))?;
@@ -226,6 +780,7 @@ fn forms_to_hir(forms: &Vec<Form>) -> Result<HIR, Error> {
                         .transpose()?;
 
                     let val_form = form_to_hir(val_form)?;
+                    macro_env::shadow_symbol(sym);
 
                     collected_bindings.push((sym.clone(), val_form));
                 }
@@ -241,46 +796,70 @@ fn forms_to_hir(forms: &Vec<Form>) -> Result<HIR, Error> {
             }
 
             Form::Symbol(s) if is(s, "lambda") => {
+                let _scope = macro_env::SymbolScope::enter();
                 let mut name = None;
                 let parsed_arglist;
-                let body;
+                let body_forms;
+
+                type ParsedArglist = (Vec<String>, Vec<Option<ArgType>>, Option<String>);
 
-                fn parse_arglist(arglist: &Form) -> Result<(Vec<String>, Option<String>), Error> {
+                fn parse_arg(arg: &Form) -> Result<(String, Option<ArgType>), Error> {
+                    if let Some(sym) = to_symbol(arg) {
+                        return Ok((sym.clone(), None));
+                    }
+
+                    let annotated = to_list(arg)
+                        .filter(|l| l.len() == 2)
+                        .ok_or_else(|| Error::new(ErrorType::Reader, "not a symbol in arglist"))?;
+
+                    let name = to_symbol(&annotated[0]).ok_or_else(|| {
+                        Error::new(ErrorType::Reader, "not a symbol in annotated argument")
+                    })?;
+
+                    let ty = to_symbol(&annotated[1])
+                        .and_then(|t| ArgType::from_name(t))
+                        .ok_or_else(|| {
+                            Error::new(
+                                ErrorType::Reader,
+                                format!("unknown type annotation for argument {}", name),
+                            )
+                        })?;
+
+                    Ok((name.clone(), Some(ty)))
+                }
+
+                fn parse_arglist(arglist: &Form) -> Result<ParsedArglist, Error> {
                     let arglist = to_list(arglist).ok_or_else(|| {
                         Error::new(ErrorType::Reader, "not a list in lambda arglist")
                     })?;
 
                     let arglist = arglist
-                        .into_iter()
-                        .map(|arg| {
-                            to_symbol(arg).cloned().ok_or_else(|| {
-                                Error::new(ErrorType::Reader, "not a symbol in arglist")
-                            })
-                        })
+                        .iter()
+                        .map(parse_arg)
                         .collect::<Result<Vec<_>, _>>()?;
 
                     let mut iter = arglist.into_iter();
 
-                    let simple_args = iter
+                    let (simple_args, arg_types): (Vec<_>, Vec<_>) = iter
                         .by_ref()
-                        .take_while(|s| *s != "&".to_string())
-                        .collect();
+                        .take_while(|(s, _)| *s != "&".to_string())
+                        .unzip();
 
                     let restargs = iter.collect::<Vec<_>>();
                     let restarg = if restargs.is_empty() {
                         None
                     } else {
-                        if restargs.len() != 1 {
+                        if restargs.len() != 1 || restargs[0].1.is_some() {
                             return Err(Error::new(
                                 ErrorType::Reader,
                                 "wrong syntax near '&' in lambda",
                             ));
                         } else {
-                            restargs.into_iter().next()
+                            restargs.into_iter().next().map(|(name, _)| name)
                         }
                     };
 
-                    Ok((simple_args, restarg))
+                    Ok((simple_args, arg_types, restarg))
                 }
 
                 let name_or_arglist = forms
@@ -294,12 +873,12 @@ fn forms_to_hir(forms: &Vec<Form>) -> Result<HIR, Error> {
                             .get(2)
                             .ok_or_else(|| Error::new(ErrorType::Reader, "no arglist in lambda"))?;
                         parsed_arglist = parse_arglist(arglist)?;
-                        body = forms_to_hirs(&forms[3..])?;
+                        body_forms = &forms[3..];
                     }
 
                     Form::List(_) => {
                         parsed_arglist = parse_arglist(name_or_arglist)?;
-                        body = forms_to_hirs(&forms[2..])?;
+                        body_forms = &forms[2..];
                     }
 
                     _ => {
@@ -310,13 +889,38 @@ fn forms_to_hir(forms: &Vec<Form>) -> Result<HIR, Error> {
                     }
                 };
 
-                let (simple_args, restarg) = parsed_arglist;
+                let (simple_args, arg_types, restarg) = parsed_arglist;
+
+                if simple_args.len() > MAX_PARAMS {
+                    return Err(Error::new(
+                        ErrorType::Compilation,
+                        format!(
+                            "{} has {} parameters, at most {} are supported",
+                            name.as_ref().map_or("lambda", |n| n.as_str()),
+                            simple_args.len(),
+                            MAX_PARAMS
+                        ),
+                    ));
+                }
+
+                for arg in simple_args.iter().chain(restarg.iter()) {
+                    macro_env::shadow_symbol(arg);
+                }
+
+                let (declarations, body_forms) = parse_declarations(body_forms)?;
+
+                let outer_optimize = OPTIMIZE.with(|o| o.replace(declarations.optimize));
+                let body = forms_to_hirs(body_forms);
+                OPTIMIZE.with(|o| o.set(outer_optimize));
+                let body = body?;
 
                 let lambda = Lambda {
                     name: name,
                     arglist: simple_args,
+                    arg_types: arg_types,
                     restarg: restarg,
                     body: body,
+                    declarations: declarations,
                 };
 
                 Ok(HIR::Lambda(lambda))
@@ -342,13 +946,41 @@ fn forms_to_hir(forms: &Vec<Form>) -> Result<HIR, Error> {
                     ))?
                 }
 
+                // a symbol macro standing for a variable is a place
+                let sym = match macro_env::lookup_symbol_macro(sym) {
+                    Some(Form::Symbol(target)) => target,
+                    Some(_) => Err(Error::new(
+                        ErrorType::Reader,
+                        format!("symbol macro {} is not a settable place", sym),
+                    ))?,
+                    None => sym.clone(),
+                };
+
                 let expr = SetExpr {
-                    name: sym.clone(),
+                    name: sym,
                     val: Box::new(form_to_hir(val)?),
                 };
 
                 Ok(HIR::SetExpr(expr))
             }
+            Form::Symbol(s) if is(s, "funcall") => {
+                let f = forms
+                    .get(1)
+                    .ok_or_else(|| Error::new(ErrorType::Reader, "no function in funcall"))?;
+                let f = form_to_hir(f)?;
+
+                let call = Call {
+                    fn_name: FUNCALL_VAR.to_string(),
+                    is_lexical: true,
+                    args: forms_to_hirs(&forms[2..])?,
+                    span: current_span(),
+                };
+
+                Ok(HIR::LetBlock(LetBlock {
+                    bindings: vec![(FUNCALL_VAR.to_string(), f)],
+                    body: vec![HIR::Call(call)],
+                }))
+            }
             Form::Symbol(s) if is(s, "declare-var") => {
                 let sym = forms
                     .get(1)
@@ -372,36 +1004,126 @@ fn forms_to_hir(forms: &Vec<Form>) -> Result<HIR, Error> {
 
                 Ok(HIR::DeclareVar(decl_var))
             }
+            Form::Symbol(s) if is(s, "symbol-macrolet") => {
+                let _scope = macro_env::SymbolScope::enter();
+                let definitions = forms.get(1).ok_or_else(|| {
+                    Error::new(ErrorType::Reader, "no definitions in symbol-macrolet")
+                })?;
+                let definitions = to_list(definitions).ok_or_else(|| {
+                    Error::new(
+                        ErrorType::Reader,
+                        "symbol-macrolet definitions are not a list",
+                    )
+                })?;
+
+                for definition in definitions.iter() {
+                    let definition =
+                        to_list(definition)
+                            .filter(|d| d.len() == 2)
+                            .ok_or_else(|| {
+                                Error::new(
+                                    ErrorType::Reader,
+                                    "malformed symbol-macrolet definition",
+                                )
+                            })?;
+                    let name = to_symbol(&definition[0]).ok_or_else(|| {
+                        Error::new(
+                            ErrorType::Reader,
+                            "not a symbol in symbol-macrolet definition",
+                        )
+                    })?;
+
+                    macro_env::define_symbol_macro(name, definition[1].clone());
+                }
+
+                let body = forms_to_hirs(&forms[2..])?;
+
+                Ok(HIR::LetBlock(LetBlock {
+                    bindings: vec![],
+                    body: body,
+                }))
+            }
+            Form::Symbol(s) if is(s, "macrolet") => {
+                let definitions = forms
+                    .get(1)
+                    .ok_or_else(|| Error::new(ErrorType::Reader, "no definitions in macrolet"))?;
+                let definitions = to_list(definitions).ok_or_else(|| {
+                    Error::new(ErrorType::Reader, "macrolet definitions are not a list")
+                })?;
+
+                let mut macros = HashMap::new();
+
+                for definition in definitions.iter() {
+                    let definition =
+                        to_list(definition)
+                            .filter(|d| d.len() >= 2)
+                            .ok_or_else(|| {
+                                Error::new(ErrorType::Reader, "malformed macrolet definition")
+                            })?;
+                    let name = to_symbol(&definition[0]).ok_or_else(|| {
+                        Error::new(ErrorType::Reader, "not a symbol in macrolet definition")
+                    })?;
+
+                    let mut lambda = vec![Form::Symbol("lambda".to_string())];
+                    lambda.extend(definition.iter().cloned());
+                    let lambda = form_to_hir_with_transforms(&Form::List(lambda))?;
+
+                    let f = macro_env::compile_local_macro(&lambda)
+                        .map_err(|e| e.convert(ErrorType::Macroexpansion))?;
+
+                    macros.insert(name.clone(), f);
+                }
+
+                let body = macro_env::with_local_macros(macros, || forms_to_hirs(&forms[2..]))?;
+
+                Ok(HIR::LetBlock(LetBlock {
+                    bindings: vec![],
+                    body: body,
+                }))
+            }
+            // lexical variables shadow global functions and macros
+            Form::Symbol(s) if macro_env::is_lexical_variable(s) => {
+                let call = Call {
+                    fn_name: s.clone(),
+                    is_lexical: true,
+                    args: forms_to_hirs(&forms[1..])?,
+                    span: current_span(),
+                };
+
+                Ok(HIR::Call(call))
+            }
             Form::Symbol(s) => unsafe {
                 let call_sym = symbols::get_or_intern_symbol(s.clone());
-                let sym_fn = (*call_sym).function;
+                let sym_fn = macro_env::lookup_local_macro(s).unwrap_or((*call_sym).function);
 
                 let call_hir;
 
                 if sym_fn.is_null() || !(*sym_fn).is_macro {
+                    if let Some(hir) = compiler_macro_expansion(forms, call_sym, sym_fn)? {
+                        return Ok(hir);
+                    }
+
                     let call = Call {
                         fn_name: s.clone(),
+                        is_lexical: false,
                         args: forms[1..]
                             .iter()
                             .map(form_to_hir)
                             .collect::<Result<Vec<_>, _>>()?,
+                        span: current_span(),
+                    };
+
+                    let optimized = if OPTIMIZE.with(Cell::get) {
+                        fold::fold_constant_call(&call, sym_fn)
+                            .or_else(|| inline::inline_call(&call, sym_fn))
+                    } else {
+                        None
                     };
 
-                    call_hir = HIR::Call(call);
+                    call_hir = optimized.unwrap_or(HIR::Call(call));
                 } else {
-                    let arg_objs_list = forms[1..]
-                        .iter()
-                        .map(form_to_runtime_object)
-                        .collect::<Result<Vec<_>, _>>()?
-                        .into_iter()
-                        .rev()
-                        .fold(defs::ListLike::from_nil(), |acc, obj| acc.cons(obj));
-                    let expanded = predefined::call_macro(sym_fn, arg_objs_list)
-                        .map_err(|e| Error::rt_error(e).convert(ErrorType::Macroexpansion))?;
-                    let form = runtime_object_to_form(expanded)
-                        .map_err(|e| e.convert(ErrorType::Macroexpansion))?;
-                    call_hir =
-                        form_to_hir(&form).map_err(|e| e.convert(ErrorType::Macroexpansion))?;
+                    let (expanded, form) = expand_call(sym_fn, "macro", forms)?;
+                    call_hir = expansion_to_hir(expanded, &form)?;
                 }
 
                 Ok(call_hir)
@@ -411,208 +1133,259 @@ fn forms_to_hir(forms: &Vec<Form>) -> Result<HIR, Error> {
 }
 
 pub fn form_to_hir(form: &Form) -> Result<HIR, Error> {
+    if let Form::Symbol(s) = form {
+        if let Some(expansion) = macro_env::lookup_symbol_macro(s) {
+            return form_to_hir(&expansion);
+        }
+    }
+
     match form {
+        // keywords like `:radix` evaluate to themselves
+        Form::Symbol(s) if s.starts_with(':') => Ok(HIR::Quote(Quote {
+            body: Literal::SymbolLiteral(s.clone()),
+        })),
+
         literal @ Form::T
         | literal @ Form::Symbol(_)
         | literal @ Form::Integer(_)
+        | literal @ Form::BigInteger(_)
+        | literal @ Form::Float(_)
+        | literal @ Form::Char(_)
         | literal @ Form::String(_) => Ok(HIR::Literal(form_to_literal(literal))),
 
         Form::List(list) => forms_to_hir(list),
+        Form::DottedList(..) => Err(Error::new(
+            ErrorType::Reader,
+            "improper list cannot be evaluated",
+        )),
+        Form::Labeled(..) | Form::LabelRef(_) => Err(labels_error()),
     }
 }
 
 pub fn form_to_hir_with_transforms(form: &Form) -> Result<HIR, Error> {
+    if exceeds_max_nesting_depth(form) {
+        return Err(nesting_depth_error(
+            ErrorType::Compilation,
+            max_nesting_depth(),
+        ));
+    }
+
+    // quoted data isn't converted by form_to_hir, which only rejects labels
+    // where code is expected
+    if has_labels(form) {
+        return Err(labels_error());
+    }
+
     let hir = form_to_hir(form)?;
     Ok(convert_into_closures(&hir))
 }
 
-fn convert_lambda_body_item(
-    bound_vars: &mut Vec<HashSet<String>>,
-    free_vars: &mut HashSet<String>,
-    body_item: &HIR,
-) -> HIR {
-    let is_bound = |s| {
-        for frame in bound_vars.iter().rev() {
-            if frame.get(s).is_some() {
-                return true;
-            }
-        }
+/// Like `form_to_hir_with_transforms`, with calls carrying their span in
+/// `file`, `spans` are the ones the reader recorded for `form`.
+pub fn form_to_hir_with_spans(form: &Form, spans: &FormSpans, file: &str) -> Result<HIR, Error> {
+    let file = source_locations::intern_file(file);
 
-        false
-    };
+    let outer = SPANS.with(|s| s.replace(collect_spans(form, spans, file)));
+    let outer_conses = CONS_SPANS.with(|s| s.replace(HashMap::new()));
+    let result = form_to_hir_with_transforms(form);
+    SPANS.with(|s| *s.borrow_mut() = outer);
+    CONS_SPANS.with(|s| *s.borrow_mut() = outer_conses);
 
-    match body_item {
-        HIR::SetExpr(e) => {
-            if !is_bound(&e.name) {
-                free_vars.insert(e.name.clone());
-            }
+    result
+}
 
-            HIR::SetExpr(SetExpr {
-                name: e.name.clone(),
-                val: e.val.clone(),
-            })
-        }
-        HIR::Literal(Literal::SymbolLiteral(s)) => {
-            if !is_bound(s) {
-                free_vars.insert(s.clone());
-            }
+// a lexically bound name seen while converting lambdas into closures
+struct Binding {
+    name: String,
+    site: BindingSite,
+    // number of lambdas around the binding, 0 at top level
+    lambda_depth: usize,
+}
 
-            HIR::Literal(Literal::SymbolLiteral(s.clone()))
-        }
-        HIR::Literal(literal) => HIR::Literal(literal.clone()),
-        HIR::Lambda(lambda) => {
-            let closure = convert_lambda(lambda);
+struct ClosureConversion {
+    // innermost binding last, so shadowing is a search from the end
+    bindings: Vec<Binding>,
+    lambda_depth: usize,
+}
 
-            for var in closure.free_vars.iter() {
-                if !is_bound(var) {
-                    free_vars.insert(var.clone());
-                }
-            }
+impl ClosureConversion {
+    fn innermost_binding(&self, name: &str) -> Option<&Binding> {
+        self.bindings.iter().rev().find(|b| b.name == name)
+    }
+
+    // bound in the lambda being converted rather than around it
+    fn is_bound(&self, name: &str) -> bool {
+        self.innermost_binding(name)
+            .map_or(false, |b| b.lambda_depth == self.lambda_depth)
+    }
 
-            HIR::Closure(closure)
+    // where a free variable of a closure created here comes from
+    fn binding_site(&self, name: &str) -> BindingSite {
+        match self.innermost_binding(name) {
+            None => BindingSite::Global,
+            Some(b) if b.lambda_depth == self.lambda_depth => b.site,
+            Some(_) => BindingSite::OuterCapture,
         }
-        HIR::Closure(_) => panic!("unexpected closure"),
-        HIR::Call(call) => {
-            let call = Call {
-                fn_name: call.fn_name.clone(),
-                args: call
-                    .args
-                    .iter()
-                    .map(|hir| convert_lambda_body_item(bound_vars, free_vars, hir))
-                    .collect(),
-            };
+    }
+
+    fn bind(&mut self, name: &str, site: BindingSite) {
+        self.bindings.push(Binding {
+            name: name.to_string(),
+            site: site,
+            lambda_depth: self.lambda_depth,
+        });
+    }
 
-            HIR::Call(call)
+    fn note_reference(&self, free_vars: &mut HashSet<String>, name: &str) {
+        if !self.is_bound(name) {
+            free_vars.insert(name.to_string());
         }
-        HIR::LetBlock(let_block) => {
-            let mut new_bindings = vec![];
+    }
 
-            for (name, val) in let_block.bindings.iter() {
-                let new_val = convert_lambda_body_item(bound_vars, free_vars, val);
+    fn convert(&mut self, free_vars: &mut HashSet<String>, hir: &HIR) -> HIR {
+        match hir {
+            HIR::SetExpr(e) => {
+                self.note_reference(free_vars, &e.name);
 
-                let mut frame = HashSet::new();
-                frame.insert(name.clone());
+                HIR::SetExpr(SetExpr {
+                    name: e.name.clone(),
+                    val: e.val.clone(),
+                })
+            }
+            HIR::Literal(Literal::SymbolLiteral(s)) => {
+                self.note_reference(free_vars, s);
 
-                bound_vars.push(frame);
-                new_bindings.push((name.clone(), new_val));
+                HIR::Literal(Literal::SymbolLiteral(s.clone()))
             }
+            HIR::Literal(literal) => HIR::Literal(literal.clone()),
+            HIR::Lambda(lambda) => {
+                let closure = self.convert_lambda(lambda);
 
-            let new_body = let_block
-                .body
-                .iter()
-                .map(|hir| convert_lambda_body_item(bound_vars, free_vars, hir))
-                .collect();
+                for var in closure.free_vars.iter() {
+                    self.note_reference(free_vars, var);
+                }
 
-            for _ in let_block.bindings.iter() {
-                bound_vars.pop();
+                HIR::Closure(closure)
             }
+            HIR::Closure(_) => panic!("unexpected closure"),
+            HIR::Call(call) => {
+                if call.is_lexical {
+                    self.note_reference(free_vars, &call.fn_name);
+                }
 
-            let new_let_block = LetBlock {
-                bindings: new_bindings,
-                body: new_body,
-            };
+                HIR::Call(Call {
+                    fn_name: call.fn_name.clone(),
+                    is_lexical: call.is_lexical,
+                    args: call
+                        .args
+                        .iter()
+                        .map(|hir| self.convert(free_vars, hir))
+                        .collect(),
+                    span: call.span,
+                })
+            }
+            HIR::LetBlock(let_block) => {
+                let outer_bindings = self.bindings.len();
+                let mut new_bindings = vec![];
+
+                // each value sees the bindings before it
+                for (name, val) in let_block.bindings.iter() {
+                    let new_val = self.convert(free_vars, val);
+                    self.bind(name, BindingSite::LetBinding);
+                    new_bindings.push((name.clone(), new_val));
+                }
 
-            HIR::LetBlock(new_let_block)
-        }
-        HIR::Quote(quote) => HIR::Quote(quote.clone()),
-        HIR::If(if_hir) => {
-            let converted = If {
-                cond: Box::new(convert_lambda_body_item(
-                    bound_vars,
-                    free_vars,
-                    &if_hir.cond,
-                )),
-                then_hir: Box::new(convert_lambda_body_item(
-                    bound_vars,
-                    free_vars,
-                    &if_hir.then_hir,
-                )),
-                else_hir: if_hir.else_hir.as_ref().map(|box_hir| {
-                    Box::new(convert_lambda_body_item(bound_vars, free_vars, &box_hir))
-                }),
-            };
+                let new_body = let_block
+                    .body
+                    .iter()
+                    .map(|hir| self.convert(free_vars, hir))
+                    .collect();
+
+                self.bindings.truncate(outer_bindings);
 
-            HIR::If(converted)
+                HIR::LetBlock(LetBlock {
+                    bindings: new_bindings,
+                    body: new_body,
+                })
+            }
+            HIR::Quote(quote) => HIR::Quote(quote.clone()),
+            HIR::If(if_hir) => HIR::If(If {
+                cond: Box::new(self.convert(free_vars, &if_hir.cond)),
+                then_hir: Box::new(self.convert(free_vars, &if_hir.then_hir)),
+                else_hir: if_hir
+                    .else_hir
+                    .as_ref()
+                    .map(|box_hir| Box::new(self.convert(free_vars, &box_hir))),
+            }),
+            HIR::DeclareVar(decl_var) => HIR::DeclareVar(decl_var.clone()),
         }
-        HIR::DeclareVar(decl_var) => HIR::DeclareVar(decl_var.clone()),
     }
-}
 
-fn convert_lambda(lambda: &Lambda) -> Closure {
-    let mut lambda_frame: HashSet<_> = lambda.arglist.iter().map(|n| n.clone()).collect();
-    lambda
-        .restarg
-        .as_ref()
-        .map(|arg| lambda_frame.insert(arg.clone()));
+    fn convert_lambda(&mut self, lambda: &Lambda) -> Closure {
+        let outer_bindings = self.bindings.len();
+        self.lambda_depth += 1;
 
-    let mut bound_vars = vec![lambda_frame];
-    let mut free_vars = HashSet::new();
+        for arg in lambda.arglist.iter().chain(lambda.restarg.iter()) {
+            self.bind(arg, BindingSite::Parameter);
+        }
 
-    let body = lambda
-        .body
-        .iter()
-        .map(|item| convert_lambda_body_item(&mut bound_vars, &mut free_vars, item))
-        .collect();
-
-    Closure {
-        free_vars: Vec::from_iter(free_vars),
-        lambda: Lambda {
-            name: lambda.name.clone(),
-            arglist: lambda.arglist.clone(),
-            restarg: lambda.restarg.clone(),
-            body: body,
-        },
+        let mut free_vars = HashSet::new();
+        let body = lambda
+            .body
+            .iter()
+            .map(|item| self.convert(&mut free_vars, item))
+            .collect();
+
+        self.bindings.truncate(outer_bindings);
+        self.lambda_depth -= 1;
+
+        // sorted, so the closure struct layout doesn't depend on hashing
+        let mut free_vars = Vec::from_iter(free_vars);
+        free_vars.sort();
+        let free_var_sites = free_vars.iter().map(|v| self.binding_site(v)).collect();
+
+        Closure {
+            free_vars: free_vars,
+            free_var_sites: free_var_sites,
+            lambda: Lambda {
+                name: lambda.name.clone(),
+                arglist: lambda.arglist.clone(),
+                arg_types: lambda.arg_types.clone(),
+                restarg: lambda.restarg.clone(),
+                body: body,
+                declarations: lambda.declarations.clone(),
+            },
+        }
     }
 }
 
 pub fn convert_into_closures(hir: &HIR) -> HIR {
-    match hir {
-        HIR::SetExpr(e) => HIR::SetExpr(e.clone()),
-        HIR::Literal(literal) => HIR::Literal(literal.clone()),
-        HIR::Lambda(lambda) => HIR::Closure(convert_lambda(lambda)),
-        HIR::Closure(_) => panic!("unexpected closure"),
-        HIR::Call(call) => {
-            let converted = Call {
-                fn_name: call.fn_name.clone(),
-                args: call.args.iter().map(convert_into_closures).collect(),
-            };
-
-            HIR::Call(converted)
-        }
-        HIR::LetBlock(let_block) => {
-            let converted = LetBlock {
-                bindings: let_block
-                    .bindings
-                    .iter()
-                    .map(|(s, hir)| (s.clone(), convert_into_closures(hir)))
-                    .collect(),
-                body: let_block.body.iter().map(convert_into_closures).collect(),
-            };
-
-            HIR::LetBlock(converted)
-        }
-        HIR::Quote(quote) => HIR::Quote(quote.clone()),
-        HIR::If(if_hir) => {
-            let converted = If {
-                cond: Box::new(convert_into_closures(&if_hir.cond)),
-                then_hir: Box::new(convert_into_closures(&if_hir.then_hir)),
-                else_hir: if_hir
-                    .else_hir
-                    .as_ref()
-                    .map(|box_hir| Box::new(convert_into_closures(&box_hir))),
-            };
-
-            HIR::If(converted)
-        }
+    let mut conversion = ClosureConversion {
+        bindings: vec![],
+        lambda_depth: 0,
+    };
 
-        HIR::DeclareVar(decl_var) => HIR::DeclareVar(decl_var.clone()),
-    }
+    // names free at top level are globals, nothing captures them
+    conversion.convert(&mut HashSet::new(), hir)
 }
 
+/// Converts a form read as data. A form labeled with `#n=` is converted once
+/// and its `#n#` references are the same object, so shared and circular
+/// structure is read back sharing cells.
 pub fn form_to_runtime_object(form: &Form) -> Result<defs::Object, Error> {
+    form_to_runtime_object_with_labels(form, &mut HashMap::new())
+}
+
+fn form_to_runtime_object_with_labels(
+    form: &Form,
+    labels: &mut HashMap<u64, defs::Object>,
+) -> Result<defs::Object, Error> {
     let obj = match form {
         Form::Symbol(s) => defs::Object::from_symbol(symbols::get_or_intern_symbol(s.clone())),
         Form::Integer(i) => defs::Object::from_int(*i),
+        Form::BigInteger(i) => defs::Object::from_bigint(i.clone()),
+        Form::Float(f) => defs::Object::from_float(*f),
+        Form::Char(c) => defs::Object::from_char(*c),
         Form::String(s) => {
             let c_str = CString::new(s.as_str()).expect("string conversion failed");
             let c_ptr: *const c_char = c_str.into_raw();
@@ -620,49 +1393,113 @@ pub fn form_to_runtime_object(form: &Form) -> Result<defs::Object, Error> {
         }
         Form::List(list) => list
             .iter()
-            .map(form_to_runtime_object)
+            .map(|item| form_to_runtime_object_with_labels(item, labels))
             .collect::<Result<Vec<_>, _>>()?
             .into_iter()
             .rev()
             .fold(defs::ListLike::from_nil(), |acc, obj| acc.cons(obj))
             .to_object(),
+        Form::DottedList(list, tail) => {
+            let items = list
+                .iter()
+                .map(|item| form_to_runtime_object_with_labels(item, labels))
+                .collect::<Result<Vec<_>, _>>()?;
+            let tail = form_to_runtime_object_with_labels(tail, labels)?;
+
+            items.into_iter().rev().fold(tail, |acc, obj| {
+                defs::Object::from_cons(defs::cons_to_heap(defs::Cons::new(obj, acc)))
+            })
+        }
+        Form::Labeled(label, labeled) => match labeled.as_ref() {
+            // references from inside the list go to a cell made up front,
+            // which then becomes the first cell of the list
+            Form::List(items) if !items.is_empty() => label_list(*label, labeled, labels)?,
+            Form::DottedList(..) => label_list(*label, labeled, labels)?,
+            _ => {
+                let obj = form_to_runtime_object_with_labels(labeled, labels)?;
+                labels.insert(*label, obj.clone());
+                obj
+            }
+        },
+        Form::LabelRef(label) => labels
+            .get(label)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorType::Reader, format!("undefined label #{}#", label)))?,
         Form::T => defs::Object::t(),
     };
 
     Ok(obj)
 }
 
+fn label_list(
+    label: u64,
+    list: &Form,
+    labels: &mut HashMap<u64, defs::Object>,
+) -> Result<defs::Object, Error> {
+    let first = defs::cons_to_heap(defs::Cons::new(defs::Object::nil(), defs::Object::nil()));
+    let first_obj = defs::Object::from_cons(first);
+    labels.insert(label, first_obj.clone());
+
+    let converted = form_to_runtime_object_with_labels(list, labels)?;
+    unsafe { *first = (*converted.unpack_cons()).clone() };
+
+    Ok(first_obj)
+}
+
 pub unsafe fn runtime_object_to_form(t_obj: defs::Object) -> Result<Form, Error> {
+    runtime_object_to_form_at_depth(t_obj, 0)
+}
+
+// depth is the number of lists the object is nested in
+unsafe fn runtime_object_to_form_at_depth(
+    t_obj: defs::Object,
+    depth: usize,
+) -> Result<Form, Error> {
     let form = match t_obj.ty {
         defs::ObjType::Int64 => Form::Integer(t_obj.unpack_int()),
-        defs::ObjType::Box => runtime_object_to_form(t_obj.unpack_underlying())?,
+        defs::ObjType::BigInt => Form::BigInteger((*t_obj.unpack_bigint()).clone()),
+        defs::ObjType::Float64 => Form::Float(t_obj.unpack_float()),
+        defs::ObjType::Char => Form::Char(t_obj.unpack_char()),
+        defs::ObjType::Box => runtime_object_to_form_at_depth(t_obj.unpack_underlying(), depth)?,
+        defs::ObjType::Cons if depth >= max_nesting_depth() => Err(nesting_depth_error(
+            ErrorType::Macroexpansion,
+            max_nesting_depth(),
+        ))?,
         defs::ObjType::Cons => {
             let mut converted = vec![];
             let mut cons = t_obj.unpack_cons();
 
             while (*cons).cdr().ty == defs::ObjType::Cons {
-                converted.push(runtime_object_to_form((*cons).car())?);
+                converted.push(runtime_object_to_form_at_depth((*cons).car(), depth + 1)?);
                 cons = (*cons).cdr().unpack_cons();
             }
 
-            if !(*cons).cdr().is_nil() {
-                Err(Error::new(
-                    ErrorType::Macroexpansion,
-                    "embedding pairs in code is not supported yet",
-                ))?;
+            converted.push(runtime_object_to_form_at_depth((*cons).car(), depth + 1)?);
+
+            if (*cons).cdr().is_nil() {
+                Form::List(converted)
             } else {
-                converted.push(runtime_object_to_form((*cons).car())?);
+                let tail = runtime_object_to_form_at_depth((*cons).cdr(), depth + 1)?;
+                Form::DottedList(converted, Box::new(tail))
             }
-
-            Form::List(converted)
         }
         defs::ObjType::Function => Err(Error::new(
             ErrorType::Macroexpansion,
             "embedding functions in code is not supported yet",
         ))?,
+        defs::ObjType::Record => Err(Error::new(
+            ErrorType::Macroexpansion,
+            "embedding records in code is not supported yet",
+        ))?,
+        defs::ObjType::Stream => Err(Error::new(
+            ErrorType::Macroexpansion,
+            "embedding streams in code is not supported",
+        ))?,
         defs::ObjType::Symbol => {
             if t_obj.is_nil() {
                 Form::List(vec![])
+            } else if t_obj == defs::Object::t() {
+                Form::T
             } else {
                 Form::Symbol(
                     CStr::from_ptr((*t_obj.unpack_symbol()).name)
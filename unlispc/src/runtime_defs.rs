@@ -3,6 +3,10 @@ use inkwell::module::Module;
 
 use unlisp_rt::defs::*;
 use unlisp_rt::exceptions::*;
+use unlisp_rt::interrupt::*;
+use unlisp_rt::source_locations::*;
+use unlisp_rt::stats::*;
+use unlisp_rt::values::*;
 
 pub fn gen_defs(ctx: &Context, module: &Module) {
     Object::gen_llvm_def(ctx, module);
@@ -14,17 +18,26 @@ pub fn gen_defs(ctx: &Context, module: &Module) {
 
     unlisp_rt_intern_sym_gen_def(ctx, module);
     unlisp_rt_object_from_int_gen_def(ctx, module);
+    unlisp_rt_object_from_bigint_str_gen_def(ctx, module);
+    unlisp_rt_object_from_float_gen_def(ctx, module);
+    unlisp_rt_object_from_char_gen_def(ctx, module);
     unlisp_rt_object_from_string_gen_def(ctx, module);
     unlisp_rt_int_from_obj_gen_def(ctx, module);
+    unlisp_rt_function_from_obj_gen_def(ctx, module);
     unlisp_rt_object_from_function_gen_def(ctx, module);
     unlisp_rt_object_from_symbol_gen_def(ctx, module);
     unlisp_rt_object_from_cons_gen_def(ctx, module);
     unlisp_rt_object_from_list_gen_def(ctx, module);
+    unlisp_rt_copy_constant_gen_def(ctx, module);
+    unlisp_rt_object_cons_gen_def(ctx, module);
     unlisp_rt_object_is_nil_gen_def(ctx, module);
+    unlisp_rt_object_type_name_gen_def(ctx, module);
     unlisp_rt_nil_object_gen_def(ctx, module);
+    unlisp_rt_nil_list_gen_def(ctx, module);
     unlisp_rt_t_object_gen_def(ctx, module);
     unlisp_rt_check_arity_gen_def(ctx, module);
     unlisp_rt_va_list_into_list_gen_def(ctx, module);
+    unlisp_rt_apply_args_array_gen_def(ctx, module);
     unlisp_rt_list_car_gen_def(ctx, module);
     unlisp_rt_list_cdr_gen_def(ctx, module);
     unlisp_rt_list_cons_gen_def(ctx, module);
@@ -34,7 +47,13 @@ pub fn gen_defs(ctx: &Context, module: &Module) {
 
     unlisp_rt_raise_undef_fn_error_gen_def(ctx, module);
     unlisp_rt_raise_arity_error_gen_def(ctx, module);
+    unlisp_rt_raise_type_error_gen_def(ctx, module);
     unlisp_rt_run_with_global_ex_handler_gen_def(ctx, module);
+    unlisp_rt_check_interrupt_gen_def(ctx, module);
+    unlisp_rt_set_source_location_gen_def(ctx, module);
+    unlisp_rt_clear_values_gen_def(ctx, module);
+
+    unlisp_rt_allocate_gen_def(ctx, module);
 
     unlisp_rt_make_box_gen_def(ctx, module);
     unlisp_rt_box_ref_gen_def(ctx, module);
@@ -0,0 +1,95 @@
+#![allow(dead_code)]
+
+use inkwell::OptimizationLevel;
+use unlisp_rt::escapes::{self, SymbolCase};
+use unlispc::codegen::context::CodegenContext;
+use unlispc::reader;
+
+use std::fs;
+use std::sync::{Mutex, MutexGuard};
+
+// every test resets the symbol table shared by all contexts, so evaluation
+// tests can't run concurrently
+static RUNTIME_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn lock_runtime() -> MutexGuard<'static, ()> {
+    RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn stdlib_path() -> String {
+    format!("{}/../stdlib.unl", env!("CARGO_MANIFEST_DIR"))
+}
+
+pub fn eval_in_ctx(ctx: &mut CodegenContext, src: &str) -> Vec<Result<String, String>> {
+    let mut reader = reader::Reader::from_str(src);
+    let mut forms = vec![];
+    let mut read_error = None;
+
+    loop {
+        match reader.read_form() {
+            Ok(Some(form)) => forms.push(form),
+            Ok(None) => break,
+            Err(e) => {
+                read_error = Some(e.to_string());
+                break;
+            }
+        }
+    }
+
+    let mut results: Vec<_> = unsafe { ctx.eval_forms(&forms) }
+        .into_iter()
+        .map(|result| {
+            result
+                .map(|obj| format!("{}", obj))
+                .map_err(|e| e.to_string())
+        })
+        .collect();
+
+    results.extend(read_error.map(Err));
+
+    results
+}
+
+pub fn with_stdlib_ctx<R>(f: impl FnOnce(&mut CodegenContext) -> R) -> R {
+    with_stdlib_ctx_in_case(SymbolCase::Preserve, f)
+}
+
+/// Like `with_stdlib_ctx`, with symbols read and printed in the mode. The
+/// stdlib pins its own, so it's loaded the same way in all of them.
+pub fn with_stdlib_ctx_in_case<R>(case: SymbolCase, f: impl FnOnce(&mut CodegenContext) -> R) -> R {
+    with_stdlib_ctx_of(case, CodegenContext::new, f)
+}
+
+/// Like `with_stdlib_ctx`, with code generated at the optimization level.
+pub fn with_stdlib_ctx_at<R>(
+    opt_level: OptimizationLevel,
+    f: impl FnOnce(&mut CodegenContext) -> R,
+) -> R {
+    with_stdlib_ctx_of(
+        SymbolCase::Preserve,
+        || CodegenContext::with_opt_level(opt_level),
+        f,
+    )
+}
+
+fn with_stdlib_ctx_of<R>(
+    case: SymbolCase,
+    new_ctx: impl FnOnce() -> CodegenContext,
+    f: impl FnOnce(&mut CodegenContext) -> R,
+) -> R {
+    let _guard = lock_runtime();
+
+    escapes::set_symbol_case(case);
+    unlisp_rt::defs::reset_runtime();
+    let mut ctx = new_ctx();
+
+    let stdlib = fs::read_to_string(stdlib_path()).expect("stdlib file not found");
+    for result in eval_in_ctx(&mut ctx, &stdlib) {
+        result.expect("stdlib evaluation shouldn't return error");
+    }
+
+    let result = f(&mut ctx);
+    escapes::set_symbol_case(SymbolCase::Preserve);
+
+    result
+}
@@ -0,0 +1,104 @@
+mod common;
+
+use common::*;
+
+use unlispc::codegen::context::CodegenContext;
+use unlispc::codegen::unit::{CompilationUnit, Origin, SourceDescriptor};
+use unlispc::reader::Reader;
+use unlispc::repr::{self, Literal, SetExpr, HIR};
+
+use std::fs;
+use std::path::PathBuf;
+
+fn object_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("unlisp-{}-{}.o", name, std::process::id()))
+}
+
+// the unit of a source with a single form, evaluated in `ctx` on the way so
+// `-main` and the functions it calls are defined when the units are compiled
+fn read_unit(ctx: &mut CodegenContext, file: &str, src: &str) -> CompilationUnit {
+    let form = Reader::from_str(src).read_form().unwrap().unwrap();
+    let hir = repr::form_to_hir_with_transforms(&form).unwrap();
+    unsafe { ctx.eval_top_level_hir(&hir) }.unwrap();
+
+    CompilationUnit {
+        hir,
+        source: SourceDescriptor {
+            file: file.to_string(),
+            span: Some(0..src.len()),
+            origin: Origin::User,
+        },
+    }
+}
+
+fn demangled(symbols: &[String]) -> Vec<&str> {
+    symbols
+        .iter()
+        .map(|s| CodegenContext::demangle(s))
+        .collect()
+}
+
+#[test]
+fn test_units_report_their_symbols() {
+    with_stdlib_ctx(|ctx| {
+        let units = vec![
+            read_unit(ctx, "helpers.unl", "(defun unit-helper (x) (+ x 1))"),
+            read_unit(ctx, "main.unl", "(defun -main () (unit-helper 1))"),
+        ];
+
+        let out = object_path("units");
+        let reports = CodegenContext::new()
+            .compile_units_to_object(&units, &out)
+            .unwrap();
+
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].source, units[0].source);
+        assert_eq!(reports[1].source, units[1].source);
+
+        let helper_symbols = demangled(&reports[0].symbols);
+        assert!(helper_symbols.contains(&"__repl_form"));
+        assert!(helper_symbols.contains(&"unit-helper"));
+        assert!(!helper_symbols.contains(&"-main"));
+
+        let main_symbols = demangled(&reports[1].symbols);
+        assert!(main_symbols.contains(&"-main"));
+        assert!(!main_symbols.contains(&"unit-helper"));
+
+        assert!(reports.iter().all(|report| report.warnings.is_empty()));
+
+        assert!(!fs::read(&out).unwrap().is_empty());
+        fs::remove_file(&out).unwrap();
+    })
+}
+
+#[test]
+fn test_unit_errors_carry_the_source() {
+    with_stdlib_ctx(|ctx| {
+        let units = vec![
+            read_unit(ctx, "main.unl", "(defun -main () 1)"),
+            CompilationUnit {
+                hir: HIR::SetExpr(SetExpr {
+                    name: "no-such-local".to_string(),
+                    val: Box::new(HIR::Literal(Literal::IntegerLiteral(1))),
+                }),
+                source: SourceDescriptor {
+                    file: "broken.unl".to_string(),
+                    span: Some(10..20),
+                    origin: Origin::Preload,
+                },
+            },
+        ];
+
+        let out = object_path("broken-units");
+        let error = CodegenContext::new()
+            .compile_units_to_object(&units, &out)
+            .unwrap_err();
+
+        assert_eq!(error.source, Some(units[1].source.clone()));
+        assert_eq!(
+            error.to_string(),
+            "broken.unl:10..20: compilation error: no local symbol: no-such-local"
+        );
+        assert!(!out.exists());
+    })
+}
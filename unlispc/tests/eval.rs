@@ -0,0 +1,3024 @@
+mod common;
+
+use common::*;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use inkwell::OptimizationLevel;
+use unlispc::codegen::context::CodegenContext;
+use unlispc::error::ErrorType;
+use unlispc::{reader, repr};
+
+fn eval_with_stdlib(src: &str) -> Vec<Result<String, String>> {
+    with_stdlib_ctx(|ctx| eval_in_ctx(ctx, src))
+}
+
+fn eval_last(src: &str) -> Result<String, String> {
+    eval_with_stdlib(src)
+        .pop()
+        .expect("no forms evaluated")
+}
+
+#[test]
+fn test_error_formats_message() {
+    assert_eq!(
+        eval_last("(error \"bad: ~a\" 42)"),
+        Err("runtime error: bad: 42".to_string())
+    );
+}
+
+#[test]
+fn test_warn_returns_nil() {
+    assert_eq!(eval_last("(warn \"careful: ~s\" \"x\")"), Ok("nil".to_string()));
+}
+
+#[test]
+fn test_ignore_errors() {
+    assert_eq!(
+        eval_last("(ignore-errors (error \"x\"))"),
+        Ok("nil".to_string())
+    );
+    assert_eq!(eval_last("(ignore-errors 42)"), Ok("42".to_string()));
+}
+
+#[test]
+fn test_block_and_return_from() {
+    let cases = [
+        ("(block b (return-from b 1) 2)", "1"),
+        ("(block b 1 2)", "2"),
+        ("(block b (return-from b) 2)", "nil"),
+        ("(block b (+ 10 (block b (return-from b 1))))", "11"),
+        ("(block a (block b (return-from a 1)) 2)", "1"),
+        ("(block b (ignore-errors (return-from b 1)) 2)", "1"),
+        (
+            "(defun find-first (pred xs)
+               (block found
+                 (mapcar (lambda (x) (when (pred x) (return-from found x))) xs)
+                 nil))
+             (list (find-first (lambda (x) (> x 2)) (list 1 2 3 4))
+                   (find-first (lambda (x) (> x 9)) (list 1 2)))",
+            "(3 nil)",
+        ),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+}
+
+#[test]
+fn test_return_exits_function() {
+    let cases = [
+        (
+            "(defun sign (x)
+               (when (< x 0) (return (quote negative)))
+               (quote non-negative))
+             (list (sign -1) (sign 1))",
+            "(negative non-negative)",
+        ),
+        (
+            "(defun first-over (n xs)
+               (mapcar (lambda (x) (when (> x n) (return x))) xs)
+               nil)
+             (list (first-over 2 (list 1 2 3 4)) (first-over 9 (list 1 2)))",
+            "(3 nil)",
+        ),
+        ("(defun early () (return-from early 1) 2) (early)", "1"),
+        (
+            "(defun quoted () (quote (return 1))) (quoted)",
+            "(return 1)",
+        ),
+        // bodies without a return keep tail calls, so this doesn't run out
+        // of stack
+        (
+            "(defun count-down (n) (if (equal n 0) (quote done) (count-down (- n 1))))
+             (count-down 1000000)",
+            "done",
+        ),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+}
+
+#[test]
+fn test_return_from_errors() {
+    assert_eq!(
+        eval_last("(block b (error \"boom\"))"),
+        Err("runtime error: boom".to_string())
+    );
+    assert_eq!(
+        eval_last("(defvar f (block b (lambda () (return-from b 1)))) (funcall f)"),
+        Err("runtime error: return-from: the block was already exited".to_string())
+    );
+    assert_eq!(
+        eval_last("(return-from b 1)"),
+        Err("compilation error: undefined symbol: --block-b".to_string())
+    );
+}
+
+#[test]
+fn test_restarts() {
+    let cases = [
+        (
+            "(defun checked (x)
+               (restart-case (if (typep x (quote string)) (error \"not a number: ~a\" x) x)
+                 (use-value (v) v)))
+             (handler-bind ((error (lambda (msg) (invoke-restart (quote use-value) 0))))
+               (mapcar (lambda (x) (+ 1 (checked x))) (list 1 \"two\" 3)))",
+            "(2 1 4)",
+        ),
+        (
+            "(let ((seen nil))
+               (handler-bind ((error (lambda (msg) (set! seen msg) (invoke-restart (quote skip)))))
+                 (restart-case (error \"x is ~a\" 1) (skip () nil)))
+               seen)",
+            "\"x is 1\"",
+        ),
+        // the innermost restart of a name is invoked, with all its arguments
+        (
+            "(handler-bind ((error (lambda (msg) (invoke-restart (quote r) 1 2))))
+               (restart-case (restart-case (error \"boom\") (r (a b) (list a b)))
+                 (r (a b) nil)))",
+            "(1 2)",
+        ),
+        // a handler that returns declines, the next one out gets the error
+        (
+            "(handler-bind ((error (lambda (msg) (invoke-restart (quote r) 2))))
+               (restart-case (handler-bind ((error (lambda (msg) nil))) (error \"boom\"))
+                 (r (x) x)))",
+            "2",
+        ),
+        // errors caught inside don't reach the handlers around
+        (
+            "(handler-bind ((error (lambda (msg) (invoke-restart (quote r) 1))))
+               (restart-case (ignore-errors (error \"boom\")) (r (x) x)))",
+            "nil",
+        ),
+        (
+            "(let ((seen 0))
+               (handler-bind ((error (lambda (msg) (set! seen (+ seen 1)))))
+                 (ignore-errors (error \"boom\")))
+               seen)",
+            "0",
+        ),
+        (
+            "(restart-case (compute-restarts) (a () 1) (b () 2))",
+            "(a b)",
+        ),
+        ("(restart-case 1 (r () 2))", "1"),
+        ("(compute-restarts)", "nil"),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+
+    assert_eq!(
+        eval_last("(handler-bind ((error (lambda (msg) nil))) (error \"boom\"))"),
+        Err("runtime error: boom".to_string())
+    );
+    assert_eq!(
+        eval_last("(invoke-restart (quote use-value) 1)"),
+        Err("runtime error: invoke-restart: no restart named use-value is active".to_string())
+    );
+    // handlers run with the handlers around them only
+    assert_eq!(
+        eval_last(
+            "(handler-bind ((error (lambda (msg) (invoke-restart (quote r) msg))))
+               (restart-case (handler-bind ((error (lambda (msg) (error \"again\"))))
+                               (error \"boom\"))
+                 (r (msg) msg)))"
+        ),
+        Ok("\"again\"".to_string())
+    );
+}
+
+// where the error raised by each form of src is, read as file.unl
+fn error_locations(ctx: &mut CodegenContext, src: &str) -> Vec<Option<String>> {
+    let mut reader = unlispc::reader::Reader::from_str(src);
+    let mut locations = vec![];
+
+    while let Some(form) = reader.read_form().unwrap() {
+        let hir =
+            unlispc::repr::form_to_hir_with_spans(&form, reader.form_spans(), "file.unl").unwrap();
+        let result = unsafe { ctx.eval_top_level_hir(&hir) };
+        locations.push(result.err().and_then(|e| e.location).map(|l| l.to_string()));
+    }
+
+    locations
+}
+
+#[test]
+fn test_runtime_error_locations() {
+    let src = "(defun add-one (x)
+  (+ x 1))
+(add-one (quote a))
+(when t
+  (car 1))
+(let ((xs (list 1 2)))
+  (mapcar (lambda (x) (car x)) xs))
+(funcall (lambda () (error \"boom\")))
+(add-one 1)";
+
+    with_stdlib_ctx(|ctx| {
+        let at = |location: &str| Some(location.to_string());
+
+        assert_eq!(
+            error_locations(ctx, src),
+            vec![
+                None,
+                // in the function, inlined or not
+                at("file.unl:2:3"),
+                // code passed to a macro keeps its location
+                at("file.unl:5:3"),
+                at("file.unl:7:23"),
+                at("file.unl:8:21"),
+                None,
+            ]
+        );
+
+        // forms converted without spans don't get the location of the last error
+        let mut reader = unlispc::reader::Reader::from_str("(car 1)");
+        let form = reader.read_form().unwrap().unwrap();
+        let result = unsafe { ctx.eval_forms(&[form]) }.pop().unwrap();
+        assert_eq!(result.unwrap_err().location, None);
+    });
+}
+
+#[test]
+fn test_eval_with_timeout() {
+    with_stdlib_ctx(|ctx| {
+        let budget = Duration::from_millis(100);
+
+        eval_in_ctx(
+            ctx,
+            "(defun spin () (spin))
+             (defun spin-ignoring-errors () (ignore-errors (spin)) (spin-ignoring-errors))",
+        );
+
+        // ignore-errors doesn't catch the interrupt
+        for src in &["(spin)", "(spin-ignoring-errors)"] {
+            let start = Instant::now();
+            let result = unsafe { ctx.eval_str_with_timeout(src, budget) };
+
+            assert_eq!(result.map_err(|e| e.ty).err(), Some(ErrorType::Timeout));
+            assert!(start.elapsed() < Duration::from_secs(2));
+        }
+
+        let result = unsafe { ctx.eval_str_with_timeout("(+ 1 2)", budget) };
+        assert_eq!(
+            result.map(|obj| format!("{}", obj)).ok(),
+            Some("3".to_string())
+        );
+
+        // cleanups run to the end after the interrupt, functions they call
+        // included
+        let path = std::env::temp_dir().join(format!("unlisp-timeout-{}.txt", std::process::id()));
+        let path = format!("{:?}", path.to_str().unwrap());
+        eval_in_ctx(
+            ctx,
+            "(defvar cleanups 0) (defvar out nil)
+             (defun note-cleanup () (set-symbol-value! (quote cleanups) (+ cleanups 1)))",
+        );
+        let src = format!(
+            "(unwind-protect
+               (with-open-file (s {} :direction :output)
+                 (set-symbol-value! (quote out) s)
+                 (write-char #\\y s)
+                 (spin))
+               (note-cleanup))",
+            path
+        );
+        let result = unsafe { ctx.eval_str_with_timeout(&src, budget) };
+        assert_eq!(result.map_err(|e| e.ty).err(), Some(ErrorType::Timeout));
+        assert_eq!(
+            eval_in_ctx(
+                ctx,
+                &format!(
+                    "(list cleanups out (with-open-file (s {}) (read-line s)))",
+                    path
+                )
+            ),
+            vec![Ok(format!("(1 #<closed output stream {}> \"y\")", path))]
+        );
+    });
+}
+
+#[test]
+fn test_eval_str_transactional_rolls_back_failed_batches() {
+    with_stdlib_ctx(|ctx| {
+        eval_in_ctx(ctx, "(defun square (x) (* x x)) (defvar *limit* 10)");
+
+        let result = unsafe {
+            ctx.eval_str_transactional(
+                "(defun square (x) (+ x x))
+                 (defmacro twice (x) (qquote (let () (unq x) (unq x))))
+                 (set-symbol-value! (quote *limit*) 20)
+                 (defvar *added* 1)
+                 (error \"failed\")
+                 (defun never-defined () 1)",
+            )
+        };
+        assert_eq!(
+            result.map_err(|e| e.to_string()).err(),
+            Some("runtime error: failed".to_string())
+        );
+
+        assert_eq!(
+            eval_in_ctx(
+                ctx,
+                "(square 3)
+                 *limit*
+                 (fboundp (quote twice))
+                 (boundp (quote *added*))
+                 (fboundp (quote never-defined))"
+            ),
+            vec![
+                Ok("9".to_string()),
+                Ok("10".to_string()),
+                Ok("nil".to_string()),
+                Ok("nil".to_string()),
+                Ok("nil".to_string()),
+            ]
+        );
+
+        let result = unsafe { ctx.eval_str_transactional("(defun square (x) (+ x x)) (square 3)") };
+        assert_eq!(
+            result.map(|obj| format!("{}", obj)).ok(),
+            Some("6".to_string())
+        );
+        assert_eq!(eval_in_ctx(ctx, "(square 4)"), vec![Ok("8".to_string())]);
+    });
+}
+
+#[test]
+fn test_eval_many_keeps_errors_to_their_forms() {
+    with_stdlib_ctx(|ctx| {
+        let hirs: Vec<_> = reader::Reader::from_str(
+            "(defvar counter 0)
+             (set-symbol-value! (quote counter) (+ counter 1))
+             (+ no-such-var 1)
+             (set-symbol-value! (quote counter) (+ counter 1))
+             (car 1)
+             (defun add-counter (x) (+ x counter))
+             (add-counter 40)",
+        )
+        .read_all_forms()
+        .unwrap()
+        .iter()
+        .map(|(_, form)| repr::form_to_hir_with_transforms(form).unwrap())
+        .collect();
+
+        let results: Vec<_> = unsafe { ctx.eval_many(&hirs) }
+            .into_iter()
+            .map(|result| result.map(|obj| format!("{}", obj)).map_err(|e| e.ty))
+            .collect();
+
+        assert_eq!(
+            results,
+            vec![
+                Ok("nil".to_string()),
+                Ok("nil".to_string()),
+                Err(ErrorType::Compilation),
+                Ok("nil".to_string()),
+                Err(ErrorType::Runtime),
+                Ok("nil".to_string()),
+                Ok("42".to_string()),
+            ]
+        );
+    });
+}
+
+#[test]
+fn test_assert() {
+    assert_eq!(
+        eval_last("(assert (equal 1 2))"),
+        Err("runtime error: assertion failed: (equal 1 2)".to_string())
+    );
+    assert_eq!(eval_last("(assert (equal 1 1))"), Ok("nil".to_string()));
+}
+
+#[test]
+fn test_run_tests_reports_counts() {
+    let results = eval_with_stdlib(
+        "(deftest passing (assert (equal 1 1)))
+         (deftest failing (assert (equal 1 2)))
+         (run-tests)",
+    );
+
+    assert_eq!(results.last(), Some(&Ok("(1 2)".to_string())));
+}
+
+#[test]
+fn test_arg_type_annotations_accept_matching_types() {
+    assert_eq!(
+        eval_last(
+            "(defun typed ((i integer) (l list) (s symbol) (f function) (str string) untyped)
+               (list i l s str untyped))
+             (typed 1 (quote (2)) (quote a) (lambda () 1) \"x\" nil)"
+        ),
+        Ok("(1 (2) a \"x\" nil)".to_string())
+    );
+    assert_eq!(
+        eval_last("(defun typed-nil ((l list)) l) (typed-nil nil)"),
+        Ok("nil".to_string())
+    );
+}
+
+#[test]
+fn test_arg_type_annotations_reject_mismatches() {
+    let cases = [
+        ("((x integer))", "(quote (1))", "argument x of f: expected integer, got list"),
+        ("((x list))", "1", "argument x of f: expected list, got integer"),
+        ("((x symbol))", "\"s\"", "argument x of f: expected symbol, got string"),
+        ("((x function))", "(quote f)", "argument x of f: expected function, got symbol"),
+        ("((x string))", "(lambda () 1)", "argument x of f: expected string, got function"),
+    ];
+
+    for (arglist, arg, message) in cases.iter() {
+        let src = format!("(defun f {} x) (f {})", arglist, arg);
+        assert_eq!(eval_last(&src), Err(format!("runtime error: {}", message)));
+    }
+}
+
+#[test]
+fn test_arg_type_checks_run_before_body() {
+    assert_eq!(
+        eval_last(
+            "(defvar body-ran nil)
+             (defun checked ((x integer)) (set! body-ran t) x)
+             (ignore-errors (checked nil))
+             body-ran"
+        ),
+        Ok("nil".to_string())
+    );
+}
+
+#[test]
+fn test_arg_type_checks_through_apply_and_closures() {
+    assert_eq!(
+        eval_last("(defun g (a (b integer)) b) (apply (symf g) (list 1 (quote x)))"),
+        Err("runtime error: argument b of g: expected integer, got symbol".to_string())
+    );
+    assert_eq!(
+        eval_last(
+            "(let ((offset 10))
+               (funcall (lambda adder ((x integer)) (+ x offset)) 5))"
+        ),
+        Ok("15".to_string())
+    );
+    assert_eq!(
+        eval_last(
+            "(let ((offset 10))
+               (funcall (lambda adder ((x integer)) (+ x offset)) nil))"
+        ),
+        Err("runtime error: argument x of adder: expected integer, got list".to_string())
+    );
+}
+
+#[test]
+fn test_arg_checks_survive_opt_levels() {
+    let src = "(defun typed ((x integer)) x)
+               (defun two (a b) a)
+               (defun calls-two () (two 1))
+               (typed (quote x))
+               (calls-two)
+               (list (typed 1) (two 2 3))";
+
+    for opt_level in [
+        OptimizationLevel::Less,
+        OptimizationLevel::Default,
+        OptimizationLevel::Aggressive,
+    ]
+    .iter()
+    {
+        let results = with_stdlib_ctx_at(*opt_level, |ctx| eval_in_ctx(ctx, src));
+
+        assert_eq!(
+            results[3..],
+            [
+                Err("runtime error: argument x of typed: expected integer, got symbol".to_string()),
+                Err("runtime error: wrong number of arguments (1) passed to two".to_string()),
+                Ok("(1 2)".to_string()),
+            ],
+            "{:?}",
+            opt_level
+        );
+    }
+}
+
+#[test]
+fn test_room_counts_allocations() {
+    with_stdlib_ctx(|ctx| {
+        eval_in_ctx(ctx, "(defun make-adder (x) (lambda (y) (+ x y)))");
+
+        unlisp_rt::stats::reset();
+        eval_in_ctx(ctx, "(cons 1 (cons 2 (cons 3 nil)))");
+        assert_eq!(unlisp_rt::stats().conses, 3);
+
+        unlisp_rt::stats::reset();
+        eval_in_ctx(ctx, "(make-adder 1) (make-adder 2)");
+        assert_eq!(unlisp_rt::stats().closures, 2);
+        assert!(unlisp_rt::stats().bytes > 0);
+    });
+}
+
+#[test]
+fn test_room_returns_alist() {
+    assert_eq!(
+        eval_last("(reset-room) (cons 1 nil) (first (room))"),
+        Ok("(conses . 1)".to_string())
+    );
+}
+
+#[test]
+fn test_room_after_gc() {
+    assert_eq!(
+        eval_last("(reset-room) (range 100) (gc) (first (room))"),
+        Ok("(conses . 100)".to_string())
+    );
+    assert_eq!(eval_last("(gc)"), Ok("0".to_string()));
+}
+
+#[test]
+fn test_apply_closure_with_free_vars() {
+    assert_eq!(
+        eval_last(
+            "(let ((base 100))
+               (apply (lambda (x y) (+ base x y)) (list 1 2)))"
+        ),
+        Ok("103".to_string())
+    );
+    assert_eq!(
+        eval_last(
+            "(let ((base 100))
+               (apply (lambda (x & rest) (list base x rest)) 1 (list 2 3)))"
+        ),
+        Ok("(100 1 (2 3))".to_string())
+    );
+}
+
+#[test]
+fn test_dotted_pairs() {
+    assert_eq!(eval_last("(quote (a b . c))"), Ok("(a b . c)".to_string()));
+    assert_eq!(eval_last("(quote (a . (b . nil)))"), Ok("(a b)".to_string()));
+    assert_eq!(eval_last("(cons 1 2)"), Ok("(1 . 2)".to_string()));
+    assert_eq!(eval_last("(first (quote (a . b)))"), Ok("a".to_string()));
+    assert_eq!(eval_last("(rest (quote (a . b)))"), Ok("b".to_string()));
+    assert_eq!(
+        eval_last("(equal (quote (1 . 2)) (cons 1 2))"),
+        Ok("(1 . 2)".to_string())
+    );
+}
+
+#[test]
+fn test_dotted_pairs_through_macros() {
+    assert_eq!(
+        eval_last("(defmacro pair-of (x y) (list (quote quote) (cons x y))) (pair-of 1 2)"),
+        Ok("(1 . 2)".to_string())
+    );
+}
+
+#[test]
+fn test_improper_list_in_code_position() {
+    assert!(eval_last("(a . b)").is_err());
+}
+
+#[test]
+fn test_closure_apply_entry_is_set() {
+    // apply and macroexpansion both go through the closure's apply_to_f_ptr
+    assert_eq!(
+        eval_last("(let ((k 5)) (apply (lambda (& xs) (cons k xs)) 1 (list 2)))"),
+        Ok("(5 1 2)".to_string())
+    );
+    assert_eq!(
+        eval_last("(defmacro swap (a b) (list b a)) (macroexpand-1 (quote (swap 1 +)))"),
+        Ok("(+ 1)".to_string())
+    );
+}
+
+#[test]
+fn test_closure_fields_are_initialized() {
+    use std::ffi::CStr;
+    use unlisp_rt::defs::FunctionType;
+    use unlispc::{reader, repr};
+
+    with_stdlib_ctx(|ctx| unsafe {
+        let mut input = "(let ((y 1)) (lambda adder (a b & rest) (+ a b y)))".as_bytes();
+        let form = reader::Reader::create(&mut input)
+            .read_form()
+            .unwrap()
+            .unwrap();
+        let hir = repr::form_to_hir_with_transforms(&form).unwrap();
+        let f = ctx.eval_hirs(&[hir]).unwrap().unpack_function();
+
+        match (*f).ty {
+            FunctionType::Closure => (),
+            _ => panic!("expected closure"),
+        }
+        assert_eq!(CStr::from_ptr((*f).name).to_str().unwrap(), "adder");
+        assert_eq!((*f).arg_count, 2);
+        assert!(!(*f).is_macro);
+        assert!((*f).has_restarg);
+        assert!(!(*f).invoke_f_ptr.is_null());
+        assert!(!(*f).apply_to_f_ptr.is_null());
+
+        let arglist: Vec<_> = (0..(*f).arg_count as isize)
+            .map(|i| CStr::from_ptr(*(*f).arglist.offset(i)).to_str().unwrap())
+            .collect();
+        assert_eq!(arglist, ["a", "b"]);
+    });
+}
+
+#[test]
+fn test_macrolet() {
+    let results = eval_with_stdlib(
+        "(macrolet ((twice (x) (list (quote +) x x))) (twice 3))
+         (twice 3)",
+    );
+
+    assert_eq!(
+        results,
+        vec![
+            Ok("6".to_string()),
+            Err("runtime error: undefined function twice".to_string())
+        ]
+    );
+}
+
+#[test]
+fn test_macrolet_shadows_and_nests() {
+    assert_eq!(
+        eval_last(
+            "(defmacro my-id (x) x)
+             (macrolet ((my-id (x) (list (quote quote) x)))
+               (macrolet ((one () 1))
+                 (list (my-id y) (one))))"
+        ),
+        Ok("(y 1)".to_string())
+    );
+}
+
+#[test]
+fn test_apply_with_list_tail() {
+    assert_eq!(
+        eval_last("(apply (symf +) 1 (quote (2 3)))"),
+        Ok("6".to_string())
+    );
+    assert_eq!(
+        eval_last("(funcall (symf apply) (symf +) 1 (quote (2 3)))"),
+        Ok("6".to_string())
+    );
+}
+
+#[test]
+fn test_apply_non_list_last_arg() {
+    assert_eq!(
+        eval_last("(apply (symf +) 1 2 3)"),
+        Err("runtime error: apply: last argument must be a list, got int (applying +)".to_string())
+    );
+    assert_eq!(
+        eval_last("(apply (lambda (& xs) xs) \"x\")"),
+        Err(
+            "runtime error: apply: last argument must be a list, got string (applying lambda)"
+                .to_string()
+        )
+    );
+    assert_eq!(
+        eval_last("(apply (symf apply) (symf +) (quote (1 2)))"),
+        Err("runtime error: apply: last argument must be a list, got int (applying +)".to_string())
+    );
+}
+
+#[test]
+fn test_apply_without_arg_list() {
+    assert_eq!(
+        eval_last("(apply (symf +))"),
+        Err("runtime error: wrong number of arguments (1) passed to apply".to_string())
+    );
+    assert_eq!(
+        eval_last("(apply (symf apply) (list (symf +)))"),
+        Err("runtime error: wrong number of arguments (1) passed to apply".to_string())
+    );
+}
+
+#[test]
+fn test_apply_arity_error_names_target() {
+    assert_eq!(
+        eval_last("(defun two (a b) a) (apply (symf two) 1 2 (quote (3)))"),
+        Err("runtime error: wrong number of arguments (3) passed to two".to_string())
+    );
+    assert_eq!(
+        eval_last("(defun two (a b) a) (apply (symf two) (quote (1)))"),
+        Err("runtime error: wrong number of arguments (1) passed to two".to_string())
+    );
+}
+
+#[test]
+fn test_apply_does_not_cons_small_calls() {
+    with_stdlib_ctx(|ctx| {
+        // quoted lists are consed when evaluated, so they're made beforehand
+        eval_in_ctx(
+            ctx,
+            "(defun add3 (a b c) (+ a (+ b c)))
+             (defvar *one* (quote (3)))
+             (defvar *two* (quote (2 3)))
+             (defvar *three* (quote (1 2 3)))",
+        );
+
+        unlisp_rt::stats::reset();
+        let results = eval_in_ctx(
+            ctx,
+            "(funcall (symf add3) 1 2 3)
+             (apply (symf add3) 1 2 *one*)
+             (apply (symf add3) *three*)
+             (funcall (symf apply) (symf add3) 1 *two*)
+             (apply (symf apply) (symf add3) 1 (list *two*))",
+        );
+        assert!(results.iter().all(|result| result.as_ref().unwrap() == "6"));
+        assert_eq!(unlisp_rt::stats().conses, 1);
+    });
+}
+
+#[test]
+fn test_apply_spreading_keeps_behavior() {
+    assert_eq!(
+        eval_last("(apply (lambda (a & r) (list a r)) 1 (quote (2 3)))"),
+        Ok("(1 (2 3))".to_string())
+    );
+    assert_eq!(
+        eval_last("(apply (lambda (& r) r) 1 2 nil)"),
+        Ok("(1 2)".to_string())
+    );
+    assert_eq!(
+        eval_last("(apply (symf list) 1 2 3 4 5 6 7 (quote (8)))"),
+        Ok("(1 2 3 4 5 6 7 8)".to_string())
+    );
+    assert_eq!(
+        eval_last("(apply (symf list) 1 2 3 4 5 6 7 (quote (8 9 10)))"),
+        Ok("(1 2 3 4 5 6 7 8 9 10)".to_string())
+    );
+    // a dotted tail is an error on the stack path and the list path alike
+    let dotted = [
+        ("(apply (symf list) 1 (quote (2 . 3)))", "(2 . 3)"),
+        ("(apply (symf list) 1 2 3 4 5 6 7 8 (quote (9 10 . 11)))", "(9 10 . 11)"),
+        ("(apply (symf apply) (symf list) 1 (quote ((2 . 3))))", "(2 . 3)"),
+    ];
+
+    for (src, tail) in dotted.iter() {
+        assert_eq!(
+            eval_last(src),
+            Err(format!(
+                "runtime error: apply: last argument must be a proper list, got {} (applying list)",
+                tail
+            )),
+            "{}",
+            src
+        );
+    }
+    assert_eq!(
+        eval_last("(defun two (a b) a) (apply (symf two) 1 2 3 4 5 6 7 8 (quote (9)))"),
+        Err("runtime error: wrong number of arguments (9) passed to two".to_string())
+    );
+}
+
+#[test]
+fn test_wrong_arity_is_warned_at_compile_time() {
+    let src = "(defun two (a b) a)
+               (defun calls-two () (two 1 2 3))
+               (defun needs-one (a & more) a)
+               (defun calls-needs-one () (needs-one))
+               (defun grow (a) a)
+               (defun grow (a b) (if a (grow nil b) b))
+               (list (two 1 2) (needs-one 1 2 3) (grow 1 2))
+               (calls-two)";
+
+    let (results, warnings) = with_stdlib_ctx(|ctx| {
+        ctx.take_warnings();
+        let results = eval_in_ctx(ctx, src);
+        (results, ctx.take_warnings())
+    });
+
+    assert_eq!(
+        warnings,
+        vec![
+            "wrong number of arguments (3) passed to two, which takes 2",
+            "wrong number of arguments (0) passed to needs-one, which takes at least 1",
+        ]
+    );
+    // the calls are compiled with the usual runtime check
+    assert_eq!(results[6], Ok("(1 1 2)".to_string()));
+    assert_eq!(
+        results[7],
+        Err("runtime error: wrong number of arguments (3) passed to two".to_string())
+    );
+}
+
+#[test]
+fn test_warnings_as_errors() {
+    let src = "(defun two (a b) a)
+               (defun calls-two () (two 1 2 3))
+               (fboundp (quote calls-two))
+               (two 1 2)";
+
+    let (results, warnings) = with_stdlib_ctx(|ctx| {
+        ctx.set_warnings_are_errors(true);
+        let results = eval_in_ctx(ctx, src);
+        (results, ctx.take_warnings())
+    });
+
+    assert_eq!(
+        warnings,
+        vec!["wrong number of arguments (3) passed to two, which takes 2"]
+    );
+    let error = results[1].as_ref().unwrap_err();
+    assert_eq!(
+        error,
+        "compilation error: the form has warnings, which are treated as errors (see --werror)"
+    );
+    // the failed form isn't run
+    assert_eq!(results[2], Ok("nil".to_string()));
+    assert_eq!(results[3], Ok("1".to_string()));
+}
+
+#[test]
+fn test_symbol_macrolet() {
+    let results = eval_with_stdlib(
+        "(defvar x 10)
+         (symbol-macrolet ((x (+ 1 2))) (list x (quote x)))
+         x",
+    );
+
+    assert_eq!(
+        results,
+        vec![
+            Ok("nil".to_string()),
+            Ok("(3 x)".to_string()),
+            Ok("10".to_string())
+        ]
+    );
+}
+
+#[test]
+fn test_symbol_macrolet_shadowed_by_bindings() {
+    assert_eq!(
+        eval_last(
+            "(symbol-macrolet ((x (+ 1 2)))
+               (list x (let ((x 5)) x) (funcall (lambda (x) x) 7)))"
+        ),
+        Ok("(3 5 7)".to_string())
+    );
+}
+
+#[test]
+fn test_symbol_macrolet_set() {
+    assert_eq!(
+        eval_last("(let ((y 1)) (symbol-macrolet ((x y)) (set! x 2) y))"),
+        Ok("2".to_string())
+    );
+}
+
+#[test]
+fn test_int_literals_are_constant_objects() {
+    with_stdlib_ctx(|ctx| {
+        let mut input = "(+ 1 (- 5 2))".as_bytes();
+        let form = unlispc::reader::Reader::create(&mut input)
+            .read_form()
+            .unwrap()
+            .unwrap();
+        let hir = unlispc::repr::form_to_hir_with_transforms(&form).unwrap();
+
+        ctx.compile_hirs(&[hir]).unwrap();
+        let ir = ctx.get_module().print_to_string().to_string();
+
+        assert!(!ir
+            .lines()
+            .any(|l| l.contains("call") && l.contains("@unlisp_rt_object_from_int")));
+    });
+
+    assert_eq!(eval_last("(+ 1 (- 5 2))"), Ok("4".to_string()));
+    assert_eq!(
+        eval_last("(- 0 9223372036854775807)"),
+        Ok("-9223372036854775807".to_string())
+    );
+    assert_eq!(
+        eval_last("(+ 1 (quote x))"),
+        Err("runtime error: cannot cast symbol to int".to_string())
+    );
+}
+
+#[test]
+fn test_destructuring_bind_mismatch() {
+    assert_eq!(
+        eval_last("(destructuring-bind (a (b c)) (list 1 (list 2)) (list a b c))"),
+        Err("runtime error: destructuring-bind: expected a non-empty list, got nil".to_string())
+    );
+    assert_eq!(
+        eval_last("(destructuring-bind (a b) (list 1 2 3) (list a b))"),
+        Err("runtime error: destructuring-bind: too many elements, (3) left over".to_string())
+    );
+}
+
+#[test]
+fn test_destructuring_bind_doesnt_capture_variables() {
+    assert_eq!(
+        eval_last(
+            "(let ((--destructuring-value 5) (--destructuring-check 6))
+               (destructuring-bind (a) (list 1)
+                 (list a --destructuring-value --destructuring-check)))"
+        ),
+        Ok("(1 5 6)".to_string())
+    );
+}
+
+#[test]
+fn test_eval_forms_batch() {
+    with_stdlib_ctx(|ctx| {
+        let mut input = "(defun add-one (x) (+ x 1))
+                         (+ 1 2)
+                         (error \"broken\")
+                         (add-one 41)"
+            .as_bytes();
+        let mut reader = unlispc::reader::Reader::create(&mut input);
+        let mut forms = vec![];
+        while let Some(form) = reader.read_form().unwrap() {
+            forms.push(form);
+        }
+
+        let results: Vec<_> = unsafe { ctx.eval_forms(&forms) }
+            .into_iter()
+            .map(|r| r.map(|obj| format!("{}", obj)).map_err(|e| e.to_string()))
+            .collect();
+
+        assert_eq!(
+            results,
+            vec![
+                Ok("nil".to_string()),
+                Ok("3".to_string()),
+                Err("runtime error: broken".to_string()),
+                Ok("42".to_string())
+            ]
+        );
+    });
+}
+
+#[test]
+fn test_prog1_prog2_evaluate_all_forms() {
+    assert_eq!(
+        eval_last(
+            "(let ((x 0))
+               (list (prog1 x (set! x (+ x 1)) (set! x (+ x 1)))
+                     (prog2 (set! x 10) x (set! x 20))
+                     x))"
+        ),
+        Ok("(0 10 20)".to_string())
+    );
+}
+
+#[test]
+fn test_prog1_doesnt_capture_variables() {
+    assert_eq!(
+        eval_last(
+            "(let ((--prog1-result 5))
+               (list (prog1 1 (set! --prog1-result 7)) --prog1-result))"
+        ),
+        Ok("(1 7)".to_string())
+    );
+}
+
+#[test]
+fn test_function_redefined_as_macro() {
+    with_stdlib_ctx(|ctx| {
+        let results = eval_in_ctx(
+            ctx,
+            "(defun f (x) (list x x))
+             (f 1)
+             (defmacro f (x) (list (quote quote) x))
+             (f 1)
+             (defun f (x) x)
+             (f 2)",
+        );
+
+        assert_eq!(
+            results,
+            vec![
+                Ok("nil".to_string()),
+                Ok("(1 1)".to_string()),
+                Ok("nil".to_string()),
+                Ok("1".to_string()),
+                Ok("nil".to_string()),
+                Ok("2".to_string())
+            ]
+        );
+        assert_eq!(unlisp_rt::predefined::namespace_conflicts(), 2);
+    });
+}
+
+#[test]
+fn test_macro_arity_is_checked_at_expansion() {
+    assert_eq!(
+        eval_last("(defmacro m (x) x) (m 1 2)"),
+        Err("macroexpansion error: wrong number of argument forms (2) passed to macro m".to_string())
+    );
+    assert_eq!(
+        eval_last("(defmacro m (x y & more) x) (m 1)"),
+        Err("macroexpansion error: wrong number of argument forms (1) passed to macro m".to_string())
+    );
+}
+
+#[test]
+fn test_if_let_when_let() {
+    assert_eq!(
+        eval_last("(if-let (x (first (list 1 2))) (list x x) x)"),
+        Ok("(1 1)".to_string())
+    );
+    assert_eq!(
+        eval_last("(if-let (x (first nil)) (list x x) (list x))"),
+        Ok("(nil)".to_string())
+    );
+    assert_eq!(
+        eval_last("(when-let (x (rest (list 1 2))) (set! x (first x)) (+ x 1))"),
+        Ok("3".to_string())
+    );
+    assert_eq!(
+        eval_last("(when-let (x (rest (list 1))) (error \"not reached\"))"),
+        Ok("nil".to_string())
+    );
+}
+
+#[test]
+fn test_mutual_tail_recursion() {
+    assert_eq!(
+        eval_last(
+            "(defun my-even? (n) (if (equal n 0) t (my-odd? (- n 1))))
+             (defun my-odd? (n) (if (equal n 0) nil (my-even? (- n 1))))
+             (list (my-even? 1000000) (my-odd? 1000001) (my-even? 7))"
+        ),
+        Ok("(t t nil)".to_string())
+    );
+}
+
+#[test]
+fn test_tail_calls_are_marked() {
+    with_stdlib_ctx(|ctx| {
+        let mut input = "(lambda (n) (if n (foo n) (let () (bar) (baz n))))".as_bytes();
+        let form = unlispc::reader::Reader::create(&mut input)
+            .read_form()
+            .unwrap()
+            .unwrap();
+        let hir = unlispc::repr::form_to_hir_with_transforms(&form).unwrap();
+
+        ctx.compile_hirs(&[hir]).unwrap();
+        let ir = ctx.get_module().print_to_string().to_string();
+
+        let invokes: Vec<_> = ir
+            .lines()
+            .filter(|l| l.contains("call") && l.contains("invoke_result"))
+            .collect();
+
+        // foo and baz are in tail position, bar is not; each call site has a
+        // vararg and a fixed-arity variant
+        assert_eq!(invokes.len(), 6);
+        assert_eq!(invokes.iter().filter(|l| l.contains("tail call")).count(), 4);
+    });
+}
+
+#[test]
+fn test_nested_closures_are_mangled_hierarchically() {
+    with_stdlib_ctx(|ctx| {
+        let mut input =
+            "(lambda outer () (lambda () 1) (lambda inner? () (lambda () 2)))".as_bytes();
+        let form = unlispc::reader::Reader::create(&mut input)
+            .read_form()
+            .unwrap()
+            .unwrap();
+        let hir = unlispc::repr::form_to_hir_with_transforms(&form).unwrap();
+
+        ctx.compile_hirs(&[hir]).unwrap();
+        let ir = ctx.get_module().print_to_string().to_string();
+
+        assert!(ir.contains("@outer__unlisp_"));
+        assert!(ir.contains("@\"outer/lambda__unlisp_"));
+        assert!(ir.contains("@\"outer/invoke_closure__unlisp_"));
+        assert!(ir.contains("@\"outer/inner_/lambda__unlisp_"));
+        assert!(ir.contains("c\"outer/inner?/lambda\\00\""));
+    });
+
+    assert_eq!(
+        eval_last("(defun outer () (lambda (a) a)) (funcall (outer) 1 2)"),
+        Err("runtime error: wrong number of arguments (2) passed to outer/lambda".to_string())
+    );
+}
+
+#[test]
+fn test_mangled_names_are_stable() {
+    let src = "(defun outer () (lambda () 1) (lambda () 2)) (defun other () 3)";
+
+    let compile = || {
+        with_stdlib_ctx(|ctx| {
+            let dumps = Rc::new(RefCell::new(String::new()));
+            let sink = dumps.clone();
+            ctx.set_ir_dump_hook(
+                Some(Box::new(move |dump: &str| sink.borrow_mut().push_str(dump))),
+                None,
+            );
+
+            eval_in_ctx(ctx, src);
+            dumps.replace(String::new())
+        })
+    };
+
+    let first = compile();
+    assert_eq!(first, compile());
+    // ids count per name, so the lambdas of other definitions don't shift
+    // them
+    assert!(first.contains("@\"outer/lambda__unlisp_1\""));
+    assert!(first.contains("@\"outer/lambda__unlisp_2\""));
+    assert!(first.contains("@other__unlisp_1"));
+
+    assert_eq!(
+        CodegenContext::demangle("outer/lambda__unlisp_2"),
+        "outer/lambda"
+    );
+    assert_eq!(
+        CodegenContext::demangle("a__unlisp_1__unlisp_3"),
+        "a__unlisp_1"
+    );
+    assert_eq!(CodegenContext::demangle("a__unlisp_"), "a__unlisp_");
+    assert_eq!(CodegenContext::demangle("main"), "main");
+}
+
+#[test]
+fn test_constant_defvars_compile_to_static_values() {
+    with_stdlib_ctx(|ctx| {
+        ctx.set_static_values(true);
+
+        let mut input = "(defvar *limit* (* 10 10))".as_bytes();
+        let form = unlispc::reader::Reader::create(&mut input)
+            .read_form()
+            .unwrap()
+            .unwrap();
+        let hir = unlispc::repr::form_to_hir_with_transforms(&form).unwrap();
+
+        ctx.reinitialize();
+        ctx.compile_hirs(&[hir]).unwrap();
+        let ir = ctx.get_module().print_to_string().to_string();
+
+        assert!(ir.contains("@\"*limit*_value__unlisp_1\" = private global"));
+        assert!(ir.contains("inttoptr (i64 100 to i8*)"));
+        assert!(!ir.contains("set-symbol-value!"));
+        // the module's constructor points the symbol at it
+        assert!(!top_level_ir(&ir).contains("*limit*_value"));
+
+        let results = eval_in_ctx(
+            ctx,
+            "(defvar *limit* 100)
+             (defvar *half* 2.5)
+             (defvar *name* \"limit\")
+             (defvar *unit* (quote items))
+             (defvar *range* (list 1 (quote to) 100))
+             (defvar *bounds* (quote (0 . 100)))
+             (defvar *dynamic* (+ *limit* (length (list 1 2))))
+             (defvar *half* 3.5)
+             (list *limit* *half* *name* *unit* *range* *bounds* *dynamic*)",
+        );
+        assert_eq!(
+            results.last(),
+            Some(&Ok("(100 3.5 \"limit\" items (1 to 100) (0 . 100) 102)".to_string()))
+        );
+    });
+}
+
+#[test]
+fn test_constant_defvars_are_set_before_any_code_of_the_program() {
+    with_stdlib_ctx(|ctx| {
+        ctx.set_static_values(true);
+
+        let units = [
+            "(defun limit () (symbol-value (quote *limit*)))
+             (defvar *early* (list (limit) (symbol-value (quote *greeting*))))",
+            "(defvar *limit* 100)
+             (defvar *greeting* \"hi\")
+             (defvar *dynamic* (+ *limit* 2))",
+        ];
+
+        let mut irs = vec![];
+        for (i, src) in units.iter().enumerate() {
+            let hirs: Vec<_> = unlispc::reader::Reader::from_str(src)
+                .read_all_forms()
+                .unwrap()
+                .iter()
+                .map(|(_, form)| unlispc::repr::form_to_hir_with_transforms(form).unwrap())
+                .collect();
+
+            ctx.compile_unit_to_memory(&hirs, &format!("unit{}", i)).unwrap();
+            irs.push(ctx.get_module().print_to_string().to_string());
+        }
+
+        // no code stores the constant values, the constructor of the second
+        // unit does, the other value is set at run time
+        let code = top_level_ir(&irs[1]);
+        assert!(!code.contains("*limit*_value"), "{}", code);
+        assert!(!code.contains("*greeting*_value"), "{}", code);
+        assert!(code.contains("set-symbol-value!"), "{}", code);
+        assert!(irs[1].contains("@unit1_constants()"), "{}", irs[1]);
+
+        for result in eval_in_ctx(ctx, "(defun -main () nil)") {
+            result.unwrap();
+        }
+        ctx.compile_main_to_memory(&["unit0".to_string(), "unit1".to_string()])
+            .unwrap();
+        let main = ctx.get_module().print_to_string().to_string();
+
+        // main runs the constructors of all units before the code of any
+        let calls: Vec<_> = ["@unit0_constants()", "@unit1_constants()", "@unit0()", "@unit1()"]
+            .iter()
+            .map(|call| {
+                main.lines()
+                    .position(|line| line.contains("call") && line.contains(call))
+                    .expect(call)
+            })
+            .collect();
+        assert!(calls.windows(2).all(|pair| pair[0] < pair[1]), "{}", main);
+    });
+}
+
+#[test]
+fn test_arithmetic_int_fast_path_matches_mixed_input() {
+    let cases = [
+        ("(+ 1 2 3)", "6"),
+        ("(+ 1 2 0.5 3)", "6.5"),
+        ("(- 10 1 0.5 2)", "6.5"),
+        ("(- 0.5 1 2)", "-2.5"),
+        ("(* 2 3 0.5)", "3.0"),
+        ("(+ 9223372036854775807 1 -1)", "9223372036854775807"),
+        ("(- -9223372036854775807 2 -2)", "-9223372036854775807"),
+        ("(let ((x 1)) (set! x 2) (+ 1 x 3))", "6"),
+        ("(apply (symf +) (list 1 2 0.5))", "3.5"),
+        ("(apply (symf -) (list 1 0.5 1))", "-0.5"),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+}
+
+#[test]
+fn test_bignum_factorial() {
+    assert_eq!(
+        eval_last(
+            "(defun fact (n) (if (equal n 0) 1 (* n (fact (- n 1)))))
+             (fact 30)"
+        ),
+        Ok("265252859812191058636308480000000".to_string())
+    );
+}
+
+#[test]
+fn test_bignum_demotion() {
+    assert_eq!(
+        eval_last("(- (+ 9223372036854775807 10) 10)"),
+        Ok("9223372036854775807".to_string())
+    );
+    // a bignum is never equal to a fixnum, so this only holds after demotion
+    assert_eq!(
+        eval_last(
+            "(equal (- (* 4611686018427387904 4) 9223372036854775809)
+                    9223372036854775807)"
+        ),
+        Ok("9223372036854775807".to_string())
+    );
+    assert_eq!(
+        eval_last("(- -9223372036854775808 1)"),
+        Ok("-9223372036854775809".to_string())
+    );
+}
+
+#[test]
+fn test_large_counts_are_bignums() {
+    use unlisp_rt::defs::Object;
+
+    let largest_fixnum = i64::max_value() as u64;
+
+    assert_eq!(
+        Object::from_count(largest_fixnum).to_string(),
+        "9223372036854775807"
+    );
+    assert_eq!(
+        Object::from_count(largest_fixnum + 1).to_string(),
+        "9223372036854775808"
+    );
+    assert_eq!(
+        Object::from_count(u64::max_value()).to_string(),
+        "18446744073709551615"
+    );
+    // the stdlib's length counts with +, which promotes past the largest fixnum
+    assert_eq!(
+        eval_last("(+ 9223372036854775807 (length (list 1 2)))"),
+        Ok("9223372036854775809".to_string())
+    );
+}
+
+#[test]
+fn test_bignum_literals_and_comparison() {
+    assert_eq!(
+        eval_last("(list 99999999999999999999 (quote (-18446744073709551616)))"),
+        Ok("(99999999999999999999 (-18446744073709551616))".to_string())
+    );
+    assert_eq!(
+        eval_last(
+            "(list (< 9223372036854775807 9223372036854775808)
+                   (> -99999999999999999999 -99999999999999999998)
+                   (< 1.5 99999999999999999999)
+                   (equal 99999999999999999999 99999999999999999999))"
+        ),
+        Ok("(t nil t 99999999999999999999)".to_string())
+    );
+    assert_eq!(
+        eval_last("(format \"~d ~a\" 99999999999999999999 -99999999999999999999)"),
+        Ok("\"99999999999999999999 -99999999999999999999\"".to_string())
+    );
+}
+
+#[test]
+fn test_bignum_string_round_trip() {
+    let cases = [
+        ("99999999999999999999", "10"),
+        ("-18446744073709551616", "10"),
+        ("9223372036854775808", "16"),
+        ("-9223372036854775809", "2"),
+        ("265252859812191058636308480000000", "36"),
+    ];
+
+    for (n, radix) in cases.iter() {
+        let src = format!(
+            "(list (number->string {0} {1}) (string->number (number->string {0} {1}) {1}))",
+            n, radix
+        );
+        let printed = eval_last(&format!("(number->string {} {})", n, radix)).unwrap();
+        assert_eq!(
+            eval_last(&src),
+            Ok(format!("({} {})", printed, n)),
+            "{}",
+            src
+        );
+    }
+
+    // past the fixnum range and back, through the reader and the printer
+    assert_eq!(
+        eval_last(
+            "(list (string->number (number->string (* 9223372036854775807 2)))
+                   (string->number (number->string (- 9223372036854775808 1))))"
+        ),
+        Ok("(18446744073709551614 9223372036854775807)".to_string())
+    );
+}
+
+#[test]
+fn test_bignums_are_integers_in_messages() {
+    assert_eq!(
+        eval_last("(defun f ((x float)) x) (f 99999999999999999999)"),
+        Err("runtime error: argument x of f: expected float, got integer".to_string())
+    );
+    assert_eq!(
+        eval_last("(coerce 99999999999999999999 (quote char))"),
+        Err("runtime error: coerce: cannot coerce integer to char".to_string())
+    );
+}
+
+#[test]
+fn test_lexical_call_heads() {
+    assert_eq!(
+        eval_last("(funcall (lambda (f) (f 1 2)) (symf +))"),
+        Ok("3".to_string())
+    );
+    assert_eq!(
+        eval_last(
+            "(defun compose (f g) (lambda (x) (f (g x))))
+             (funcall (compose (lambda (x) (* x 2)) (lambda (x) (+ x 1))) 5)"
+        ),
+        Ok("12".to_string())
+    );
+    assert_eq!(
+        eval_last("(funcall (lambda (& fs) (let ((f (first fs))) (f 3))) (lambda (x) x))"),
+        Ok("3".to_string())
+    );
+}
+
+#[test]
+fn test_lexical_call_head_shadows_global() {
+    assert_eq!(
+        eval_last(
+            "(defun call-with-list (list) (list 1 2))
+             (list (call-with-list (symf +)) (list 1 2))"
+        ),
+        Ok("(3 (1 2))".to_string())
+    );
+    // macros are shadowed too
+    assert_eq!(
+        eval_last("(let ((when (lambda (x y) y))) (when nil 1))"),
+        Ok("1".to_string())
+    );
+}
+
+#[test]
+fn test_lexical_call_errors() {
+    assert_eq!(
+        eval_last("(funcall (lambda adder (a b) a) 1)"),
+        Err("runtime error: wrong number of arguments (1) passed to adder".to_string())
+    );
+    assert_eq!(
+        eval_last("(let ((f (lambda (a) a))) (f 1 2))"),
+        Err("runtime error: wrong number of arguments (2) passed to lambda".to_string())
+    );
+    assert_eq!(
+        eval_last("(let ((f 1)) (f))"),
+        Err("runtime error: cannot cast int to function".to_string())
+    );
+}
+
+#[test]
+fn test_setf_built_in_places() {
+    let cases = [
+        ("(let ((xs (list 1 2))) (setf (first xs) 10) xs)", "(10 2)"),
+        ("(let ((xs (list 1 2))) (setf (rest xs) (list 3)) xs)", "(1 3)"),
+        ("(defvar setf-v 1) (setf (symbol-value (quote setf-v)) 2) setf-v", "2"),
+        (
+            "(setf (symbol-function (quote setf-f)) (lambda (x) (+ x 1))) (setf-f 1)",
+            "2",
+        ),
+        ("(let ((x 1)) (setf x 2) x)", "2"),
+        ("(setf (first (list 1)) 5)", "5"),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+}
+
+#[test]
+fn test_setf_evaluates_subforms_once_in_order() {
+    assert_eq!(
+        eval_last(
+            "(let ((log ()) (xs (list 1 2)))
+               (setf (first (prog1 xs (set! log (cons 1 log))))
+                     (prog1 3 (set! log (cons 2 log))))
+               (list xs log))"
+        ),
+        Ok("((3 2) (2 1))".to_string())
+    );
+}
+
+#[test]
+fn test_setf_user_defined_place() {
+    assert_eq!(
+        eval_last(
+            "(defun my-second (x) (first (rest x)))
+             (defun set-my-second! (x v) (set-first! (rest x) v))
+             (defsetf my-second set-my-second!)
+             (let ((xs (list 1 2))) (setf (my-second xs) 5) xs)"
+        ),
+        Ok("(1 5)".to_string())
+    );
+    assert_eq!(
+        eval_last("(setf (no-such-place 1) 2)"),
+        Err("macroexpansion error: setf: no setter defined for no-such-place".to_string())
+    );
+}
+
+#[test]
+fn test_defstruct() {
+    let src = "(defstruct point x y)
+               (defstruct size w h)
+               (defvar p (make-point 1 2))
+               (set-point-x! p 10)
+               (setf (point-y p) (+ (point-y p) 1))
+               (list p (point-x p) (point-y p)
+                     (point-p p) (point-p (make-size 1 2)) (point-p (list 1 2)) (point-p nil)
+                     (record-type p))";
+
+    assert_eq!(
+        eval_last(src),
+        Ok("(#<point 10 3> 10 3 t nil nil nil point)".to_string())
+    );
+    assert_eq!(
+        eval_last("(defstruct point x y) (point-x (list 1 2))"),
+        Err("runtime error: expected a point, got (1 2)".to_string())
+    );
+    assert_eq!(
+        eval_last("(defstruct point x y) (defstruct size w h) (point-x (make-size 1 2))"),
+        Err("runtime error: expected a point, got #<size 1 2>".to_string())
+    );
+}
+
+#[test]
+fn test_print_hooks() {
+    let src = "(defstruct point x y)
+               (defstruct line from to)
+               (defmethod-print point
+                 (lambda (p) (format nil \"#<point ~a,~a>\" (point-x p) (point-y p))))";
+
+    assert_eq!(
+        eval_last(&format!(
+            "{} (list (make-point 1 2) (make-line (make-point 3 4) nil))",
+            src
+        )),
+        Ok("(#<point 1,2> #<line #<point 3,4> nil>)".to_string())
+    );
+    assert_eq!(
+        eval_last(&format!("{} (format nil \"~s\" (make-point 1 2))", src)),
+        Ok("\"#<point 1,2>\"".to_string())
+    );
+    assert_eq!(
+        eval_last(&format!(
+            "{} (defmethod-print point nil) (make-point 1 2)",
+            src
+        )),
+        Ok("#<point 1 2>".to_string())
+    );
+}
+
+#[test]
+fn test_print_hook_errors() {
+    let print_with_hook = |hook: &str| {
+        eval_last(&format!(
+            "(defstruct point x y) (defmethod-print point {}) (list (make-point 1 2) 3)",
+            hook
+        ))
+    };
+
+    assert_eq!(
+        print_with_hook("(lambda (p) (error \"boom ~a\" (point-x p)))"),
+        Ok("(#<print-error for point: boom 1> 3)".to_string())
+    );
+    assert_eq!(
+        print_with_hook("(lambda (p) (point-x p))"),
+        Ok("(#<print-error for point: print hook returned 1, not a string> 3)".to_string())
+    );
+    assert_eq!(
+        print_with_hook("(lambda () \"x\")"),
+        Ok(
+            "(#<print-error for point: wrong number of arguments (1) passed to lambda> 3)"
+                .to_string()
+        )
+    );
+}
+
+#[test]
+fn test_print_hook_recursion_is_limited() {
+    let depth = unlisp_rt::print_hooks::MAX_HOOK_DEPTH;
+
+    assert_eq!(
+        eval_last(
+            "(defstruct point x y)
+             (defmethod-print point (lambda (p) (format nil \"[~a]\" p)))
+             (make-point 1 2)"
+        ),
+        Ok(format!(
+            "{}#<point 1 2>{}",
+            "[".repeat(depth),
+            "]".repeat(depth)
+        ))
+    );
+}
+
+#[test]
+fn test_print_hook_exiting_to_a_block_restores_the_depth() {
+    let depth = unlisp_rt::print_hooks::MAX_HOOK_DEPTH;
+
+    assert_eq!(
+        eval_last(&format!(
+            "(defstruct point x y)
+             (defun escape-print ()
+               (block escape
+                 (defmethod-print point (lambda (p) (return-from escape 0)))
+                 (format nil \"~s\" (make-point 1 2))))
+             (mapcar (lambda (i) (escape-print)) (range {}))
+             (defmethod-print point (lambda (p) \"#<pt>\"))
+             (make-point 1 2)",
+            depth + 1
+        )),
+        Ok("#<pt>".to_string())
+    );
+}
+
+#[test]
+fn test_char_and_string_comparisons() {
+    let cases = [
+        ("(char< #\\a #\\b)", "t"),
+        ("(char> #\\a #\\b)", "nil"),
+        ("(char= #\\a #\\a)", "t"),
+        ("(string< \"abc\" \"abd\")", "t"),
+        ("(string< \"ab\" \"abc\")", "t"),
+        ("(string> \"abc\" \"abd\")", "nil"),
+        ("(string= \"abc\" \"abc\")", "t"),
+        ("(string= \"abc\" \"ab\")", "nil"),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+
+    assert_eq!(
+        eval_last("(char< 1 #\\a)"),
+        Err("runtime error: cannot cast int to char".to_string())
+    );
+}
+
+#[test]
+fn test_char_literals_print_readably() {
+    assert_eq!(
+        eval_last("(list #\\a #\\space (quote (#\\( #\\newline)))"),
+        Ok("(#\\a #\\space (#\\( #\\newline))".to_string())
+    );
+    assert_eq!(
+        eval_last("(format \"~a~s\" #\\a #\\b)"),
+        Ok("\"a#\\b\"".to_string())
+    );
+}
+
+#[test]
+fn test_split_string_and_join() {
+    let cases = [
+        ("(split-string \"a,b,,c\" \",\")", "(\"a\" \"b\" \"\" \"c\")"),
+        ("(split-string \"a::b\" \"::\")", "(\"a\" \"b\")"),
+        ("(split-string \"abc\" \"\")", "(\"a\" \"b\" \"c\")"),
+        ("(split-string \"\" \",\")", "(\"\")"),
+        ("(split-string \"\" \"\")", "(\"\")"),
+        ("(join (list \"a\" \"b\" \"c\") \", \")", "\"a, b, c\""),
+        ("(join nil \",\")", "\"\""),
+        ("(concat \"foo\" \"bar\" \"\")", "\"foobar\""),
+        ("(concat)", "\"\""),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+
+    for s in ["a,b,,c", ",", "", "no separators", ",a,"].iter() {
+        let src = format!("(join (split-string {:?} \",\") \",\")", s);
+        assert_eq!(eval_last(&src), Ok(format!("{:?}", s)), "{}", src);
+    }
+
+    assert_eq!(
+        eval_last("(join (list \"a\" 1) \",\")"),
+        Err("runtime error: cannot cast int to string".to_string())
+    );
+}
+
+#[test]
+fn test_string_case_conversion() {
+    let cases = [
+        ("(string-upcase \"Hello, World 42\")", "\"HELLO, WORLD 42\""),
+        ("(string-downcase \"Hello, World 42\")", "\"hello, world 42\""),
+        ("(string-upcase \"straße\")", "\"STRASSE\""),
+        ("(string-downcase \"ÀÉÎ\")", "\"àéî\""),
+        ("(string-upcase \"\")", "\"\""),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+
+    assert_eq!(
+        eval_last("(let ((s \"MiXeD\")) (string-upcase s) s)"),
+        Ok("\"MiXeD\"".to_string())
+    );
+}
+
+#[test]
+fn test_parse_integer() {
+    let cases = [
+        ("(parse-integer \"42\")", "42"),
+        ("(parse-integer \"  -17 \")", "-17"),
+        ("(parse-integer \"+7\")", "7"),
+        ("(parse-integer \"ff\" :radix 16)", "255"),
+        ("(parse-integer \"-101\" :radix 2)", "-5"),
+        ("(parse-integer \"-9223372036854775808\")", "-9223372036854775808"),
+        ("(apply (symf parse-integer) (list \"z\" :radix 36))", "35"),
+        ("(ignore-errors (parse-integer \"12x\"))", "nil"),
+        (":radix", ":radix"),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+
+    let errors = [
+        (
+            "(parse-integer \"12x4\")",
+            "parse-integer: junk 'x' at position 2 in \"12x4\"",
+        ),
+        (
+            "(parse-integer \"12 3\")",
+            "parse-integer: junk '3' at position 3 in \"12 3\"",
+        ),
+        (
+            "(parse-integer \"9223372036854775808\")",
+            "parse-integer: integer overflow at position 18 in \"9223372036854775808\"",
+        ),
+        ("(parse-integer \" - \")", "parse-integer: no digits in \" - \""),
+        (
+            "(parse-integer \"12\" :radix 1)",
+            "parse-integer: radix must be between 2 and 36, got 1",
+        ),
+        (
+            "(parse-integer \"12\" :base 8)",
+            "parse-integer: unknown option :base",
+        ),
+    ];
+
+    for (src, expected) in errors.iter() {
+        assert_eq!(
+            eval_last(src),
+            Err(format!("runtime error: {}", expected)),
+            "{}",
+            src
+        );
+    }
+}
+
+#[test]
+fn test_number_string_conversions() {
+    let cases = [
+        ("(number->string 42)", "\"42\""),
+        ("(number->string -255 16)", "\"-ff\""),
+        ("(number->string 5 2)", "\"101\""),
+        ("(number->string 99999999999999999999 36)", "\"l3r41ifs0q5tr\""),
+        ("(number->string -0.0)", "\"-0.0\""),
+        ("(number->string 12.0 16)", "\"0x1.8p3\""),
+        ("(number->string 0.1 16)", "\"0x1.999999999999ap-4\""),
+        ("(number->string (- 0.0 (* 1e308 10)) 16)", "\"-inf.0\""),
+        ("(string->number \"42\")", "42"),
+        ("(string->number \"-ff\" 16)", "-255"),
+        ("(string->number \"l3r41ifs0q5tr\" 36)", "99999999999999999999"),
+        ("(string->number \"-0.0\")", "-0.0"),
+        ("(string->number \"0x1.8p3\")", "12.0"),
+        ("(string->number \"-0x1p-1074\")", "-5e-324"),
+        ("(string->number \"+nan.0\")", "+nan.0"),
+        ("(string->number \"1.5\" 16)", "nil"),
+        ("(string->number \"0x1p\")", "nil"),
+        ("(string->number \"abc\")", "nil"),
+        ("(apply (symf number->string) (list 10 2))", "\"1010\""),
+        ("(apply (symf string->number) (list \"z\" 36))", "35"),
+        // read back bit for bit, signed zeros included
+        (
+            "(mapcar (lambda (x) (equal (string->number (number->string x 16)) x))
+                     (list -0.0 0.1 1e-310 5e-324 1.7976931348623157e308))",
+            "(-0.0 0.1 1e-310 5e-324 1.7976931348623157e308)",
+        ),
+        (
+            "(let ((x -0.0))
+               (list (equal (string->number (number->string x)) x)
+                     (equal (string->number (number->string x)) 0.0)))",
+            "(-0.0 nil)",
+        ),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+
+    let errors = [
+        (
+            "(number->string 10 1)",
+            "number->string: radix must be an integer between 2 and 36, got 1",
+        ),
+        (
+            "(number->string 1.5 3)",
+            "number->string: floats are written in radix 10 or 16, not 3",
+        ),
+        (
+            "(number->string \"1\")",
+            "number->string: expected a number, got \"1\"",
+        ),
+        (
+            "(string->number 1)",
+            "string->number: expected a string, got 1",
+        ),
+        (
+            "(string->number \"1\" 10 10)",
+            "string->number: expected at most a radix",
+        ),
+    ];
+
+    for (src, expected) in errors.iter() {
+        assert_eq!(
+            eval_last(src),
+            Err(format!("runtime error: {}", expected)),
+            "{}",
+            src
+        );
+    }
+}
+
+#[test]
+fn test_strings_print_readably() {
+    let cases = [
+        (r#""say \"hi\"""#, r#""say \"hi\"""#),
+        (r#""a\\b""#, r#""a\\b""#),
+        ("\"two\nlines\ttab\"", r#""two\nlines\ttab""#),
+        (r#""\x01ß""#, r#""\x01ß""#),
+        (r#"(format "~a|~s" "q\"" "q\"")"#, r#""q\"|\"q\\\"\"""#),
+    ];
+
+    for (src, expected) in cases.iter() {
+        let printed = eval_last(src);
+        assert_eq!(printed, Ok(expected.to_string()), "{}", src);
+
+        // printing again what was read from the printed string gives the
+        // same text
+        let reprinted = eval_last(&format!("(quote {})", expected));
+        assert_eq!(reprinted, Ok(expected.to_string()), "{}", src);
+    }
+}
+
+#[test]
+fn test_random_is_reproducible_after_srand() {
+    let src = "(srand 42) (mapcar (lambda (x) (random 1000)) (range 20))";
+
+    let first = eval_last(src);
+    let second = eval_last(src);
+    assert!(first.is_ok());
+    assert_eq!(first, second);
+
+    let other_seed = eval_last("(srand 43) (mapcar (lambda (x) (random 1000)) (range 20))");
+    assert_ne!(first, other_seed);
+
+    assert_eq!(
+        eval_last("(srand 1) (if (every? (lambda (x) (equal (random 1) 0)) (range 100)) 1 0)"),
+        Ok("1".to_string())
+    );
+    assert_eq!(
+        eval_last(
+            "(srand 1) (if (every? (lambda (x) (let ((r (random 3))) (and (< -1 r) (< r 3)))) (range 100)) 1 0)"
+        ),
+        Ok("1".to_string())
+    );
+
+    for bound in ["0", "-5"].iter() {
+        assert_eq!(
+            eval_last(&format!("(random {})", bound)),
+            Err(format!(
+                "runtime error: random: bound must be positive, got {}",
+                bound
+            ))
+        );
+    }
+}
+
+#[test]
+fn test_all_symbols_are_sorted_by_name() {
+    assert_eq!(
+        eval_last(
+            "(quote (sorted-c sorted-a sorted-b sorted-a10 sorted-a2)) (all-symbols \"sorted-\")"
+        ),
+        Ok("(sorted-a sorted-a10 sorted-a2 sorted-b sorted-c)".to_string())
+    );
+    assert_eq!(
+        eval_last("(all-symbols \"no-such-prefix-\")"),
+        Ok("nil".to_string())
+    );
+    assert_eq!(
+        eval_last("(quote unsorted-x) (apply (symf all-symbols) (list \"unsorted-\"))"),
+        Ok("(unsorted-x)".to_string())
+    );
+    assert_eq!(
+        eval_last("(all-symbols \"a\" \"b\")"),
+        Err("runtime error: all-symbols: expected at most one prefix".to_string())
+    );
+}
+
+fn compile_to_ir(ctx: &mut unlispc::codegen::context::CodegenContext, src: &str) -> String {
+    let mut input = src.as_bytes();
+    let form = unlispc::reader::Reader::create(&mut input)
+        .read_form()
+        .unwrap()
+        .unwrap();
+    let hir = unlispc::repr::form_to_hir_with_transforms(&form).unwrap();
+
+    ctx.compile_hirs(&[hir]).unwrap();
+    ctx.get_module().print_to_string().to_string()
+}
+
+// the IR of the function compiled for the top-level form, without the
+// functions it defines or the module's constructor
+fn top_level_ir(ir: &str) -> &str {
+    let start = ir.find("@__repl_form").expect("no top-level function");
+    let end = ir[start..].find("\n}\n").map_or(ir.len(), |end| start + end);
+
+    &ir[start..end]
+}
+
+#[test]
+fn test_constant_list_constructors_are_folded() {
+    let src = "(reverse (append (list 1 \"two\" (quote three)) (cons 4 (cons 5 nil))))";
+
+    with_stdlib_ctx(|ctx| {
+        let ir = compile_to_ir(ctx, src);
+        assert!(!ir.contains("invoke_result"), "{}", ir);
+
+        // the module's constructor builds the folded list, the code only
+        // copies it
+        ctx.reinitialize();
+        let ir = compile_to_ir(ctx, src);
+        let code = top_level_ir(&ir);
+        assert!(code.contains("@unlisp_rt_copy_constant"), "{}", code);
+        assert!(!code.contains("@unlisp_rt_list_cons"), "{}", code);
+        assert!(!code.contains("@unlisp_rt_intern_sym"), "{}", code);
+        assert!(ir.contains("@unlisp_rt_list_cons"), "{}", ir);
+
+        // a variable argument isn't constant
+        let ir = compile_to_ir(ctx, "(lambda (x) (list 1 x))");
+        assert!(ir.contains("invoke_result"));
+
+        // neither a lexical function nor a redefined one is folded
+        let ir = compile_to_ir(ctx, "(let ((list (lambda (& xs) 0))) (list 1 2))");
+        assert!(ir.contains("invoke_result"));
+
+        for result in eval_in_ctx(ctx, "(defun reverse (x) x)") {
+            result.unwrap();
+        }
+        let ir = compile_to_ir(ctx, "(reverse (list 1 2))");
+        assert!(ir.contains("invoke_result"));
+        assert_eq!(
+            eval_in_ctx(ctx, "(reverse (list 1 2))"),
+            vec![Ok("(1 2)".to_string())]
+        );
+
+        // integer and float arithmetic
+        ctx.reinitialize();
+        let ir = compile_to_ir(ctx, "(+ 1 2)");
+        assert!(!ir.contains("invoke_result"), "{}", ir);
+        assert!(
+            !ir.lines()
+                .any(|l| l.contains("call") && l.contains("@unlisp_rt_object_from_int")),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("i64 3"), "{}", ir);
+
+        // results that overflow are left to the runtime
+        let ir = compile_to_ir(ctx, "(* 9223372036854775807 2)");
+        assert!(ir.contains("invoke_result"));
+
+        let ir = compile_to_ir(ctx, "(lambda (x) (+ 1 x))");
+        assert!(ir.contains("invoke_result"));
+
+        let ir = compile_to_ir(ctx, "(* 0.5 (- 0.0 1))");
+        assert!(!ir.contains("invoke_result"), "{}", ir);
+
+        // NaNs are left to the runtime
+        let ir = compile_to_ir(ctx, "(- +inf.0 +inf.0)");
+        assert!(ir.contains("invoke_result"));
+    });
+
+    let cases = [
+        (src, "(5 4 three \"two\" 1)"),
+        ("(cons 1 2)", "(1 . 2)"),
+        ("(cons 1 (quote (2 . 3)))", "(1 2 . 3)"),
+        ("(append nil 5)", "5"),
+        ("(append (list 1) (cons 2 3))", "(1 2 . 3)"),
+        ("(list)", "nil"),
+        ("(cons 1)", "runtime error: wrong number of arguments (1) passed to cons"),
+        ("(defun fresh () (list 1 2)) (set-first! (fresh) 10) (fresh)", "(1 2)"),
+        ("(defun fresh () (list (list 1) 2)) (set-first! (first (fresh)) 10) (fresh)", "((1) 2)"),
+        ("(+ 1 2)", "3"),
+        ("(- 10 1 2)", "7"),
+        ("(- 5)", "5"),
+        ("(*)", "1"),
+        ("(* 2 (+ 3 4))", "14"),
+        ("(* 9223372036854775807 2)", "18446744073709551614"),
+        ("(- -9223372036854775808 1)", "-9223372036854775809"),
+        ("(< 1 2)", "t"),
+        ("(> 1 2)", "nil"),
+        ("(equal 3 3)", "3"),
+        ("(equal 3 4)", "nil"),
+        ("(+ 1 (quote 2))", "3"),
+        ("(+ 1 0.5)", "1.5"),
+        ("(- 0.0)", "0.0"),
+        ("(+ -0.0)", "0.0"),
+        ("(* -1 0.0)", "-0.0"),
+        ("(+ 9007199254740993 1 0.5)", "9007199254740994.0"),
+        ("(< 9007199254740993 9007199254740992.0)", "nil"),
+        ("(equal 0.0 -0.0)", "nil"),
+        ("(equal 1 1.0)", "nil"),
+        ("(equal -0.0 -0.0)", "-0.0"),
+        ("(< +nan.0 1)", "nil"),
+        ("(< 1)", "runtime error: wrong number of arguments (1) passed to <"),
+        ("(defun + (& xs) 0) (+ 1 2)", "0"),
+    ];
+
+    for (src, expected) in cases.iter() {
+        let folded = eval_last(src);
+
+        unlispc::fold::set_constant_folding(false);
+        let not_folded = eval_last(src);
+        unlispc::fold::set_constant_folding(true);
+
+        let folded = folded.unwrap_or_else(|e| e);
+        assert_eq!(folded, expected.to_string(), "{}", src);
+        assert_eq!(not_folded.unwrap_or_else(|e| e), folded, "{}", src);
+    }
+}
+
+#[test]
+fn test_constant_if_compiles_only_the_taken_branch() {
+    let cases = [
+        ("(if t 1 undefined-thing)", "1"),
+        ("(if nil undefined-thing 2)", "2"),
+        ("(if nil (undefined-fn))", "nil"),
+        ("(if (quote ()) undefined-thing 3)", "3"),
+        ("(if (quote x) 4 undefined-thing)", "4"),
+        ("(if \"\" 5 undefined-thing)", "5"),
+        ("(if (< 2 1) (undefined-fn) 6)", "6"),
+        ("((lambda (x) (if t x undefined-thing)) 7)", "7"),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+
+    assert!(eval_last("(if (car (list t)) 1 undefined-thing)")
+        .unwrap_err()
+        .contains("undefined symbol: undefined-thing"));
+
+    with_stdlib_ctx(|ctx| {
+        let ir = compile_to_ir(ctx, "(if t 1 2)");
+        assert!(!ir.contains("is_nil"), "{}", ir);
+    });
+
+    unlispc::fold::set_constant_folding(false);
+    let not_folded = eval_last("(if t 1 undefined-thing)");
+    unlispc::fold::set_constant_folding(true);
+    assert!(not_folded
+        .unwrap_err()
+        .contains("undefined symbol: undefined-thing"));
+}
+
+#[test]
+fn test_functions_declared_inline_are_inlined() {
+    let cases = [
+        ("(defun bump (x) (declare (inline)) (+ x 1)) (bump 41)", "42"),
+        (
+            "(defvar y 10) (defun add-y (x) (declare (inline)) (+ x y)) (let ((y 1)) (add-y 1))",
+            "11",
+        ),
+        (
+            "(defun sub (a b) (declare (inline)) (- a b)) (let ((a 5) (b 3)) (sub b a))",
+            "-2",
+        ),
+        (
+            "(defun twice (x) (declare (inline)) (* x 2))
+             (defun quad (x) (declare (inline)) (twice (twice x)))
+             (quad 3)",
+            "12",
+        ),
+        (
+            "(defun call-with-one (f) (declare (inline)) (f 1))
+             (call-with-one (lambda (x) (+ x 41)))",
+            "42",
+        ),
+        // calls of functions that aren't declared inline see redefinitions
+        (
+            "(defun bump (x) (+ x 1))
+             (defun user (x) (bump x))
+             (defun bump (x) (+ x 100))
+             (list (user 1) (bump 1))",
+            "(101 101)",
+        ),
+        // callers compiled before a redefinition keep the inlined body
+        (
+            "(defun bump (x) (declare (inline)) (+ x 1))
+             (defun user (x) (bump x))
+             (defun bump (x) (+ x 100))
+             (list (user 1) (bump 1))",
+            "(2 101)",
+        ),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+
+    assert_eq!(
+        eval_last("(defun bump (x) (declare (inline)) (+ x 1)) (bump 1 2)"),
+        Err("runtime error: wrong number of arguments (2) passed to bump".to_string())
+    );
+
+    with_stdlib_ctx(|ctx| {
+        let src = "(defun bump-by-one (x) (declare (inline)) (+ x 1))
+                   (defun add-one (x) (+ x 1))";
+        for result in eval_in_ctx(ctx, src) {
+            result.unwrap();
+        }
+
+        ctx.reinitialize();
+        let ir = compile_to_ir(ctx, "(lambda (y) (bump-by-one y))");
+        assert!(!ir.contains("bump-by-one"), "{}", ir);
+
+        ctx.reinitialize();
+        let ir = compile_to_ir(ctx, "(lambda (y) (add-one y))");
+        assert!(ir.contains("add-one"), "{}", ir);
+
+        unlispc::inline::set_inline_threshold(0);
+        ctx.reinitialize();
+        let ir = compile_to_ir(ctx, "(lambda (y) (bump-by-one y))");
+        unlispc::inline::set_inline_threshold(unlispc::inline::DEFAULT_INLINE_THRESHOLD);
+        assert!(ir.contains("bump-by-one"), "{}", ir);
+    });
+}
+
+#[test]
+fn test_declarations() {
+    let cases = [
+        ("(defun b (x) (declare (inline)) (+ x 1)) (b 41)", "42"),
+        ("(defun k (x) (declare (notinline)) (+ x 1)) (k 41)", "42"),
+        ("(defun slow () (declare (optimize 0)) (+ 1 2)) (slow)", "3"),
+        ("(funcall (lambda (x) (declare) x) 1)", "1"),
+        ("(defun e (x) (declare (inline)) (return x) 2) (e 1)", "1"),
+        (
+            "(defun count-down (n) (declare (inline)) (if (< n 1) 0 (count-down (- n 1))))
+             (count-down 3)",
+            "0",
+        ),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+
+    assert_eq!(
+        eval_last("(lambda () (declare (optimize fast)) 1)"),
+        Err("reader error: optimize declaration takes a level, 0 or more".to_string())
+    );
+    assert_eq!(
+        eval_last("(lambda () (declare 1) 1)"),
+        Err("reader error: declare option is not a list".to_string())
+    );
+
+    with_stdlib_ctx(|ctx| {
+        let src = "(defun small (x) (+ x 1))
+                   (defun big (x) (+ x (* x 2) (* x 3) (* x 4) (* x 5)))
+                   (defun big-inline (x)
+                     (declare (inline))
+                     (+ x (* x 2) (* x 3) (* x 4) (* x 5)))
+                   (defun small-notinline (x) (declare (notinline)) (+ x 1))
+                   (defun loops (n) (declare (inline)) (if (< n 1) 0 (loops (- n 1))))
+                   (defun odd-option () (declare (fast) (inline)) 1)
+                   (defun bare-option () (declare inline) 1)";
+        ctx.take_warnings();
+        for result in eval_in_ctx(ctx, src) {
+            result.unwrap();
+        }
+
+        assert_eq!(
+            ctx.take_warnings(),
+            vec![
+                "loops is declared inline but isn't inlined: it calls itself".to_string(),
+                "odd-option: unknown declare option fast, ignored".to_string(),
+                "bare-option: declare option inline isn't a list, ignored (write (inline))"
+                    .to_string(),
+            ]
+        );
+
+        for (f, inlined) in &[
+            ("small", false),
+            ("big", false),
+            ("big-inline", true),
+            ("small-notinline", false),
+            ("loops", false),
+        ] {
+            ctx.reinitialize();
+            let ir = compile_to_ir(ctx, &format!("(lambda (y) ({} y))", f));
+            let name = format!("c\"{}\\00\"", f);
+            assert_eq!(!ir.contains(&name), *inlined, "{}: {}", f, ir);
+        }
+
+        // the body of a function declared (optimize 0) keeps its calls
+        ctx.reinitialize();
+        let ir = compile_to_ir(ctx, "(lambda () (+ 1 2))");
+        assert!(!ir.contains("invoke_result"), "{}", ir);
+
+        ctx.reinitialize();
+        let ir = compile_to_ir(ctx, "(lambda () (declare (optimize 0)) (+ 1 2))");
+        assert!(ir.contains("invoke_result"), "{}", ir);
+
+        ctx.reinitialize();
+        let ir = compile_to_ir(ctx, "(lambda (y) (declare (optimize 0)) (small y))");
+        assert!(ir.contains("c\"small\\00\""), "{}", ir);
+
+        // nested lambdas too, unless they declare otherwise
+        ctx.reinitialize();
+        let ir = compile_to_ir(
+            ctx,
+            "(lambda () (declare (optimize 0)) (lambda () (declare (optimize 1)) (+ 1 2)))",
+        );
+        assert!(!ir.contains("invoke_result"), "{}", ir);
+    });
+}
+
+#[test]
+fn test_compiler_macros_rewrite_matching_calls() {
+    with_stdlib_ctx(|ctx| {
+        let src = "(defun member (x xs)
+                     (if xs (if (equal x (first xs)) xs (member x (rest xs))) nil))
+                   (defun member-chain (tail)
+                     (if tail
+                         (qquote (if (equal --item (quote (unq (first tail))))
+                                     (quote (unq tail))
+                                     (unq (member-chain (rest tail)))))
+                         nil))
+                   (define-compiler-macro member (item xs)
+                     (if (and (listp xs) (equal (first xs) (quote quote)))
+                         (qquote (let ((--item (unq item)))
+                                   (unq (member-chain (first (rest xs))))))
+                         (qquote (member (unq item) (unq xs)))))";
+        for result in eval_in_ctx(ctx, src) {
+            result.unwrap();
+        }
+
+        let calls_member = |ctx: &mut CodegenContext, src: &str| {
+            ctx.reinitialize();
+            compile_to_ir(ctx, src).contains("c\"member\\00\"")
+        };
+
+        // a quoted list is turned into comparisons, anything else is
+        // declined, and calls through the function object aren't rewritten
+        let quoted_list = "(lambda (x) (member x (quote (a b c))))";
+        assert!(!calls_member(ctx, quoted_list));
+        assert!(calls_member(ctx, "(lambda (x ys) (member x ys))"));
+        assert!(calls_member(
+            ctx,
+            "(lambda (x) (funcall (symf member) x (quote (a b c))))"
+        ));
+
+        let src = "(member (quote b) (quote (a b c)))
+                   (member (quote d) (quote (a b c)))
+                   (funcall (symf member) (quote b) (quote (a b c)))
+                   (apply (symf member) (list (quote c) (quote (a b c))))";
+        assert_eq!(
+            eval_in_ctx(ctx, src),
+            vec![
+                Ok("(b c)".to_string()),
+                Ok("nil".to_string()),
+                Ok("(b c)".to_string()),
+                Ok("(c)".to_string()),
+            ]
+        );
+
+        // a function declared (notinline) keeps its calls
+        let src = "(defun member (x xs)
+                     (declare (notinline))
+                     (if xs (if (equal x (first xs)) xs (member x (rest xs))) nil))";
+        for result in eval_in_ctx(ctx, src) {
+            result.unwrap();
+        }
+        assert!(calls_member(ctx, quoted_list));
+    });
+}
+
+#[test]
+fn test_debug_natives() {
+    let src = "(defvar xs (list 1 2 3))
+               (defvar cycle (list 1 2))
+               (set-rest! (rest cycle) cycle)
+               (list (shared-structure? xs (rest xs))
+                     (shared-structure? (rest xs) xs)
+                     (shared-structure? xs (copy-list xs))
+                     (shared-structure? (list 1) (list 1))
+                     (shared-structure? cycle (rest cycle))
+                     (list-cells xs)
+                     (list-cells (cons xs xs))
+                     (list-cells (list xs (copy-list xs)))
+                     (list-cells cycle)
+                     (list-cells nil))";
+
+    let results = with_stdlib_ctx(|ctx| {
+        unlisp_rt::debug::init();
+        eval_in_ctx(ctx, src)
+    });
+
+    assert_eq!(
+        results.last().unwrap(),
+        &Ok("(t t nil nil t 3 4 8 2 0)".to_string())
+    );
+}
+
+#[test]
+fn test_print_shared() {
+    let src = "(defun share-deeply (x n)
+                 (if (equal n 0) x (share-deeply (list x x) (- n 1))))
+               (format nil \"~s\" (share-deeply (list 1 2) 2))
+               (with-print-shared (format nil \"~s\" (share-deeply (list 1 2) 2)))
+               (defvar tree (share-deeply (list 1 2) 40))
+               (defvar printed (with-print-shared (format nil \"~s\" tree)))
+               printed
+               (defvar reread (read printed))
+               (list (list-cells tree)
+                     (list-cells reread)
+                     (shared-structure? (first reread) (second reread))
+                     (equal (with-print-shared (format nil \"~s\" reread)) printed))
+               (defvar cycle (list 1 2))
+               (let () (set-rest! (rest cycle) cycle) nil)
+               (with-print-shared (format nil \"~s\" cycle))
+               (with-print-shared (format nil \"~s\" (read \"#1=(1 2 . #1#)\")))
+               (with-print-shared (format nil \"~s\" (read \"(#1=(a) #1# (#1#))\")))
+               (format nil \"~s\" (read \"(#1=(a) #1# (#1#))\"))";
+
+    let results = with_stdlib_ctx(|ctx| {
+        unlisp_rt::debug::init();
+        eval_in_ctx(ctx, src)
+    });
+
+    assert_eq!(
+        results[1],
+        Ok("\"(((1 2) (1 2)) ((1 2) (1 2)))\"".to_string())
+    );
+    assert_eq!(results[2], Ok("\"(#1=(#2=(1 2) #2#) #1#)\"".to_string()));
+
+    // printed expanded, the tree would take terabytes
+    let printed = results[5].as_ref().unwrap();
+    assert!(printed.len() < 1000, "{}", printed);
+    assert!(printed.starts_with("\"(#1=(#2=(#3=("), "{}", printed);
+
+    assert_eq!(results[7], Ok("(82 82 t t)".to_string()));
+    assert_eq!(results[10], Ok("\"#1=(1 2 . #1#)\"".to_string()));
+    assert_eq!(results[11], results[10]);
+    assert_eq!(results[12], Ok("\"(#1=(a) #1# (#1#))\"".to_string()));
+    assert_eq!(results[13], Ok("\"((a) (a) ((a)))\"".to_string()));
+}
+
+#[test]
+fn test_labels_are_only_data() {
+    for src in ["#1=(list 1)", "(quote #1=(a . #1#))"].iter() {
+        assert_eq!(
+            eval_last(src),
+            Err("compilation error: #n= and #n# labels can only be read as data".to_string()),
+            "{}",
+            src
+        );
+    }
+
+    let cases = [
+        ("(quote (#1#))", "reader error: undefined label #1#"),
+        ("(#1=a #1=b)", "reader error: label #1= defined twice"),
+        ("(quote (a #1=))", "reader error: nothing after label"),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Err(expected.to_string()), "{}", src);
+    }
+}
+
+#[test]
+fn test_internal_real_time() {
+    assert_eq!(
+        eval_last("internal-time-units-per-second"),
+        Ok("1000000".to_string())
+    );
+
+    let elapsed = eval_last(
+        "(let ((start (get-internal-real-time)))
+           (sleep 0.05)
+           (- (get-internal-real-time) start))",
+    )
+    .unwrap()
+    .parse::<i64>()
+    .unwrap();
+    // at least the time slept, and less than a minute
+    assert!(elapsed >= 50_000, "{}", elapsed);
+    assert!(elapsed < 60_000_000, "{}", elapsed);
+
+    assert_eq!(
+        eval_last("(let ((x (get-internal-real-time))) (< (get-internal-real-time) x))"),
+        Ok("nil".to_string())
+    );
+    assert_eq!(eval_last("(sleep 0)"), Ok("nil".to_string()));
+    for (seconds, printed) in [
+        ("-1", "-1.0"),
+        ("+nan.0", "+nan.0"),
+        ("+inf.0", "+inf.0"),
+        ("18446744073709551616.0", "1.8446744073709552e19"),
+    ]
+    .iter()
+    {
+        assert_eq!(
+            eval_last(&format!("(sleep {})", seconds)),
+            Err(format!("runtime error: sleep: invalid duration {}", printed))
+        );
+    }
+}
+
+#[test]
+fn test_typep() {
+    let cases = [
+        ("(typep 1 (quote integer))", "t"),
+        ("(typep 100000000000000000000 (quote integer))", "t"),
+        ("(typep 1.5 (quote integer))", "nil"),
+        ("(typep 1.5 (quote float))", "t"),
+        ("(typep 1 (quote float))", "nil"),
+        ("(typep 1 (quote number))", "t"),
+        ("(typep 1.5 (quote number))", "t"),
+        ("(typep \"1\" (quote number))", "nil"),
+        ("(typep #\\a (quote char))", "t"),
+        ("(typep \"a\" (quote char))", "nil"),
+        ("(typep \"a\" (quote string))", "t"),
+        ("(typep (quote a) (quote string))", "nil"),
+        ("(typep (quote a) (quote symbol))", "t"),
+        ("(typep nil (quote symbol))", "t"),
+        ("(typep (symf list) (quote function))", "t"),
+        ("(typep (quote list) (quote function))", "nil"),
+        ("(typep nil (quote list))", "t"),
+        ("(typep (list 1) (quote list))", "t"),
+        ("(typep 1 (quote list))", "nil"),
+        ("(typep nil (quote cons))", "nil"),
+        ("(typep (list 1) (quote cons))", "t"),
+        ("(typep nil (quote null))", "t"),
+        ("(typep (list 1) (quote null))", "nil"),
+        (
+            "(defstruct point x y) (typep (make-point 1 2) (quote record))",
+            "t",
+        ),
+        ("(typep (list 1 2) (quote record))", "nil"),
+        ("(let ((x 1)) (set! x \"s\") (typep x (quote string)))", "t"),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+
+    assert_eq!(
+        eval_last("(typep 1 (quote widget))"),
+        Err("runtime error: typep: unknown type designator widget".to_string())
+    );
+}
+
+#[test]
+fn test_coerce() {
+    let cases = [
+        ("(coerce 2 (quote float))", "2.0"),
+        ("(coerce -3 (quote float))", "-3.0"),
+        ("(coerce 100000000000000000000 (quote float))", "1e20"),
+        ("(coerce 1.5 (quote float))", "1.5"),
+        ("(coerce #\\a (quote integer))", "97"),
+        ("(coerce #\\é (quote number))", "233"),
+        ("(coerce \"hé\" (quote list))", "(#\\h #\\é)"),
+        ("(coerce \"\" (quote list))", "nil"),
+        ("(coerce (list 1 2) (quote list))", "(1 2)"),
+        (
+            "(let ((x 1)) (set! x #\\b) (coerce x (quote integer)))",
+            "98",
+        ),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+
+    assert_eq!(
+        eval_last("(coerce 1.5 (quote integer))"),
+        Err("runtime error: coerce: cannot coerce float to integer".to_string())
+    );
+    assert_eq!(
+        eval_last("(coerce (list 1 2) (quote string))"),
+        Err("runtime error: coerce: cannot coerce cons to string".to_string())
+    );
+    assert_eq!(
+        eval_last("(coerce 1 (quote vector))"),
+        Err("runtime error: coerce: unknown type designator vector".to_string())
+    );
+}
+
+#[test]
+fn test_multiple_values() {
+    let cases = [
+        ("(values 10 20 30)", "10"),
+        ("(values)", "nil"),
+        ("(nth-value 1 (values 10 20 30))", "20"),
+        ("(nth-value 3 (values 10 20 30))", "nil"),
+        ("(nth-value 0 (+ 1 2))", "3"),
+        ("(multiple-value-list (values-list (list 1 2)))", "(1 2)"),
+        ("(multiple-value-list (values (list 1 2)))", "((1 2))"),
+        ("(multiple-value-list (list (values 1 2)))", "((1))"),
+        ("(+ (values 1 2) 10)", "11"),
+        // values end with the call unless it's returned
+        ("(multiple-value-list (let () (values 1 2) 1))", "(1)"),
+        ("(multiple-value-list (let ((x (values 1 2))) x))", "(1)"),
+        (
+            "(defun two-values () (values 1 2)) (multiple-value-list (two-values))",
+            "(1 2)",
+        ),
+        (
+            "(multiple-value-bind (a b) (values-list (list 1 2)) (list b a))",
+            "(2 1)",
+        ),
+        ("(multiple-value-bind (a b) 1 (list a b))", "(1 nil)"),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+}
+
+#[test]
+fn test_last_and_butlast() {
+    let cases = [
+        ("(last nil)", "nil"),
+        ("(last (list 1))", "(1)"),
+        ("(last (list 1 2 3))", "(3)"),
+        ("(last (list 1 2 3) 0)", "nil"),
+        ("(last (list 1 2 3) 2)", "(2 3)"),
+        ("(last (list 1 2 3) 3)", "(1 2 3)"),
+        ("(last (list 1 2 3) 4)", "(1 2 3)"),
+        ("(last nil 2)", "nil"),
+        ("(butlast nil)", "nil"),
+        ("(butlast (list 1))", "nil"),
+        ("(butlast (list 1 2))", "(1)"),
+        ("(butlast (list 1 2 3))", "(1 2)"),
+        // a fresh list, the argument is left as it was
+        (
+            "(let ((xs (list 1 2 3))) (set-first! (butlast xs) 10) xs)",
+            "(1 2 3)",
+        ),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+}
+
+#[test]
+fn test_escaped_symbols() {
+    let cases = [
+        ("(quote |hello world|)", "|hello world|"),
+        ("(quote f\\(o)", "|f(o|"),
+        ("(quote |a||b|)", "|a||b|"),
+        ("(quote (|| |.| |12| |1.5| x))", "(|| |.| |12| |1.5| x)"),
+        ("(equal (quote |foo|) (quote foo))", "t"),
+        ("(equal (quote |foo|) (quote f\\oo))", "t"),
+        ("(quote (|nil| |t| nil t))", "(|nil| |t| nil t)"),
+        ("(equal (quote |nil|) nil)", "nil"),
+        ("(listp (quote |nil|))", "nil"),
+        ("(symbolp (quote |12|))", "t"),
+        ("(format \"~a ~s\" (quote |a b|) (quote |a b|))", "\"a b |a b|\""),
+        ("(let ((|my var| 2)) (+ |my var| 1))", "3"),
+        ("(defmacro always-one () (quote (if t 1 2))) (always-one)", "1"),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+
+    assert_eq!(
+        eval_last("|nil|"),
+        Err("compilation error: undefined symbol: nil".to_string())
+    );
+}
+
+#[test]
+fn test_dump_closures() {
+    let src = "(defvar *greeting* \"hi\")
+               (defun make-counter (start)
+                 (let ((step 1))
+                   (lambda (x)
+                     (lambda () (cons *greeting* (+ start step x))))))
+               (funcall (funcall (make-counter 10) 5))";
+
+    let dumps = Rc::new(RefCell::new(String::new()));
+    let sink = dumps.clone();
+
+    let results = with_stdlib_ctx(|ctx| {
+        ctx.set_closure_dump_hook(Some(Box::new(move |dump: &str| {
+            sink.borrow_mut().push_str(dump)
+        })));
+        eval_in_ctx(ctx, src)
+    });
+
+    assert_eq!(results.last().unwrap(), &Ok("(\"hi\" . 16)".to_string()));
+    assert_eq!(
+        *dumps.borrow(),
+        "closure make-counter
+  arglist: (start)
+  restarg: none
+  free variables:
+    *greeting*: global
+  fields:
+    0-7: function header
+    8: unused
+closure make-counter/lambda
+  arglist: (x)
+  restarg: none
+  free variables:
+    *greeting*: global
+    start: parameter
+    step: let binding
+  fields:
+    0-7: function header
+    8: start
+    9: step
+    10: unused
+closure make-counter/lambda/lambda
+  arglist: ()
+  restarg: none
+  free variables:
+    *greeting*: global
+    start: outer capture
+    step: outer capture
+    x: parameter
+  fields:
+    0-7: function header
+    8: start
+    9: step
+    10: x
+    11: unused
+"
+    );
+}
+
+#[test]
+fn test_read_all() {
+    let cases = [
+        ("(read-all \"1 2 (3 4)\")", "(1 2 (3 4))"),
+        ("(length (read-all \"1 2 (3 4)\"))", "3"),
+        ("(read-all \"\")", "nil"),
+        ("(read-all \" ; just a comment\")", "nil"),
+        ("(read-all \"foo \\\"bar\\\" (a . b) nil\")", "(foo \"bar\" (a . b) nil)"),
+        ("(equal (first (read-all \"foo\")) (quote foo))", "t"),
+        ("(read \"(+ 1 2) )\")", "(+ 1 2)"),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+
+    assert_eq!(
+        eval_last("(read-all \"1 (2\")"),
+        Err("runtime error: read-all: unexpected end of file at position 4".to_string())
+    );
+    assert_eq!(
+        eval_last("(read-all \"1 2) 3\")"),
+        Err("runtime error: read-all: reader error: unbalanced parens at position 4".to_string())
+    );
+    assert_eq!(
+        eval_last("(read \"\")"),
+        Err("runtime error: read: no form in string".to_string())
+    );
+}
+
+#[test]
+fn test_prin1_princ_return_their_argument() {
+    let cases = [
+        (r#"(princ "a\"b")"#, r#""a\"b""#),
+        (r#"(prin1 "a\"b")"#, r#""a\"b""#),
+        ("(print (quote (a |b c|)))", "(a |b c|)"),
+        (
+            r#"(format "~a ~s" (list "x" #\y (quote |z z|)) (list "x" #\y))"#,
+            r#""(x y z z) (\"x\" #\\y)""#,
+        ),
+    ];
+
+    for (src, expected) in cases.iter() {
+        assert_eq!(eval_last(src), Ok(expected.to_string()), "{}", src);
+    }
+}
+
+#[test]
+fn test_set_definition_failed() {
+    let error = Err("runtime error: definition of f failed to compile".to_string());
+
+    assert_eq!(
+        eval_last("(defun f (x) x) (set-definition-failed! (quote f)) (f 1 2)"),
+        error
+    );
+    assert_eq!(
+        eval_last("(set-definition-failed! (quote f)) (apply (symbol-function (quote f)) nil)"),
+        error
+    );
+}
+
+fn numbers(range: std::ops::Range<usize>) -> String {
+    range.map(|i| i.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+#[test]
+fn test_calls_with_many_arguments() {
+    for n in [1, 7, 32, 100, 255].iter() {
+        let n = *n;
+        let params = (0..n).map(|i| format!("p{}", i)).collect::<Vec<_>>().join(" ");
+        let src = format!(
+            "(defun f ({params}) (list p0 p{last}))
+             (defun r ({params} & rest) (list p0 p{last} rest))
+             (list (f {args}) (apply (symf f) (list {args}))
+                   (r {args} {more}) (apply (symf r) 0 (list {args_rest} {more})))",
+            params = params,
+            last = n - 1,
+            args = numbers(0..n),
+            args_rest = numbers(1..n),
+            more = numbers(n..n + 2),
+        );
+
+        let expected = format!(
+            "((0 {last}) (0 {last}) (0 {last} ({n} {n1})) (0 {last} ({n} {n1})))",
+            last = n - 1,
+            n = n,
+            n1 = n + 1
+        );
+        assert_eq!(eval_last(&src), Ok(expected), "{} parameters", n);
+    }
+
+    assert_eq!(
+        eval_last(&format!("(+ {})", numbers(0..300))),
+        Ok("44850".to_string())
+    );
+    assert_eq!(
+        eval_last(&format!("(defun two (a b) a) (two {})", numbers(0..20))),
+        Err("runtime error: wrong number of arguments (20) passed to two".to_string())
+    );
+}
+
+#[test]
+fn test_too_many_parameters() {
+    let params = (0..256).map(|i| format!("p{}", i)).collect::<Vec<_>>().join(" ");
+
+    assert_eq!(
+        eval_last(&format!("(defun f256 ({}) p0)", params)),
+        Err("compilation error: f256 has 256 parameters, at most 255 are supported".to_string())
+    );
+}
+
+#[test]
+fn test_nesting_depth_limit_in_transforms() {
+    with_stdlib_ctx(|ctx| {
+        unlispc::repr::set_max_nesting_depth(50);
+
+        let nested = |depth| {
+            (0..depth).fold(unlispc::repr::Form::Integer(1), |form, _| {
+                unlispc::repr::Form::List(vec![form])
+            })
+        };
+        let quoted = |depth| {
+            unlispc::repr::Form::List(vec![
+                unlispc::repr::Form::Symbol("quote".to_string()),
+                nested(depth),
+            ])
+        };
+
+        let at_limit = unlispc::repr::form_to_hir_with_transforms(&quoted(49));
+        let beyond_limit = unlispc::repr::form_to_hir_with_transforms(&quoted(50));
+
+        let results = eval_in_ctx(
+            ctx,
+            "(defun nest (n) (if (equal n 0) 1 (list (nest (- n 1)))))
+             (defmacro deep-quote (n) (list (quote quote) (nest n)))
+             (deep-quote 10)
+             (deep-quote 60)",
+        );
+
+        unlispc::repr::set_max_nesting_depth(unlispc::repr::DEFAULT_MAX_NESTING_DEPTH);
+
+        assert!(at_limit.is_ok());
+        assert_eq!(
+            beyond_limit.unwrap_err().to_string(),
+            "compilation error: form nested deeper than 50 levels (see --max-nesting-depth)"
+        );
+        assert_eq!(results[2], Ok("((((((((((1))))))))))".to_string()));
+        assert_eq!(
+            results[3],
+            Err(
+                "macroexpansion error: form nested deeper than 50 levels (see --max-nesting-depth)"
+                    .to_string()
+            )
+        );
+    });
+}
+
+#[test]
+fn test_deeply_nested_constants() {
+    let depth = 2_000;
+    let src = format!("(quote {}1{})", "(".repeat(depth), ")".repeat(depth));
+
+    assert_eq!(
+        eval_last(&format!("(first (first {}))", src)),
+        Ok(format!("{}1{}", "(".repeat(depth - 2), ")".repeat(depth - 2)))
+    );
+}
+
+#[test]
+fn test_threads() {
+    assert_eq!(
+        eval_last(
+            "(let ((threads (mapcar (lambda (n) (spawn-thread (lambda () (fibo n))))
+                                    (list 20 20 20 20 20 20 20 20))))
+               (apply + (mapcar (lambda (th) (join-thread th)) threads)))"
+        ),
+        Ok((8 * 10946).to_string())
+    );
+    assert_eq!(
+        eval_last(
+            "(defvar shared 1)
+             (join-thread (spawn-thread (lambda () (set-symbol-value! (quote shared) 2))))
+             shared"
+        ),
+        Ok("2".to_string())
+    );
+    assert_eq!(
+        eval_last("(join-thread (spawn-thread (lambda () (error \"boom ~a\" 1))))"),
+        Err("runtime error: boom 1".to_string())
+    );
+    assert_eq!(
+        eval_last(
+            "(let ((failing (spawn-thread (lambda () (error \"boom\"))))
+                   (working (spawn-thread (lambda () (fibo 10)))))
+               (list (ignore-errors (join-thread failing)) (join-thread working)))"
+        ),
+        Ok("(nil 89)".to_string())
+    );
+    assert_eq!(
+        eval_last("(let ((th (spawn-thread (lambda () 1)))) (join-thread th) (join-thread th))"),
+        Err("runtime error: #<thread 0> was already joined".to_string())
+    );
+}
+
+#[test]
+fn test_channels() {
+    assert_eq!(
+        eval_last(
+            "(let ((requests (make-channel))
+                   (replies (make-channel)))
+               (let ((worker (spawn-thread
+                              (lambda ()
+                                (send replies (* 2 (recv requests)))
+                                (send replies (* 2 (recv requests)))))))
+                 (send requests 20)
+                 (send requests 21)
+                 (join-thread worker)
+                 (list (recv replies) (recv replies))))"
+        ),
+        Ok("(40 42)".to_string())
+    );
+    assert_eq!(
+        eval_last("(send 1 2)"),
+        Err("runtime error: expected a channel, got 1".to_string())
+    );
+}
+
+#[test]
+fn test_unwind_protect() {
+    assert_eq!(
+        eval_last(
+            "(defvar cleaned nil)
+             (list (block b
+                     (unwind-protect (return-from b 1)
+                       (set-symbol-value! (quote cleaned) t)))
+                   cleaned)"
+        ),
+        Ok("(1 t)".to_string())
+    );
+    assert_eq!(
+        eval_last(
+            "(defvar cleaned nil)
+             (list (ignore-errors
+                     (unwind-protect (error \"boom\")
+                       (set-symbol-value! (quote cleaned) t)))
+                   cleaned)"
+        ),
+        Ok("(nil t)".to_string())
+    );
+    assert_eq!(eval_last("(unwind-protect (+ 1 2) 4)"), Ok("3".to_string()));
+}
+
+#[test]
+fn test_with_open_file() {
+    let path = std::env::temp_dir().join(format!("unlisp-streams-{}.txt", std::process::id()));
+    let path = format!("{:?}", path.to_str().unwrap());
+
+    let results = eval_with_stdlib(&format!(
+        "(with-open-file (s {path} :direction :output)
+           (write-char #\\h s)
+           (write-char #\\é s)
+           (write-char #\\newline s)
+           (write-char #\\x s))
+         (with-open-file (s {path})
+           (list (read-line s) (read-char s) (read-char s) (read-line s)))
+         (defvar leaked nil)
+         (ignore-errors
+           (with-open-file (s {path} :direction :output)
+             (set-symbol-value! (quote leaked) s)
+             (write-char #\\y s)
+             (error \"boom\")))
+         (list leaked (with-open-file (s {path}) (read-line s)))
+         (with-open-file (s {path}) (write-char #\\z s))
+         (read-char leaked)",
+        path = path
+    ));
+
+    assert_eq!(results[1], Ok("(\"hé\" #\\x nil nil)".to_string()));
+    assert_eq!(
+        results[4],
+        Ok(format!("(#<closed output stream {}> \"y\")", path))
+    );
+    assert_eq!(
+        results[5],
+        Err(format!(
+            "runtime error: write-char: #<input stream {}> is not an output stream",
+            path
+        ))
+    );
+    assert_eq!(
+        results[6],
+        Err(format!(
+            "runtime error: read-char: #<closed output stream {}> is closed",
+            path
+        ))
+    );
+}
+
+#[test]
+fn test_format_destinations() {
+    let path = std::env::temp_dir().join(format!("unlisp-format-{}.txt", std::process::id()));
+    let path = format!("{:?}", path.to_str().unwrap());
+
+    let results = eval_with_stdlib(&format!(
+        "(format nil \"~a-~s\" 1 \"x\")
+         (format \"~a-~s\" 1 \"x\")
+         (with-open-file (s {path} :direction :output)
+           (list (format s \"~a~%\" (quote (1 2))) (format s \"~d\" 3)))
+         (with-open-file (s {path}) (list (read-line s) (read-line s)))
+         (format 1 \"x\")
+         (format nil)",
+        path = path
+    ));
+
+    assert_eq!(results[0], Ok("\"1-\\\"x\\\"\"".to_string()));
+    assert_eq!(results[1], results[0]);
+    assert_eq!(results[2], Ok("(nil nil)".to_string()));
+    assert_eq!(results[3], Ok("(\"(1 2)\" \"3\")".to_string()));
+    assert_eq!(
+        results[4],
+        Err(
+            "runtime error: format: expected nil, t or a stream as the destination, got 1"
+                .to_string()
+        )
+    );
+    assert_eq!(
+        results[5],
+        Err("runtime error: format: no control string".to_string())
+    );
+}
+
+#[test]
+fn test_symbol_case_modes() {
+    use unlisp_rt::escapes::SymbolCase;
+
+    // escaped names are read as written in every mode
+    let fixture = "(|list| (|quote| Foo) (|quote| |Foo|) (|quote| foo)
+                           (|equal| (|quote| Foo) (|quote| foo)))";
+    let cases = [
+        (SymbolCase::Preserve, "(Foo Foo foo nil)"),
+        (SymbolCase::Downcase, "(foo |Foo| foo foo)"),
+        (SymbolCase::Upcase, "(FOO |Foo| FOO FOO)"),
+    ];
+
+    for (case, expected) in cases.iter() {
+        let results = with_stdlib_ctx_in_case(*case, |ctx| eval_in_ctx(ctx, fixture));
+        assert_eq!(results, vec![Ok(expected.to_string())], "{:?}", case);
+    }
+
+    let results = with_stdlib_ctx_in_case(SymbolCase::Upcase, |ctx| {
+        eval_in_ctx(
+            ctx,
+            "(DEFUN Twice (x) (List X x))
+             (LET ((y (twice (QUOTE Foo)))) (IF Nil Y (list* (quote list) (quote |LIST|) y)))",
+        )
+    });
+    assert_eq!(
+        results,
+        vec![
+            Ok("nil".to_string()),
+            Ok("(list |LIST| FOO FOO)".to_string()),
+        ]
+    );
+
+    let results = with_stdlib_ctx_in_case(SymbolCase::Downcase, |ctx| {
+        eval_in_ctx(
+            ctx,
+            "(DEFVAR Counter 1)
+             (List counter (quote |Counter|) (Read (format nil \"~s\" (quote |Counter|))))
+             (%readtable-case preserve)
+             (quote Counter)",
+        )
+    });
+    assert_eq!(
+        results,
+        vec![
+            Ok("counter".to_string()),
+            Ok("(1 |Counter| |Counter|)".to_string()),
+            Ok("|Counter|".to_string()),
+        ]
+    );
+}
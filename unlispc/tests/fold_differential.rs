@@ -0,0 +1,113 @@
+//! Compares what the constant folder computes for numeric calls with what
+//! the runtime computes for the same calls when they aren't folded. Any
+//! operation the folder learns to fold should get operands here.
+mod common;
+
+use common::*;
+
+use unlisp_rt::defs::{ObjType, Object};
+use unlispc::codegen::context::CodegenContext;
+use unlispc::fold;
+use unlispc::reader::Reader;
+
+const OPERATORS: &[&str] = &["+", "-", "*", "<", ">", "equal"];
+
+const OPERANDS: &[&str] = &[
+    "0",
+    "1",
+    "-1",
+    "3",
+    "0.0",
+    "-0.0",
+    "0.1",
+    "-2.5",
+    "1e308",
+    "-1e308",
+    "5e-324",
+    "+inf.0",
+    "-inf.0",
+    "+nan.0",
+    "9007199254740993",
+    "9007199254740992.0",
+    "9223372036854775807",
+    "-9223372036854775808",
+];
+
+// operands of calls with three arguments, which are many more
+const SOME_OPERANDS: &[&str] = &["1", "-0.0", "0.1", "1e308", "+inf.0", "9007199254740993"];
+
+// floats by their bits, so 0.0 and -0.0 differ and so do NaN payloads
+fn describe(o: &Object) -> String {
+    if o.ty == ObjType::Float64 {
+        format!("float {:#018x}", o.unpack_float().to_bits())
+    } else {
+        o.to_string()
+    }
+}
+
+// the results of the calls, evaluated as a single list
+fn eval_calls(ctx: &mut CodegenContext, calls: &[String], folding: bool) -> Vec<String> {
+    let src = format!("(list {})", calls.join(" "));
+    let form = Reader::from_str(&src).read_form().unwrap().unwrap();
+
+    fold::set_constant_folding(folding);
+    let result = unsafe { ctx.eval_forms(&[form]) }.pop().unwrap();
+    fold::set_constant_folding(true);
+
+    let mut results = vec![];
+    let mut list = result.unwrap().unpack_list_like();
+    while !list.is_nil() {
+        results.push(describe(&list.car()));
+        list = list.cdr();
+    }
+
+    results
+}
+
+fn numeric_calls(operands: &[&str], arity: usize) -> Vec<String> {
+    let mut arg_lists = vec![String::new()];
+    for _ in 0..arity {
+        arg_lists = arg_lists
+            .iter()
+            .flat_map(|args| operands.iter().map(move |x| format!("{} {}", args, x)))
+            .collect();
+    }
+
+    OPERATORS
+        .iter()
+        .filter(|op| arity == 2 || !["<", ">", "equal"].contains(op))
+        .flat_map(|op| {
+            arg_lists
+                .iter()
+                .map(move |args| format!("({}{})", op, args))
+        })
+        .collect()
+}
+
+#[test]
+fn test_folded_numbers_match_the_runtime() {
+    let mut calls = numeric_calls(OPERANDS, 1);
+    calls.extend(numeric_calls(OPERANDS, 2));
+    calls.extend(numeric_calls(SOME_OPERANDS, 3));
+
+    let (folded, not_folded) = with_stdlib_ctx(|ctx| {
+        (
+            eval_calls(ctx, &calls, true),
+            eval_calls(ctx, &calls, false),
+        )
+    });
+
+    assert_eq!(folded.len(), calls.len());
+    assert_eq!(not_folded.len(), calls.len());
+
+    let divergences: Vec<_> = calls
+        .iter()
+        .zip(folded.iter().zip(not_folded.iter()))
+        .filter(|(_, (folded, not_folded))| folded != not_folded)
+        .map(|(call, (folded, not_folded))| {
+            format!("{}: folded {}, runtime {}", call, folded, not_folded)
+        })
+        .collect();
+
+    assert!(divergences.is_empty(), "\n{}", divergences.join("\n"));
+}
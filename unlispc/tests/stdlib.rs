@@ -0,0 +1,64 @@
+mod common;
+
+use common::*;
+
+use std::fs;
+
+use unlisp_rt::escapes::SymbolCase;
+
+fn stdlib_tests_path() -> String {
+    format!("{}/tests/stdlib-tests.unl", env!("CARGO_MANIFEST_DIR"))
+}
+
+fn failing_stdlib_tests(case: SymbolCase) -> Vec<String> {
+    with_stdlib_ctx_in_case(case, |ctx| {
+        let source = fs::read_to_string(stdlib_tests_path()).expect("stdlib tests file not found");
+
+        for result in eval_in_ctx(ctx, &source) {
+            result.expect("stdlib tests file shouldn't fail to load");
+        }
+
+        let outcomes = unsafe { unlisp_rt::test_registry::run_registered_tests() };
+        assert!(!outcomes.is_empty(), "no tests were registered");
+
+        outcomes
+            .into_iter()
+            .filter_map(|o| o.error.map(|e| format!("{}: {}", o.name, e)))
+            .collect::<Vec<_>>()
+    })
+}
+
+#[test]
+fn test_stdlib() {
+    let failures = failing_stdlib_tests(SymbolCase::Preserve);
+
+    assert!(
+        failures.is_empty(),
+        "failing stdlib tests:\n{}",
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn test_stdlib_with_downcased_symbols() {
+    let failures = failing_stdlib_tests(SymbolCase::Downcase);
+
+    assert!(
+        failures.is_empty(),
+        "failing stdlib tests:\n{}",
+        failures.join("\n")
+    );
+}
+
+// the names of the tests and their own definitions are read in uppercase,
+// the builtins they use in lowercase
+#[test]
+fn test_stdlib_with_upcased_symbols() {
+    let failures = failing_stdlib_tests(SymbolCase::Upcase);
+
+    assert!(
+        failures.is_empty(),
+        "failing stdlib tests:\n{}",
+        failures.join("\n")
+    );
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use common::*;
+use unlispc::codegen::context::CodegenContext;
+
+use std::thread;
+
+const FORMS_PER_THREAD: usize = 1000;
+
+fn run_worker(id: usize) -> Vec<Result<String, String>> {
+    unlisp_rt::defs::unlisp_rt_init_runtime();
+    let mut ctx = CodegenContext::new();
+
+    let mut src = format!("(defun worker-{} (x) (+ x {}))", id, id * 1000);
+    for i in 0..FORMS_PER_THREAD {
+        if i % 100 == 0 {
+            src.push_str(&format!(" (error \"worker {} form {}\")", id, i));
+        } else {
+            src.push_str(&format!(" (worker-{} {})", id, i));
+        }
+    }
+
+    eval_in_ctx(&mut ctx, &src)
+}
+
+#[test]
+fn test_contexts_on_different_threads() {
+    // workers use the stdlib loaded by this context, definitions are
+    // visible to every context in the process
+    with_stdlib_ctx(|ctx| {
+        let workers: Vec<_> = (1..=2)
+            .map(|id| thread::spawn(move || run_worker(id)))
+            .collect();
+
+        for (id, worker) in (1..=2).zip(workers) {
+            let results = worker.join().expect("worker thread panicked");
+            assert_eq!(results.len(), FORMS_PER_THREAD + 1);
+            assert_eq!(results[0], Ok("nil".to_string()));
+
+            for (i, result) in results[1..].iter().enumerate() {
+                let expected = if i % 100 == 0 {
+                    Err(format!("runtime error: worker {} form {}", id, i))
+                } else {
+                    Ok((i + id * 1000).to_string())
+                };
+                assert_eq!(*result, expected);
+            }
+
+            assert_eq!(
+                eval_in_ctx(ctx, &format!("(worker-{} 1)", id)),
+                vec![Ok((1 + id * 1000).to_string())]
+            );
+        }
+    });
+}